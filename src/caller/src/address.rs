@@ -0,0 +1,114 @@
+use ic_cdk::management_canister::BitcoinNetwork;
+use k256::elliptic_curve::sec1::ToEncodedPoint;
+use k256::PublicKey;
+use ripemd::Ripemd160;
+use sha2::{Digest, Sha256};
+use sha3::Keccak256;
+
+/// Encodes the pay-to-public-key-hash (P2PKH) address of a SEC1-encoded public key.
+// A P2PKH address is the base58check encoding of a version byte, which identifies the network,
+// followed by the HASH160 (RIPEMD-160 of SHA-256) of the public key. The checksum is the first
+// four bytes of the double SHA-256 of those bytes. The address commits to the exact encoding of
+// the key, so the compressed keys returned by `ecdsa_public_key` give compressed-key addresses.
+pub fn bitcoin_p2pkh_address(public_key: &[u8], network: BitcoinNetwork) -> String {
+    let version = match network {
+        BitcoinNetwork::Mainnet => 0x00,
+        BitcoinNetwork::Testnet | BitcoinNetwork::Regtest => 0x6f,
+    };
+    let mut payload = vec![version];
+    payload.extend(Ripemd160::digest(Sha256::digest(public_key)));
+    let checksum = Sha256::digest(Sha256::digest(&payload));
+    payload.extend(&checksum[..4]);
+    bs58::encode(payload).into_string()
+}
+
+/// Computes the EIP-55 checksummed Ethereum address of a SEC1-encoded secp256k1 public key.
+// The address is the last 20 bytes of the Keccak-256 hash of the uncompressed public key,
+// without its leading `0x04` tag byte. `ecdsa_public_key` returns compressed keys, so we first
+// have to decompress the key, which requires doing some curve arithmetic.
+pub fn ethereum_address(public_key: &[u8]) -> Result<String, String> {
+    let public_key = PublicKey::from_sec1_bytes(public_key)
+        .map_err(|e| format!("Invalid secp256k1 public key: {}", e))?;
+    let uncompressed = public_key.to_encoded_point(false);
+    let hash = Keccak256::digest(&uncompressed.as_bytes()[1..]);
+    Ok(eip55_checksum(&hash[12..]))
+}
+
+/// Hex-encodes a 20-byte address with the EIP-55 mixed-case checksum.
+// Each letter of the lowercase hex address is uppercased if the corresponding nibble of the
+// Keccak-256 hash of that lowercase hex string is 8 or more. Digits stay as they are.
+fn eip55_checksum(address: &[u8]) -> String {
+    let lowercase = hex::encode(address);
+    let hash = Keccak256::digest(lowercase.as_bytes());
+    let checksummed: String = lowercase
+        .chars()
+        .enumerate()
+        .map(|(i, c)| {
+            let nibble = (hash[i / 2] >> (if i % 2 == 0 { 4 } else { 0 })) & 0x0f;
+            if nibble >= 8 {
+                c.to_ascii_uppercase()
+            } else {
+                c
+            }
+        })
+        .collect();
+    format!("0x{}", checksummed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The example key from the Bitcoin wiki's "Technical background of version 1 Bitcoin
+    // addresses".
+    const PUBLIC_KEY: &str = "0250863ad64a87ae8a2fe83c1af1a8403cb53f53e486d8511dad8a04887e5b2352";
+
+    #[test]
+    fn test_mainnet_address() {
+        let public_key = hex::decode(PUBLIC_KEY).unwrap();
+        assert_eq!(
+            bitcoin_p2pkh_address(&public_key, BitcoinNetwork::Mainnet),
+            "1PMycacnJaSqwwJqjawXBErnLsZ7RkXUAs"
+        );
+    }
+
+    #[test]
+    fn test_testnet_address() {
+        let public_key = hex::decode(PUBLIC_KEY).unwrap();
+        let address = bitcoin_p2pkh_address(&public_key, BitcoinNetwork::Testnet);
+        // Testnet P2PKH addresses start with `m` or `n`.
+        assert!(address.starts_with('m') || address.starts_with('n'), "{}", address);
+        assert_eq!(address, bitcoin_p2pkh_address(&public_key, BitcoinNetwork::Regtest));
+    }
+
+    #[test]
+    fn test_ethereum_address() {
+        // The generator point, i.e., the public key of the private key 1, compressed.
+        let public_key =
+            hex::decode("0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798").unwrap();
+        assert_eq!(
+            ethereum_address(&public_key),
+            Ok("0x7E5F4552091A69125d5DfCb7b8C2659029395Bdf".to_string())
+        );
+    }
+
+    #[test]
+    fn test_eip55_checksum() {
+        // Test vectors from EIP-55.
+        for expected in [
+            "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed",
+            "0xfB6916095ca1df60bB79Ce92cE3Ea74c37c5d359",
+            "0xdbF03B407c01E7cD3CBea99509d93f8DDDC8C6FB",
+        ] {
+            let address = hex::decode(&expected[2..]).unwrap();
+            assert_eq!(eip55_checksum(&address), expected);
+        }
+    }
+
+    #[test]
+    fn test_ethereum_address_rejects_invalid_key() {
+        // 0x05 is not a valid SEC1 tag.
+        assert!(ethereum_address(&[0x05; 33]).is_err());
+        assert!(ethereum_address(&[0x02; 10]).is_err());
+    }
+}
@@ -0,0 +1,169 @@
+use candid::{Nat, Principal};
+use ic_cdk::call::Call;
+
+use crate::deadline::Deadline;
+
+/// How long `update_counter` keeps retrying before giving up, in seconds.
+pub const UPDATE_TIMEOUT_SECS: u64 = 60;
+
+/// The counter methods that `update_counter` relies on.
+pub trait CasCounter {
+    async fn get(&self) -> Result<Nat, String>;
+
+    /// Sets the value to `new` if it's `expected`, and returns `new`; otherwise, returns the
+    /// current value as the error.
+    async fn compare_and_swap(&self, expected: Nat, new: Nat) -> Result<Result<Nat, Nat>, String>;
+}
+
+// We use unbounded waits: with a bounded wait and a `SysUnknown` error, we couldn't tell whether
+// our swap happened, since a later failed swap might be due to our own earlier one.
+impl CasCounter for Principal {
+    async fn get(&self) -> Result<Nat, String> {
+        Call::unbounded_wait(*self, "get")
+            .call()
+            .await
+            .map_err(|e| format!("Failed to get the value: {:?}", e))
+    }
+
+    async fn compare_and_swap(&self, expected: Nat, new: Nat) -> Result<Result<Nat, Nat>, String> {
+        Call::unbounded_wait(*self, "compare_and_swap")
+            .with_args(&(expected, new))
+            .call()
+            .await
+            .map_err(|e| format!("Failed to swap the value: {:?}", e))
+    }
+}
+
+/// Replaces the counter's value `v` by `f(v)`, and returns the new value. Retries if someone
+/// else changed the value in the meantime, for up to `UPDATE_TIMEOUT_SECS`.
+pub async fn update_counter<F: Fn(Nat) -> Nat>(counter: Principal, f: F) -> Result<Nat, String> {
+    let deadline = Deadline::in_secs(UPDATE_TIMEOUT_SECS);
+    update_counter_until(&counter, f, deadline, ic_cdk::api::time).await
+}
+
+/// Like `update_counter`, but gives up once `deadline` passes, according to `now`.
+// Unlike `get` followed by `set`, this can't lose another caller's update: if the value changed
+// after we read it, the swap fails, and we apply `f` again to the value the counter returned.
+// Nothing is locked, so a slow caller never holds up the others; the price is that `f` may run
+// several times, so it must not have side effects.
+pub async fn update_counter_until<C: CasCounter, F: Fn(Nat) -> Nat>(
+    counter: &C,
+    f: F,
+    deadline: Deadline,
+    now: impl Fn() -> u64,
+) -> Result<Nat, String> {
+    let mut expected = counter.get().await?;
+    while !deadline.is_expired_at(now()) {
+        let new = f(expected.clone());
+        match counter.compare_and_swap(expected, new).await? {
+            Ok(new) => return Ok(new),
+            // Someone changed the value in the meantime; try again from the current value.
+            Err(current) => expected = current,
+        }
+    }
+    Err("The value kept changing until the deadline passed".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use std::cell::{Cell, RefCell};
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    struct YieldNow(bool);
+
+    impl Future for YieldNow {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            if self.0 {
+                Poll::Ready(())
+            } else {
+                self.0 = true;
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+    }
+
+    // A counter canister in memory. A read returns the value when the call was made, and lets
+    // other tasks run before the reply arrives, as on the IC.
+    struct MockCounter {
+        value: RefCell<Nat>,
+        swaps: Cell<u32>,
+    }
+
+    impl MockCounter {
+        fn new(value: u32) -> Self {
+            Self {
+                value: RefCell::new(Nat::from(value)),
+                swaps: Cell::new(0),
+            }
+        }
+    }
+
+    impl CasCounter for MockCounter {
+        async fn get(&self) -> Result<Nat, String> {
+            let value = self.value.borrow().clone();
+            YieldNow(false).await;
+            Ok(value)
+        }
+
+        async fn compare_and_swap(&self, expected: Nat, new: Nat) -> Result<Result<Nat, Nat>, String> {
+            self.swaps.set(self.swaps.get() + 1);
+            let mut value = self.value.borrow_mut();
+            if *value == expected {
+                *value = new.clone();
+                Ok(Ok(new))
+            } else {
+                Ok(Err(value.clone()))
+            }
+        }
+    }
+
+    fn double(v: Nat) -> Nat {
+        v * 2_u32
+    }
+
+    #[test]
+    fn test_uncontended_update() {
+        let counter = MockCounter::new(5);
+        let deadline = Deadline::in_secs_from(0, 60);
+
+        let result = block_on(update_counter_until(&counter, double, deadline, || 0));
+
+        assert_eq!(result, Ok(Nat::from(10_u32)));
+        assert_eq!(counter.swaps.get(), 1);
+    }
+
+    #[test]
+    fn test_concurrent_write_forces_retry() {
+        let counter = MockCounter::new(5);
+        let deadline = Deadline::in_secs_from(0, 60);
+
+        // The writer changes the value while our read is in flight, so our first swap fails.
+        let (result, ()) = block_on(async {
+            futures::join!(update_counter_until(&counter, double, deadline, || 0), async {
+                *counter.value.borrow_mut() = Nat::from(7_u32);
+            })
+        });
+
+        // The writer's value is doubled, not lost.
+        assert_eq!(result, Ok(Nat::from(14_u32)));
+        assert_eq!(counter.swaps.get(), 2);
+    }
+
+    #[test]
+    fn test_gives_up_after_deadline() {
+        let counter = MockCounter::new(5);
+        let deadline = Deadline::in_secs_from(0, 60);
+
+        let result = block_on(update_counter_until(&counter, double, deadline, || u64::MAX));
+
+        assert!(result.is_err());
+        assert_eq!(counter.swaps.get(), 0);
+    }
+}
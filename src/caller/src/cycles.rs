@@ -0,0 +1,265 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::msg_cycles_refunded;
+use ic_cdk::call::{Call, CallError, StateUnknown};
+use std::cell::RefCell;
+use std::future::Future;
+
+use crate::error::AppError;
+
+/// Running totals of the cycles attached to outbound calls, and of the cycles that came back.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CyclesReport {
+    pub attached: u128,
+    pub refunded: u128,
+}
+
+thread_local! {
+    static CYCLES_REPORT: RefCell<CyclesReport> = RefCell::new(CyclesReport::default());
+}
+
+/// Records a call that attached `attached` cycles, given our cycles balance just before issuing
+/// the call and just after its response came back.
+// Whatever the callee didn't accept is refunded to us, so the balance only drops by the accepted
+// amount (plus the fees for sending the call). Note that other messages may execute while we're
+// awaiting the response and change the balance too, so on a busy canister this is an estimate.
+pub fn record_call(attached: u128, balance_before: u128, balance_after: u128) {
+    let spent = balance_before.saturating_sub(balance_after);
+    record_refund(attached, attached.saturating_sub(spent));
+}
+
+/// Records a call that attached `attached` cycles, of which `refunded` came back.
+pub fn record_refund(attached: u128, refunded: u128) {
+    CYCLES_REPORT.with(|report| {
+        let mut report = report.borrow_mut();
+        report.attached += attached;
+        report.refunded += refunded;
+    });
+}
+
+/// Calls `method` on `target` with `cycles` attached, and returns the reply together with the
+/// number of cycles refunded. The refund is also added to the report.
+// Unlike `record_call`, this doesn't estimate the refund from our balance: right after a call
+// returns, the system tells us exactly how many of the attached cycles came back.
+pub async fn call_with_cycles_accounted<R: CandidType + for<'de> Deserialize<'de>>(
+    target: Principal,
+    method: &str,
+    arg_bytes: Vec<u8>,
+    cycles: u128,
+) -> Result<(R, u128), String> {
+    let call = async {
+        Call::bounded_wait(target, method)
+            .with_raw_args(&arg_bytes)
+            .with_cycles(cycles)
+            .call::<R>()
+            .await
+    };
+    with_refund_accounting(cycles, call, |result| refund_of(result, cycles))
+        .await
+        .map_err(|e| match e {
+            // The system doesn't know whether the callee accepted the cycles, so none are
+            // refunded, even if the call never reached the callee.
+            CallError::StateUnknown(StateUnknown::SysUnknown(e)) => format!(
+                "The outcome of {} is unknown ({:?}), and the attached cycles are lost",
+                method, e
+            ),
+            e => format!("Error calling {}: {:?}", method, e),
+        })
+}
+
+/// The cycles refunded for a call that attached `attached` cycles and came back with `result`.
+// A synchronously rejected call never left, so we keep all its cycles. There's no response to
+// read the refund from, either: `msg_cycles_refunded` would trap.
+fn refund_of<R>(result: &Result<R, CallError>, attached: u128) -> u128 {
+    match result {
+        Err(CallError::CallRejected(rejection)) if rejection.is_sync() => attached,
+        _ => msg_cycles_refunded(),
+    }
+}
+
+/// Awaits `call`, which attached `attached` cycles, and records the refund that `refunded`
+/// reports for its result.
+pub async fn with_refund_accounting<R, E>(
+    attached: u128,
+    call: impl Future<Output = Result<R, E>>,
+    refunded: impl FnOnce(&Result<R, E>) -> u128,
+) -> Result<(R, u128), E> {
+    let result = call.await;
+    let refunded = refunded(&result);
+    record_refund(attached, refunded);
+    result.map(|reply| (reply, refunded))
+}
+
+/// Returns the totals recorded so far.
+pub fn report() -> CyclesReport {
+    CYCLES_REPORT.with(|report| *report.borrow())
+}
+
+/// What a threshold signature costs on a 13-node subnet.
+const SIGNING_COST_13_NODES: u128 = 10_000_000_000;
+
+/// Returns the cycles to attach to a threshold ECDSA or Schnorr signing request for a key held
+/// on a subnet of `subnet_size` nodes.
+// Like most fees on the IC, the signing fee is quoted for a 13-node subnet and grows linearly
+// with the number of nodes: a 34-node subnet, such as the one holding the mainnet keys, charges
+// about 26 billion cycles. Rounding up means that we may attach a few cycles too many, which are
+// refunded, rather than too few, which fails the call.
+pub fn signing_cost(subnet_size: u32) -> u128 {
+    (SIGNING_COST_13_NODES * subnet_size as u128).div_ceil(13)
+}
+
+/// Checks that a balance of `available` cycles covers attaching `required` cycles to a call.
+pub fn ensure_cycles(available: u128, required: u128) -> Result<(), AppError> {
+    if available < required {
+        Err(AppError::InsufficientCycles {
+            required,
+            shortfall: required - available,
+        })
+    } else {
+        Ok(())
+    }
+}
+
+/// Tracks the cycles spent by a retry loop, so that it can stop once a budget is used up.
+pub struct RetryBudget {
+    max_cycles: u128,
+    spent: u128,
+}
+
+impl RetryBudget {
+    pub fn new(max_cycles: u128) -> Self {
+        Self {
+            max_cycles,
+            spent: 0,
+        }
+    }
+
+    /// Records one attempt, given our cycles balance before and after it.
+    pub fn charge(&mut self, balance_before: u128, balance_after: u128) {
+        self.spent += balance_before.saturating_sub(balance_after);
+    }
+
+    /// Returns true once the attempts so far cost at least the budget.
+    pub fn is_exhausted(&self) -> bool {
+        self.spent >= self.max_cycles
+    }
+
+    pub fn spent(&self) -> u128 {
+        self.spent
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signing_cost_scales_with_subnet_size() {
+        assert_eq!(signing_cost(13), 10_000_000_000);
+        assert_eq!(signing_cost(34), 26_153_846_154);
+        assert!(signing_cost(28) < signing_cost(34));
+        assert_eq!(signing_cost(26), 2 * signing_cost(13));
+    }
+
+    #[test]
+    fn test_report_starts_empty() {
+        assert_eq!(report(), CyclesReport::default());
+    }
+
+    #[test]
+    fn test_record_call_with_refund() {
+        // We attached 10 cycles, but our balance only dropped by 4, so 6 came back.
+        record_call(10, 100, 96);
+        assert_eq!(
+            report(),
+            CyclesReport {
+                attached: 10,
+                refunded: 6
+            }
+        );
+        // Nothing came back this time; the totals accumulate.
+        record_call(5, 96, 91);
+        assert_eq!(
+            report(),
+            CyclesReport {
+                attached: 15,
+                refunded: 6
+            }
+        );
+    }
+
+    #[test]
+    fn test_record_call_balance_grew() {
+        // Someone topped us up while we were waiting; we can't tell what was accepted, so we
+        // assume everything came back.
+        record_call(10, 100, 150);
+        assert_eq!(report().refunded, 10);
+    }
+
+    #[test]
+    fn test_refund_is_reported() {
+        let result = futures::executor::block_on(with_refund_accounting(
+            1_000,
+            async { Ok::<_, String>("signature") },
+            |_| 400,
+        ));
+        assert_eq!(result, Ok(("signature", 400)));
+        assert_eq!(
+            report(),
+            CyclesReport {
+                attached: 1_000,
+                refunded: 400
+            }
+        );
+    }
+
+    #[test]
+    fn test_refund_is_recorded_on_error() {
+        // Stands in for `refund_of`, which counts a call that never left as fully refunded
+        // without asking the system.
+        let result = futures::executor::block_on(with_refund_accounting(
+            1_000,
+            async { Err::<(), _>("rejected synchronously".to_string()) },
+            |result| match result {
+                Err(e) if e.ends_with("synchronously") => 1_000,
+                _ => panic!("The refund must not be read"),
+            },
+        ));
+        assert!(result.is_err());
+        assert_eq!(report().refunded, 1_000);
+    }
+
+    #[test]
+    fn test_ensure_cycles_below_signing_fee() {
+        assert_eq!(
+            ensure_cycles(4_000_000_000, 10_000_000_000),
+            Err(AppError::InsufficientCycles {
+                required: 10_000_000_000,
+                shortfall: 6_000_000_000
+            })
+        );
+        assert_eq!(ensure_cycles(10_000_000_000, 10_000_000_000), Ok(()));
+    }
+
+    #[test]
+    fn test_retry_budget_stops_loop() {
+        let mut budget = RetryBudget::new(1_000);
+        let mut balance = 10_000;
+        let mut attempts = 0;
+        // Each simulated attempt costs 300 cycles and fails, so we keep retrying until the
+        // budget runs out.
+        while !budget.is_exhausted() {
+            attempts += 1;
+            budget.charge(balance, balance - 300);
+            balance -= 300;
+        }
+        assert_eq!(attempts, 4);
+        assert_eq!(budget.spent(), 1_200);
+    }
+
+    #[test]
+    fn test_retry_budget_ignores_top_ups() {
+        let mut budget = RetryBudget::new(100);
+        budget.charge(1_000, 5_000);
+        assert!(!budget.is_exhausted());
+    }
+}
@@ -0,0 +1,76 @@
+use ic_cdk::api::time;
+use std::time::Duration;
+
+/// A point in IC time after which we stop retrying.
+// The methods without a `now` argument read the IC time; the `_at` variants take the current
+// time as an argument instead, which is what the tests use.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Deadline {
+    // Nanoseconds since the epoch, like `time()`.
+    at: u64,
+}
+
+impl Deadline {
+    /// The deadline `secs` seconds from now.
+    pub fn in_secs(secs: u64) -> Self {
+        Self::in_secs_from(time(), secs)
+    }
+
+    pub fn in_secs_from(now: u64, secs: u64) -> Self {
+        let timeout = secs.saturating_mul(1_000_000_000);
+        Self {
+            at: now.saturating_add(timeout),
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.is_expired_at(time())
+    }
+
+    pub fn is_expired_at(&self, now: u64) -> bool {
+        now > self.at
+    }
+
+    /// The time left until the deadline; zero once it has passed.
+    pub fn remaining(&self) -> Duration {
+        self.remaining_at(time())
+    }
+
+    pub fn remaining_at(&self, now: u64) -> Duration {
+        Duration::from_nanos(self.at.saturating_sub(now))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: u64 = 1_000_000_000;
+
+    #[test]
+    fn test_expiry() {
+        let now = 1_000 * SECOND;
+        let deadline = Deadline::in_secs_from(now, 60);
+        assert!(!deadline.is_expired_at(now));
+        assert!(!deadline.is_expired_at(now + 60 * SECOND));
+        assert!(deadline.is_expired_at(now + 60 * SECOND + 1));
+    }
+
+    #[test]
+    fn test_remaining() {
+        let now = 1_000 * SECOND;
+        let deadline = Deadline::in_secs_from(now, 60);
+        assert_eq!(deadline.remaining_at(now), Duration::from_secs(60));
+        assert_eq!(deadline.remaining_at(now + 45 * SECOND), Duration::from_secs(15));
+        assert_eq!(deadline.remaining_at(now + 61 * SECOND), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_far_deadline_saturates() {
+        let deadline = Deadline::in_secs_from(u64::MAX - 1, 60);
+        assert!(!deadline.is_expired_at(u64::MAX));
+        // Too many seconds to count in nanoseconds.
+        let deadline = Deadline::in_secs_from(0, u64::MAX);
+        assert!(!deadline.is_expired_at(u64::MAX));
+    }
+}
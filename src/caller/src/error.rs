@@ -0,0 +1,60 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::call::RejectCode;
+use std::fmt;
+
+/// Structured errors produced by our helpers. The endpoints turn them into strings.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum AppError {
+    /// We don't hold enough cycles to attach `required` cycles to a call.
+    InsufficientCycles { required: u128, shortfall: u128 },
+    /// No canister `target` exists, or it has no code installed. The user most likely passed
+    /// the wrong principal.
+    InvalidTarget { target: Principal },
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::InsufficientCycles { required, shortfall } => write!(
+                f,
+                "Insufficient cycles: the call requires {} cycles, and we are {} cycles short",
+                required, shortfall
+            ),
+            AppError::InvalidTarget { target } => write!(
+                f,
+                "Canister {} doesn't exist or is empty; check that you passed the right principal",
+                target
+            ),
+        }
+    }
+}
+
+impl From<AppError> for String {
+    fn from(e: AppError) -> String {
+        e.to_string()
+    }
+}
+
+/// Returns the error to report for a call to `target` that was rejected with `code`, if the
+/// reject means that the target doesn't exist.
+// Retrying such a call won't help, but the user can fix it, so we tell them what's wrong rather
+// than passing on the reject.
+pub fn invalid_target(target: Principal, code: RejectCode) -> Option<AppError> {
+    (code == RejectCode::DestinationInvalid).then_some(AppError::InvalidTarget { target })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_destination_invalid_is_an_invalid_target() {
+        let target = Principal::from_slice(&[9; 10]);
+        assert_eq!(
+            invalid_target(target, RejectCode::DestinationInvalid),
+            Some(AppError::InvalidTarget { target })
+        );
+        assert_eq!(invalid_target(target, RejectCode::SysTransient), None);
+        assert_eq!(invalid_target(target, RejectCode::CanisterReject), None);
+    }
+}
@@ -0,0 +1,117 @@
+use candid::{Nat, Principal};
+use futures::stream::{self, StreamExt};
+use std::future::Future;
+
+/// Reads each of `counters` with `get`, and returns the results in the same order. At most
+/// `max_concurrency` reads are in flight at any time; a limit of 0 is treated as 1.
+// Issuing all the calls at once, e.g., with `join_all`, is fastest, but a long list of counters
+// then means as many open calls at once. Each one reserves memory in our output queue and cycles
+// for the response, and the system rejects calls beyond its per-canister limits, as do HTTPS
+// outcalls beyond their quota. `buffer_unordered` starts the next call as soon as one finishes,
+// so the limit holds without waiting for whole batches.
+pub async fn get_many_limited<F, Fut>(
+    counters: Vec<Principal>,
+    max_concurrency: usize,
+    get: F,
+) -> Vec<Result<Nat, String>>
+where
+    F: Fn(Principal) -> Fut,
+    Fut: Future<Output = Result<Nat, String>>,
+{
+    // `buffer_unordered(0)` would place no limit at all.
+    let max_concurrency = max_concurrency.max(1);
+    let mut results: Vec<(usize, Result<Nat, String>)> = stream::iter(counters.into_iter().enumerate())
+        .map(|(i, counter)| {
+            let read = get(counter);
+            async move { (i, read.await) }
+        })
+        .buffer_unordered(max_concurrency)
+        .collect()
+        .await;
+    // The reads complete in any order; put the results back in the order of the counters.
+    results.sort_by_key(|(i, _)| *i);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use std::cell::Cell;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    // Stays pending for `polls` polls, like a call waiting for its reply.
+    struct Delay(u32);
+
+    impl Future for Delay {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            if self.0 == 0 {
+                Poll::Ready(())
+            } else {
+                self.0 -= 1;
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+    }
+
+    fn counters(n: u8) -> Vec<Principal> {
+        (0..n).map(|i| Principal::from_slice(&[i; 10])).collect()
+    }
+
+    // Reads every counter as its first byte, taking longer for the earlier counters, and records
+    // the most reads that were in flight at once.
+    fn get_all(n: u8, max_concurrency: usize) -> (Vec<Result<Nat, String>>, u32) {
+        let in_flight = Cell::new(0_u32);
+        let max_in_flight = Cell::new(0_u32);
+        let results = block_on(get_many_limited(counters(n), max_concurrency, |counter| {
+            let (in_flight, max_in_flight) = (&in_flight, &max_in_flight);
+            async move {
+                in_flight.set(in_flight.get() + 1);
+                max_in_flight.set(max_in_flight.get().max(in_flight.get()));
+                let byte = counter.as_slice()[0];
+                Delay(u32::from(n - byte)).await;
+                in_flight.set(in_flight.get() - 1);
+                if byte == 3 {
+                    Err("Counter 3 is down".to_string())
+                } else {
+                    Ok(Nat::from(byte))
+                }
+            }
+        }));
+        (results, max_in_flight.get())
+    }
+
+    #[test]
+    fn test_in_flight_calls_stay_within_limit() {
+        let (results, max_in_flight) = get_all(10, 3);
+
+        assert_eq!(max_in_flight, 3);
+        assert_eq!(results.len(), 10);
+    }
+
+    #[test]
+    fn test_results_keep_order_and_failures() {
+        let (results, _) = get_all(5, 2);
+
+        let expected = vec![
+            Ok(Nat::from(0_u8)),
+            Ok(Nat::from(1_u8)),
+            Ok(Nat::from(2_u8)),
+            Err("Counter 3 is down".to_string()),
+            Ok(Nat::from(4_u8)),
+        ];
+        assert_eq!(results, expected);
+    }
+
+    #[test]
+    fn test_zero_limit_runs_one_at_a_time() {
+        let (results, max_in_flight) = get_all(4, 0);
+
+        assert_eq!(max_in_flight, 1);
+        assert_eq!(results.len(), 4);
+    }
+}
@@ -0,0 +1,60 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk::management_canister::BitcoinNetwork;
+
+/// The argument of the management canister's `bitcoin_get_current_fee_percentiles`.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct GetCurrentFeePercentilesRequest {
+    pub network: BitcoinNetwork,
+}
+
+/// The cycles that `bitcoin_get_current_fee_percentiles` charges on `network`, as listed in the
+/// Bitcoin API documentation.
+pub fn fee_percentiles_cycles(network: BitcoinNetwork) -> u128 {
+    match network {
+        BitcoinNetwork::Mainnet => 100_000_000,
+        BitcoinNetwork::Testnet | BitcoinNetwork::Regtest => 40_000_000,
+    }
+}
+
+/// Decodes the reply of `bitcoin_get_current_fee_percentiles`: the 1st to 100th percentiles of
+/// the fees of the recent transactions, in millisatoshi per byte, lowest first.
+// The list is empty if there weren't any recent transactions to compute the percentiles from,
+// e.g., on a fresh regtest network. We pass that on, and leave it to the caller to pick a fee.
+pub fn decode_fee_percentiles(reply: &[u8]) -> Result<Vec<u64>, String> {
+    candid::decode_one(reply).map_err(|e| format!("Error decoding the fee percentiles: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_percentiles() {
+        let percentiles: Vec<u64> = (1..=100).map(|p| p * 1_000).collect();
+        let reply = candid::encode_one(&percentiles).unwrap();
+        let decoded = decode_fee_percentiles(&reply).unwrap();
+        assert_eq!(decoded.len(), 100);
+        // The median, in millisatoshi per byte.
+        assert_eq!(decoded[49], 50_000);
+    }
+
+    #[test]
+    fn test_decode_empty_percentiles() {
+        let reply = candid::encode_one(Vec::<u64>::new()).unwrap();
+        assert_eq!(decode_fee_percentiles(&reply), Ok(vec![]));
+    }
+
+    #[test]
+    fn test_decode_unexpected_reply() {
+        let reply = candid::encode_one("not percentiles").unwrap();
+        assert!(decode_fee_percentiles(&reply).is_err());
+    }
+
+    #[test]
+    fn test_mainnet_costs_more() {
+        assert!(
+            fee_percentiles_cycles(BitcoinNetwork::Mainnet)
+                > fee_percentiles_cycles(BitcoinNetwork::Testnet)
+        );
+    }
+}
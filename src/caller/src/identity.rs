@@ -0,0 +1,80 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::management_canister::BitcoinNetwork;
+use ic_ledger_types::{AccountIdentifier, DEFAULT_SUBACCOUNT};
+use std::cell::RefCell;
+
+use crate::address;
+
+/// Everything others need to send funds to this canister.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct IdentityInfo {
+    pub canister_id: Principal,
+    /// The P2PKH address of our threshold ECDSA key, on the network matching the key.
+    pub bitcoin_address: String,
+    /// The EIP-55 checksummed address of our threshold ECDSA key.
+    pub ethereum_address: String,
+    /// The hex-encoded ICP ledger account of our default subaccount.
+    pub icp_account_id: String,
+}
+
+thread_local! {
+    // The addresses only change with the key, which is fixed until the next upgrade, and heap
+    // state doesn't survive upgrades anyway.
+    static IDENTITY_INFO: RefCell<Option<IdentityInfo>> = const { RefCell::new(None) };
+}
+
+/// Derives the identity info of `canister_id` from its SEC1-encoded ECDSA `public_key`.
+pub fn identity_info_from(
+    canister_id: Principal,
+    public_key: &[u8],
+    network: BitcoinNetwork,
+) -> Result<IdentityInfo, String> {
+    Ok(IdentityInfo {
+        canister_id,
+        bitcoin_address: address::bitcoin_p2pkh_address(public_key, network),
+        ethereum_address: address::ethereum_address(public_key)?,
+        icp_account_id: AccountIdentifier::new(&canister_id, &DEFAULT_SUBACCOUNT).to_string(),
+    })
+}
+
+/// The identity info computed earlier, if any.
+pub fn cached() -> Option<IdentityInfo> {
+    IDENTITY_INFO.with(|i| i.borrow().clone())
+}
+
+pub fn cache(info: IdentityInfo) {
+    IDENTITY_INFO.with(|i| *i.borrow_mut() = Some(info));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The example key from the Bitcoin wiki, as in the `address` tests.
+    const PUBLIC_KEY: &str = "0250863ad64a87ae8a2fe83c1af1a8403cb53f53e486d8511dad8a04887e5b2352";
+
+    #[test]
+    fn test_all_fields_are_populated() {
+        let canister_id = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap();
+        let public_key = hex::decode(PUBLIC_KEY).unwrap();
+
+        let info = identity_info_from(canister_id, &public_key, BitcoinNetwork::Mainnet).unwrap();
+
+        assert_eq!(info.canister_id, canister_id);
+        assert_eq!(info.bitcoin_address, "1PMycacnJaSqwwJqjawXBErnLsZ7RkXUAs");
+        assert!(info.ethereum_address.starts_with("0x"), "{}", info.ethereum_address);
+        assert_eq!(info.ethereum_address.len(), 42);
+        // 4 bytes of checksum and 28 bytes of hash, hex-encoded.
+        assert_eq!(info.icp_account_id.len(), 64);
+    }
+
+    #[test]
+    fn test_info_is_cached() {
+        assert_eq!(cached(), None);
+        let public_key = hex::decode(PUBLIC_KEY).unwrap();
+        let info =
+            identity_info_from(Principal::anonymous(), &public_key, BitcoinNetwork::Testnet).unwrap();
+        cache(info.clone());
+        assert_eq!(cached(), Some(info));
+    }
+}
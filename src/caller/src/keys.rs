@@ -0,0 +1,91 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk::management_canister::BitcoinNetwork;
+use std::cell::Cell;
+
+/// Where the canister runs, which determines the threshold ECDSA key it can use.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum KeyEnv {
+    /// A local replica started by `dfx`.
+    #[default]
+    Local,
+    /// The mainnet, with the key meant for testing. It's cheaper, but may be rotated.
+    TestMainnet,
+    /// The mainnet, with the production key.
+    ProdMainnet,
+}
+
+impl KeyEnv {
+    /// The name of the secp256k1 key available in this environment.
+    pub fn key_name(self) -> &'static str {
+        match self {
+            KeyEnv::Local => "dfx_test_key",
+            KeyEnv::TestMainnet => "test_key_1",
+            KeyEnv::ProdMainnet => "key_1",
+        }
+    }
+
+    /// The number of nodes of the subnet holding the key, which determines the signing fee.
+    // The production key is held by the 34-node fiduciary subnet, and the test key by a 13-node
+    // subnet. The IC dashboard lists the nodes of each subnet, should the keys move. A local
+    // replica charges the fees of a 13-node subnet.
+    pub fn subnet_size(self) -> u32 {
+        match self {
+            KeyEnv::Local | KeyEnv::TestMainnet => 13,
+            KeyEnv::ProdMainnet => 34,
+        }
+    }
+
+    /// The Bitcoin network whose addresses we derive in this environment.
+    pub fn bitcoin_network(self) -> BitcoinNetwork {
+        match self {
+            KeyEnv::Local => BitcoinNetwork::Regtest,
+            KeyEnv::TestMainnet => BitcoinNetwork::Testnet,
+            KeyEnv::ProdMainnet => BitcoinNetwork::Mainnet,
+        }
+    }
+}
+
+thread_local! {
+    static KEY_ENV: Cell<KeyEnv> = const { Cell::new(KeyEnv::Local) };
+}
+
+pub fn set_key_env(env: KeyEnv) {
+    KEY_ENV.with(|e| e.set(env));
+}
+
+pub fn key_env() -> KeyEnv {
+    KEY_ENV.with(|e| e.get())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_names() {
+        assert_eq!(KeyEnv::Local.key_name(), "dfx_test_key");
+        assert_eq!(KeyEnv::TestMainnet.key_name(), "test_key_1");
+        assert_eq!(KeyEnv::ProdMainnet.key_name(), "key_1");
+    }
+
+    #[test]
+    fn test_subnet_sizes() {
+        assert_eq!(KeyEnv::Local.subnet_size(), 13);
+        assert_eq!(KeyEnv::TestMainnet.subnet_size(), 13);
+        assert_eq!(KeyEnv::ProdMainnet.subnet_size(), 34);
+    }
+
+    #[test]
+    fn test_bitcoin_networks() {
+        assert_eq!(KeyEnv::Local.bitcoin_network(), BitcoinNetwork::Regtest);
+        assert_eq!(KeyEnv::TestMainnet.bitcoin_network(), BitcoinNetwork::Testnet);
+        assert_eq!(KeyEnv::ProdMainnet.bitcoin_network(), BitcoinNetwork::Mainnet);
+    }
+
+    #[test]
+    fn test_env_defaults_to_local() {
+        assert_eq!(key_env(), KeyEnv::Local);
+        set_key_env(KeyEnv::ProdMainnet);
+        assert_eq!(key_env().key_name(), "key_1");
+    }
+}
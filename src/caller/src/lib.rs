@@ -1,17 +1,79 @@
 use candid::{Nat, Principal};
 use ic_cdk::api::management_canister::ecdsa::SignWithEcdsaResponse;
-use ic_cdk::api::time;
-use ic_cdk::call::{Call, CallError, RejectCode};
-use ic_cdk::management_canister::{EcdsaCurve, EcdsaKeyId, SignWithEcdsaArgs};
-use ic_cdk_macros::update;
+use ic_cdk::api::{canister_cycle_balance, msg_caller};
+use ic_cdk::call::{Call, CallError, RejectCode, StateUnknown};
+use ic_cdk::management_canister::{
+    BitcoinNetwork, EcdsaCurve, EcdsaKeyId, EcdsaPublicKeyArgs, EcdsaPublicKeyResult,
+    SignWithEcdsaArgs,
+};
+use ic_cdk_macros::{init, post_upgrade, query, update};
 use sha2::{Digest, Sha256};
 
+use deadline::Deadline;
+use identity::IdentityInfo;
+use keys::KeyEnv;
+use retry::RetryPolicy;
+use utxos::{GetUtxosRequest, GetUtxosResponse, Utxo, UtxoFilter};
+
+mod address;
+mod cas;
+mod cycles;
+mod deadline;
+mod error;
+mod fan_out;
+mod fee_percentiles;
+mod identity;
+mod keys;
+mod nonces;
+mod retry;
+mod shutdown;
+mod signature;
+mod utxos;
+
+/// Guard that rejects calls from the anonymous principal (`2vxsx-fae`).
+// Ingress messages can be sent anonymously, without any signature. Calls from other canisters
+// always carry the calling canister's principal, so they are never anonymous. Guards run before
+// the method body, so a rejected call has no effect. Each canister in this repository is a
+// standalone example with its own copy of this guard; keep the copies identical.
+fn reject_anonymous() -> Result<(), String> {
+    check_not_anonymous(msg_caller())
+}
+
+fn check_not_anonymous(caller: Principal) -> Result<(), String> {
+    if caller == Principal::anonymous() {
+        Err("Anonymous callers are not allowed to call this method".to_string())
+    } else {
+        Ok(())
+    }
+}
+
+/// Guard that only lets the canister's controllers through.
+fn controllers_only() -> Result<(), String> {
+    if ic_cdk::api::is_controller(&msg_caller()) {
+        Ok(())
+    } else {
+        Err("Only controllers can call this method".to_string())
+    }
+}
+
+/// Takes the environment that determines the ECDSA key to use, defaulting to a local replica.
+// Heap state doesn't survive upgrades, so the environment must be passed again on upgrade.
+#[init]
+fn init(env: Option<KeyEnv>) {
+    keys::set_key_env(env.unwrap_or_default());
+}
+
+#[post_upgrade]
+fn post_upgrade(env: Option<KeyEnv>) {
+    keys::set_key_env(env.unwrap_or_default());
+}
+
 // When calling other canisters:
 // 1. The simplest is to mark your function as `update`. Then you can always call any public
 //    endpoint on any other canister.
 // 2. Mark the function as `async`. Then you can use the `Call` API to call other canisters.
 // We expect the caller to provide the principal (i.e., ID) of the counter canister.
-#[update]
+#[update(guard = "reject_anonymous")]
 pub async fn call_get_and_set(counter: Principal, new_value: Nat) -> Nat {
     // To make a call, you must provide the principal (i.e., ID) of the canister you're
     // calling, and the method name that you're calling. Here, we require our own caller to provide
@@ -32,12 +94,21 @@ pub async fn call_get_and_set(counter: Principal, new_value: Nat) -> Nat {
         .call::<Nat>()
         .await
         // Calls can *always* fail. Robust applications must handle failures properly, but for this
-        // first example we just panic if an error happens.
-        .expect("Failed to get the old value. Bail out");
+        // first example we just panic if an error happens. We do single out a wrong principal,
+        // the likeliest mistake, so that the panic message tells the user what to fix.
+        .unwrap_or_else(|e| match e {
+            CallError::CallRejected(ref rejection) => {
+                match error::invalid_target(counter, rejection.reject_code()) {
+                    Some(error) => panic!("{}", error),
+                    None => panic!("Failed to get the old value. Bail out: {:?}", e),
+                }
+            }
+            e => panic!("Failed to get the old value. Bail out: {:?}", e),
+        });
     old
 }
 
-#[update]
+#[update(guard = "reject_anonymous")]
 pub async fn set_then_get(counter: Principal, new_value: Nat) -> Nat {
     Call::unbounded_wait(counter, "set")
         .with_arg(&new_value)
@@ -57,8 +128,9 @@ pub async fn set_then_get(counter: Principal, new_value: Nat) -> Nat {
     current_value
 }
 
-#[update]
+#[update(guard = "reject_anonymous")]
 pub async fn call_increment(counter: Principal) -> Result<(), String> {
+    let deadline = Deadline::in_secs(10 * 60);
     match Call::new(counter, "increment")
         .call::<()>()
         .await {
@@ -104,7 +176,7 @@ pub async fn call_increment(counter: Principal) -> Result<(), String> {
             // If the immediately_retryable() method
             if e.immediately_retryable() => {
                 // Even if we can retry, don't if we're out of time
-                if time() > deadline {
+                if deadline.is_expired() {
                     return Err("Timed out while trying to set the value".to_string());
                 } else {
                     continue
@@ -114,30 +186,32 @@ pub async fn call_increment(counter: Principal) -> Result<(), String> {
             // and provide some means of informing the caller once the call succeeds.
             CallError::CallRejected(_) =>
                 return Err(format!("Failed to get the value and cannot retry: {:?}", e)),
-            // In the `OutcomeUnknown` case, we don't know whether the call was executed.
+            // In the `StateUnknown` case, we don't know whether the call was executed.
             // The counter may be set to the value we provided, or it may not.
-            CallError::OutcomeUnknown(e) => match e {
+            CallError::StateUnknown(e) => match e {
                 // The first case is that the callee returned a result, but the
                 // deserialization of the result failed because the result wasn't of the
                 // type we specified. For example, we could have been passed a wrong
                 // principal for the counter canister, and could have called a canister that
                 // has a `set` method that returns a non-unit value.
-                OutcomeUnknown::CandidDecodingFailed(err, payload) =>
+                StateUnknown::CandidDecodeFailed(msg) =>
                 // We can't do much in this case; just report the error.
-                    return Err(format!("The counter canister returned an non-unit response: {:?} {:?}", err, payload)),
+                    return Err(format!("The counter canister returned an non-unit response: {}", msg)),
                 // The callee trapped while processing our request. Our call may or may
                 // not have taken effect.
-                OutcomeUnknown::CanisterError(err) =>
+                StateUnknown::CanisterError(err) =>
                 // We could try to get the value to see if it was set as a form of error
                 // recovery. But for now, we'll just report the error back.
                     return Err(format!("The counter canister returned an error while trying to get the value: {:?}", err)),
                 // This error type distinguishes bounded-wait calls from unbounded-wait calls. It means that the
                 // system gave up waiting for the response, and the call may or may not have been executed.
-                OutcomeUnknown::SysUnknown => {
+                // The payload describes what the system knows about the call; we pass it on
+                // if we give up.
+                StateUnknown::SysUnknown(unknown) => {
                     // Since the `set` method is idempotent, we can retry, even if it
                     // already executed. However, let's first check if we're out of time.
-                    if time() > deadline {
-                        return Err("Timed out while trying to set the value".to_string());
+                    if deadline.is_expired() {
+                        return Err(format!("Timed out while trying to set the value: {:?}", unknown));
                     } else {
                         continue
                     }
@@ -147,16 +221,71 @@ pub async fn call_increment(counter: Principal) -> Result<(), String> {
     }
 }
 
+/// Sets the counter with its `set_checked` method, which rejects values that are too large.
+#[update(guard = "reject_anonymous")]
+pub async fn call_set_checked(counter: Principal, value: Nat) -> Result<(), String> {
+    Call::bounded_wait(counter, "set_checked")
+        .with_arg(&value)
+        .call::<()>()
+        .await
+        .map_err(call_set_checked_error)
+}
+
+fn call_set_checked_error(e: CallError) -> String {
+    match e {
+        CallError::CallRejected(e) => set_checked_error(e.reject_code(), &e.reject_message()),
+        e => format!("The outcome of setting the value is unknown: {:?}", e),
+    }
+}
+
+// Of all reject codes, only `CanisterReject` comes from the callee's code (here, its call to
+// `msg_reject`); the others come from the system. The callee's message is meant for us, so we
+// pass it on as is.
+fn set_checked_error(code: RejectCode, message: &str) -> String {
+    match code {
+        RejectCode::CanisterReject => format!("The counter refused the value: {}", message),
+        code => format!("The call was rejected by the system: {:?} {}", code, message),
+    }
+}
+
+/// Increments the counter with its `compare_and_swap` method, and returns the value it was
+/// incremented to. See `cas::update_counter` for how concurrent updates are handled.
+#[update(guard = "reject_anonymous")]
+pub async fn cas_increment(counter: Principal) -> Result<Nat, String> {
+    let _in_flight = shutdown::begin()?;
+    cas::update_counter(counter, |v| v + 1_u32).await
+}
+
+/// Adds two numbers using the counter's `add` method, which takes two arguments.
+#[update(guard = "reject_anonymous")]
+pub async fn call_multi_arg(target: Principal, a: Nat, b: Nat) -> Result<Nat, String> {
+    // Candid methods take a *sequence* of arguments, and `with_arg` encodes a sequence of just one.
+    // For several arguments, pass them as a tuple to `with_args`, which encodes each element of
+    // the tuple as a separate argument, matching the callee's `(nat, nat)` signature.
+    // Note the difference to `with_arg(&(a, b))`: that encodes a *single* argument, a record with
+    // two fields, which the callee can't decode as two `nat`s, and rejects.
+    Call::bounded_wait(target, "add")
+        .with_args(&(a, b))
+        .call::<Nat>()
+        .await
+        .map_err(|e| format!("Failed to add the numbers: {:?}", e))
+}
+
 /// Retries setting the counter to the provided value even if errors appear, until it succeeds,
-/// times out, or hits an unrecoverable error.
-#[update]
+/// runs out of attempts or time under `RetryPolicy::default()`, or hits an unrecoverable error.
+#[update(guard = "reject_anonymous")]
 pub async fn stubborn_set(counter: Principal, value: Nat) -> Result<(), String> {
-    // Let's set a timeout to 10 minutes.
-    let timeout = std::time::Duration::from_secs(10 * 60).as_nanos() as u64;
-    // Compute the deadline based on the current IC time.
-    let deadline = time() + timeout;
+    // Retrying keeps a call context open, and a canister with open call contexts can't finish
+    // stopping. So we don't start retrying once a stop is being prepared; see `prepare_stop`.
+    let _in_flight = shutdown::begin()?;
+    // The policy bounds both the number of attempts and the time, starting from the current IC
+    // time, and spaces out the retries.
+    let policy = RetryPolicy::default();
+    let started_at = ic_cdk::api::time();
+    let mut attempts = 0;
     // We'll try to set the counter to the provided value, retrying where possible.
     loop {
+        attempts += 1;
         // Bounded-wait calls are guaranteed to respond even if the callee takes a long
         // time to respond (or never responds). This is useful when you want to always provide
         // an answer quickly, and also when calling canisters that you don't trust to respond
@@ -166,7 +295,7 @@ pub async fn stubborn_set(counter: Principal, value: Nat) -> Result<(), String>
             .with_arg(&value)
             .call().await {
 
-            Ok(()) => return (),
+            Ok(()) => return Ok(()),
             // Let's look into errors in more detail
             Err(e) => match e {
                 // In the `CallRejected` case, we know that the call wasn't executed.
@@ -179,20 +308,21 @@ pub async fn stubborn_set(counter: Principal, value: Nat) -> Result<(), String>
                 CallError::CallRejected(e)
                     // Check if we can retry immediately
                     if e.immediately_retryable() => {
-                    // Even if we can retry, don't if we're out of time
-                    if time() > deadline {
-                        return Err("Timed out while trying to set the value".to_string());
-                    } else {
-                        continue
+                    // Even if we can retry, don't if we're out of attempts or time. Retrying
+                    // immediately is allowed, but giving the system a moment makes the retry
+                    // more likely to succeed.
+                    match policy.next_delay(attempts, started_at, ic_cdk::api::time()) {
+                        Some(delay) => retry::sleep(delay).await,
+                        None => return Err(format!("Gave up setting the value after {} attempts", attempts)),
                     }
                 },
                 // We can't immediately retry. We could retry in the background using timers,
                 // and provide some means of informing the caller once the call succeeds.
                 CallError::CallRejected(_) =>
                     return Err(format!("Failed to get the value and cannot retry: {:?}", e)),
-                // In the `OutcomeUnknown` case, we don't know whether the call was executed.
+                // In the `StateUnknown` case, we don't know whether the call was executed.
                 // The counter may be set to the value we provided, or it may not.
-                CallError::OutcomeUnknown(e) => match e {
+                CallError::StateUnknown(e) => match e {
                     // The first case is that the callee returned a result, but the
                     // deserialization of the result failed because the result wasn't of the
                     // type we specified. For example, we could have been passed a wrong
@@ -200,14 +330,14 @@ pub async fn stubborn_set(counter: Principal, value: Nat) -> Result<(), String>
                     // has a `set` method that returns a non-unit value.
                     // We don't expect this to happen in our example because we tightly control
                     // the callee (counter).
-                    OutcomeUnknown::CandidDecodingFailed(err, payload) =>
+                    StateUnknown::CandidDecodeFailed(msg) =>
                         // We can't do much in this case; just report the error.
-                        return Err(format!("The counter canister returned an non-unit response: {:?} {:?}", err, payload)),
+                        return Err(format!("The counter canister returned an non-unit response: {}", msg)),
                     // The callee trapped while processing our request. Our call may or may
                     // not have taken effect, i.e., the counter may or may not have been set.
                     // However, our callee is so simple that we can reasonably expect this case
                     // not to happen.
-                    OutcomeUnknown::CanisterError(err) =>
+                    StateUnknown::CanisterError(err) =>
                         // There is no immediately clear recovery action for our example.
                         // For example, we could retry setting the counter, but that might fail with
                         // the same error again. Let's just report the error back.
@@ -218,15 +348,19 @@ pub async fn stubborn_set(counter: Principal, value: Nat) -> Result<(), String>
                     // This error type distinguishes bounded-wait calls from unbounded-wait calls.
                     // That is, unbounded-wait calls have the exact same error cases, except that
                     // this one is unreachable.
-                    OutcomeUnknown::SysUnknown => {
+                    // The payload describes what the system knows about the call, which we
+                    // report if we give up.
+                    StateUnknown::SysUnknown(unknown) => {
                         // We can safely retry here, but only because the `set` method is idempotent.
                         // Even if it was already executed, there is no harm in executing it again.
-                        // Let's do that, but let's first check if we're out of time, since we don't
-                        // want to retry forever.
-                        if time() > deadline {
-                            return Err("Timed out while trying to set the value".to_string());
-                        } else {
-                            continue
+                        // Let's do that, but let's first check if we're out of attempts or time,
+                        // since we don't want to retry forever.
+                        match policy.next_delay(attempts, started_at, ic_cdk::api::time()) {
+                            Some(delay) => retry::sleep(delay).await,
+                            None => return Err(format!(
+                                "Gave up setting the value after {} attempts; the last one's outcome is unknown: {:?}",
+                                attempts, unknown
+                            )),
                         }
                     },
                 },
@@ -235,41 +369,354 @@ pub async fn stubborn_set(counter: Principal, value: Nat) -> Result<(), String>
     }
 }
 
-#[update]
+/// Stops accepting new calls to the methods that retry (`stubborn_set`, `stubborn_set_budget`,
+/// and `cas_increment`), and returns how many of them are still running. Once this returns 0,
+/// stopping the canister doesn't cut any of them short. Upgrading the canister lifts the ban.
+// `stop_canister` waits for all open call contexts to close, but a method that keeps retrying
+// may keep its context open until its deadline, and a stopping canister rejects the calls that
+// the method retries with. Letting the retries run out first, while the canister still works,
+// loses no work.
+#[update(guard = "controllers_only")]
+pub fn prepare_stop() -> u64 {
+    shutdown::drain()
+}
+
+/// Returns how many calls to the methods that retry are running.
+#[query]
+pub fn inflight_count() -> u64 {
+    shutdown::in_flight()
+}
+
+/// Reads the value of each of `counters`, in the same order, with at most `max_concurrency`
+/// calls in flight at any time. A counter that can't be read doesn't affect the others.
+#[update(guard = "reject_anonymous")]
+pub async fn get_many_counters_limited(
+    counters: Vec<Principal>,
+    max_concurrency: u32,
+) -> Vec<Result<Nat, String>> {
+    fan_out::get_many_limited(counters, max_concurrency as usize, |counter| async move {
+        Call::bounded_wait(counter, "get")
+            .call::<Nat>()
+            .await
+            .map_err(|e| format!("Failed to get the value of {}: {:?}", counter, e))
+    })
+    .await
+}
+
+/// Like `stubborn_set`, but instead of a deadline, stops retrying once the attempts cost more
+/// than `max_cycles` cycles in total.
+// Time isn't always the scarcest resource: a canister with a small cycles balance may prefer to
+// bound how much it spends on retries. We measure the cost of each attempt by comparing our
+// balance before and after it. This includes the fees for sending the call and receiving the
+// response.
+#[update(guard = "reject_anonymous")]
+pub async fn stubborn_set_budget(counter: Principal, value: Nat, max_cycles: u128) -> Result<(), String> {
+    let _in_flight = shutdown::begin()?;
+    let mut budget = cycles::RetryBudget::new(max_cycles);
+    loop {
+        let balance_before = canister_cycle_balance();
+        let result = Call::bounded_wait(counter, "set")
+            .with_arg(&value)
+            .call::<()>()
+            .await;
+        budget.charge(balance_before, canister_cycle_balance());
+
+        match result {
+            Ok(()) => return Ok(()),
+            // The same cases are retryable as in `stubborn_set`: rejections that we can retry
+            // immediately, and `SysUnknown`, since `set` is idempotent.
+            Err(CallError::CallRejected(e)) if e.immediately_retryable() => (),
+            Err(CallError::StateUnknown(StateUnknown::SysUnknown(_))) => (),
+            Err(e) => return Err(format!("Failed to set the value and cannot retry: {:?}", e)),
+        }
+
+        if budget.is_exhausted() {
+            return Err(format!(
+                "Gave up setting the value after spending {} cycles",
+                budget.spent()
+            ));
+        }
+    }
+}
+
+/// Signs the SHA-256 hash of `message` with our key, and returns the hex-encoded signature.
+// Our key is the one behind our Bitcoin and Ethereum addresses, so whoever can sign with it can
+// spend from them. Only controllers may sign.
+#[update(guard = "controllers_only")]
 pub async fn sign_message(message: String) -> Result<String, String> {
     let message_hash = Sha256::digest(&message).to_vec();
+    // We don't use the fancier signing features here
+    let signature = sign_digest(message_hash, vec![]).await?;
+    Ok(hex::encode(signature))
+}
+
+/// Like `sign_message`, but returns the hex-encoded signature in ASN.1 DER, the encoding that
+/// Bitcoin transactions use.
+#[update(guard = "controllers_only")]
+pub async fn sign_message_der(message: String) -> Result<String, String> {
+    let message_hash = Sha256::digest(&message).to_vec();
+    let raw = sign_digest(message_hash, vec![]).await?;
+    Ok(hex::encode(signature::to_der(&raw)?))
+}
+
+/// Signs a pre-computed 32-byte digest, such as a Bitcoin sighash or the Keccak-256 hash of an
+/// Ethereum transaction, with our key derived along `derivation_path`. Returns the 64-byte
+/// signature (r || s).
+// The canister doesn't hash the digest again, so it's up to the caller to compute it the way
+// the target chain expects. The empty derivation path gives the key behind our addresses, and we
+// sign with the key of the environment we were installed for, so like `sign_message`, this is
+// for controllers only.
+#[update(guard = "controllers_only")]
+pub async fn sign_digest(digest: Vec<u8>, derivation_path: Vec<Vec<u8>>) -> Result<Vec<u8>, String> {
+    signature::check_digest(&digest)?;
+    let key_env = keys::key_env();
 
     let request = SignWithEcdsaArgs {
-        message_hash,
-        // We don't use the fancier signing features here
-        derivation_path: vec![],
-        key_id: EcdsaKeyId {
-            curve: EcdsaCurve::Secp256k1,
-            // This is the key name used for local testing; different
-            // key names are needed for the mainnet
-            name: "dfx_test_key".to_string(),
-        },
+        message_hash: digest,
+        derivation_path,
+        key_id: ecdsa_key_id(key_env),
     };
 
+    // The fee depends on the size of the subnet holding the key, see `KeyEnv::subnet_size`.
+    let signing_fee = cycles::signing_cost(key_env.subnet_size());
+
+    // Report a clear error if we can't afford the signature, rather than failing the call.
+    cycles::ensure_cycles(canister_cycle_balance(), signing_fee)?;
+
     // We use bounded-wait calls in this example, since the amount attached is
-    // fairly low, and losing the attached cycles isn't catastrophic.
-    match Call::bounded_wait(Principal::management_canister(), "sign_with_ecdsa")
+    // fairly low, and losing the attached cycles isn't catastrophic. The wrapper records how many
+    // of the attached cycles came back, see `cycles_report`.
+    let (signature, _refunded) = cycles::call_with_cycles_accounted::<SignWithEcdsaResponse>(
+        Principal::management_canister(),
+        "sign_with_ecdsa",
+        candid::encode_one(&request).unwrap(),
+        signing_fee,
+    )
+    .await
+    .map_err(|e| format!("Error signing digest: {}", e))?;
+    Ok(signature.signature)
+}
+
+/// The threshold ECDSA key of `env`, which we sign with and derive addresses from.
+fn ecdsa_key_id(env: KeyEnv) -> EcdsaKeyId {
+    EcdsaKeyId {
+        curve: EcdsaCurve::Secp256k1,
+        // Each environment has its own key names; see `KeyEnv`.
+        name: env.key_name().to_string(),
+    }
+}
+
+/// Fetches our ECDSA public key, i.e., the key that verifies the signatures of `sign_message`,
+/// in compressed SEC1 encoding.
+// Unlike signing, getting the public key doesn't cost any cycles. The key only changes if the
+// derivation path or the key ID do, so a production canister would cache it.
+async fn get_ecdsa_public_key() -> Result<Vec<u8>, String> {
+    let request = EcdsaPublicKeyArgs {
+        // `None` stands for our own canister.
+        canister_id: None,
+        derivation_path: vec![],
+        key_id: ecdsa_key_id(keys::key_env()),
+    };
+    Call::bounded_wait(Principal::management_canister(), "ecdsa_public_key")
         .with_arg(&request)
-        // Signing with a test key requires 10 billion cycles
-        .with_cycles(10_000_000_000)
-        .call::<SignWithEcdsaResponse>()
+        .call::<EcdsaPublicKeyResult>()
         .await
-    {
-        Ok(signature) => Ok(hex::encode(signature.signature)),
-        Err(e) => match e {
-            // A SysUnknown error means that we won't get any cycles refunded, even
-            // if the call didn't make it to the callee. But we don't care here since
-            // we only attached a small amount of cycles.
-            CallError::OutComeUnknown(OutcomeUnknown::SysUnknown(err)) => Err(format!(
-                "Got a SysUnknown error while signing message: {:?}; cycles are not refunded",
-                err
-            )),
-            _ => Err(format!("Error signing message: {:?}", e)),
-        },
+        .map(|result| result.public_key)
+        .map_err(|e| format!("Error getting the ECDSA public key: {:?}", e))
+}
+
+/// Returns the Bitcoin P2PKH address controlled by our threshold ECDSA key on `network`.
+#[update(guard = "reject_anonymous")]
+pub async fn bitcoin_p2pkh_address(network: BitcoinNetwork) -> Result<String, String> {
+    let public_key = get_ecdsa_public_key().await?;
+    Ok(address::bitcoin_p2pkh_address(&public_key, network))
+}
+
+/// Returns the Ethereum address controlled by our threshold ECDSA key, with EIP-55 checksum
+/// casing.
+#[update(guard = "reject_anonymous")]
+pub async fn ethereum_address() -> Result<String, String> {
+    let public_key = get_ecdsa_public_key().await?;
+    address::ethereum_address(&public_key)
+}
+
+/// Returns our own principal, and the addresses at which we can receive Bitcoin, Ether and ICP,
+/// so that frontends can get all of them with one call.
+// Deriving the addresses requires fetching our public key, which costs cycles and a round trip,
+// so we do it only on the first call.
+#[update(guard = "reject_anonymous")]
+pub async fn identity_info() -> Result<IdentityInfo, String> {
+    if let Some(info) = identity::cached() {
+        return Ok(info);
+    }
+    let public_key = get_ecdsa_public_key().await?;
+    let info = identity::identity_info_from(
+        ic_cdk::api::canister_self(),
+        &public_key,
+        keys::key_env().bitcoin_network(),
+    )?;
+    identity::cache(info.clone());
+    Ok(info)
+}
+
+/// Returns the nonce to use for the next transaction sent from our Ethereum address. Pass the
+/// address's `eth_getTransactionCount` (e.g., as obtained via the EVM RPC canister), if known, to
+/// bring our count up to date with the chain first.
+// Only those who can sign transactions need nonces, so like signing, this is for controllers.
+// Anyone else could use up nonces, or move our count far ahead of the chain.
+#[update(guard = "controllers_only")]
+pub async fn next_eth_nonce(transaction_count: Option<u64>) -> Result<u64, String> {
+    let address = ethereum_address().await?;
+    if let Some(count) = transaction_count {
+        nonces::reconcile(&address, count)?;
+    }
+    nonces::next_nonce(&address)
+}
+
+/// Verifies a signature produced by `sign_message` against a public key, both hex-encoded.
+/// Use it to check signatures without leaving the IC.
+#[query]
+pub fn verify_ecdsa(message: String, signature_hex: String, pubkey_hex: String) -> Result<bool, String> {
+    signature::verify(&message, &signature_hex, &pubkey_hex)
+}
+
+/// The cycles to attach to a `bitcoin_get_utxos` call. Whatever the Bitcoin canister doesn't
+/// charge is refunded.
+fn get_utxos_cycles(network: BitcoinNetwork) -> u128 {
+    match network {
+        BitcoinNetwork::Mainnet => 10_000_000_000,
+        BitcoinNetwork::Testnet | BitcoinNetwork::Regtest => 4_000_000_000,
+    }
+}
+
+async fn fetch_utxos_page(
+    address: String,
+    network: BitcoinNetwork,
+    page: Option<Vec<u8>>,
+) -> Result<GetUtxosResponse, String> {
+    let fee = get_utxos_cycles(network);
+    let balance_before = canister_cycle_balance();
+    cycles::ensure_cycles(balance_before, fee)?;
+    let request = GetUtxosRequest {
+        address,
+        network,
+        filter: page.map(UtxoFilter::Page),
+    };
+    let result = Call::unbounded_wait(Principal::management_canister(), "bitcoin_get_utxos")
+        .with_arg(&request)
+        .with_cycles(fee)
+        .call::<GetUtxosResponse>()
+        .await;
+    cycles::record_call(fee, balance_before, canister_cycle_balance());
+    result.map_err(|e| format!("Error getting the UTXOs of {}: {:?}", request.address, e))
+}
+
+/// Fetches all UTXOs of a Bitcoin address, page by page, into stable memory, replacing the
+/// UTXOs stored before. Returns how many there are; read them with `read_utxos`.
+#[update(guard = "reject_anonymous")]
+pub async fn store_utxos(address: String, network: BitcoinNetwork) -> Result<u64, String> {
+    utxos::replace_stored_utxos(|page| fetch_utxos_page(address.clone(), network, page)).await
+}
+
+/// Returns the current Bitcoin fee percentiles on `network`, in millisatoshi per byte, to
+/// estimate the fee of a transaction before building it.
+#[update(guard = "reject_anonymous")]
+pub async fn bitcoin_fee_percentiles(network: BitcoinNetwork) -> Result<Vec<u64>, String> {
+    let fee = fee_percentiles::fee_percentiles_cycles(network);
+    let balance_before = canister_cycle_balance();
+    cycles::ensure_cycles(balance_before, fee)?;
+    let request = fee_percentiles::GetCurrentFeePercentilesRequest { network };
+    let result = Call::unbounded_wait(
+        Principal::management_canister(),
+        "bitcoin_get_current_fee_percentiles",
+    )
+    .with_arg(&request)
+    .with_cycles(fee)
+    .call_raw()
+    .await;
+    cycles::record_call(fee, balance_before, canister_cycle_balance());
+    let reply = result.map_err(|e| format!("Error getting the fee percentiles: {:?}", e))?;
+    fee_percentiles::decode_fee_percentiles(&reply)
+}
+
+/// Returns up to `limit` of the UTXOs stored by `store_utxos`, starting at index `offset`.
+#[query]
+pub fn read_utxos(offset: u64, limit: u16) -> Vec<Utxo> {
+    utxos::read_utxos(offset, limit)
+}
+
+/// Returns the total cycles attached to outbound calls so far, and how many of them were refunded.
+#[query]
+pub fn cycles_report() -> (u128, u128) {
+    let report = cycles::report();
+    (report.attached, report.refunded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    #[test]
+    fn test_caller_sees_custom_reject_message() {
+        // The message that the counter's `set_checked` rejects a large value with.
+        let message = "The value 2_000_000 exceeds the maximum of 1_000_000";
+        assert_eq!(
+            set_checked_error(RejectCode::CanisterReject, message),
+            format!("The counter refused the value: {}", message)
+        );
+    }
+
+    #[test]
+    fn test_two_args_round_trip() {
+        // What `with_args(&(a, b))` sends, and how the callee decodes it.
+        let bytes = candid::encode_args((Nat::from(2_u32), Nat::from(3_u32))).unwrap();
+        let (a, b): (Nat, Nat) = candid::decode_args(&bytes).unwrap();
+        assert_eq!((a, b), (Nat::from(2_u32), Nat::from(3_u32)));
+        // A tuple passed as one argument is a record, not two `nat`s.
+        let bytes = candid::encode_one((Nat::from(2_u32), Nat::from(3_u32))).unwrap();
+        assert!(candid::decode_args::<(Nat, Nat)>(&bytes).is_err());
+    }
+
+    #[test]
+    fn test_stubborn_set_is_rejected_after_prepare_stop() {
+        assert_eq!(prepare_stop(), 0);
+
+        let result = block_on(stubborn_set(Principal::anonymous(), Nat::from(1_u32)));
+
+        assert!(result.unwrap_err().contains("preparing to stop"));
+        assert_eq!(inflight_count(), 0);
+    }
+
+    #[test]
+    fn test_system_rejects_are_told_apart() {
+        for code in [
+            RejectCode::SysFatal,
+            RejectCode::SysTransient,
+            RejectCode::DestinationInvalid,
+            RejectCode::CanisterError,
+            RejectCode::SysUnknown,
+        ] {
+            let error = set_checked_error(code, "Canister is stopping");
+            assert!(error.contains("rejected by the system"), "{:?}: {}", code, error);
+            assert!(error.contains("Canister is stopping"), "{:?}: {}", code, error);
+        }
+    }
+
+    #[test]
+    fn test_set_checked_with_unknown_outcome() {
+        let e = CallError::StateUnknown(StateUnknown::CandidDecodeFailed(
+            "expected unit".to_string(),
+        ));
+        let error = call_set_checked_error(e);
+        assert!(error.contains("outcome of setting the value is unknown"), "{}", error);
+        assert!(error.contains("expected unit"), "{}", error);
+    }
+
+    #[test]
+    fn test_anonymous_callers_are_rejected() {
+        let error = check_not_anonymous(Principal::anonymous()).unwrap_err();
+        assert!(error.contains("Anonymous callers are not allowed"), "{}", error);
+        assert_eq!(check_not_anonymous(Principal::from_slice(&[1; 10])), Ok(()));
     }
 }
@@ -0,0 +1,119 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+/// Hands out Ethereum transaction nonces per sending address, so that transactions sent in
+/// quick succession don't reuse a nonce.
+// The chain only learns about a nonce once the transaction using it is mined (or at least in the
+// mempool), so asking `eth_getTransactionCount` before each transaction would hand out the same
+// nonce twice if we send two in a row. We count locally instead, and reconcile with the chain
+// when we learn its count, e.g., after our canister was reinstalled or a transaction was sent
+// from elsewhere.
+#[derive(Default)]
+pub struct Nonces {
+    // The next nonce to hand out, keyed by the lowercase hex address.
+    next: BTreeMap<String, u64>,
+}
+
+/// How far a reported transaction count may be ahead of our own count. A count further ahead is
+/// more likely a mistake than transactions sent from elsewhere, and accepting it would skip
+/// nonces that the chain then waits for forever.
+pub const MAX_RECONCILE_JUMP: u64 = 1_000;
+
+thread_local! {
+    pub static NONCES: RefCell<Nonces> = RefCell::new(Nonces::default());
+}
+
+// Addresses may come with or without EIP-55 checksum casing; they're the same account.
+fn key(address: &str) -> String {
+    address.to_ascii_lowercase()
+}
+
+impl Nonces {
+    /// Returns the nonce to use for the next transaction from `address`, and counts it as used.
+    pub fn next_nonce(&mut self, address: &str) -> Result<u64, String> {
+        let next = self.next.entry(key(address)).or_insert(0);
+        let nonce = *next;
+        *next = nonce
+            .checked_add(1)
+            .ok_or_else(|| format!("Address {} has used up its nonces", address))?;
+        Ok(nonce)
+    }
+
+    /// Takes into account that the chain has seen `transaction_count` transactions from
+    /// `address`, as reported by `eth_getTransactionCount`.
+    // We only ever move forward: a count below ours just means that some of our transactions
+    // haven't made it to the node we asked yet, and handing out their nonces again would replace
+    // them. And we only move forward by up to `MAX_RECONCILE_JUMP`, as we can never move back.
+    pub fn reconcile(&mut self, address: &str, transaction_count: u64) -> Result<(), String> {
+        let next = self.next.entry(key(address)).or_insert(0);
+        if transaction_count > next.saturating_add(MAX_RECONCILE_JUMP) {
+            return Err(format!(
+                "The transaction count {} of {} is more than {} ahead of our count {}",
+                transaction_count, address, MAX_RECONCILE_JUMP, next
+            ));
+        }
+        *next = (*next).max(transaction_count);
+        Ok(())
+    }
+}
+
+pub fn next_nonce(address: &str) -> Result<u64, String> {
+    NONCES.with(|n| n.borrow_mut().next_nonce(address))
+}
+
+pub fn reconcile(address: &str, transaction_count: u64) -> Result<(), String> {
+    NONCES.with(|n| n.borrow_mut().reconcile(address, transaction_count))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDRESS: &str = "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed";
+
+    #[test]
+    fn test_nonces_increment_per_address() {
+        let mut nonces = Nonces::default();
+        assert_eq!(nonces.next_nonce(ADDRESS), Ok(0));
+        assert_eq!(nonces.next_nonce(ADDRESS), Ok(1));
+        assert_eq!(nonces.next_nonce("0x0000000000000000000000000000000000000001"), Ok(0));
+        // The casing of the address doesn't matter.
+        assert_eq!(nonces.next_nonce(&ADDRESS.to_lowercase()), Ok(2));
+    }
+
+    #[test]
+    fn test_reconcile_with_chain_ahead() {
+        let mut nonces = Nonces::default();
+        nonces.next_nonce(ADDRESS).unwrap();
+        nonces.reconcile(ADDRESS, 7).unwrap();
+        assert_eq!(nonces.next_nonce(ADDRESS), Ok(7));
+        assert_eq!(nonces.next_nonce(ADDRESS), Ok(8));
+    }
+
+    #[test]
+    fn test_reconcile_too_far_ahead_is_rejected() {
+        let mut nonces = Nonces::default();
+        nonces.reconcile(ADDRESS, MAX_RECONCILE_JUMP).unwrap();
+        assert!(nonces.reconcile(ADDRESS, 2 * MAX_RECONCILE_JUMP + 1).is_err());
+        assert!(nonces.reconcile(ADDRESS, u64::MAX).is_err());
+        assert_eq!(nonces.next_nonce(ADDRESS), Ok(MAX_RECONCILE_JUMP));
+    }
+
+    #[test]
+    fn test_last_nonce_is_not_handed_out_twice() {
+        let mut nonces = Nonces::default();
+        nonces.next.insert(key(ADDRESS), u64::MAX);
+        assert!(nonces.next_nonce(ADDRESS).is_err());
+        assert!(nonces.next_nonce(ADDRESS).is_err());
+    }
+
+    #[test]
+    fn test_reconcile_with_chain_behind_keeps_local_count() {
+        let mut nonces = Nonces::default();
+        for _ in 0..3 {
+            nonces.next_nonce(ADDRESS).unwrap();
+        }
+        nonces.reconcile(ADDRESS, 1).unwrap();
+        assert_eq!(nonces.next_nonce(ADDRESS), Ok(3));
+    }
+}
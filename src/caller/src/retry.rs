@@ -0,0 +1,136 @@
+use std::cell::RefCell;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+/// How often, and for how long, `stubborn_set` retries.
+// The same knobs as the backend's `RetryPolicy`, so that both examples retry alike.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// How many times to try a call, including the first attempt.
+    pub max_attempts: u32,
+    /// How long after the first attempt to stop retrying, in seconds.
+    pub deadline_secs: u64,
+    /// How long to wait before the first retry, in milliseconds; doubled for every later retry.
+    pub base_delay_ms: u64,
+    /// The longest we ever wait between two attempts, in milliseconds.
+    pub max_delay_ms: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 10,
+            deadline_secs: 60,
+            base_delay_ms: 100,
+            max_delay_ms: 5_000,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The wait before the next attempt, after `attempts` attempts that started at `started_at`,
+    /// or `None` once either the attempts or the time run out. Times are in nanoseconds, like
+    /// `ic_cdk::api::time()`.
+    pub fn next_delay(&self, attempts: u32, started_at: u64, now: u64) -> Option<Duration> {
+        if attempts >= self.max_attempts {
+            return None;
+        }
+        let exponent = attempts.saturating_sub(1).min(63);
+        let delay_ms = self
+            .base_delay_ms
+            .saturating_mul(1_u64 << exponent)
+            .min(self.max_delay_ms);
+        let deadline = started_at.saturating_add(self.deadline_secs.saturating_mul(1_000_000_000));
+        if now.saturating_add(delay_ms.saturating_mul(1_000_000)) > deadline {
+            return None;
+        }
+        Some(Duration::from_millis(delay_ms))
+    }
+}
+
+/// Completes after `duration`, letting other messages run in the meantime.
+pub async fn sleep(duration: Duration) {
+    if !duration.is_zero() {
+        TimerSleep::new(duration).await
+    }
+}
+
+// A canister can't block, so we schedule a timer and wait for it to wake us up.
+struct TimerSleep {
+    // Whether the timer fired, and the waker of the task awaiting it.
+    state: Rc<RefCell<(bool, Option<Waker>)>>,
+}
+
+impl TimerSleep {
+    fn new(duration: Duration) -> Self {
+        let state = Rc::new(RefCell::new((false, None::<Waker>)));
+        let timer_state = state.clone();
+        ic_cdk_timers::set_timer(duration, move || {
+            let mut state = timer_state.borrow_mut();
+            state.0 = true;
+            if let Some(waker) = state.1.take() {
+                waker.wake();
+            }
+        });
+        Self { state }
+    }
+}
+
+impl Future for TimerSleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut state = self.state.borrow_mut();
+        if state.0 {
+            Poll::Ready(())
+        } else {
+            state.1 = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: u64 = 1_000_000_000;
+
+    #[test]
+    fn test_attempts_run_out_first() {
+        // Plenty of time, but only three attempts.
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            deadline_secs: 3_600,
+            ..RetryPolicy::default()
+        };
+        assert_eq!(policy.next_delay(1, 0, 0), Some(Duration::from_millis(100)));
+        assert_eq!(policy.next_delay(2, 0, 0), Some(Duration::from_millis(200)));
+        assert_eq!(policy.next_delay(3, 0, 0), None);
+    }
+
+    #[test]
+    fn test_deadline_runs_out_first() {
+        // Plenty of attempts, but a retry 100 ms from now would start after the deadline.
+        let policy = RetryPolicy {
+            max_attempts: 1_000,
+            deadline_secs: 1,
+            ..RetryPolicy::default()
+        };
+        assert!(policy.next_delay(1, 0, SECOND / 2).is_some());
+        assert_eq!(policy.next_delay(1, 0, SECOND - 1), None);
+    }
+
+    #[test]
+    fn test_delay_is_capped() {
+        let policy = RetryPolicy {
+            max_attempts: u32::MAX,
+            deadline_secs: u64::MAX,
+            ..RetryPolicy::default()
+        };
+        assert_eq!(policy.next_delay(200, 0, 0), Some(Duration::from_secs(5)));
+    }
+}
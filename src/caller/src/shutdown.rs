@@ -0,0 +1,64 @@
+use std::cell::Cell;
+
+thread_local! {
+    // Set by `prepare_stop`. Heap memory doesn't survive upgrades, so neither does the flag.
+    static DRAINING: Cell<bool> = const { Cell::new(false) };
+    static IN_FLIGHT: Cell<u64> = const { Cell::new(0) };
+}
+
+/// Marks a retrying operation as in flight for as long as it's alive.
+// Dropping the guard also covers the early returns and the `?`s of the operation. If the
+// operation traps after an `await`, its future is dropped during cleanup as well.
+pub struct InFlight(());
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        IN_FLIGHT.with(|n| n.set(n.get() - 1));
+    }
+}
+
+/// Registers a new retrying operation, unless we're draining for a stop.
+pub fn begin() -> Result<InFlight, String> {
+    if DRAINING.with(|d| d.get()) {
+        return Err("The canister is preparing to stop and doesn't accept new operations".to_string());
+    }
+    IN_FLIGHT.with(|n| n.set(n.get() + 1));
+    Ok(InFlight(()))
+}
+
+/// Stops accepting new retrying operations, and returns how many are still in flight.
+pub fn drain() -> u64 {
+    DRAINING.with(|d| d.set(true));
+    in_flight()
+}
+
+pub fn in_flight() -> u64 {
+    IN_FLIGHT.with(|n| n.get())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_guards_count_operations_in_flight() {
+        let first = begin().unwrap();
+        let second = begin().unwrap();
+        assert_eq!(in_flight(), 2);
+        drop(first);
+        assert_eq!(in_flight(), 1);
+        drop(second);
+        assert_eq!(in_flight(), 0);
+    }
+
+    #[test]
+    fn test_draining_rejects_new_operations_but_keeps_old_ones() {
+        let running = begin().unwrap();
+
+        assert_eq!(drain(), 1);
+
+        assert!(begin().is_err());
+        drop(running);
+        assert_eq!(in_flight(), 0);
+    }
+}
@@ -0,0 +1,140 @@
+use k256::ecdsa::signature::hazmat::PrehashVerifier;
+use k256::ecdsa::{Signature, VerifyingKey};
+use sha2::{Digest, Sha256};
+
+/// The length of the digests that threshold ECDSA signs, in bytes.
+pub const DIGEST_LEN: usize = 32;
+
+/// Checks that `digest` has the length of a SHA-256 (or Keccak-256) hash.
+// The management canister rejects other lengths too, but only after we've attached the signing
+// fee, so we check up front.
+pub fn check_digest(digest: &[u8]) -> Result<(), String> {
+    if digest.len() == DIGEST_LEN {
+        Ok(())
+    } else {
+        Err(format!(
+            "The digest must be {} bytes long, got {}",
+            DIGEST_LEN,
+            digest.len()
+        ))
+    }
+}
+
+/// Checks a hex-encoded signature on `message`, as returned by `sign_message`, against a
+/// hex-encoded SEC1 public key, as returned by `ecdsa_public_key`. Malformed inputs are errors;
+/// a well-formed signature that doesn't match yields `Ok(false)`.
+// `sign_message` signs the SHA-256 hash of the message, so we verify against that same hash.
+// ECDSA signatures come in pairs: if (r, s) is valid, so is (r, -s). The `k256` crate only
+// accepts the "low" one of the two, so we normalize first.
+pub fn verify(message: &str, signature_hex: &str, public_key_hex: &str) -> Result<bool, String> {
+    let signature = hex::decode(signature_hex).map_err(|e| format!("Invalid signature hex: {}", e))?;
+    let signature =
+        Signature::from_slice(&signature).map_err(|e| format!("Invalid signature: {}", e))?;
+    let signature = signature.normalize_s().unwrap_or(signature);
+    let public_key =
+        hex::decode(public_key_hex).map_err(|e| format!("Invalid public key hex: {}", e))?;
+    let public_key = VerifyingKey::from_sec1_bytes(&public_key)
+        .map_err(|e| format!("Invalid public key: {}", e))?;
+    let message_hash = Sha256::digest(message);
+    Ok(public_key.verify_prehash(&message_hash, &signature).is_ok())
+}
+
+/// Encodes a raw signature, the 64 bytes r || s that threshold ECDSA returns, as ASN.1 DER.
+// Besides the encoding, Bitcoin's standardness rules require the low one of (r, s) and (r, -s);
+// nodes don't relay transactions with a high s. Threshold ECDSA may return either.
+pub fn to_der(signature: &[u8]) -> Result<Vec<u8>, String> {
+    let signature =
+        Signature::from_slice(signature).map_err(|e| format!("Invalid signature: {}", e))?;
+    let signature = signature.normalize_s().unwrap_or(signature);
+    Ok(signature.to_der().as_bytes().to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use k256::ecdsa::signature::hazmat::PrehashSigner;
+    use k256::ecdsa::SigningKey;
+
+    // Stands in for the threshold key: signs the way `sign_message` does.
+    fn sign(key: &SigningKey, message: &str) -> String {
+        let signature: Signature = key.sign_prehash(&Sha256::digest(message)).unwrap();
+        hex::encode(signature.to_bytes())
+    }
+
+    fn key() -> SigningKey {
+        SigningKey::from_slice(&[7; 32]).unwrap()
+    }
+
+    fn public_key_hex(key: &SigningKey) -> String {
+        hex::encode(key.verifying_key().to_sec1_bytes())
+    }
+
+    #[test]
+    fn test_sign_then_verify() {
+        let signature = sign(&key(), "hello");
+        assert_eq!(verify("hello", &signature, &public_key_hex(&key())), Ok(true));
+    }
+
+    #[test]
+    fn test_wrong_message_or_key_fails() {
+        let signature = sign(&key(), "hello");
+        assert_eq!(verify("goodbye", &signature, &public_key_hex(&key())), Ok(false));
+        let other = SigningKey::from_slice(&[8; 32]).unwrap();
+        assert_eq!(verify("hello", &signature, &public_key_hex(&other)), Ok(false));
+    }
+
+    #[test]
+    fn test_malformed_inputs_are_errors() {
+        let signature = sign(&key(), "hello");
+        assert!(verify("hello", "not hex", &public_key_hex(&key())).is_err());
+        assert!(verify("hello", &signature[..10], &public_key_hex(&key())).is_err());
+        assert!(verify("hello", &signature, "02").is_err());
+    }
+
+    #[test]
+    fn test_der_parses_back_to_the_same_signature() {
+        let raw = hex::decode(sign(&key(), "hello")).unwrap();
+
+        let der = to_der(&raw).unwrap();
+
+        // A SEQUENCE of the two INTEGERs r and s.
+        assert_eq!(der[0], 0x30);
+        let parsed = Signature::from_der(&der).unwrap();
+        let (r, s) = parsed.split_bytes();
+        assert_eq!(r.as_slice(), &raw[..32]);
+        assert_eq!(s.as_slice(), &raw[32..]);
+    }
+
+    #[test]
+    fn test_der_has_low_s() {
+        let signature = Signature::from_slice(&hex::decode(sign(&key(), "hello")).unwrap()).unwrap();
+        // The same signature with a high s, which threshold ECDSA may return as well.
+        let high_s = Signature::from_scalars(signature.r(), -*signature.s()).unwrap();
+        assert!(high_s.normalize_s().is_some());
+
+        let der = to_der(&high_s.to_bytes()).unwrap();
+
+        assert_eq!(Signature::from_der(&der).unwrap(), signature);
+    }
+
+    #[test]
+    fn test_der_rejects_malformed_signature() {
+        assert!(to_der(&[0; 10]).is_err());
+        // r and s must be non-zero.
+        assert!(to_der(&[0; 64]).is_err());
+    }
+
+    #[test]
+    fn test_digest_of_right_length_is_accepted() {
+        assert_eq!(check_digest(&Sha256::digest("hello")), Ok(()));
+        assert_eq!(check_digest(&[0; DIGEST_LEN]), Ok(()));
+    }
+
+    #[test]
+    fn test_digest_of_wrong_length_is_rejected() {
+        for len in [0, 20, DIGEST_LEN - 1, DIGEST_LEN + 1, 64] {
+            let error = check_digest(&vec![0; len]).unwrap_err();
+            assert!(error.contains(&len.to_string()), "{}", error);
+        }
+    }
+}
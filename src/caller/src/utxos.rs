@@ -0,0 +1,215 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk::management_canister::BitcoinNetwork;
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{DefaultMemoryImpl, Memory, StableVec, Storable};
+use paginate::visit_pages;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::future::Future;
+
+/// The stable memory holding the UTXOs of the last address we fetched.
+const UTXOS_MEMORY_ID: MemoryId = MemoryId::new(0);
+
+/// The most UTXOs returned by a single page of `read_utxos`.
+pub const MAX_READ_PAGE: u16 = 1_000;
+
+/// The most pages of `bitcoin_get_utxos` that `store_all_pages` fetches before giving up.
+pub const MAX_FETCH_PAGES: u32 = 1_000;
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Outpoint {
+    pub txid: Vec<u8>,
+    pub vout: u32,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Utxo {
+    pub outpoint: Outpoint,
+    /// In satoshi.
+    pub value: u64,
+    /// The height of the block containing the UTXO.
+    pub height: u32,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum UtxoFilter {
+    #[serde(rename = "min_confirmations")]
+    MinConfirmations(u32),
+    #[serde(rename = "page")]
+    Page(Vec<u8>),
+}
+
+/// The arguments of `bitcoin_get_utxos`.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct GetUtxosRequest {
+    pub address: String,
+    pub network: BitcoinNetwork,
+    pub filter: Option<UtxoFilter>,
+}
+
+/// The parts of the `bitcoin_get_utxos` reply that we use; Candid skips the other fields.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct GetUtxosResponse {
+    pub utxos: Vec<Utxo>,
+    /// Pass this as the `page` filter to get the next page, if there is one.
+    pub next_page: Option<Vec<u8>>,
+}
+
+// A UTXO takes a fixed 48 bytes in stable memory: a 32-byte transaction ID, followed by the
+// output index, the value and the height in little-endian.
+const UTXO_SIZE: usize = 48;
+
+impl Storable for Utxo {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut bytes = Vec::with_capacity(UTXO_SIZE);
+        bytes.extend_from_slice(&self.outpoint.txid);
+        bytes.extend_from_slice(&self.outpoint.vout.to_le_bytes());
+        bytes.extend_from_slice(&self.value.to_le_bytes());
+        bytes.extend_from_slice(&self.height.to_le_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Utxo {
+            outpoint: Outpoint {
+                txid: bytes[..32].to_vec(),
+                vout: u32::from_le_bytes(bytes[32..36].try_into().unwrap()),
+            },
+            value: u64::from_le_bytes(bytes[36..44].try_into().unwrap()),
+            height: u32::from_le_bytes(bytes[44..48].try_into().unwrap()),
+        }
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: UTXO_SIZE as u32,
+        is_fixed_size: true,
+    };
+}
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+}
+
+fn utxos_memory() -> VirtualMemory<DefaultMemoryImpl> {
+    MEMORY_MANAGER.with(|m| m.borrow().get(UTXOS_MEMORY_ID))
+}
+
+/// Fetches all pages of UTXOs with `fetch_page`, which is given the page token (`None` for the
+/// first page), and appends them to `store`. Returns the number of UTXOs stored. Fails if there
+/// are more than `MAX_FETCH_PAGES` pages.
+// We only keep one page in the heap at a time. The full UTXO set of a busy address can exceed
+// both our heap budget and the reply size limit, so it's never assembled in a single message.
+pub async fn store_all_pages<M, F, Fut>(
+    store: &StableVec<Utxo, M>,
+    fetch_page: F,
+) -> Result<u64, String>
+where
+    M: Memory,
+    F: FnMut(Option<Vec<u8>>) -> Fut,
+    Fut: Future<Output = Result<GetUtxosResponse, String>>,
+{
+    visit_pages(
+        fetch_page,
+        |response: GetUtxosResponse| {
+            for utxo in &response.utxos {
+                if utxo.outpoint.txid.len() != 32 {
+                    return Err(format!("Invalid transaction ID in {:?}", utxo.outpoint));
+                }
+                store
+                    .push(utxo)
+                    .map_err(|e| format!("Out of stable memory for UTXOs: {:?}", e))?;
+            }
+            Ok(())
+        },
+        |response: &GetUtxosResponse| Ok(response.next_page.clone()),
+        MAX_FETCH_PAGES,
+    )
+    .await?;
+    Ok(store.len())
+}
+
+/// Replaces the stored UTXOs by the ones fetched with `fetch_page`, see `store_all_pages`.
+// The vector's length lives in stable memory, so every handle on the same memory sees the same
+// contents, and we don't need to keep one around. Readers that run while we await the next page
+// see the UTXOs stored so far.
+pub async fn replace_stored_utxos<F, Fut>(fetch_page: F) -> Result<u64, String>
+where
+    F: FnMut(Option<Vec<u8>>) -> Fut,
+    Fut: Future<Output = Result<GetUtxosResponse, String>>,
+{
+    // `new`, unlike `init`, discards whatever the memory held.
+    let store = StableVec::new(utxos_memory())
+        .map_err(|e| format!("Failed to clear the UTXO store: {:?}", e))?;
+    store_all_pages(&store, fetch_page).await
+}
+
+/// Returns up to `limit` stored UTXOs, starting at index `offset`.
+pub fn read_utxos(offset: u64, limit: u16) -> Vec<Utxo> {
+    let limit = limit.min(MAX_READ_PAGE) as u64;
+    let utxos: StableVec<Utxo, _> =
+        StableVec::init(utxos_memory()).expect("The UTXO store is corrupted");
+    (offset..offset.saturating_add(limit).min(utxos.len()))
+        .filter_map(|i| utxos.get(i))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use ic_stable_structures::VectorMemory;
+
+    fn utxo(n: u8) -> Utxo {
+        Utxo {
+            outpoint: Outpoint {
+                txid: vec![n; 32],
+                vout: n as u32,
+            },
+            value: 1_000 * n as u64,
+            height: 800_000 + n as u32,
+        }
+    }
+
+    // Serves three pages of two UTXOs each, chained by page tokens.
+    async fn fetch_mock_page(page: Option<Vec<u8>>) -> Result<GetUtxosResponse, String> {
+        let index = page.map_or(0, |token| token[0]);
+        Ok(GetUtxosResponse {
+            utxos: vec![utxo(2 * index), utxo(2 * index + 1)],
+            next_page: (index < 2).then(|| vec![index + 1]),
+        })
+    }
+
+    #[test]
+    fn test_all_pages_land_in_stable_storage() {
+        let store = StableVec::new(VectorMemory::default()).unwrap();
+
+        let count = block_on(store_all_pages(&store, fetch_mock_page)).unwrap();
+
+        assert_eq!(count, 6);
+        let stored: Vec<Utxo> = store.iter().collect();
+        assert_eq!(stored, (0..6).map(utxo).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_read_utxos_pages() {
+        assert_eq!(block_on(replace_stored_utxos(fetch_mock_page)), Ok(6));
+
+        assert_eq!(read_utxos(0, 4), (0..4).map(utxo).collect::<Vec<_>>());
+        assert_eq!(read_utxos(4, 4), vec![utxo(4), utxo(5)]);
+        assert!(read_utxos(6, 4).is_empty());
+    }
+
+    #[test]
+    fn test_failed_page_is_reported() {
+        let store = StableVec::new(VectorMemory::default()).unwrap();
+        let result = block_on(store_all_pages(&store, |page: Option<Vec<u8>>| async move {
+            match page {
+                None => fetch_mock_page(None).await,
+                Some(_) => Err("Bitcoin canister unavailable".to_string()),
+            }
+        }));
+        assert_eq!(result, Err("Bitcoin canister unavailable".to_string()));
+    }
+}
@@ -1,10 +1,29 @@
 use candid::types::number::Nat;
+use candid::Principal;
+use ic_cdk::api::msg_caller;
 use std::cell::RefCell;
 
 thread_local! {
     static COUNTER: RefCell<Nat> = RefCell::new(Nat::from(0_u32));
 }
 
+/// Guard that rejects calls from the anonymous principal (`2vxsx-fae`).
+// Ingress messages can be sent anonymously, without any signature. Calls from other canisters
+// always carry the calling canister's principal, so they are never anonymous. Guards run before
+// the method body, so a rejected call has no effect. Each canister in this repository is a
+// standalone example with its own copy of this guard; keep the copies identical.
+fn reject_anonymous() -> Result<(), String> {
+    check_not_anonymous(msg_caller())
+}
+
+fn check_not_anonymous(caller: Principal) -> Result<(), String> {
+    if caller == Principal::anonymous() {
+        Err("Anonymous callers are not allowed to call this method".to_string())
+    } else {
+        Ok(())
+    }
+}
+
 /// Get the value of the counter.
 #[ic_cdk_macros::query]
 fn get() -> Nat {
@@ -12,18 +31,93 @@ fn get() -> Nat {
 }
 
 /// Set the value of the counter.
-#[ic_cdk_macros::update]
+#[ic_cdk_macros::update(guard = "reject_anonymous")]
 fn set(n: Nat) {
     // COUNTER.replace(n);  // requires #![feature(local_key_cell_methods)]
     COUNTER.with(|count| *count.borrow_mut() = n);
 }
 
+/// The largest value that `set_checked` accepts.
+const MAX_CHECKED_VALUE: u32 = 1_000_000;
+
+/// Checks whether `set_checked` accepts `n`, and if not, explains why.
+fn check_value(n: &Nat) -> Result<(), String> {
+    if *n > Nat::from(MAX_CHECKED_VALUE) {
+        Err(format!(
+            "The value {} exceeds the maximum of {}",
+            n,
+            Nat::from(MAX_CHECKED_VALUE)
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Like `set`, but rejects values above `MAX_CHECKED_VALUE`.
+// This shows the callee's side of the error model: rejecting the call explicitly, rather than
+// returning an `Err` inside a successful reply. The caller observes a `CanisterReject` with our
+// message, and knows that the call had no effect. With `manual_reply`, the method doesn't reply
+// when it returns, and must call either `msg_reply` or `msg_reject` itself. Trapping would also
+// reject the call, but as a `CanisterError`, and it would roll back any state changes.
+#[ic_cdk_macros::update(guard = "reject_anonymous", manual_reply = true)]
+fn set_checked(n: Nat) {
+    match check_value(&n) {
+        Ok(()) => {
+            set(n);
+            ic_cdk::api::msg_reply(candid::encode_args(()).unwrap());
+        }
+        Err(message) => ic_cdk::api::msg_reject(message),
+    }
+}
+
 /// Increment the value of the counter.
-#[ic_cdk_macros::update]
+#[ic_cdk_macros::update(guard = "reject_anonymous")]
 fn increment() {
     COUNTER.with(|counter| *counter.borrow_mut() += 1_u32);
 }
 
+/// Set the counter to `new`, but only if its value is `expected`. Returns `Ok(new)` if the value
+/// was set, and `Err(current)` with the unchanged value otherwise.
+// Callers that read the value, compute a new one and write it back may overwrite a write that
+// another caller made in between. With this method, such a write makes the swap fail instead,
+// and the caller can recompute from the current value it got back, without any locks.
+#[ic_cdk_macros::update(guard = "reject_anonymous")]
+fn compare_and_swap(expected: Nat, new: Nat) -> Result<Nat, Nat> {
+    COUNTER.with(|counter| {
+        let mut counter = counter.borrow_mut();
+        if *counter == expected {
+            *counter = new.clone();
+            Ok(new)
+        } else {
+            Err(counter.clone())
+        }
+    })
+}
+
+/// Add two numbers, without touching the counter.
+// A method with more than one parameter, for the caller's multi-argument example.
+#[ic_cdk_macros::query]
+fn add(a: Nat, b: Nat) -> Nat {
+    a + b
+}
+
+/// Add `delta` to the counter and return the new value.
+// A method without any `await` runs to completion before any other message is processed, so the
+// read and the write can't be interleaved with other callers. Callers that need to know the value
+// their own increment produced should use this method, rather than calling `increment` and `get`
+// separately, which another caller may squeeze in between.
+#[ic_cdk_macros::update(guard = "reject_anonymous")]
+fn increment_by(delta: Nat) -> Nat {
+    COUNTER.with(|counter| {
+        let mut counter = counter.borrow_mut();
+        // Adding zero doesn't change anything; skip the write.
+        if delta != Nat::from(0_u32) {
+            *counter += delta;
+        }
+        counter.clone()
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -47,4 +141,71 @@ mod tests {
             assert_eq!(get(), Nat::from(i));
         }
     }
+
+    #[test]
+    fn test_check_value() {
+        assert_eq!(check_value(&Nat::from(MAX_CHECKED_VALUE)), Ok(()));
+        let error = check_value(&Nat::from(MAX_CHECKED_VALUE + 1)).unwrap_err();
+        assert!(error.contains("exceeds the maximum"), "{}", error);
+    }
+
+    #[test]
+    fn test_increment_by() {
+        set(Nat::from(5_u32));
+        assert_eq!(increment_by(Nat::from(3_u32)), Nat::from(8_u32));
+        assert_eq!(get(), Nat::from(8_u32));
+    }
+
+    #[test]
+    fn test_compare_and_swap_succeeds_on_expected_value() {
+        set(Nat::from(3_u32));
+        assert_eq!(
+            compare_and_swap(Nat::from(3_u32), Nat::from(4_u32)),
+            Ok(Nat::from(4_u32))
+        );
+        assert_eq!(get(), Nat::from(4_u32));
+    }
+
+    #[test]
+    fn test_compare_and_swap_fails_on_contention() {
+        set(Nat::from(3_u32));
+        // Another caller increments the value after we read it.
+        let read = get();
+        increment();
+        assert_eq!(
+            compare_and_swap(read, Nat::from(10_u32)),
+            Err(Nat::from(4_u32))
+        );
+        assert_eq!(get(), Nat::from(4_u32));
+        // Retrying from the value we got back succeeds.
+        assert_eq!(
+            compare_and_swap(Nat::from(4_u32), Nat::from(5_u32)),
+            Ok(Nat::from(5_u32))
+        );
+    }
+
+    #[test]
+    fn test_add() {
+        set(Nat::from(1_u32));
+        assert_eq!(add(Nat::from(2_u32), Nat::from(3_u32)), Nat::from(5_u32));
+        assert_eq!(get(), Nat::from(1_u32));
+    }
+
+    #[test]
+    fn test_increment_by_zero() {
+        set(Nat::from(7_u32));
+        assert_eq!(increment_by(Nat::from(0_u32)), Nat::from(7_u32));
+        assert_eq!(get(), Nat::from(7_u32));
+    }
+
+    #[test]
+    fn test_increment_by_returns_the_new_value() {
+        // Each call adds its delta to the value before the call, and returns the sum.
+        let deltas = [4_u32, 1, 10, 2, 7];
+        for delta in deltas {
+            let before = get();
+            assert_eq!(increment_by(Nat::from(delta)), before + Nat::from(delta));
+        }
+        assert_eq!(get(), Nat::from(deltas.iter().sum::<u32>()));
+    }
 }
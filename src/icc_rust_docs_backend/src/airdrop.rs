@@ -0,0 +1,345 @@
+use candid::{CandidType, Deserialize, Nat, Principal};
+use icrc_ledger_types::icrc1::account::Account;
+use icrc_ledger_types::icrc1::transfer::{Memo, NumTokens, TransferArg};
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use crate::outbox::{send_transfer, Attempt, MAX_JOB_ATTEMPTS};
+use crate::transport::Transport;
+
+/// How often the processor sends the next batch of transfers.
+pub const PROCESS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How many transfers the processor sends per run, over all airdrops.
+// Each transfer is a call made one after the other, so a run takes a few seconds per batch.
+// Bounding the batch keeps runs from overlapping and leaves room for the canister's other work.
+pub const BATCH_SIZE: usize = 50;
+
+/// Where the transfer to one recipient of an airdrop stands.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum Delivery {
+    Pending { attempts: u32 },
+    Done { block_index: Nat },
+    Failed { error: String },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Recipient {
+    pub to: Account,
+    pub amount: NumTokens,
+    /// Set on the first attempt, so that every attempt sends the same transfer. Setting it then
+    /// rather than when the airdrop is queued keeps the later recipients of a large airdrop within
+    /// the ledger's deduplication window.
+    pub created_at_time: Option<u64>,
+    pub delivery: Delivery,
+}
+
+/// Transfers from our default account on `ledger` to many recipients.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Airdrop {
+    pub ledger: Principal,
+    /// Fetched once when the airdrop is queued, and sent with every transfer. If the ledger
+    /// changes its fee meanwhile, the remaining transfers fail with `BadFee`.
+    pub fee: NumTokens,
+    pub recipients: Vec<Recipient>,
+}
+
+/// The next transfer to send for an airdrop.
+struct Pending {
+    id: u64,
+    index: u32,
+    ledger: Principal,
+    arg: TransferArg,
+}
+
+/// The airdrops that we queued, and how far along they are.
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct Airdrops {
+    next_id: u64,
+    airdrops: BTreeMap<u64, Airdrop>,
+}
+
+impl Airdrops {
+    /// Queues an airdrop, and returns its ID.
+    pub fn enqueue(&mut self, ledger: Principal, fee: NumTokens, recipients: Vec<(Account, NumTokens)>) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        let recipients = recipients
+            .into_iter()
+            .map(|(to, amount)| Recipient {
+                to,
+                amount,
+                created_at_time: None,
+                delivery: Delivery::Pending { attempts: 0 },
+            })
+            .collect();
+        self.airdrops.insert(id, Airdrop { ledger, fee, recipients });
+        id
+    }
+
+    /// Returns how many transfers of airdrop `id` are done, how many it has in total, and how
+    /// many failed, or `None` if there's no such airdrop.
+    pub fn progress(&self, id: u64) -> Option<(u32, u32, u32)> {
+        let airdrop = self.airdrops.get(&id)?;
+        let count = |done: fn(&Delivery) -> bool| {
+            airdrop.recipients.iter().filter(|r| done(&r.delivery)).count() as u32
+        };
+        Some((
+            count(|d| matches!(d, Delivery::Done { .. })),
+            airdrop.recipients.len() as u32,
+            count(|d| matches!(d, Delivery::Failed { .. })),
+        ))
+    }
+
+    /// The number of transfers, over all airdrops, that aren't done or failed yet.
+    pub fn pending_transfers(&self) -> usize {
+        self.airdrops
+            .values()
+            .flat_map(|a| a.recipients.iter())
+            .filter(|r| matches!(r.delivery, Delivery::Pending { .. }))
+            .count()
+    }
+
+    /// Returns up to `limit` pending transfers, oldest airdrop first, fixing the time of those
+    /// that haven't been tried yet to `now`.
+    fn next_batch(&mut self, limit: usize, now: u64) -> Vec<Pending> {
+        let mut batch = Vec::new();
+        for (id, airdrop) in self.airdrops.iter_mut() {
+            for (index, recipient) in airdrop.recipients.iter_mut().enumerate() {
+                if batch.len() >= limit {
+                    return batch;
+                }
+                if !matches!(recipient.delivery, Delivery::Pending { .. }) {
+                    continue;
+                }
+                let created_at_time = *recipient.created_at_time.get_or_insert(now);
+                batch.push(Pending {
+                    id: *id,
+                    index: index as u32,
+                    ledger: airdrop.ledger,
+                    arg: TransferArg {
+                        from_subaccount: None,
+                        to: recipient.to,
+                        fee: Some(airdrop.fee.clone()),
+                        created_at_time: Some(created_at_time),
+                        memo: Some(memo(*id, index as u32)),
+                        amount: recipient.amount.clone(),
+                    },
+                });
+            }
+        }
+        batch
+    }
+
+    fn record(&mut self, id: u64, index: u32, outcome: Attempt) {
+        let Some(recipient) = self
+            .airdrops
+            .get_mut(&id)
+            .and_then(|a| a.recipients.get_mut(index as usize))
+        else {
+            return;
+        };
+        let attempts = match recipient.delivery {
+            Delivery::Pending { attempts } => attempts + 1,
+            _ => return,
+        };
+        recipient.delivery = match outcome {
+            Attempt::Done(block_index) => Delivery::Done { block_index },
+            Attempt::Failed(error) => Delivery::Failed { error },
+            Attempt::Retry(error) if attempts >= MAX_JOB_ATTEMPTS => Delivery::Failed {
+                error: format!("Giving up after {} attempts: {}", attempts, error),
+            },
+            Attempt::Retry(_) => Delivery::Pending { attempts },
+        };
+    }
+}
+
+/// The memo of the transfer to recipient `index` of airdrop `id`.
+// Two recipients may have the same account and amount, and their first attempts may fall in the
+// same nanosecond; the memo keeps the ledger from taking one transfer for a duplicate of the
+// other. It's longer than the outbox's 8-byte memos, so it can't match those either.
+fn memo(id: u64, index: u32) -> Memo {
+    let mut bytes = id.to_be_bytes().to_vec();
+    bytes.extend_from_slice(&index.to_be_bytes());
+    Memo::from(bytes)
+}
+
+thread_local! {
+    pub static AIRDROPS: RefCell<Airdrops> = RefCell::new(Airdrops::default());
+    // Whether the processor is sending a batch, so that timers don't start a second run.
+    static PROCESSING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Marks the processor as running for as long as it's alive, like the outbox's.
+struct Processing(());
+
+impl Drop for Processing {
+    fn drop(&mut self) {
+        PROCESSING.with(|p| p.set(false));
+    }
+}
+
+/// Whether the processor is sending a batch.
+pub fn is_processing() -> bool {
+    PROCESSING.with(|p| p.get())
+}
+
+/// Marks the processor as running, unless it already is.
+fn begin_processing() -> Option<Processing> {
+    if PROCESSING.with(|p| p.replace(true)) {
+        None
+    } else {
+        Some(Processing(()))
+    }
+}
+
+/// Sends up to `limit` pending transfers, and records the outcomes.
+// As in the outbox, each outcome is recorded as soon as it's known, so a trap in a later transfer
+// or an upgrade doesn't lose it.
+pub async fn process_batch<T: Transport>(transport: &T, limit: usize) {
+    let Some(_processing) = begin_processing() else {
+        return;
+    };
+    let batch = AIRDROPS.with(|a| a.borrow_mut().next_batch(limit, transport.time()));
+    for pending in batch {
+        let outcome = send_transfer(transport, pending.ledger, &pending.arg).await;
+        AIRDROPS.with(|a| a.borrow_mut().record(pending.id, pending.index, outcome));
+    }
+}
+
+/// Sends the next batch every `PROCESS_INTERVAL`.
+pub fn start() {
+    ic_cdk_timers::set_timer_interval(PROCESS_INTERVAL, run);
+}
+
+/// Sends the next batch right away, rather than at the next interval.
+pub fn run() {
+    ic_cdk::futures::spawn(async {
+        process_batch(&crate::transport::default_transport(), BATCH_SIZE).await
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::mock::{sys_unknown, MockTransport};
+    use futures::executor::block_on;
+    use icrc_ledger_types::icrc1::transfer::TransferError;
+
+    fn account(n: u8) -> Account {
+        Account {
+            owner: Principal::from_slice(&[n; 29]),
+            subaccount: None,
+        }
+    }
+
+    fn enqueue(recipients: u8) -> u64 {
+        let recipients = (0..recipients).map(|n| (account(n), Nat::from(100_u64))).collect();
+        AIRDROPS.with(|a| {
+            a.borrow_mut()
+                .enqueue(Principal::anonymous(), Nat::from(10_u64), recipients)
+        })
+    }
+
+    fn progress(id: u64) -> Option<(u32, u32, u32)> {
+        AIRDROPS.with(|a| a.borrow().progress(id))
+    }
+
+    fn block(index: u64) -> Result<Nat, TransferError> {
+        Ok(Nat::from(index))
+    }
+
+    #[test]
+    fn test_progress_advances_batch_by_batch() {
+        let transport = MockTransport::new();
+        for index in 0..5 {
+            transport.reply(&block(index));
+        }
+        let id = enqueue(5);
+        assert_eq!(progress(id), Some((0, 5, 0)));
+
+        block_on(process_batch(&transport, 2));
+        assert_eq!(progress(id), Some((2, 5, 0)));
+        block_on(process_batch(&transport, 2));
+        assert_eq!(progress(id), Some((4, 5, 0)));
+        block_on(process_batch(&transport, 2));
+        assert_eq!(progress(id), Some((5, 5, 0)));
+
+        // Nothing is sent twice, and every transfer carries the fee fetched up front.
+        block_on(process_batch(&transport, 2));
+        let args: Vec<TransferArg> = transport
+            .calls()
+            .iter()
+            .map(|c| candid::decode_one(&c.args).unwrap())
+            .collect();
+        assert_eq!(args.len(), 5);
+        assert!(args.iter().all(|a| a.fee == Some(Nat::from(10_u64))));
+        assert_eq!(args[3].to, account(3));
+        assert_eq!(args[3].memo, Some(memo(id, 3)));
+    }
+
+    #[test]
+    fn test_failed_recipients_are_counted_and_skipped() {
+        let transport = MockTransport::new();
+        transport
+            .reply(&block(0))
+            .reply(&Err::<Nat, TransferError>(TransferError::InsufficientFunds {
+                balance: Nat::from(50_u64),
+            }))
+            .reply(&block(1));
+        let id = enqueue(3);
+
+        block_on(process_batch(&transport, 10));
+
+        assert_eq!(progress(id), Some((2, 3, 1)));
+        AIRDROPS.with(|a| match &a.borrow().airdrops[&id].recipients[1].delivery {
+            Delivery::Failed { error } => assert!(error.contains("InsufficientFunds"), "{}", error),
+            delivery => panic!("Expected a failed transfer, got {:?}", delivery),
+        });
+    }
+
+    #[test]
+    fn test_unknown_outcome_is_retried_with_the_same_transfer() {
+        let transport = MockTransport::new();
+        transport
+            .fail(sys_unknown())
+            .reply(&Err::<Nat, TransferError>(TransferError::Duplicate {
+                duplicate_of: Nat::from(4_u64),
+            }));
+        let id = enqueue(1);
+
+        block_on(process_batch(&transport, 10));
+        assert_eq!(progress(id), Some((0, 1, 0)));
+        transport.now.set(transport.now.get() + 1_000);
+        block_on(process_batch(&transport, 10));
+
+        assert_eq!(progress(id), Some((1, 1, 0)));
+        let calls = transport.calls();
+        assert_eq!(calls[0].args, calls[1].args);
+    }
+
+    #[test]
+    fn test_dropped_batch_lets_the_next_one_start() {
+        let id = enqueue(2);
+        // What's left of a batch that trapped after an `await`.
+        let batch = begin_processing();
+        assert!(batch.is_some());
+        block_on(process_batch(&MockTransport::new(), 10));
+        assert_eq!(AIRDROPS.with(|a| a.borrow().pending_transfers()), 2);
+        drop(batch);
+
+        let transport = MockTransport::new();
+        transport.reply(&block(0)).reply(&block(1));
+        block_on(process_batch(&transport, 10));
+        assert_eq!(progress(id), Some((2, 2, 0)));
+        assert_eq!(AIRDROPS.with(|a| a.borrow().pending_transfers()), 0);
+    }
+
+    #[test]
+    fn test_same_recipient_twice_gets_different_memos() {
+        assert_ne!(memo(0, 1), memo(0, 2));
+        assert_ne!(memo(0, 1), memo(1, 0));
+        assert_eq!(progress(42), None);
+    }
+}
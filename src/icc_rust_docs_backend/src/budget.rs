@@ -0,0 +1,200 @@
+use candid::Principal;
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+
+use crate::error::AppError;
+use crate::xrc::XRC_CANISTER_ID;
+
+/// The most cycles we attach to a single XRC request: ten times its current fee.
+pub const XRC_CYCLES_CAP: u128 = 10_000_000_000;
+
+/// The most cycles we attach to a single management canister call, enough to create a canister
+/// or to top one up (see `NEW_CANISTER_CYCLES` in `lib.rs` and `CALLEE_TOP_UP_CYCLES` in
+/// `ledger.rs`).
+pub const MANAGEMENT_CYCLES_CAP: u128 = 2_000_000_000_000;
+
+/// The most cycles we attach to a single call to each target. Targets without a cap get no
+/// cycles at all.
+// Attached cycles are gone once the callee accepts them, and a canister we don't control accepts
+// whatever we send. A bug that computes the wrong amount, or a fee configured with a few zeros
+// too many, would otherwise hand over our balance in one call. The caps are deliberately loose;
+// they catch absurd amounts, not slightly wrong ones.
+pub struct CycleBudget {
+    caps: BTreeMap<Principal, u128>,
+}
+
+impl Default for CycleBudget {
+    fn default() -> Self {
+        let mut budget = CycleBudget {
+            caps: BTreeMap::new(),
+        };
+        budget.set_cap(Principal::from_text(XRC_CANISTER_ID).unwrap(), XRC_CYCLES_CAP);
+        budget.set_cap(Principal::management_canister(), MANAGEMENT_CYCLES_CAP);
+        budget
+    }
+}
+
+impl CycleBudget {
+    pub fn set_cap(&mut self, target: Principal, cap: u128) {
+        self.caps.insert(target, cap);
+    }
+
+    /// Checks that attaching `cycles` to a call to `target` stays within `target`'s cap.
+    pub fn check(&self, target: Principal, cycles: u128) -> Result<(), AppError> {
+        let cap = self.caps.get(&target).copied().unwrap_or(0);
+        if cycles > cap {
+            Err(AppError::CycleCapExceeded {
+                target,
+                cycles,
+                cap,
+            })
+        } else {
+            Ok(())
+        }
+    }
+}
+
+thread_local! {
+    static CYCLE_BUDGET: RefCell<CycleBudget> = RefCell::new(CycleBudget::default());
+}
+
+/// Checks `cycles` against the cap of `target` in the canister's budget. Helpers that attach
+/// cycles call this before issuing the call.
+pub fn check_cycles(target: Principal, cycles: u128) -> Result<(), AppError> {
+    CYCLE_BUDGET.with(|b| b.borrow().check(target, cycles))
+}
+
+/// The most cycles that a single execution of each method may attach to its calls in total,
+/// unless changed with `set_method_limit`. Methods that aren't listed have no limit.
+// The caps above bound each call, but a method that makes several calls, such as one that retries
+// the XRC while it's busy, multiplies them. These methods fetch a rate, which takes at most four
+// attempts at the XRC's 1B cycle fee; the limits leave some room above that.
+pub const DEFAULT_METHOD_LIMITS: [(&str, u128); 3] = [
+    ("get_exchange_rate", 5_000_000_000),
+    ("get_exchange_rate_full", 5_000_000_000),
+    ("transfer_usd_worth", 5_000_000_000),
+];
+
+thread_local! {
+    static METHOD_LIMITS: RefCell<BTreeMap<String, u128>> = RefCell::new(
+        DEFAULT_METHOD_LIMITS
+            .iter()
+            .map(|(method, limit)| (method.to_string(), *limit))
+            .collect(),
+    );
+}
+
+pub fn set_method_limit(method: &str, limit: u128) {
+    METHOD_LIMITS.with(|l| l.borrow_mut().insert(method.to_string(), limit));
+}
+
+/// The cycles that one execution of a method attached to its calls so far.
+// Executions of the same method interleave at every `await`, so each one tracks its own spending
+// rather than sharing a counter. We count the cycles we attach, not those the callees end up
+// keeping: the refunds only arrive with the replies, and the limit is about the worst case.
+pub struct MethodSpend {
+    method: String,
+    limit: Option<u128>,
+    spent: Cell<u128>,
+}
+
+impl MethodSpend {
+    /// Starts tracking an execution of `method`, under its current limit.
+    pub fn start(method: &str) -> Self {
+        MethodSpend {
+            method: method.to_string(),
+            limit: METHOD_LIMITS.with(|l| l.borrow().get(method).copied()),
+            spent: Cell::new(0),
+        }
+    }
+
+    /// Records that we're about to attach `cycles` to a call, unless that would take the
+    /// execution over its method's limit, in which case the call mustn't be made.
+    pub fn charge(&self, cycles: u128) -> Result<(), AppError> {
+        let spent = self.spent.get().saturating_add(cycles);
+        match self.limit {
+            Some(limit) if spent > limit => Err(AppError::MethodCyclesLimitExceeded {
+                method: self.method.clone(),
+                spent,
+                limit,
+            }),
+            _ => {
+                self.spent.set(spent);
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn xrc() -> Principal {
+        Principal::from_text(XRC_CANISTER_ID).unwrap()
+    }
+
+    #[test]
+    fn test_attachment_within_cap_is_allowed() {
+        assert_eq!(check_cycles(xrc(), 1_000_000_000), Ok(()));
+        assert_eq!(check_cycles(xrc(), XRC_CYCLES_CAP), Ok(()));
+    }
+
+    #[test]
+    fn test_attachment_over_cap_is_rejected() {
+        assert_eq!(
+            check_cycles(xrc(), XRC_CYCLES_CAP + 1),
+            Err(AppError::CycleCapExceeded {
+                target: xrc(),
+                cycles: XRC_CYCLES_CAP + 1,
+                cap: XRC_CYCLES_CAP,
+            })
+        );
+    }
+
+    #[test]
+    fn test_method_over_its_limit_is_rejected() {
+        set_method_limit("get_exchange_rate", 2_500_000_000);
+        let spend = MethodSpend::start("get_exchange_rate");
+
+        assert_eq!(spend.charge(1_000_000_000), Ok(()));
+        assert_eq!(spend.charge(1_000_000_000), Ok(()));
+        assert_eq!(
+            spend.charge(1_000_000_000),
+            Err(AppError::MethodCyclesLimitExceeded {
+                method: "get_exchange_rate".to_string(),
+                spent: 3_000_000_000,
+                limit: 2_500_000_000,
+            })
+        );
+        // The refused cycles don't count, so a smaller call still fits.
+        assert_eq!(spend.charge(500_000_000), Ok(()));
+    }
+
+    #[test]
+    fn test_executions_are_limited_separately() {
+        let first = MethodSpend::start("transfer_usd_worth");
+        let second = MethodSpend::start("transfer_usd_worth");
+
+        assert_eq!(first.charge(5_000_000_000), Ok(()));
+        assert_eq!(second.charge(5_000_000_000), Ok(()));
+        assert!(first.charge(1).is_err());
+    }
+
+    #[test]
+    fn test_methods_without_limit_are_unbounded() {
+        let spend = MethodSpend::start("some_other_method");
+        assert_eq!(spend.charge(u128::MAX), Ok(()));
+    }
+
+    #[test]
+    fn test_targets_without_cap_get_no_cycles() {
+        let target = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap();
+        assert_eq!(check_cycles(target, 0), Ok(()));
+        assert!(check_cycles(target, 1).is_err());
+
+        let mut budget = CycleBudget::default();
+        budget.set_cap(target, 100);
+        assert_eq!(budget.check(target, 100), Ok(()));
+    }
+}
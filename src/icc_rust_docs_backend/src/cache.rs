@@ -0,0 +1,213 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::rc::Rc;
+use std::time::Duration;
+
+/// A cache of values that expire a given time after they're fetched, holding at most a given
+/// number of entries. Clones share the same entries, so a `thread_local` can hand out a clone that
+/// outlives the `with` closure, across `await`s.
+// When the cache is full, inserting evicts the least recently used entry. Expired entries are
+// dropped first, and on lookup. Two executions that miss the same key at the same time both
+// fetch it; the later one to finish wins. Deduplicating the fetches would need a shared future,
+// which isn't worth it for the handful of values we cache.
+pub struct TtlCache<K, V> {
+    inner: Rc<RefCell<Inner<K, V>>>,
+}
+
+struct Inner<K, V> {
+    max_entries: usize,
+    entries: BTreeMap<K, Entry<V>>,
+    // Counts lookups and inserts, to order entries by when they were last used.
+    clock: u64,
+}
+
+struct Entry<V> {
+    value: V,
+    expires_at: u64,
+    last_used: u64,
+}
+
+impl<K, V> Clone for TtlCache<K, V> {
+    fn clone(&self) -> Self {
+        TtlCache {
+            inner: Rc::clone(&self.inner),
+        }
+    }
+}
+
+impl<K: Ord + Clone, V: Clone> TtlCache<K, V> {
+    /// Creates an empty cache that holds at most `max_entries` entries (at least one).
+    pub fn new(max_entries: usize) -> Self {
+        TtlCache {
+            inner: Rc::new(RefCell::new(Inner {
+                max_entries: max_entries.max(1),
+                entries: BTreeMap::new(),
+                clock: 0,
+            })),
+        }
+    }
+
+    /// Returns the value of `key`, unless there's none or it expired by `now` (in nanoseconds).
+    pub fn get(&self, key: &K, now: u64) -> Option<V> {
+        let mut inner = self.inner.borrow_mut();
+        inner.clock += 1;
+        let clock = inner.clock;
+        match inner.entries.get_mut(key) {
+            Some(entry) if now < entry.expires_at => {
+                entry.last_used = clock;
+                Some(entry.value.clone())
+            }
+            Some(_) => {
+                inner.entries.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Stores `value` under `key` until `ttl` after `now`.
+    pub fn insert(&self, key: K, value: V, ttl: Duration, now: u64) {
+        let mut inner = self.inner.borrow_mut();
+        inner.clock += 1;
+        let entry = Entry {
+            value,
+            expires_at: now.saturating_add(ttl.as_nanos().try_into().unwrap_or(u64::MAX)),
+            last_used: inner.clock,
+        };
+        if !inner.entries.contains_key(&key) && inner.entries.len() >= inner.max_entries {
+            inner.entries.retain(|_, entry| now < entry.expires_at);
+            if inner.entries.len() >= inner.max_entries {
+                inner.evict_least_recently_used();
+            }
+        }
+        inner.entries.insert(key, entry);
+    }
+
+    /// Returns the value of `key` if it's cached, and otherwise awaits `fetch`, caching its value
+    /// for `ttl` if it succeeds. Errors aren't cached, so the next call fetches again.
+    pub async fn get_or_insert_with_async<E, Fut>(
+        &self,
+        key: K,
+        ttl: Duration,
+        now: u64,
+        fetch: Fut,
+    ) -> Result<V, E>
+    where
+        Fut: Future<Output = Result<V, E>>,
+    {
+        if let Some(value) = self.get(&key, now) {
+            return Ok(value);
+        }
+        // No borrow is held across the `await`, so other executions can use the cache meanwhile.
+        let value = fetch.await?;
+        self.insert(key, value.clone(), ttl, now);
+        Ok(value)
+    }
+}
+
+impl<K: Ord + Clone, V> Inner<K, V> {
+    fn evict_least_recently_used(&mut self) {
+        let oldest = self
+            .entries
+            .iter()
+            .min_by_key(|(_, entry)| entry.last_used)
+            .map(|(key, _)| key.clone());
+        if let Some(key) = oldest {
+            self.entries.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use std::cell::Cell;
+
+    const TTL: Duration = Duration::from_secs(60);
+    const TTL_NS: u64 = 60_000_000_000;
+
+    // Fetches `value`, counting the fetches.
+    async fn fetch(fetches: &Cell<u32>, value: u32) -> Result<u32, String> {
+        fetches.set(fetches.get() + 1);
+        Ok(value)
+    }
+
+    #[test]
+    fn test_hit() {
+        let cache = TtlCache::new(4);
+        cache.insert("fee", 10, TTL, 0);
+        let fetches = Cell::new(0);
+
+        let value = block_on(cache.get_or_insert_with_async("fee", TTL, 5, fetch(&fetches, 20)));
+
+        assert_eq!(value, Ok(10));
+        assert_eq!(fetches.get(), 0);
+    }
+
+    #[test]
+    fn test_miss_then_populate() {
+        let cache = TtlCache::new(4);
+        let fetches = Cell::new(0);
+
+        let first = block_on(cache.get_or_insert_with_async("fee", TTL, 0, fetch(&fetches, 10)));
+        let second = block_on(cache.get_or_insert_with_async("fee", TTL, 1, fetch(&fetches, 20)));
+
+        assert_eq!((first, second), (Ok(10), Ok(10)));
+        assert_eq!(fetches.get(), 1);
+    }
+
+    #[test]
+    fn test_errors_are_not_cached() {
+        let cache: TtlCache<&str, u32> = TtlCache::new(4);
+
+        let failed = block_on(cache.get_or_insert_with_async("fee", TTL, 0, async {
+            Err::<u32, _>("The ledger is down".to_string())
+        }));
+
+        assert!(failed.is_err());
+        assert_eq!(cache.get(&"fee", 0), None);
+    }
+
+    #[test]
+    fn test_expiry() {
+        let cache = TtlCache::new(4);
+        cache.insert("fee", 10, TTL, 0);
+
+        assert_eq!(cache.get(&"fee", TTL_NS - 1), Some(10));
+        assert_eq!(cache.get(&"fee", TTL_NS), None);
+        let fetches = Cell::new(0);
+        let value = block_on(cache.get_or_insert_with_async("fee", TTL, TTL_NS, fetch(&fetches, 20)));
+        assert_eq!(value, Ok(20));
+        assert_eq!(fetches.get(), 1);
+    }
+
+    #[test]
+    fn test_least_recently_used_is_evicted_at_capacity() {
+        let cache = TtlCache::new(2);
+        cache.insert("a", 1, TTL, 0);
+        cache.insert("b", 2, TTL, 0);
+        // Using "a" makes "b" the least recently used entry.
+        assert_eq!(cache.get(&"a", 1), Some(1));
+
+        cache.insert("c", 3, TTL, 2);
+
+        assert_eq!(cache.get(&"a", 3), Some(1));
+        assert_eq!(cache.get(&"b", 3), None);
+        assert_eq!(cache.get(&"c", 3), Some(3));
+    }
+
+    #[test]
+    fn test_expired_entries_are_evicted_first() {
+        let cache = TtlCache::new(2);
+        cache.insert("short", 1, Duration::from_secs(1), 0);
+        cache.insert("long", 2, TTL, 0);
+        assert_eq!(cache.get(&"short", 1), Some(1));
+
+        cache.insert("new", 3, TTL, 2_000_000_000);
+
+        assert_eq!(cache.get(&"long", 2_000_000_000), Some(2));
+        assert_eq!(cache.get(&"new", 2_000_000_000), Some(3));
+    }
+}
@@ -0,0 +1,179 @@
+use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_ledger_types::{
+    AccountIdentifier, BlockIndex, Memo, Subaccount, Timestamp, Tokens, TransferArgs,
+    TransferError,
+};
+
+use crate::decode::decode_or_describe;
+use crate::transport::{OutboundCall, Transport};
+
+/// The ID of the cycles minting canister (CMC) on the IC mainnet.
+pub const CMC_CANISTER_ID: &str = "rkp4c-7iaaa-aaaaa-aaaca-cai";
+
+/// The memo that marks a transfer to the CMC as a canister top-up: "TPUP" in ASCII.
+pub const MEMO_TOP_UP_CANISTER: Memo = Memo(0x5055_5054);
+
+/// The subaccount of the CMC that ICP must be sent to in order to top up `target`.
+// The CMC learns which canister to top up from the subaccount the ICP was sent to: the first
+// byte is the length of the principal, followed by the principal's bytes, padded with zeros.
+// Principals are at most 29 bytes long, so they always fit.
+pub fn canister_subaccount(target: Principal) -> [u8; 32] {
+    let bytes = target.as_slice();
+    let mut subaccount = [0; 32];
+    subaccount[0] = bytes.len() as u8;
+    subaccount[1..1 + bytes.len()].copy_from_slice(bytes);
+    subaccount
+}
+
+#[derive(CandidType, Deserialize)]
+struct NotifyTopUpArg {
+    block_index: BlockIndex,
+    canister_id: Principal,
+}
+
+/// The errors of the CMC's `notify_top_up`.
+#[derive(CandidType, Deserialize, Debug)]
+enum NotifyError {
+    Refunded {
+        reason: String,
+        block_index: Option<BlockIndex>,
+    },
+    Processing,
+    TransactionTooOld(BlockIndex),
+    InvalidTransaction(String),
+    Other {
+        error_code: u64,
+        error_message: String,
+    },
+}
+
+/// Converts `amount` ICP of our own into cycles for `target`, and returns the number of cycles
+/// that `target` received. `fee` is the ICP ledger's transfer fee.
+// A top-up takes two steps: we send the ICP to the CMC, and then tell the CMC about the transfer
+// so that it mints the cycles. If the second step fails, the ICP stays with the CMC; calling
+// `notify_top_up` again with the block index from the error completes the top-up.
+pub async fn top_up_with_icp_via<T: Transport>(
+    transport: &T,
+    icp_ledger: Principal,
+    target: Principal,
+    amount: Tokens,
+    fee: Tokens,
+) -> Result<Nat, String> {
+    let cmc = Principal::from_text(CMC_CANISTER_ID).unwrap();
+    let args = TransferArgs {
+        memo: MEMO_TOP_UP_CANISTER,
+        to: AccountIdentifier::new(&cmc, &Subaccount(canister_subaccount(target))),
+        amount,
+        fee,
+        from_subaccount: None,
+        created_at_time: Some(Timestamp {
+            timestamp_nanos: transport.time(),
+        }),
+    };
+    let reply = transport
+        .call(OutboundCall {
+            target: icp_ledger,
+            method: "transfer".to_string(),
+            args: candid::encode_one(&args).unwrap(),
+            cycles: 0,
+            bounded_wait: false,
+            untrusted: false,
+        })
+        .await
+        .map_err(|e| format!("Error sending the ICP to the CMC: {:?}", e))?;
+    let block_index = decode_or_describe::<Result<BlockIndex, TransferError>>(&reply)?
+        .map_err(|e| format!("Ledger returned an error: {:?}", e))?;
+
+    let arg = NotifyTopUpArg {
+        block_index,
+        canister_id: target,
+    };
+    let reply = transport
+        .call(OutboundCall {
+            target: cmc,
+            method: "notify_top_up".to_string(),
+            args: candid::encode_one(arg).unwrap(),
+            cycles: 0,
+            bounded_wait: false,
+            untrusted: false,
+        })
+        .await
+        .map_err(|e| {
+            format!(
+                "Sent the ICP in block {}, but notifying the CMC failed: {:?}",
+                block_index, e
+            )
+        })?;
+    decode_or_describe::<Result<Nat, NotifyError>>(&reply)?.map_err(|e| {
+        format!(
+            "Sent the ICP in block {}, but the CMC returned an error: {:?}",
+            block_index, e
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::mock::MockTransport;
+    use futures::executor::block_on;
+
+    #[test]
+    fn test_canister_subaccount() {
+        // The canister with ID 0x00000000000000010101.
+        let target = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap();
+        let mut expected = [0; 32];
+        expected[..11].copy_from_slice(&[10, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1]);
+        assert_eq!(canister_subaccount(target), expected);
+    }
+
+    #[test]
+    fn test_top_up_sends_to_target_subaccount_then_notifies() {
+        let transport = MockTransport::new();
+        transport
+            .reply(&Ok::<BlockIndex, TransferError>(7))
+            .reply(&Ok::<Nat, NotifyError>(Nat::from(1_000_000_u64)));
+        let ledger = Principal::from_text(crate::ledger::ICP_LEDGER_CANISTER_ID).unwrap();
+        let target = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap();
+
+        let result = block_on(top_up_with_icp_via(
+            &transport,
+            ledger,
+            target,
+            Tokens::from_e8s(100_000_000),
+            Tokens::from_e8s(10_000),
+        ));
+
+        assert_eq!(result, Ok(Nat::from(1_000_000_u64)));
+        let calls = transport.calls();
+        let transfer: TransferArgs = candid::decode_one(&calls[0].args).unwrap();
+        let cmc = Principal::from_text(CMC_CANISTER_ID).unwrap();
+        assert_eq!(
+            transfer.to,
+            AccountIdentifier::new(&cmc, &Subaccount(canister_subaccount(target)))
+        );
+        assert_eq!(transfer.memo, MEMO_TOP_UP_CANISTER);
+        let notify: NotifyTopUpArg = candid::decode_one(&calls[1].args).unwrap();
+        assert_eq!((notify.block_index, notify.canister_id), (7, target));
+    }
+
+    #[test]
+    fn test_failed_notification_reports_block() {
+        let transport = MockTransport::new();
+        transport
+            .reply(&Ok::<BlockIndex, TransferError>(7))
+            .reply(&Err::<Nat, NotifyError>(NotifyError::Processing));
+        let ledger = Principal::from_text(crate::ledger::ICP_LEDGER_CANISTER_ID).unwrap();
+
+        let error = block_on(top_up_with_icp_via(
+            &transport,
+            ledger,
+            Principal::management_canister(),
+            Tokens::from_e8s(100_000_000),
+            Tokens::from_e8s(10_000),
+        ))
+        .unwrap_err();
+
+        assert!(error.contains("block 7"), "{}", error);
+    }
+}
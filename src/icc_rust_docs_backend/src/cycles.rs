@@ -0,0 +1,350 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::call::RejectCode;
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeSet;
+use std::future::Future;
+
+use crate::budget::check_cycles;
+use crate::decode::decode_or_describe;
+use crate::error::AppError;
+use crate::transport::{CallFailure, OutboundCall, Transport};
+
+/// Running totals of the cycles attached to outbound calls, and of the cycles that came back.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CyclesReport {
+    pub attached: u128,
+    pub refunded: u128,
+}
+
+thread_local! {
+    static CYCLES_REPORT: RefCell<CyclesReport> = RefCell::new(CyclesReport::default());
+    // Callees that kept more of the attached cycles than their service costs, and that we no
+    // longer attach cycles to. Like the rest of the heap, the set is cleared by upgrades.
+    static BLOCKED_CALLEES: RefCell<BTreeSet<Principal>> = const { RefCell::new(BTreeSet::new()) };
+    // Whether to add callees to `BLOCKED_CALLEES` when they overcharge, see `InitArgs`.
+    static BLOCK_OVERCHARGING: Cell<bool> = const { Cell::new(false) };
+}
+
+pub fn set_block_overcharging(block: bool) {
+    BLOCK_OVERCHARGING.with(|b| b.set(block));
+}
+
+/// Lets us attach cycles to `callee` again, after it was blocked for overcharging.
+pub fn unblock(callee: Principal) {
+    BLOCKED_CALLEES.with(|b| b.borrow_mut().remove(&callee));
+}
+
+/// Fails if `callee` was blocked for overcharging.
+pub fn check_not_blocked(callee: Principal) -> Result<(), String> {
+    if BLOCKED_CALLEES.with(|b| b.borrow().contains(&callee)) {
+        Err(format!(
+            "Canister {} kept more cycles than its service costs, so we no longer attach cycles to \
+             it; the controllers can call unblock_callee to allow it again",
+            callee
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Describes the anomaly if a successful call to a service that should cost at most
+/// `expected_charge` cycles only refunded `refunded` of the `attached` cycles.
+// A callee may accept any part of the attached cycles, and the system can't tell a fair charge
+// from an unfair one; only we know what the service should cost. An untrusted callee could thus
+// keep everything we attach, and drain our balance one call at a time. Charging less than
+// expected is fine: services such as the XRC sometimes charge less for cached results.
+pub fn refund_anomaly(attached: u128, refunded: u128, expected_charge: u128) -> Option<String> {
+    let charged = attached.saturating_sub(refunded);
+    (charged > expected_charge).then(|| {
+        format!(
+            "The callee kept {} of the {} attached cycles, but the service should cost at most {}",
+            charged, attached, expected_charge
+        )
+    })
+}
+
+/// Reports the anomaly of a call to `callee` to `log`, and blocks the callee if we're configured
+/// to do so.
+pub fn on_refund_anomaly(callee: Principal, anomaly: String, log: impl FnOnce(String)) {
+    let blocked = BLOCK_OVERCHARGING.with(|b| b.get());
+    if blocked {
+        BLOCKED_CALLEES.with(|b| b.borrow_mut().insert(callee));
+    }
+    log(format!(
+        "Refund anomaly on {}: {}{}",
+        callee,
+        anomaly,
+        if blocked { "; blocking the callee" } else { "" }
+    ));
+}
+
+/// Returns the totals recorded so far.
+pub fn report() -> CyclesReport {
+    CYCLES_REPORT.with(|report| *report.borrow())
+}
+
+/// Calls `method` on `target` with `cycles` attached, and returns the reply together with the
+/// number of cycles refunded. The refund is also added to the report, and checked against
+/// `expected_charge`, the most that the call should cost, see `refund_anomaly`.
+// Right after a call returns, the system tells us how many of the attached cycles came back:
+// the callee may accept only part of them (the XRC, for instance, refunds what it doesn't charge
+// for a request), and they all come back if the call is rejected before reaching the callee.
+pub async fn call_with_cycles_accounted<T: Transport, R: CandidType + for<'de> Deserialize<'de>>(
+    transport: &T,
+    target: Principal,
+    method: &str,
+    arg_bytes: Vec<u8>,
+    cycles: u128,
+    expected_charge: u128,
+) -> Result<(R, u128), String> {
+    check_cycles(target, cycles)?;
+    check_not_blocked(target)?;
+    let refunded_before = transport.cycles_refunded();
+    let call = transport.call(OutboundCall {
+        target,
+        method: method.to_string(),
+        args: arg_bytes,
+        cycles,
+        bounded_wait: true,
+        untrusted: false,
+    });
+    let result = refund_on_failure(
+        cycles,
+        call,
+        |result| refund_of(result, cycles, transport.cycles_refunded() - refunded_before),
+        |e| matches!(e, CallFailure::Rejected { .. }),
+        |discrepancy| ic_cdk::println!("{}: {}", method, discrepancy),
+    )
+    .await;
+    if let Ok((_, refunded)) = &result {
+        if let Some(anomaly) = refund_anomaly(cycles, *refunded, expected_charge) {
+            on_refund_anomaly(target, anomaly, |message| ic_cdk::println!("{}: {}", method, message));
+        }
+    }
+    let (reply, refunded) = result.map_err(|e| match e {
+        // The cycles came back, but the user needs to fix the target before retrying.
+        CallFailure::Rejected {
+            code: RejectCode::DestinationInvalid,
+            ..
+        } => AppError::InvalidTarget { target }.to_string(),
+        // Nothing is refunded if the outcome is unknown, even if the call never reached the
+        // callee.
+        CallFailure::SysUnknown(e) => format!(
+            "The outcome of {} is unknown ({}), and the attached cycles are lost",
+            method, e
+        ),
+        e => format!("Error calling {}: {:?}", method, e),
+    })?;
+    let reply = decode_or_describe(&reply).map_err(|e| format!("Error calling {}: {}", method, e))?;
+    Ok((reply, refunded))
+}
+
+/// The cycles refunded for a call that attached `attached` cycles and came back with `result`,
+/// given that the transport reported `reported` cycles as refunded since the call was made.
+// The system only reports a refund once a response came back. A call that it rejected
+// synchronously never left, so all the cycles are still ours, even though no refund is reported.
+fn refund_of<R>(result: &Result<R, CallFailure>, attached: u128, reported: u128) -> u128 {
+    match result {
+        Err(CallFailure::Rejected { sync: true, .. }) => attached,
+        _ => reported,
+    }
+}
+
+/// Like `with_refund_accounting`, but if the call failed with an error that `is_rejection`
+/// recognizes, also checks that all `attached` cycles were refunded, and passes a description of
+/// the discrepancy to `log` if not.
+// A rejected call never executed, so the callee had no chance to accept any cycles, and the
+// system must refund all of them. A shortfall means that our accounting, or the system's, is off.
+// We don't trap, as the cycles are gone either way, but we want to hear about it.
+pub async fn refund_on_failure<R, E>(
+    attached: u128,
+    call: impl Future<Output = Result<R, E>>,
+    refunded: impl FnOnce(&Result<R, E>) -> u128,
+    is_rejection: impl FnOnce(&E) -> bool,
+    log: impl FnOnce(String),
+) -> Result<(R, u128), E> {
+    let mut refund = 0;
+    let result = with_refund_accounting(attached, call, |result| {
+        refund = refunded(result);
+        refund
+    })
+    .await;
+    if let Err(e) = &result {
+        if is_rejection(e) && refund < attached {
+            log(format!(
+                "The call was rejected, but only {} of the {} attached cycles were refunded",
+                refund, attached
+            ));
+        }
+    }
+    result
+}
+
+/// Awaits `call`, which attached `attached` cycles, and records the refund that `refunded`
+/// reports for its result.
+pub async fn with_refund_accounting<R, E>(
+    attached: u128,
+    call: impl Future<Output = Result<R, E>>,
+    refunded: impl FnOnce(&Result<R, E>) -> u128,
+) -> Result<(R, u128), E> {
+    let result = call.await;
+    let refunded = refunded(&result);
+    CYCLES_REPORT.with(|report| {
+        let mut report = report.borrow_mut();
+        report.attached += attached;
+        report.refunded += refunded;
+    });
+    result.map(|reply| (reply, refunded))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::mock::MockTransport;
+    use futures::executor::block_on;
+
+    #[test]
+    fn test_refund_is_reported() {
+        let result = block_on(with_refund_accounting(
+            1_000_000_000,
+            async { Ok::<_, String>(42_u64) },
+            |_| 250_000_000,
+        ));
+
+        assert_eq!(result, Ok((42, 250_000_000)));
+        assert_eq!(
+            report(),
+            CyclesReport {
+                attached: 1_000_000_000,
+                refunded: 250_000_000
+            }
+        );
+    }
+
+    fn rejected_call(attached: u128, refunded: u128) -> Option<String> {
+        let logged = RefCell::new(None);
+        let result = block_on(refund_on_failure(
+            attached,
+            async { Err::<(), _>("rejected".to_string()) },
+            |_| refunded,
+            |e| e == "rejected",
+            |discrepancy| *logged.borrow_mut() = Some(discrepancy),
+        ));
+        assert!(result.is_err());
+        logged.into_inner()
+    }
+
+    #[test]
+    fn test_full_refund_on_rejection_is_verified() {
+        assert_eq!(rejected_call(1_000, 1_000), None);
+    }
+
+    #[test]
+    fn test_missing_refund_on_rejection_is_logged() {
+        let discrepancy = rejected_call(1_000, 400).unwrap();
+        assert!(discrepancy.contains("only 400 of the 1000"), "{}", discrepancy);
+    }
+
+    #[test]
+    fn test_partial_refund_on_success_is_fine() {
+        let logged = RefCell::new(None::<String>);
+        let result = block_on(refund_on_failure(
+            1_000,
+            async { Ok::<_, String>(()) },
+            |_| 400,
+            |_| true,
+            |discrepancy| *logged.borrow_mut() = Some(discrepancy),
+        ));
+        assert_eq!(result, Ok(((), 400)));
+        assert_eq!(logged.into_inner(), None);
+    }
+
+    #[test]
+    fn test_low_refund_on_success_is_an_anomaly() {
+        let callee = Principal::from_slice(&[7; 10]);
+        // The service costs 400, but the callee kept all 1000 cycles.
+        let anomaly = refund_anomaly(1_000, 0, 400).unwrap();
+        assert!(anomaly.contains("kept 1000 of the 1000"), "{}", anomaly);
+        let logged = RefCell::new(None);
+
+        set_block_overcharging(true);
+        on_refund_anomaly(callee, anomaly, |message| *logged.borrow_mut() = Some(message));
+
+        let logged = logged.into_inner().unwrap();
+        assert!(logged.starts_with(&format!("Refund anomaly on {}", callee)), "{}", logged);
+        assert!(logged.contains("blocking"), "{}", logged);
+        assert!(check_not_blocked(callee).is_err());
+        unblock(callee);
+        assert_eq!(check_not_blocked(callee), Ok(()));
+    }
+
+    #[test]
+    fn test_expected_charge_is_not_an_anomaly() {
+        assert_eq!(refund_anomaly(1_000, 600, 400), None);
+        assert_eq!(refund_anomaly(1_000, 1_000, 400), None);
+        assert!(refund_anomaly(1_000, 599, 400).is_some());
+    }
+
+    #[test]
+    fn test_cycles_call_goes_through_the_transport() {
+        let transport = MockTransport::new();
+        transport.refund_per_call.set(400);
+        transport.reply(&42_u64);
+        let target = Principal::from_slice(&[8; 10]);
+
+        let result = block_on(call_with_cycles_accounted::<_, u64>(
+            &transport,
+            target,
+            "get_exchange_rate",
+            candid::encode_args(()).unwrap(),
+            1_000,
+            600,
+        ));
+
+        assert_eq!(result, Ok((42, 400)));
+        let call = &transport.calls()[0];
+        assert_eq!((call.target, call.cycles, call.bounded_wait), (target, 1_000, true));
+    }
+
+    #[test]
+    fn test_sync_rejection_refunds_everything() {
+        let transport = MockTransport::new();
+        transport.refund_per_call.set(400);
+        transport.fail(CallFailure::Rejected {
+            code: RejectCode::SysTransient,
+            message: "Queue full".to_string(),
+            sync: true,
+        });
+        let result = block_on(call_with_cycles_accounted::<_, u64>(
+            &transport,
+            Principal::from_slice(&[8; 10]),
+            "get_exchange_rate",
+            candid::encode_args(()).unwrap(),
+            1_000,
+            600,
+        ));
+
+        assert!(result.unwrap_err().contains("Queue full"));
+        // The transport reports no refund for a call that never left, but the cycles are ours.
+        assert_eq!(transport.cycles_refunded(), 0);
+        assert_eq!(
+            report(),
+            CyclesReport {
+                attached: 1_000,
+                refunded: 1_000
+            }
+        );
+    }
+
+    #[test]
+    fn test_refund_is_recorded_on_error() {
+        let result = block_on(with_refund_accounting(
+            1_000,
+            async { Err::<(), _>("rejected".to_string()) },
+            |_| 1_000,
+        ));
+
+        assert!(result.is_err());
+        assert_eq!(report().refunded, 1_000);
+    }
+}
@@ -0,0 +1,212 @@
+use candid::types::value::IDLValue;
+use candid::{CandidType, Deserialize, IDLArgs, Nat};
+
+/// Renders a Candid-encoded reply in the Candid text format, e.g., `("hello", 42 : nat)`.
+/// If the reply isn't even valid Candid, shows its bytes in hex.
+// Useful in error messages when a reply doesn't decode into the type we expected: Candid
+// messages describe their own types, so we can always show what the callee actually sent.
+pub fn describe_reply(reply: &[u8]) -> String {
+    match IDLArgs::from_bytes(reply) {
+        Ok(args) => args.to_string(),
+        Err(_) => format!("0x{}", hex::encode(reply)),
+    }
+}
+
+/// Decodes a Candid-encoded reply into `R`. If that fails, the error shows what the reply
+/// contained, as rendered by `describe_reply`.
+pub fn decode_or_describe<R: CandidType + for<'de> Deserialize<'de>>(bytes: &[u8]) -> Result<R, String> {
+    candid::decode_one(bytes).map_err(|e| {
+        format!(
+            "Unable to decode the reply as {} ({}); the reply was: {}",
+            std::any::type_name::<R>(),
+            e,
+            describe_reply(bytes)
+        )
+    })
+}
+
+/// Decodes a reply of type `variant { Ok : T; Err : E }`, whatever `T` and `E` are. Returns the
+/// `Ok` value, Candid-encoded on its own, or the `Err` value in the Candid text format.
+// Many canisters return their errors this way, but without bindings for `E`, `decode_one` has
+// nothing to decode into. Candid messages describe their own types, so we decode the reply as a
+// generic value instead. Messages only carry hashes of the field and variant names, though, so
+// the error shows names as numbers (see `candid::idl_hash`), except for the values themselves,
+// such as error messages, which come through as they are.
+pub fn decode_variant_result(bytes: &[u8]) -> Result<Vec<u8>, String> {
+    let not_a_result = || {
+        format!(
+            "Expected a variant with Ok and Err arms; the reply was: {}",
+            describe_reply(bytes)
+        )
+    };
+    let args = IDLArgs::from_bytes(bytes).map_err(|_| not_a_result())?;
+    let [IDLValue::Variant(variant)] = args.args.as_slice() else {
+        return Err(not_a_result());
+    };
+    let arm = &variant.0;
+    let label = arm.id.get_id();
+    if label == candid::idl_hash("Ok") {
+        IDLArgs::new(&[arm.val.clone()])
+            .to_bytes()
+            .map_err(|e| format!("Unable to re-encode the Ok value: {}", e))
+    } else if label == candid::idl_hash("Err") {
+        Err(format!("The callee returned an error: {}", arm.val))
+    } else {
+        Err(not_a_result())
+    }
+}
+
+/// How `decode_fee` made sense of a fee.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FeeEncoding {
+    /// A `nat`, as ICRC-1 specifies.
+    Standard,
+    /// Some other number, possibly wrapped, found by `find_number`.
+    Fallback,
+}
+
+/// Decodes the reply of a ledger's `icrc1_fee`, and says how.
+// ICRC-1 specifies the fee as a `nat`, but not every ledger sticks to it: some return a `nat64`,
+// others wrap the fee in an option or a record. Candid won't turn those into a `nat`, so rather
+// than failing the transfer, we look at the reply as a generic value.
+pub fn decode_fee(bytes: &[u8]) -> Result<(Nat, FeeEncoding), String> {
+    let error = match candid::decode_one::<Nat>(bytes) {
+        Ok(fee) => return Ok((fee, FeeEncoding::Standard)),
+        Err(e) => e,
+    };
+    IDLArgs::from_bytes(bytes)
+        .ok()
+        .and_then(|args| match args.args.as_slice() {
+            [value] => find_number(value),
+            _ => None,
+        })
+        .map(|fee| (fee, FeeEncoding::Fallback))
+        .ok_or_else(|| {
+            format!(
+                "Unable to decode the fee ({}); the reply was: {}",
+                error,
+                describe_reply(bytes)
+            )
+        })
+}
+
+/// Returns the unsigned number in `value`, looking into options and single-field records.
+fn find_number(value: &IDLValue) -> Option<Nat> {
+    match value {
+        IDLValue::Nat(n) => Some(n.clone()),
+        IDLValue::Nat64(n) => Some(Nat::from(*n)),
+        IDLValue::Nat32(n) => Some(Nat::from(*n)),
+        IDLValue::Nat16(n) => Some(Nat::from(*n)),
+        IDLValue::Nat8(n) => Some(Nat::from(*n)),
+        IDLValue::Opt(inner) => find_number(inner),
+        IDLValue::Record(fields) if fields.len() == 1 => find_number(&fields[0].val),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_describe_candid_reply() {
+        let reply = candid::encode_args(("hello", 42_u64)).unwrap();
+        let description = describe_reply(&reply);
+        assert!(description.contains("\"hello\""), "{}", description);
+        assert!(description.contains("42"), "{}", description);
+    }
+
+    #[test]
+    fn test_variant_err_is_readable() {
+        #[derive(CandidType)]
+        enum CalleeError {
+            Unauthorized { reason: String },
+        }
+
+        let reply = candid::encode_one(Err::<u64, _>("The list is full".to_string())).unwrap();
+        assert_eq!(
+            decode_variant_result(&reply),
+            Err("The callee returned an error: \"The list is full\"".to_string())
+        );
+
+        let reply = candid::encode_one(Err::<u64, _>(CalleeError::Unauthorized {
+            reason: "not an admin".to_string(),
+        }))
+        .unwrap();
+        let error = decode_variant_result(&reply).unwrap_err();
+        assert!(error.contains("\"not an admin\""), "{}", error);
+    }
+
+    #[test]
+    fn test_variant_ok_is_reencoded() {
+        let reply = candid::encode_one(Ok::<Nat, String>(Nat::from(42_u64))).unwrap();
+        let ok = decode_variant_result(&reply).unwrap();
+        assert_eq!(candid::decode_one::<Nat>(&ok).unwrap(), Nat::from(42_u64));
+    }
+
+    #[test]
+    fn test_non_result_reply_is_an_error() {
+        let reply = candid::encode_one(42_u64).unwrap();
+        assert!(decode_variant_result(&reply).unwrap_err().contains("Ok and Err"));
+    }
+
+    #[test]
+    fn test_describe_invalid_reply() {
+        assert_eq!(describe_reply(&[0xde, 0xad]), "0xdead");
+    }
+
+    #[test]
+    fn test_decode_or_describe_success() {
+        let reply = candid::encode_one(Nat::from(7_u64)).unwrap();
+        assert_eq!(decode_or_describe::<Nat>(&reply), Ok(Nat::from(7_u64)));
+    }
+
+    #[test]
+    fn test_standard_fee_decodes() {
+        let reply = candid::encode_one(Nat::from(10_000_u64)).unwrap();
+        assert_eq!(
+            decode_fee(&reply),
+            Ok((Nat::from(10_000_u64), FeeEncoding::Standard))
+        );
+    }
+
+    #[test]
+    fn test_nonstandard_fees_decode() {
+        #[derive(CandidType)]
+        struct Wrapped {
+            fee: Nat,
+        }
+
+        let replies = [
+            candid::encode_one(10_000_u64).unwrap(),
+            candid::encode_one(Wrapped {
+                fee: Nat::from(10_000_u64),
+            })
+            .unwrap(),
+            candid::encode_one(Some(10_000_u64)).unwrap(),
+        ];
+        for reply in replies {
+            assert_eq!(
+                decode_fee(&reply),
+                Ok((Nat::from(10_000_u64), FeeEncoding::Fallback)),
+                "{}",
+                describe_reply(&reply)
+            );
+        }
+    }
+
+    #[test]
+    fn test_non_numeric_fee_is_an_error() {
+        let reply = candid::encode_one("ten thousand").unwrap();
+        let error = decode_fee(&reply).unwrap_err();
+        assert!(error.contains("\"ten thousand\""), "{}", error);
+    }
+
+    #[test]
+    fn test_decode_or_describe_mismatched_type() {
+        let reply = candid::encode_one("seven").unwrap();
+        let error = decode_or_describe::<Nat>(&reply).unwrap_err();
+        assert!(error.contains("Unable to decode the reply as candid::"), "{}", error);
+        assert!(error.contains("\"seven\""), "{}", error);
+    }
+}
@@ -0,0 +1,263 @@
+use candid::{CandidType, Deserialize, Principal};
+use std::fmt;
+
+use crate::transport::{describe_reject, CallFailure};
+
+/// Errors that our helpers report in a structured way, before they're turned into the strings
+/// returned to users.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum AppError {
+    /// We don't hold enough cycles to attach `required` cycles to a call.
+    InsufficientCycles { required: u128, shortfall: u128 },
+    /// `callee` didn't have enough cycles to process our call, so the call never executed. If we
+    /// control the callee, we deposited `topped_up` cycles into it, and retrying may now succeed.
+    CalleeOutOfCycles {
+        callee: Principal,
+        topped_up: Option<u128>,
+    },
+    /// The caller made too many calls recently, and may try again after `retry_after_secs`.
+    RateLimited { retry_after_secs: u64 },
+    /// We gave up after `attempts` attempts, each of which failed with an error worth retrying.
+    RetriesExhausted { attempts: u32 },
+    /// We gave up after `attempts` attempts, and the last one may or may not have taken effect.
+    /// `reason` is what the system told us about it.
+    OutcomeUnknown { attempts: u32, reason: String },
+    /// No canister `target` exists, or it has no code installed. Retrying won't help; the user
+    /// most likely passed the wrong principal.
+    InvalidTarget { target: Principal },
+    /// `target` was stopped through all of our `attempts`. A stop for an upgrade usually ends
+    /// within our retries, so the canister may be stopped for good.
+    CalleeStopped { target: Principal, attempts: u32 },
+    /// Arguments that we were given already encoded aren't valid Candid, so we didn't send them.
+    InvalidArgs { message: String },
+    /// The call was rejected, so the callee didn't execute it.
+    CallFailed { message: String },
+    /// The ledger processed our call, but returned an error.
+    LedgerError { message: String },
+    /// Our cycle balance dropped below the watermark, so we don't take on work that spends
+    /// cycles until the canister is topped up.
+    Paused,
+    /// We refused to attach `cycles` to a call to `target`, as that's more than its `cap`, see
+    /// `budget::CycleBudget`.
+    CycleCapExceeded {
+        target: Principal,
+        cycles: u128,
+        cap: u128,
+    },
+    /// The reply of `method` on `target` exceeded the size limit of the call.
+    ReplyTooLarge { target: Principal, method: String },
+    /// The calls of an execution of `method` would attach `spent` cycles in total, more than the
+    /// method's `limit`, see `budget::MethodSpend`.
+    MethodCyclesLimitExceeded {
+        method: String,
+        spent: u128,
+        limit: u128,
+    },
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::InsufficientCycles { required, shortfall } => write!(
+                f,
+                "Insufficient cycles: the call requires {} cycles, and we are {} cycles short",
+                required, shortfall
+            ),
+            AppError::CalleeOutOfCycles {
+                callee,
+                topped_up: None,
+            } => write!(f, "Canister {} is out of cycles; ask its controllers to top it up", callee),
+            AppError::CalleeOutOfCycles {
+                callee,
+                topped_up: Some(cycles),
+            } => write!(
+                f,
+                "Canister {} was out of cycles; we topped it up with {} cycles, please retry",
+                callee, cycles
+            ),
+            AppError::RateLimited { retry_after_secs } => write!(
+                f,
+                "Too many requests; retry in {} seconds",
+                retry_after_secs
+            ),
+            AppError::RetriesExhausted { attempts } => {
+                write!(f, "Giving up after {} attempts; please retry later", attempts)
+            }
+            AppError::OutcomeUnknown { attempts: 1, reason } => write!(
+                f,
+                "We don't know whether the call took effect ({}); check before retrying",
+                reason
+            ),
+            AppError::OutcomeUnknown { attempts, reason } => write!(
+                f,
+                "Giving up after {} attempts, without knowing whether the last one took effect \
+                 ({}); check before retrying",
+                attempts, reason
+            ),
+            AppError::InvalidTarget { target } => write!(
+                f,
+                "Canister {} doesn't exist or is empty; check that you passed the right principal",
+                target
+            ),
+            AppError::CalleeStopped { target, attempts } => write!(
+                f,
+                "Canister {} was still stopped after {} attempts; if it isn't being upgraded, ask \
+                 its controllers to start it",
+                target, attempts
+            ),
+            AppError::InvalidArgs { message } => write!(f, "The arguments aren't valid Candid: {}", message),
+            AppError::CallFailed { message } => write!(f, "Error calling canister: {}", message),
+            AppError::LedgerError { message } => write!(f, "Ledger returned an error: {}", message),
+            AppError::Paused => write!(
+                f,
+                "The canister is low on cycles and paused; retry once it has been topped up"
+            ),
+            AppError::CycleCapExceeded { target, cycles, cap } => write!(
+                f,
+                "Refusing to attach {} cycles to a call to {}; the cap is {} cycles",
+                cycles, target, cap
+            ),
+            AppError::ReplyTooLarge { target, method } => write!(
+                f,
+                "The reply of {} on {} is too large; use an unbounded wait call if you trust the \
+                 callee, or fetch the data in pages",
+                method, target
+            ),
+            AppError::MethodCyclesLimitExceeded { method, spent, limit } => write!(
+                f,
+                "{} would attach {} cycles to its calls in total, more than its limit of {} cycles",
+                method, spent, limit
+            ),
+        }
+    }
+}
+
+// Our endpoints return `Result<_, String>`, so let `?` convert an `AppError` on the way out.
+impl From<AppError> for String {
+    fn from(e: AppError) -> String {
+        e.to_string()
+    }
+}
+
+/// Checks that a balance of `available` cycles covers attaching `required` cycles to a call.
+// If we attached more cycles than we have, the system would reject the call, or worse, we'd
+// dip into the cycles reserved for the freezing threshold. Checking up front lets us tell the
+// user exactly how many cycles are missing.
+pub fn ensure_cycles(available: u128, required: u128) -> Result<(), AppError> {
+    if available < required {
+        Err(AppError::InsufficientCycles {
+            required,
+            shortfall: required - available,
+        })
+    } else {
+        Ok(())
+    }
+}
+
+/// Collapses the result of a ledger call, whose reply is itself a `Result`, into a single
+/// `Result`.
+// A call to a ledger can fail twice over: the call itself can fail, or the ledger can reply with
+// an error. Callers usually only care whether they got a `T`, so `?` is more convenient than a
+// nested match. The error still says which of the two happened, and whether the call may have
+// taken effect: a rejected call didn't, but if the ledger trapped, or the system gave up waiting,
+// it may have. Callers that can't decode the reply report that as an unknown outcome, too. Our ICP
+// transfers aren't deduplicated, so we can't just retry those. The call may have failed with a
+// `CallError`, as `Call` reports it, or with a `CallFailure`, as our transports report it.
+pub fn flatten_ledger_result<T, E: fmt::Debug, F: Into<CallFailure>>(
+    r: Result<Result<T, E>, F>,
+) -> Result<T, AppError> {
+    match r.map_err(Into::into) {
+        Ok(Ok(value)) => Ok(value),
+        Ok(Err(e)) => Err(AppError::LedgerError {
+            message: format!("{:?}", e),
+        }),
+        Err(CallFailure::Rejected { code, message, .. }) => Err(AppError::CallFailed {
+            message: describe_reject(code, &message),
+        }),
+        Err(CallFailure::CanisterError(reason) | CallFailure::SysUnknown(reason)) => {
+            Err(outcome_unknown(1, reason))
+        }
+    }
+}
+
+/// The error for a ledger call whose last attempt, the `attempts`th, reached the ledger, but
+/// whose outcome we couldn't learn: the ledger trapped, or replied with something we can't decode.
+pub fn outcome_unknown(attempts: u32, reason: String) -> AppError {
+    AppError::OutcomeUnknown { attempts, reason }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_cdk::call::{CallError, RejectCode, StateUnknown};
+
+    #[test]
+    fn test_ensure_cycles_below_fee() {
+        assert_eq!(
+            ensure_cycles(400_000_000, 1_000_000_000),
+            Err(AppError::InsufficientCycles {
+                required: 1_000_000_000,
+                shortfall: 600_000_000
+            })
+        );
+    }
+
+    #[test]
+    fn test_ensure_cycles_enough() {
+        assert_eq!(ensure_cycles(1_000_000_000, 1_000_000_000), Ok(()));
+        assert_eq!(ensure_cycles(5_000_000_000, 1_000_000_000), Ok(()));
+    }
+
+    #[test]
+    fn test_flatten_ledger_reply() {
+        let result: Result<Result<u64, String>, CallFailure> = Ok(Ok(42));
+        assert_eq!(flatten_ledger_result(result), Ok(42));
+    }
+
+    #[test]
+    fn test_flatten_ledger_error() {
+        let result: Result<Result<u64, &str>, CallFailure> = Ok(Err("InsufficientFunds"));
+        let error = flatten_ledger_result(result).unwrap_err();
+        assert!(matches!(error, AppError::LedgerError { .. }));
+        assert!(error.to_string().contains("InsufficientFunds"), "{}", error);
+    }
+
+    #[test]
+    fn test_flatten_rejected_call() {
+        let result: Result<Result<u64, String>, CallFailure> = Err(CallFailure::Rejected {
+            code: RejectCode::SysTransient,
+            message: "Queue full".to_string(),
+            sync: true,
+        });
+        let error = flatten_ledger_result(result).unwrap_err();
+        assert!(matches!(error, AppError::CallFailed { .. }));
+        assert!(error.to_string().contains("Queue full"), "{}", error);
+    }
+
+    #[test]
+    fn test_flatten_crashed_ledger_is_outcome_unknown() {
+        let result: Result<Result<u64, String>, CallFailure> =
+            Err(CallFailure::CanisterError("Canister trapped".to_string()));
+        let error = flatten_ledger_result(result).unwrap_err();
+        assert!(matches!(error, AppError::OutcomeUnknown { attempts: 1, .. }));
+        assert!(error.to_string().contains("Canister trapped"), "{}", error);
+        assert!(error.to_string().contains("check before retrying"), "{}", error);
+    }
+
+    #[test]
+    fn test_flatten_undecodable_reply_is_outcome_unknown() {
+        let result: Result<Result<u64, String>, CallError> = Err(CallError::StateUnknown(
+            StateUnknown::CandidDecodeFailed("type mismatch".to_string()),
+        ));
+        let error = flatten_ledger_result(result).unwrap_err();
+        assert!(matches!(error, AppError::OutcomeUnknown { attempts: 1, .. }));
+        assert!(error.to_string().contains("type mismatch"), "{}", error);
+    }
+
+
+    #[test]
+    fn test_error_message_mentions_shortfall() {
+        let message: String = ensure_cycles(1, 10).unwrap_err().into();
+        assert!(message.contains("9 cycles short"));
+    }
+}
@@ -0,0 +1,150 @@
+use candid::{CandidType, Deserialize, Principal};
+
+use crate::decode::decode_or_describe;
+use crate::transport::{OutboundCall, Transport};
+
+/// The ID of the NNS governance canister on the IC mainnet.
+pub const GOVERNANCE_CANISTER_ID: &str = "rrkah-fqaaa-aaaaa-aaaaq-cai";
+
+// The governance canister's `ProposalInfo` has a couple of dozen fields, most of them nested
+// records we don't need. Candid lets a reply decode into a record with only some of its fields:
+// the decoder skips the others. So we declare just the fields we read, and the types keep
+// decoding when the governance canister adds fields. Fields we declare must keep their names and
+// types, though, and making one of them optional in the interface would break us.
+
+/// The part of the governance canister's `ProposalInfo` that we read.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ProposalInfo {
+    /// See `status_name`.
+    pub status: i32,
+    pub deadline_timestamp_seconds: Option<u64>,
+    pub proposal: Option<Proposal>,
+}
+
+/// The part of the governance canister's `Proposal` that we read.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Proposal {
+    pub title: Option<String>,
+}
+
+/// The name of a proposal status, as the governance canister numbers them.
+pub fn status_name(status: i32) -> &'static str {
+    match status {
+        1 => "open",
+        2 => "rejected",
+        3 => "adopted",
+        4 => "executed",
+        5 => "failed",
+        _ => "unspecified",
+    }
+}
+
+/// Describes a proposal in one line, e.g., `"Upgrade the ledger" (adopted, voting ends at
+/// 1700000000)`, with the deadline in seconds since the epoch.
+pub fn summarize(info: &ProposalInfo) -> String {
+    let title = info
+        .proposal
+        .as_ref()
+        .and_then(|p| p.title.as_deref())
+        .unwrap_or("(untitled)");
+    let deadline = match info.deadline_timestamp_seconds {
+        Some(seconds) => format!(", voting ends at {}", seconds),
+        None => String::new(),
+    };
+    format!("\"{}\" ({}{})", title, status_name(info.status), deadline)
+}
+
+/// Fetches proposal `id` from the NNS governance canister and summarizes it. The canister
+/// returns `None` for proposals it doesn't know, which we turn into an error.
+// `get_proposal_info` is a query, but we call it like an update, from an update: canisters can't
+// call each other's queries as queries. Governance is a system canister, so we trust it with an
+// unbounded wait.
+pub async fn get_proposal_via<T: Transport>(transport: &T, id: u64) -> Result<String, String> {
+    let governance = Principal::from_text(GOVERNANCE_CANISTER_ID).unwrap();
+    let reply = transport
+        .call(OutboundCall {
+            target: governance,
+            method: "get_proposal_info".to_string(),
+            args: candid::encode_one(id).unwrap(),
+            cycles: 0,
+            bounded_wait: false,
+            untrusted: false,
+        })
+        .await
+        .map_err(|e| format!("Error calling get_proposal_info: {:?}", e))?;
+    match decode_or_describe::<Option<ProposalInfo>>(&reply)
+        .map_err(|e| format!("Error calling get_proposal_info: {}", e))?
+    {
+        Some(info) => Ok(summarize(&info)),
+        None => Err(format!("There is no proposal {}", id)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::mock::MockTransport;
+    use futures::executor::block_on;
+
+    // A reply with more of the fields the real `ProposalInfo` has, which we don't declare.
+    #[derive(CandidType)]
+    struct NeuronId {
+        id: u64,
+    }
+
+    #[derive(CandidType)]
+    struct FullProposal {
+        title: Option<String>,
+        summary: String,
+        url: String,
+    }
+
+    #[derive(CandidType)]
+    struct FullProposalInfo {
+        id: Option<NeuronId>,
+        status: i32,
+        topic: i32,
+        proposer: Option<NeuronId>,
+        proposal_timestamp_seconds: u64,
+        deadline_timestamp_seconds: Option<u64>,
+        proposal: Option<FullProposal>,
+    }
+
+    #[test]
+    fn test_proposal_info_decodes_into_a_summary() {
+        let transport = MockTransport::new();
+        transport.reply(&Some(FullProposalInfo {
+            id: Some(NeuronId { id: 132_411 }),
+            status: 3,
+            topic: 4,
+            proposer: Some(NeuronId { id: 27 }),
+            proposal_timestamp_seconds: 1_699_600_000,
+            deadline_timestamp_seconds: Some(1_700_000_000),
+            proposal: Some(FullProposal {
+                title: Some("Upgrade the ledger".to_string()),
+                summary: "Fixes a bug".to_string(),
+                url: "https://forum.dfinity.org".to_string(),
+            }),
+        }));
+
+        let summary = block_on(get_proposal_via(&transport, 132_411));
+
+        assert_eq!(
+            summary,
+            Ok("\"Upgrade the ledger\" (adopted, voting ends at 1700000000)".to_string())
+        );
+        let call = &transport.calls()[0];
+        assert_eq!(call.target, Principal::from_text(GOVERNANCE_CANISTER_ID).unwrap());
+        assert_eq!(candid::decode_one::<u64>(&call.args).unwrap(), 132_411);
+    }
+
+    #[test]
+    fn test_unknown_proposal_is_an_error() {
+        let transport = MockTransport::new();
+        transport.reply(&None::<ProposalInfo>);
+
+        let result = block_on(get_proposal_via(&transport, 7));
+
+        assert_eq!(result, Err("There is no proposal 7".to_string()));
+    }
+}
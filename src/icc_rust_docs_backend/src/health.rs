@@ -0,0 +1,109 @@
+use candid::{CandidType, Deserialize, Principal};
+use std::cell::Cell;
+
+use crate::airdrop::Airdrops;
+use crate::outbox::Outbox;
+use crate::proposals::Proposals;
+
+/// The size of a stable memory page, in bytes.
+const WASM_PAGE_SIZE: u64 = 65_536;
+
+/// A snapshot of the canister's health, for monitoring tools to scrape.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct HealthStatus {
+    pub cycles: u128,
+    pub stable_memory_bytes: u64,
+    /// Transfer proposals that are still waiting for approvals or being executed, and queued
+    /// or airdropped transfers that aren't done or failed yet.
+    pub pending_jobs: u64,
+    /// Whether an owner that can be authenticated is configured for `icp_transfer`.
+    pub owner_set: bool,
+    /// Nanoseconds since the code currently running was installed or upgraded.
+    pub uptime_nanos: u64,
+}
+
+thread_local! {
+    // Heap memory is wiped on upgrades, so this is the time of the last install or upgrade.
+    static STARTED_AT: Cell<u64> = const { Cell::new(0) };
+}
+
+/// Records `now` as the time the canister started.
+pub fn mark_started(now: u64) {
+    STARTED_AT.with(|s| s.set(now));
+}
+
+/// Assembles the health status from values that the caller read from the system API.
+pub fn health_status(
+    now: u64,
+    cycles: u128,
+    stable_pages: u64,
+    proposals: &Proposals,
+    outbox: &Outbox,
+    airdrops: &Airdrops,
+    owner: &str,
+) -> HealthStatus {
+    HealthStatus {
+        cycles,
+        stable_memory_bytes: stable_pages * WASM_PAGE_SIZE,
+        pending_jobs: (proposals.pending() + outbox.pending_jobs() + airdrops.pending_transfers()) as u64,
+        owner_set: Principal::from_text(owner).is_ok_and(|p| p != Principal::anonymous()),
+        uptime_nanos: now.saturating_sub(STARTED_AT.with(|s| s.get())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::Nat;
+    use ic_ledger_types::{AccountIdentifier, Tokens, DEFAULT_SUBACCOUNT};
+    use icrc_ledger_types::icrc1::account::Account;
+
+    const OWNER: &str = "gl542-2r2m3-znmmo-cjhz7-p332z-mbe6x-hmrnu-rv37c-mncas-i46u2-sqe";
+
+    #[test]
+    fn test_fields_populated_after_init() {
+        mark_started(1_000);
+        let mut proposals = Proposals::default();
+        let controller = Principal::from_slice(&[1; 29]);
+        proposals.propose(
+            controller,
+            AccountIdentifier::new(&controller, &DEFAULT_SUBACCOUNT),
+            Tokens::from_e8s(1),
+        );
+        let mut outbox = Outbox::default();
+        let to = Account {
+            owner: controller,
+            subaccount: None,
+        };
+        outbox.enqueue(controller, to, Nat::from(1_u64), 1_000);
+        let mut airdrops = Airdrops::default();
+        airdrops.enqueue(controller, Nat::from(1_u64), vec![(to, Nat::from(1_u64)); 2]);
+
+        let status = health_status(5_000, 3_000_000_000_000, 2, &proposals, &outbox, &airdrops, OWNER);
+
+        assert_eq!(
+            status,
+            HealthStatus {
+                cycles: 3_000_000_000_000,
+                stable_memory_bytes: 131_072,
+                pending_jobs: 4,
+                owner_set: true,
+                uptime_nanos: 4_000,
+            }
+        );
+    }
+
+    #[test]
+    fn test_anonymous_owner_is_not_set() {
+        let status = health_status(
+            0,
+            0,
+            0,
+            &Proposals::default(),
+            &Outbox::default(),
+            &Airdrops::default(),
+            "2vxsx-fae",
+        );
+        assert!(!status.owner_set);
+    }
+}
@@ -0,0 +1,111 @@
+use candid::{CandidType, Deserialize, Nat, Principal};
+use std::cell::RefCell;
+
+/// The most records returned by a single page, whatever the requested limit.
+pub const MAX_PAGE_SIZE: u16 = 100;
+
+/// A transfer that we successfully executed.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct TransferRecord {
+    /// Sequential, starting at 0. Used as the pagination cursor.
+    pub id: u64,
+    pub timestamp: u64,
+    pub ledger: Principal,
+    /// The recipient, as an ICRC-1 account or a hex-encoded ICP account identifier.
+    pub to: String,
+    pub amount: Nat,
+    pub block_index: Nat,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct History {
+    records: Vec<TransferRecord>,
+}
+
+thread_local! {
+    pub static HISTORY: RefCell<History> = RefCell::new(History::default());
+}
+
+impl History {
+    /// Appends a record, assigning it the next ID.
+    pub fn record(&mut self, timestamp: u64, ledger: Principal, to: String, amount: Nat, block_index: Nat) {
+        let id = self.records.len() as u64;
+        self.records.push(TransferRecord {
+            id,
+            timestamp,
+            ledger,
+            to,
+            amount,
+            block_index,
+        });
+    }
+
+    /// Returns up to `limit` records following the record with ID `after` (or from the start if
+    /// `after` is `None`), and the cursor to pass as `after` to get the next page, if there is one.
+    pub fn page(&self, after: Option<u64>, limit: u16) -> (Vec<TransferRecord>, Option<u64>) {
+        let limit = limit.clamp(1, MAX_PAGE_SIZE) as usize;
+        // IDs are indices into `records`.
+        let start = after.map_or(0, |id| id.saturating_add(1)) as usize;
+        let page: Vec<TransferRecord> = self.records.iter().skip(start).take(limit).cloned().collect();
+        let next_cursor = match page.last() {
+            Some(last) if (last.id as usize) + 1 < self.records.len() => Some(last.id),
+            _ => None,
+        };
+        (page, next_cursor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn history_of(n: u64) -> History {
+        let mut history = History::default();
+        for i in 0..n {
+            history.record(
+                i,
+                Principal::management_canister(),
+                format!("recipient-{}", i),
+                Nat::from(i),
+                Nat::from(i),
+            );
+        }
+        history
+    }
+
+    fn ids(page: &[TransferRecord]) -> Vec<u64> {
+        page.iter().map(|r| r.id).collect()
+    }
+
+    #[test]
+    fn test_first_page() {
+        let (page, next) = history_of(5).page(None, 2);
+        assert_eq!(ids(&page), vec![0, 1]);
+        assert_eq!(next, Some(1));
+    }
+
+    #[test]
+    fn test_middle_page() {
+        let (page, next) = history_of(5).page(Some(1), 2);
+        assert_eq!(ids(&page), vec![2, 3]);
+        assert_eq!(next, Some(3));
+    }
+
+    #[test]
+    fn test_last_and_exhausted_pages() {
+        let history = history_of(5);
+        let (page, next) = history.page(Some(3), 2);
+        assert_eq!(ids(&page), vec![4]);
+        assert_eq!(next, None);
+        let (page, next) = history.page(Some(4), 2);
+        assert!(page.is_empty());
+        assert_eq!(next, None);
+    }
+
+    #[test]
+    fn test_limit_is_capped() {
+        let (page, next) = history_of(150).page(None, u16::MAX);
+        assert_eq!(page.len(), MAX_PAGE_SIZE as usize);
+        assert_eq!(next, Some(MAX_PAGE_SIZE as u64 - 1));
+    }
+}
@@ -0,0 +1,146 @@
+use candid::Principal;
+use ic_cdk::management_canister::{
+    cost_http_request, HttpMethod, HttpRequestArgs, HttpRequestResult, TransformContext,
+};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use crate::decode::decode_or_describe;
+use crate::transport::{OutboundCall, Transport};
+
+/// The largest response we accept from an HTTP outcall, in bytes. We pay for the limit, not for
+/// the actual size, so we keep it close to what we expect.
+pub const MAX_RESPONSE_BYTES: u64 = 10_000;
+
+/// The name of the transform that `get_price_via_http` uses unless told otherwise.
+pub const DEFAULT_TRANSFORM: &str = "strip_headers";
+
+/// A transform: turns a response as received by one replica into the part that all replicas
+/// must agree on.
+pub type TransformFn = fn(HttpRequestResult) -> HttpRequestResult;
+
+thread_local! {
+    static TRANSFORMS: RefCell<BTreeMap<String, TransformFn>> = RefCell::new(BTreeMap::new());
+}
+
+/// Registers the transforms that come with the canister. Canisters calling other endpoints
+/// register their own transforms next to these.
+pub fn register_default_transforms() {
+    register_transform(DEFAULT_TRANSFORM, strip_headers);
+}
+
+/// Makes `transform` available under `name`, replacing any transform of the same name.
+// Each replica makes the HTTP request itself, and the responses only pass consensus if they're
+// identical. Servers differ in what varies between responses (dates, request IDs, cookies, ...),
+// so each endpoint we call may need its own transform.
+pub fn register_transform(name: &str, transform: TransformFn) {
+    TRANSFORMS.with(|t| t.borrow_mut().insert(name.to_string(), transform));
+}
+
+/// Applies the transform registered under `name` to `response`.
+pub fn apply_transform(name: &str, response: HttpRequestResult) -> Result<HttpRequestResult, String> {
+    let transform = TRANSFORMS
+        .with(|t| t.borrow().get(name).copied())
+        .ok_or_else(|| format!("No transform is registered under {}", name))?;
+    Ok(transform(response))
+}
+
+/// Keeps the status and the body, and drops the headers, which typically contain dates and
+/// other values that differ between replicas.
+pub fn strip_headers(response: HttpRequestResult) -> HttpRequestResult {
+    HttpRequestResult {
+        headers: vec![],
+        ..response
+    }
+}
+
+/// Fetches `url` with a GET request, applying the transform registered under `transform` to the
+/// response, and returns the response body.
+// The system calls the transform through one of our query methods, which we give the name of
+// the transform as the context. A single method thus serves every registered transform, see
+// `transform` in `lib.rs`. The outcall is a call to the management canister, which charges the
+// cycles that `cost_http_request` computes.
+pub async fn get_price_via_http<T: Transport>(
+    transport: &T,
+    url: String,
+    transform: &str,
+) -> Result<String, String> {
+    // Fail before paying for the outcall if the transform doesn't exist; the system would
+    // otherwise only find out when calling it.
+    if !TRANSFORMS.with(|t| t.borrow().contains_key(transform)) {
+        return Err(format!("No transform is registered under {}", transform));
+    }
+    let args = HttpRequestArgs {
+        url,
+        max_response_bytes: Some(MAX_RESPONSE_BYTES),
+        method: HttpMethod::GET,
+        headers: vec![],
+        body: None,
+        transform: Some(TransformContext::from_name(
+            "transform".to_string(),
+            transform.as_bytes().to_vec(),
+        )),
+    };
+    let reply = transport
+        .call(OutboundCall {
+            target: Principal::management_canister(),
+            method: "http_request".to_string(),
+            args: candid::encode_one(&args).unwrap(),
+            cycles: cost_http_request(&args),
+            bounded_wait: false,
+            untrusted: false,
+        })
+        .await
+        .map_err(|e| format!("The HTTP outcall failed: {:?}", e))?;
+    let response: HttpRequestResult =
+        decode_or_describe(&reply).map_err(|e| format!("The HTTP outcall failed: {}", e))?;
+    String::from_utf8(response.body).map_err(|e| format!("The response isn't UTF-8: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::Nat;
+    use ic_cdk::management_canister::HttpHeader;
+
+    fn response() -> HttpRequestResult {
+        HttpRequestResult {
+            status: Nat::from(200_u32),
+            headers: vec![HttpHeader {
+                name: "date".to_string(),
+                value: "Fri, 16 Oct 2026 10:00:00 GMT".to_string(),
+            }],
+            body: br#"{"price":"12.34","request_id":"abc"}"#.to_vec(),
+        }
+    }
+
+    // For an endpoint where only the status matters, e.g., a health check.
+    fn status_only(response: HttpRequestResult) -> HttpRequestResult {
+        HttpRequestResult {
+            status: response.status,
+            headers: vec![],
+            body: vec![],
+        }
+    }
+
+    #[test]
+    fn test_default_transform_strips_headers() {
+        register_default_transforms();
+        let transformed = apply_transform(DEFAULT_TRANSFORM, response()).unwrap();
+        assert!(transformed.headers.is_empty());
+        assert_eq!(transformed.body, response().body);
+    }
+
+    #[test]
+    fn test_custom_transform_is_applied() {
+        register_transform("status_only", status_only);
+        let transformed = apply_transform("status_only", response()).unwrap();
+        assert!(transformed.body.is_empty());
+        assert_eq!(transformed.status, Nat::from(200_u32));
+    }
+
+    #[test]
+    fn test_unknown_transform_is_an_error() {
+        assert!(apply_transform("status_only", response()).is_err());
+    }
+}
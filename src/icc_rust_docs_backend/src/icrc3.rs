@@ -0,0 +1,457 @@
+use candid::{CandidType, Deserialize, Nat, Principal};
+use icrc_ledger_types::icrc::generic_value::ICRC3Value;
+use icrc_ledger_types::icrc1::account::Account;
+use paginate::read_paginated;
+use std::collections::BTreeMap;
+
+use crate::decode::decode_or_describe;
+use crate::transport::{OutboundCall, Transport};
+
+/// A range of blocks to fetch.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct GetBlocksArgs {
+    pub start: Nat,
+    pub length: Nat,
+}
+
+/// A block together with its index in the ledger.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct BlockWithId {
+    pub id: Nat,
+    pub block: ICRC3Value,
+}
+
+candid::define_function!(pub ArchiveFn : (Vec<GetBlocksArgs>) -> (GetBlocksResult) query);
+
+/// Ranges of blocks that the ledger no longer holds, and the archive method serving them.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ArchivedBlocks {
+    pub args: Vec<GetBlocksArgs>,
+    pub callback: ArchiveFn,
+}
+
+/// The reply of `icrc3_get_blocks`, both on ledgers and on archives.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct GetBlocksResult {
+    pub log_length: Nat,
+    pub blocks: Vec<BlockWithId>,
+    pub archived_blocks: Vec<ArchivedBlocks>,
+}
+
+fn to_u64(n: &Nat) -> Result<u64, String> {
+    u64::try_from(&n.0).map_err(|_| format!("Block index {} is out of range", n))
+}
+
+// Block pages are much larger than the replies the `untrusted` preset accepts, so we use a
+// bounded wait with the default timeout but don't limit the reply size.
+async fn get_blocks<T: Transport>(
+    transport: &T,
+    target: Principal,
+    method: &str,
+    start: u64,
+    length: u64,
+) -> Result<GetBlocksResult, String> {
+    let args = vec![GetBlocksArgs {
+        start: Nat::from(start),
+        length: Nat::from(length),
+    }];
+    let reply = transport
+        .call(OutboundCall {
+            target,
+            method: method.to_string(),
+            args: candid::encode_one(args).unwrap(),
+            cycles: 0,
+            bounded_wait: true,
+            untrusted: false,
+        })
+        .await
+        .map_err(|e| format!("Error calling {} on {}: {:?}", method, target, e))?;
+    decode_or_describe(&reply).map_err(|e| format!("Error calling {} on {}: {}", method, target, e))
+}
+
+/// The most pages we read from one archive for one range.
+const MAX_ARCHIVE_PAGES: u32 = 1_000;
+
+/// The most blocks that `get_all_blocks` returns at once.
+// All blocks end up in a single reply, which the system caps at 2 MiB, and every block we fetch
+// costs us cycles. Callers that want more blocks ask again from the index after the last block
+// they got.
+pub const MAX_BLOCKS_PER_CALL: u64 = 1_000;
+
+// Archives serve at most their maximum page size per call, which may be less than the range the
+// ledger pointed us to, so we keep asking from the first missing block until we have the whole
+// range.
+async fn fetch_archived<T: Transport>(
+    transport: &T,
+    archive: &ArchiveFn,
+    start: u64,
+    length: u64,
+    blocks: &mut BTreeMap<u64, ICRC3Value>,
+) -> Result<(), String> {
+    let end = start.saturating_add(length);
+    if start >= end {
+        return Ok(());
+    }
+    let (principal, method) = (archive.0.principal, &archive.0.method);
+    // Each page is paired with the index it starts from, which is also the continuation.
+    let fetched = read_paginated(
+        |from: Option<u64>| async move {
+            let from = from.unwrap_or(start);
+            let page = get_blocks(transport, principal, method, from, end - from).await?;
+            Ok((from, page))
+        },
+        |(_, page): (u64, GetBlocksResult)| {
+            page.blocks
+                .into_iter()
+                .map(|block| Ok::<_, String>((to_u64(&block.id)?, block.block)))
+                .collect()
+        },
+        |(from, page): &(u64, GetBlocksResult)| {
+            let mut last = None;
+            for block in &page.blocks {
+                last = last.max(Some(to_u64(&block.id)?));
+            }
+            match last {
+                Some(last) if last >= *from => Ok(Some(last + 1).filter(|next| *next < end)),
+                _ => Err(format!(
+                    "Archive {} returned no blocks from index {}",
+                    principal, from
+                )),
+            }
+        },
+        MAX_ARCHIVE_PAGES,
+    )
+    .await?;
+    blocks.extend(fetched);
+    Ok(())
+}
+
+/// Fetches the `total` blocks starting at index `start` from `ledger`, following the ledger to
+/// its archives for the blocks it no longer holds. The blocks are returned in order; if the ledger
+/// has fewer blocks than asked for, only the existing ones are returned. At most
+/// `MAX_BLOCKS_PER_CALL` blocks are fetched, however large `total` is.
+pub async fn get_all_blocks<T: Transport>(
+    transport: &T,
+    ledger: Principal,
+    start: u64,
+    total: u64,
+) -> Result<Vec<BlockWithId>, String> {
+    let mut blocks = BTreeMap::new();
+    let mut end = start.saturating_add(total.min(MAX_BLOCKS_PER_CALL));
+    let mut next = start;
+    // The ledger, too, may return fewer blocks than asked for, so we ask again from the first
+    // block we're missing until we have the whole range.
+    while next < end {
+        let reply = get_blocks(transport, ledger, "icrc3_get_blocks", next, end - next).await?;
+        end = end.min(to_u64(&reply.log_length)?);
+        for block in reply.blocks {
+            blocks.insert(to_u64(&block.id)?, block.block);
+        }
+        for archived in reply.archived_blocks {
+            for range in archived.args {
+                let (range_start, range_length) = (to_u64(&range.start)?, to_u64(&range.length)?);
+                fetch_archived(transport, &archived.callback, range_start, range_length, &mut blocks)
+                    .await?;
+            }
+        }
+        let before = next;
+        while next < end && blocks.contains_key(&next) {
+            next += 1;
+        }
+        if next == before && next < end {
+            return Err(format!("The ledger returned no block with index {}", next));
+        }
+    }
+    Ok(blocks
+        .into_iter()
+        .filter(|(id, _)| (start..end).contains(id))
+        .map(|(id, block)| BlockWithId {
+            id: Nat::from(id),
+            block,
+        })
+        .collect())
+}
+
+/// A transfer that we made, or think we made, to look for on the ledger.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExpectedTransfer {
+    pub from: Account,
+    pub to: Account,
+    pub amount: Nat,
+    pub memo: Option<Vec<u8>>,
+}
+
+/// Fetches block `index` of `ledger`, and returns whether it holds `expected`. Use it to settle
+/// a transfer whose outcome we didn't learn, after the ledger points to a block, e.g., with a
+/// `Duplicate` error.
+// A ledger could return a `Duplicate` for a transfer that only looks the same to us, e.g., if
+// another canister sent it from the same account. Checking the block costs one more call, but
+// tells the user for sure.
+pub async fn confirm_transfer<T: Transport>(
+    transport: &T,
+    ledger: Principal,
+    index: u64,
+    expected: &ExpectedTransfer,
+) -> Result<bool, String> {
+    let blocks = get_all_blocks(transport, ledger, index, 1).await?;
+    match blocks.first() {
+        Some(block) => Ok(block_holds_transfer(&block.block, expected)),
+        None => Err(format!("Ledger {} has no block with index {}", ledger, index)),
+    }
+}
+
+fn field<'a>(value: &'a ICRC3Value, key: &str) -> Option<&'a ICRC3Value> {
+    match value {
+        ICRC3Value::Map(map) => map.get(key),
+        _ => None,
+    }
+}
+
+fn text<'a>(value: Option<&'a ICRC3Value>) -> Option<&'a str> {
+    match value {
+        Some(ICRC3Value::Text(text)) => Some(text.as_str()),
+        _ => None,
+    }
+}
+
+// ICRC-3 encodes an account as an array of the owner and, if it's not the default, the
+// subaccount, both as blobs.
+fn account(value: &ICRC3Value) -> Option<Account> {
+    let ICRC3Value::Array(parts) = value else {
+        return None;
+    };
+    let owner = match parts.first()? {
+        ICRC3Value::Blob(owner) => Principal::try_from_slice(owner.as_slice()).ok()?,
+        _ => return None,
+    };
+    let subaccount = match parts.get(1) {
+        None => None,
+        Some(ICRC3Value::Blob(subaccount)) => Some(<[u8; 32]>::try_from(subaccount.as_slice()).ok()?),
+        Some(_) => return None,
+    };
+    Some(Account { owner, subaccount })
+}
+
+/// Whether `block` records `expected`, following the ICRC-3 schema for ICRC-1 transfers.
+// Older ledgers name the operation in `tx.op`, newer ones in the block's `btype`. Blocks we can't
+// make sense of don't hold our transfer, as far as we can tell.
+fn block_holds_transfer(block: &ICRC3Value, expected: &ExpectedTransfer) -> bool {
+    let Some(tx) = field(block, "tx") else {
+        return false;
+    };
+    let is_transfer =
+        text(field(tx, "op")) == Some("xfer") || text(field(block, "btype")) == Some("1xfer");
+    let memo = match field(tx, "memo") {
+        Some(ICRC3Value::Blob(memo)) => Some(memo.to_vec()),
+        _ => None,
+    };
+    is_transfer
+        && field(tx, "from").and_then(account) == Some(expected.from)
+        && field(tx, "to").and_then(account) == Some(expected.to)
+        && field(tx, "amt") == Some(&ICRC3Value::Nat(expected.amount.clone()))
+        && memo == expected.memo
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::mock::MockTransport;
+    use futures::executor::block_on;
+
+    fn ledger() -> Principal {
+        Principal::from_slice(&[1; 10])
+    }
+
+    fn archive(n: u8) -> ArchiveFn {
+        ArchiveFn(candid::Func {
+            principal: Principal::from_slice(&[n; 10]),
+            method: "get_blocks".to_string(),
+        })
+    }
+
+    fn blocks(ids: std::ops::Range<u64>) -> Vec<BlockWithId> {
+        ids.map(|id| BlockWithId {
+            id: Nat::from(id),
+            block: ICRC3Value::Nat(Nat::from(id * 100)),
+        })
+        .collect()
+    }
+
+    fn range(start: u64, length: u64) -> GetBlocksArgs {
+        GetBlocksArgs {
+            start: Nat::from(start),
+            length: Nat::from(length),
+        }
+    }
+
+    fn page(blocks: Vec<BlockWithId>) -> GetBlocksResult {
+        GetBlocksResult {
+            log_length: Nat::from(0_u64),
+            blocks,
+            archived_blocks: vec![],
+        }
+    }
+
+    #[test]
+    fn test_blocks_are_stitched_across_archives() {
+        let transport = MockTransport::new();
+        transport
+            // The ledger holds blocks 7 to 9, and points us to two archives for the rest.
+            .reply(&GetBlocksResult {
+                log_length: Nat::from(10_u64),
+                blocks: blocks(7..10),
+                archived_blocks: vec![
+                    ArchivedBlocks {
+                        args: vec![range(0, 4)],
+                        callback: archive(2),
+                    },
+                    ArchivedBlocks {
+                        args: vec![range(4, 3)],
+                        callback: archive(3),
+                    },
+                ],
+            })
+            // The first archive serves at most two blocks at a time.
+            .reply(&page(blocks(0..2)))
+            .reply(&page(blocks(2..4)))
+            .reply(&page(blocks(4..7)));
+
+        let result = block_on(get_all_blocks(&transport, ledger(), 0, 20)).unwrap();
+
+        assert_eq!(result, blocks(0..10));
+        let targets: Vec<_> = transport.calls().iter().map(|c| c.target).collect();
+        assert_eq!(
+            targets,
+            vec![
+                ledger(),
+                archive(2).0.principal,
+                archive(2).0.principal,
+                archive(3).0.principal
+            ]
+        );
+        let second: Vec<GetBlocksArgs> = candid::decode_one(&transport.calls()[2].args).unwrap();
+        assert_eq!(second, vec![range(2, 2)]);
+    }
+
+    #[test]
+    fn test_empty_archive_page_is_an_error() {
+        let transport = MockTransport::new();
+        transport
+            .reply(&GetBlocksResult {
+                log_length: Nat::from(5_u64),
+                blocks: vec![],
+                archived_blocks: vec![ArchivedBlocks {
+                    args: vec![range(0, 5)],
+                    callback: archive(2),
+                }],
+            })
+            .reply(&page(vec![]));
+
+        let result = block_on(get_all_blocks(&transport, ledger(), 0, 5));
+
+        assert!(result.unwrap_err().contains("no blocks from index 0"));
+    }
+
+    #[test]
+    fn test_blocks_per_call_are_capped() {
+        let transport = MockTransport::new();
+        transport.reply(&page(vec![]));
+
+        let result = block_on(get_all_blocks(&transport, ledger(), 5, u64::MAX));
+
+        assert_eq!(result, Ok(vec![]));
+        let asked: Vec<GetBlocksArgs> = candid::decode_one(&transport.calls()[0].args).unwrap();
+        assert_eq!(asked, vec![range(5, MAX_BLOCKS_PER_CALL)]);
+    }
+
+    fn expected() -> ExpectedTransfer {
+        ExpectedTransfer {
+            from: Account {
+                owner: Principal::from_slice(&[0xca; 10]),
+                subaccount: None,
+            },
+            to: Account {
+                owner: Principal::from_slice(&[2; 29]),
+                subaccount: Some([7; 32]),
+            },
+            amount: Nat::from(1_000_u64),
+            memo: Some(b"invoice-42".to_vec()),
+        }
+    }
+
+    fn encode_account(account: &Account) -> ICRC3Value {
+        let mut parts = vec![ICRC3Value::Blob(account.owner.as_slice().to_vec().into())];
+        if let Some(subaccount) = account.subaccount {
+            parts.push(ICRC3Value::Blob(subaccount.to_vec().into()));
+        }
+        ICRC3Value::Array(parts)
+    }
+
+    fn transfer_block(transfer: &ExpectedTransfer) -> ICRC3Value {
+        let mut tx = BTreeMap::from([
+            ("op".to_string(), ICRC3Value::Text("xfer".to_string())),
+            ("from".to_string(), encode_account(&transfer.from)),
+            ("to".to_string(), encode_account(&transfer.to)),
+            ("amt".to_string(), ICRC3Value::Nat(transfer.amount.clone())),
+        ]);
+        if let Some(memo) = &transfer.memo {
+            tx.insert("memo".to_string(), ICRC3Value::Blob(memo.clone().into()));
+        }
+        ICRC3Value::Map(BTreeMap::from([
+            ("ts".to_string(), ICRC3Value::Nat(Nat::from(1_000_u64))),
+            ("tx".to_string(), ICRC3Value::Map(tx)),
+        ]))
+    }
+
+    fn confirm(block: ICRC3Value) -> Result<bool, String> {
+        let transport = MockTransport::new();
+        transport.reply(&GetBlocksResult {
+            log_length: Nat::from(6_u64),
+            blocks: vec![BlockWithId {
+                id: Nat::from(5_u64),
+                block,
+            }],
+            archived_blocks: vec![],
+        });
+        block_on(confirm_transfer(&transport, ledger(), 5, &expected()))
+    }
+
+    #[test]
+    fn test_matching_block_confirms_the_transfer() {
+        assert_eq!(confirm(transfer_block(&expected())), Ok(true));
+    }
+
+    #[test]
+    fn test_mismatched_block_doesnt_confirm_the_transfer() {
+        let other_amount = ExpectedTransfer {
+            amount: Nat::from(999_u64),
+            ..expected()
+        };
+        let other_memo = ExpectedTransfer {
+            memo: None,
+            ..expected()
+        };
+        let other_recipient = ExpectedTransfer {
+            to: Account {
+                subaccount: None,
+                ..expected().to
+            },
+            ..expected()
+        };
+
+        assert_eq!(confirm(transfer_block(&other_amount)), Ok(false));
+        assert_eq!(confirm(transfer_block(&other_memo)), Ok(false));
+        assert_eq!(confirm(transfer_block(&other_recipient)), Ok(false));
+        assert_eq!(confirm(ICRC3Value::Nat(Nat::from(5_u64))), Ok(false));
+    }
+
+    #[test]
+    fn test_missing_block_is_an_error() {
+        let transport = MockTransport::new();
+        transport.reply(&page(vec![]));
+
+        let result = block_on(confirm_transfer(&transport, ledger(), 5, &expected()));
+
+        assert!(result.unwrap_err().contains("no block with index 5"));
+    }
+}
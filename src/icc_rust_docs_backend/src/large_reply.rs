@@ -0,0 +1,81 @@
+use candid::Principal;
+
+use crate::decode::decode_or_describe;
+use crate::transport::{OutboundCall, Transport};
+
+/// Calls `method` on `target`, which takes no arguments and returns a blob, with a bounded wait,
+/// and returns the blob.
+// Replies can't grow without bound. The system caps every reply at 2 MiB, and a callee that
+// tries to send more traps, which we see as a `CanisterError`. On top of that, we only accept
+// `MAX_UNTRUSTED_REPLY_BYTES` from callees we don't control, since decoding a huge reply costs
+// us cycles and memory. Retrying won't make the reply any smaller, so we tell the two cases
+// apart from other failures: for a trusted callee, a call without the untrusted cap works up to
+// the system's limit, and beyond that, only a paginated API helps (see the `paginate` crate).
+pub async fn fetch_blob_via<T: Transport>(
+    transport: &T,
+    target: Principal,
+    method: &str,
+) -> Result<Vec<u8>, String> {
+    let reply = transport
+        .call(OutboundCall::untrusted(target, method, candid::encode_args(()).unwrap()))
+        .await
+        .map_err(|e| match e.reply_too_large(target, method) {
+            Some(error) => error.to_string(),
+            None => format!("Error calling {}: {:?}", method, e),
+        })?;
+    decode_or_describe(&reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::AppError;
+    use crate::transport::mock::MockTransport;
+    use crate::transport::{CallFailure, MAX_UNTRUSTED_REPLY_BYTES};
+    use futures::executor::block_on;
+    use ic_cdk::call::RejectCode;
+
+    fn target() -> Principal {
+        Principal::from_slice(&[5; 10])
+    }
+
+    #[test]
+    fn test_small_blob_is_returned() {
+        let transport = MockTransport::new();
+        transport.reply(&vec![7_u8; 100]);
+
+        assert_eq!(block_on(fetch_blob_via(&transport, target(), "get_blob")), Ok(vec![7; 100]));
+    }
+
+    #[test]
+    fn test_reply_over_cap_is_too_large() {
+        let transport = MockTransport::new();
+        transport.reply(&vec![7_u8; MAX_UNTRUSTED_REPLY_BYTES + 1]);
+
+        let error = block_on(fetch_blob_via(&transport, target(), "get_blob")).unwrap_err();
+
+        let expected = AppError::ReplyTooLarge {
+            target: target(),
+            method: "get_blob".to_string(),
+        };
+        assert_eq!(error, expected.to_string());
+    }
+
+    #[test]
+    fn test_callee_exceeding_system_limit_is_too_large() {
+        let failure = CallFailure::Rejected {
+            code: RejectCode::CanisterError,
+            message: "Canister violated contract: ic0.msg_reply_data_append: application payload \
+                      size (2097153) cannot be larger than 2097152"
+                .to_string(),
+            sync: false,
+        };
+        assert!(failure.reply_too_large(target(), "get_blob").is_some());
+    }
+
+    #[test]
+    fn test_other_failures_are_not_too_large() {
+        let failure = CallFailure::CanisterError("trapped".to_string());
+        assert_eq!(failure.reply_too_large(target(), "get_blob"), None);
+    }
+}
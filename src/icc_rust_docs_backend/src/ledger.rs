@@ -0,0 +1,1473 @@
+use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_cdk::call::RejectCode;
+use ic_ledger_types::{
+    AccountBalanceArgs, AccountIdentifier, BlockIndex, Memo, Tokens, TransferArgs, TransferError,
+    TransferFee, TransferFeeArgs,
+};
+use icrc_ledger_types::icrc::generic_metadata_value::MetadataValue;
+use icrc_ledger_types::icrc1::account::{Account, Subaccount};
+use icrc_ledger_types::icrc1::transfer::{
+    Memo as Icrc1Memo, NumTokens, TransferArg, TransferError as Icrc1TransferError,
+};
+use icrc_ledger_types::icrc2::allowance::{Allowance, AllowanceArgs};
+use icrc_ledger_types::icrc2::transfer_from::{TransferFromArgs, TransferFromError};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use crate::cache::TtlCache;
+use crate::decode::{decode_fee, decode_or_describe, describe_reply, FeeEncoding};
+use crate::error::{flatten_ledger_result, outcome_unknown, AppError};
+use crate::history::HISTORY;
+use crate::lock::AsyncLock;
+use crate::management::on_callee_out_of_cycles;
+use crate::nat::{nat_add, nat_mul, nat_sub_checked};
+use crate::retry::{self, RetryPolicy};
+use crate::transport::{describe_reject, CallFailure, OutboundCall, Transport};
+use crate::upgrade;
+
+/// The ID of the ICP ledger canister on the IC mainnet.
+pub const ICP_LEDGER_CANISTER_ID: &str = "ryjl3-tyaaa-aaaaa-aaaba-cai";
+
+/// How long ledgers remember transactions for deduplication, unless they announce otherwise.
+/// This is the window of the ICP ledger and of the standard ICRC-1 ledger.
+pub const DEFAULT_DEDUP_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The metadata entry under which a ledger may announce its deduplication window, in seconds.
+// ICRC-1 doesn't standardize such an entry, so most ledgers don't have one, and we fall back to
+// `DEFAULT_DEDUP_WINDOW`.
+pub const DEDUP_WINDOW_METADATA_KEY: &str = "icrc1:tx_window_secs";
+
+/// How long we reuse the ICP ledger's transfer fee before asking it again.
+pub const ICP_FEE_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// How long we reuse a ledger's list of supported standards before asking it again.
+pub const STANDARDS_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How many ledgers we keep the fee or the standards of. We normally talk to a handful at most.
+pub const LEDGER_CACHE_ENTRIES: usize = 16;
+
+/// The cycles we deposit into a ledger that we control if it runs out of cycles while serving
+/// one of our calls.
+const CALLEE_TOP_UP_CYCLES: u128 = 1_000_000_000_000;
+
+thread_local! {
+    // The transfer fee of each ICP ledger we asked.
+    static ICP_FEE_CACHE: TtlCache<Principal, Tokens> = TtlCache::new(LEDGER_CACHE_ENTRIES);
+    // The last fee that each ICRC-1 ledger reported.
+    static ICRC1_FEES: RefCell<BTreeMap<Principal, NumTokens>> = RefCell::new(BTreeMap::new());
+    // The standards that each ledger we asked supports.
+    static STANDARDS_CACHE: TtlCache<Principal, Vec<SupportedStandard>> =
+        TtlCache::new(LEDGER_CACHE_ENTRIES);
+    // Held by ICRC-1 transfers for their whole duration, see `Icrc1Ledger::transfer_with`.
+    static TRANSFER_LOCK: AsyncLock = AsyncLock::default();
+}
+
+/// An entry of a ledger's `icrc1_supported_standards`.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct SupportedStandard {
+    /// The name of the standard, such as `"ICRC-2"`.
+    pub name: String,
+    pub url: String,
+}
+
+pub fn remember_icrc1_fee(ledger: Principal, fee: &NumTokens) {
+    ICRC1_FEES.with(|f| f.borrow_mut().insert(ledger, fee.clone()));
+}
+
+/// The last fee that `ledger` reported, if any.
+pub fn known_icrc1_fee(ledger: Principal) -> Option<NumTokens> {
+    ICRC1_FEES.with(|f| f.borrow().get(&ledger).cloned())
+}
+
+/// Checks a fee chosen by the caller against the `known` fee of the ledger, if we know it.
+// A fee below the ledger's is certain to fail with `BadFee`, so we reject it without bothering
+// the ledger. The reference ledger also rejects fees above its own, but other ledgers may accept
+// them, so we leave that decision to the ledger. If we don't know the fee, the ledger decides.
+pub fn check_fee_override(fee: &NumTokens, known: Option<&NumTokens>) -> Result<(), String> {
+    match known {
+        Some(known) if fee < known => Err(format!(
+            "The fee {} is below the ledger's fee of {}",
+            fee, known
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Returns true if `standards` include the standard `name`, such as `"ICRC-2"`.
+pub fn supports(standards: &[SupportedStandard], name: &str) -> bool {
+    standards.iter().any(|s| s.name.eq_ignore_ascii_case(name))
+}
+
+/// The arguments for an ICP transfer from our default account.
+pub fn icp_transfer_args(to: AccountIdentifier, amount: Tokens, fee: Tokens) -> TransferArgs {
+    TransferArgs {
+        // A "memo" is an arbitrary blob that has no meaning to the ledger, but can be used by
+        // the sender or receiver to attach additional information to the transaction. We
+        // just use the number 0 here as an example.
+        memo: Memo(0),
+        to,
+        amount,
+        // The ICP ledger canister charges a fee for transfers, which is deducted from the
+        // sender's account. The transfer fails unless we specify the exact fee.
+        fee,
+        // The ledger supports subaccounts, but we don't use them in this example.
+        from_subaccount: None,
+        // The created_at_time is used for deduplication, which we don't use in this example.
+        created_at_time: None,
+    }
+}
+
+/// Treats a `TxDuplicate` reply of the ICP ledger as the success of the original transfer.
+// The ledger only deduplicates transfers that have a `created_at_time`. A duplicate then means
+// that an earlier attempt of this very transfer went through, for example one whose reply we
+// never got, and the caller wants to know where that transfer ended up.
+pub fn accept_duplicate(
+    reply: Result<BlockIndex, TransferError>,
+) -> Result<BlockIndex, TransferError> {
+    match reply {
+        Err(TransferError::TxDuplicate { duplicate_of }) => Ok(duplicate_of),
+        reply => reply,
+    }
+}
+
+/// Describes an error of the ICP ledger's `transfer` in a way that tells the user what to do
+/// about it.
+pub fn icp_transfer_error_hint(error: &TransferError) -> String {
+    match error {
+        TransferError::BadFee { expected_fee } => format!(
+            "The ledger's fee is {} e8s; retry with that fee",
+            expected_fee.e8s()
+        ),
+        TransferError::InsufficientFunds { balance } => format!(
+            "The balance of {} e8s doesn't cover the amount plus the fee; top up the account or \
+             send less",
+            balance.e8s()
+        ),
+        TransferError::TxTooOld {
+            allowed_window_nanos,
+        } => format!(
+            "The transfer was created more than {} seconds ago; retry with a current \
+             created_at_time, after checking that the original transfer didn't go through",
+            allowed_window_nanos / 1_000_000_000
+        ),
+        TransferError::TxCreatedInFuture => {
+            "The transfer's created_at_time is ahead of the ledger's clock; retry in a few \
+             seconds"
+                .to_string()
+        }
+        TransferError::TxDuplicate { duplicate_of } => format!(
+            "The transfer was already executed in block {}; don't send it again",
+            duplicate_of
+        ),
+    }
+}
+
+/// The longest memo that ledgers accept unless configured otherwise, in bytes.
+// ICRC-1 leaves the limit to each ledger, and the standard ledger lets its deployer raise it. We
+// stick to the default, which every ledger accepts.
+pub const MAX_MEMO_BYTES: usize = 32;
+
+/// Checks that `memo` fits into `MAX_MEMO_BYTES`, so that the ledger doesn't reject the transfer
+/// for it.
+pub fn validate_memo(memo: Option<Vec<u8>>) -> Result<Option<Icrc1Memo>, String> {
+    match memo {
+        Some(bytes) if bytes.len() > MAX_MEMO_BYTES => Err(format!(
+            "The memo is {} bytes long, but ledgers only accept up to {} bytes",
+            bytes.len(),
+            MAX_MEMO_BYTES
+        )),
+        memo => Ok(memo.map(Icrc1Memo::from)),
+    }
+}
+
+/// The arguments for an ICRC-1 transfer from our default account.
+pub fn icrc1_transfer_arg(
+    to: Account,
+    amount: NumTokens,
+    fee: NumTokens,
+    memo: Option<Icrc1Memo>,
+    now: u64,
+) -> TransferArg {
+    TransferArg {
+        from_subaccount: None,
+        to,
+        fee: Some(fee),
+        // Setting the created time ensures that the ledger performs deduplication of transactions,
+        // such that they can be safely retried. This is very useful for bounded wait calls.
+        created_at_time: Some(now),
+        // Like the `created_at_time`, the memo is part of what the ledger compares to detect
+        // duplicates.
+        memo,
+        amount,
+    }
+}
+
+/// Converts an amount written for humans, such as `"1.5"`, into the ledger's base units, given
+/// the number of decimals of the token.
+// We work on the digits rather than going through a float: floats can't represent most decimal
+// fractions exactly, and `1.1` tokens must become exactly `110_000_000` base units, not one
+// less. Amounts with more fractional digits than the token has are rejected rather than
+// rounded, since silently moving a different amount than the user typed is worse than an error.
+pub fn parse_human_amount(amount: &str, decimals: u8) -> Result<NumTokens, String> {
+    let (whole, fraction) = amount.split_once('.').unwrap_or((amount, ""));
+    let is_digits = |s: &str| s.bytes().all(|b| b.is_ascii_digit());
+    if whole.is_empty() || !is_digits(whole) || !is_digits(fraction) || amount.ends_with('.') {
+        return Err(format!("Invalid amount {:?}: expected digits, optionally with a decimal point", amount));
+    }
+    if fraction.len() > decimals as usize {
+        return Err(format!(
+            "Invalid amount {:?}: the token has only {} decimals",
+            amount, decimals
+        ));
+    }
+    let parse = |digits: &str| -> Result<NumTokens, String> {
+        if digits.is_empty() {
+            return Ok(Nat::from(0_u32));
+        }
+        digits
+            .parse()
+            .map_err(|e| format!("Invalid amount {:?}: {:?}", amount, e))
+    };
+    // E.g., "1.5" with 8 decimals is 1 * 10^8 + 5 * 10^7 base units.
+    let whole_units = nat_mul(&parse(whole)?, &power_of_ten(decimals as usize));
+    let fraction_units = nat_mul(&parse(fraction)?, &power_of_ten(decimals as usize - fraction.len()));
+    Ok(nat_add(&whole_units, &fraction_units))
+}
+
+fn power_of_ten(exponent: usize) -> Nat {
+    let ten = Nat::from(10_u32);
+    (0..exponent).fold(Nat::from(1_u32), |power, _| nat_mul(&power, &ten))
+}
+
+/// Returns the deduplication window, in seconds, that the ledger announces in its `metadata`, or
+/// `DEFAULT_DEDUP_WINDOW` if it doesn't announce any.
+pub fn dedup_window_from_metadata(metadata: &[(String, MetadataValue)]) -> Result<u64, String> {
+    match metadata.iter().find(|(key, _)| key == DEDUP_WINDOW_METADATA_KEY) {
+        Some((_, MetadataValue::Nat(secs))) => u64::try_from(&secs.0)
+            .map_err(|_| format!("The ledger reports a dedup window of {} seconds, which is too long", secs)),
+        Some((_, value)) => Err(format!("The ledger reports a non-numeric dedup window: {:?}", value)),
+        None => Ok(DEFAULT_DEDUP_WINDOW.as_secs()),
+    }
+}
+
+/// Converts an ICP amount into the `Nat` that ICRC-1 interfaces use, both in e8s.
+pub fn tokens_to_nat(tokens: Tokens) -> NumTokens {
+    Nat::from(tokens.e8s())
+}
+
+/// The error code of the `GenericError`s that `icrc1_transfer_detailed` reports for failures
+/// that didn't come from the ledger.
+pub const NOT_FROM_LEDGER: u64 = 0;
+
+/// Why an ICRC-1 transfer failed: either the ledger said so, or we couldn't get an answer.
+#[derive(Debug)]
+pub enum TransferFailure {
+    Ledger(Icrc1TransferError),
+    App(AppError),
+    Other(String),
+}
+
+impl TransferFailure {
+    pub fn into_transfer_error(self) -> Icrc1TransferError {
+        match self {
+            TransferFailure::Ledger(e) => e,
+            TransferFailure::App(e) => Icrc1TransferError::GenericError {
+                error_code: Nat::from(NOT_FROM_LEDGER),
+                message: e.to_string(),
+            },
+            TransferFailure::Other(message) => Icrc1TransferError::GenericError {
+                error_code: Nat::from(NOT_FROM_LEDGER),
+                message,
+            },
+        }
+    }
+}
+
+impl From<String> for TransferFailure {
+    fn from(message: String) -> Self {
+        TransferFailure::Other(message)
+    }
+}
+
+impl From<AppError> for TransferFailure {
+    fn from(e: AppError) -> Self {
+        TransferFailure::App(e)
+    }
+}
+
+impl From<TransferFailure> for String {
+    fn from(failure: TransferFailure) -> String {
+        match failure {
+            TransferFailure::Ledger(e) => format!("Ledger returned an error: {:?}", e),
+            TransferFailure::App(e) => e.to_string(),
+            TransferFailure::Other(message) => message,
+        }
+    }
+}
+
+impl From<TransferFailure> for AppError {
+    fn from(failure: TransferFailure) -> AppError {
+        match failure {
+            TransferFailure::Ledger(e) => AppError::LedgerError {
+                message: format!("{:?}", e),
+            },
+            TransferFailure::App(e) => e,
+            TransferFailure::Other(message) => AppError::CallFailed { message },
+        }
+    }
+}
+
+/// How an ICRC-1 transfer deviates from the defaults of `Icrc1Ledger::transfer_with`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Icrc1TransferOptions {
+    /// The fee to pay, if the caller knows it; otherwise, we ask the ledger.
+    pub fee: Option<NumTokens>,
+    pub memo: Option<Icrc1Memo>,
+    /// The subaccount of ours to transfer from, instead of the default one.
+    pub from_subaccount: Option<Subaccount>,
+}
+
+/// The operations shared by the ICP ledger and ICRC-1 ledgers.
+// The two kinds of ledgers offer the same functionality, but with different method names,
+// account types and amount types. Code that doesn't care which kind it's talking to can use this
+// trait instead. Errors are collapsed into strings, as elsewhere in this canister.
+pub trait Ledger {
+    type Account;
+    type Amount;
+
+    /// Transfers `amount` from our default account, returning the index of the resulting block.
+    async fn transfer(&self, to: Self::Account, amount: Self::Amount) -> Result<Nat, String>;
+
+    /// Returns the balance of the given account.
+    async fn balance(&self, of: Self::Account) -> Result<Self::Amount, String>;
+
+    /// Returns the fee charged for a transfer.
+    async fn fee(&self) -> Result<Self::Amount, String>;
+}
+
+/// The ICP ledger, which predates ICRC-1 and has its own interface.
+pub struct IcpLedger<T> {
+    pub canister_id: Principal,
+    pub transport: T,
+}
+
+/// Any ledger implementing the ICRC-1 standard.
+pub struct Icrc1Ledger<T> {
+    pub canister_id: Principal,
+    pub transport: T,
+}
+
+/// Sends `arg` to `method` and decodes the reply into `R`. Calls to `untrusted` ledgers use the
+/// `OutboundCall::untrusted` preset; the others, such as the ICP ledger, which is a system
+/// canister, use an unbounded wait.
+async fn call_ledger<T: Transport, A: CandidType, R: CandidType + for<'de> Deserialize<'de>>(
+    transport: &T,
+    canister_id: Principal,
+    untrusted: bool,
+    method: &str,
+    arg: &A,
+) -> Result<R, String> {
+    let args = candid::encode_one(arg).map_err(|e| format!("Failed to encode arguments: {}", e))?;
+    let call = if untrusted {
+        OutboundCall::untrusted(canister_id, method, args)
+    } else {
+        OutboundCall {
+            target: canister_id,
+            method: method.to_string(),
+            args,
+            cycles: 0,
+            bounded_wait: false,
+            untrusted: false,
+        }
+    };
+    let reply = transport.call(call).await.map_err(|e| match e.invalid_target(canister_id) {
+        Some(error) => error.to_string(),
+        None => format!("Error calling {} on the ledger: {:?}", method, e),
+    })?;
+    decode_or_describe(&reply).map_err(|e| format!("Error calling {} on the ledger: {}", method, e))
+}
+
+/// Decodes the reply of the ICP ledger's `transfer` method.
+// Decoding fails if the reply has an unexpected type. With the real ICP ledger, this doesn't
+// happen. But our users can point us at a different canister (say, a local test ledger with a
+// different interface), so we report an error that shows what we actually received, rather than
+// trapping.
+fn decode_icp_transfer_reply(reply: &[u8]) -> Result<Result<BlockIndex, TransferError>, String> {
+    decode_or_describe(reply)
+}
+
+impl<T: Transport> Ledger for IcpLedger<T> {
+    type Account = AccountIdentifier;
+    type Amount = Tokens;
+
+    async fn transfer(&self, to: AccountIdentifier, amount: Tokens) -> Result<Nat, String> {
+        self.transfer_icp(to, amount).await.map(Nat::from)
+    }
+
+    async fn balance(&self, of: AccountIdentifier) -> Result<Tokens, String> {
+        call_ledger(
+            &self.transport,
+            self.canister_id,
+            false,
+            "account_balance",
+            &AccountBalanceArgs { account: of },
+        )
+        .await
+    }
+
+    async fn fee(&self) -> Result<Tokens, String> {
+        call_ledger::<_, _, TransferFee>(
+            &self.transport,
+            self.canister_id,
+            false,
+            "transfer_fee",
+            &TransferFeeArgs {},
+        )
+        .await
+        .map(|fee| fee.transfer_fee)
+    }
+}
+
+impl<T: Transport> IcpLedger<T> {
+    /// Like `Ledger::transfer`, but returns the ICP ledger's own `BlockIndex`.
+    pub async fn transfer_icp(
+        &self,
+        to: AccountIdentifier,
+        amount: Tokens,
+    ) -> Result<BlockIndex, String> {
+        // The transfer isn't deduplicated, so an upgrade must not cut it short, see
+        // `check_upgrade`.
+        let _in_flight = upgrade::begin();
+        let fee = self.cached_fee().await?;
+        // See `icp_transfer_args` for an explanation of the individual fields.
+        let args = icp_transfer_args(to, amount, fee);
+
+        // Unbounded wait calls ensure that the system doesn't give up waiting on the response from
+        // the ledger, though the call might still fail.
+        // Unbounded wait calls never return a `SysUnknown` error. The response might still be a
+        // different kind of error, either coming from the ledger or from the system (it's possible
+        // that the system fails the call before it reaches the ledger)
+        // The call goes through the transport, like our other calls, so that the `simulate` feature
+        // can stand in for the ledger. Note that calls must be awaited to actually send them.
+        let reply = self
+            .transport
+            .call(OutboundCall {
+                target: self.canister_id,
+                method: "transfer".to_string(),
+                args: candid::encode_one(&args).unwrap(),
+                cycles: 0,
+                bounded_wait: false,
+                untrusted: false,
+            })
+            .await;
+        // We expect the ledger to return a `BlockIndex` if the transfer was successful, or a
+        // `TransferError` if it failed. We decode the raw reply ourselves, so that we can show what
+        // the ledger sent if it isn't what we expected; the transfer may have happened in that
+        // case.
+        let result = match reply {
+            Ok(reply) => Ok(decode_icp_transfer_reply(&reply).map_err(|e| outcome_unknown(1, e))?),
+            Err(failure) => Err(failure),
+        };
+
+        // See `flatten_ledger_result` for how the different failures are reported. In short: if
+        // the system rejected our call, or the ledger returned an error (for example, because our
+        // balance was too low), the transfer didn't happen. If the ledger crashed, or replied with
+        // something we can't decode, we don't know, and tell the user to check before retrying;
+        // the ICP ledger is sufficiently well tested that we don't expect this, but other
+        // canisters may need more sophisticated handling.
+        // A `TxDuplicate` would mean that the transfer already happened (see `accept_duplicate`).
+        // We don't set a `created_at_time`, so the ledger doesn't deduplicate our transfers, but
+        // handling it costs nothing should we start doing so. We describe the other ledger errors
+        // ourselves, with a hint on what to do.
+        let block_index = match result.map(accept_duplicate) {
+            Ok(Err(e)) => {
+                return Err(format!("Ledger returned an error: {}", icp_transfer_error_hint(&e)))
+            }
+            result => flatten_ledger_result(result)?,
+        };
+        HISTORY.with(|h| {
+            h.borrow_mut().record(
+                self.transport.time(),
+                self.canister_id,
+                to.to_string(),
+                tokens_to_nat(amount),
+                Nat::from(block_index),
+            )
+        });
+        Ok(block_index)
+    }
+
+    /// Returns the transfer fee, only asking the ledger if we haven't done so in the last
+    /// `ICP_FEE_TTL`.
+    // The fee hardly ever changes, so asking for it before every transfer would mostly double the
+    // latency. Should it change, transfers fail with `BadFee` until the cached fee expires.
+    pub async fn cached_fee(&self) -> Result<Tokens, String> {
+        ICP_FEE_CACHE
+            .with(|c| c.clone())
+            .get_or_insert_with_async(self.canister_id, ICP_FEE_TTL, self.transport.time(), self.fee())
+            .await
+    }
+}
+
+impl<T: Transport> Icrc1Ledger<T> {
+    async fn metadata(&self) -> Result<Vec<(String, MetadataValue)>, String> {
+        call_ledger(&self.transport, self.canister_id, true, "icrc1_metadata", &()).await
+    }
+
+    /// Returns the number of decimals of the token, as announced in the ledger's metadata.
+    pub async fn decimals(&self) -> Result<u8, String> {
+        let metadata = self.metadata().await?;
+        match metadata.into_iter().find(|(key, _)| key == "icrc1:decimals") {
+            Some((_, MetadataValue::Nat(decimals))) => u8::try_from(&decimals.0)
+                .map_err(|_| format!("The ledger reports {} decimals, which is too many", decimals)),
+            Some((_, value)) => Err(format!("The ledger reports non-numeric decimals: {:?}", value)),
+            None => Err("The ledger's metadata doesn't include icrc1:decimals".to_string()),
+        }
+    }
+
+    /// Returns the standards that the ledger implements.
+    // Ledgers only add standards when they're upgraded, which is rare, so we ask each ledger at
+    // most once per `STANDARDS_TTL`.
+    pub async fn supported_standards(&self) -> Result<Vec<SupportedStandard>, String> {
+        let fetch = call_ledger(
+            &self.transport,
+            self.canister_id,
+            true,
+            "icrc1_supported_standards",
+            &(),
+        );
+        STANDARDS_CACHE
+            .with(|c| c.clone())
+            .get_or_insert_with_async(self.canister_id, STANDARDS_TTL, self.transport.time(), fetch)
+            .await
+    }
+
+    /// Returns how long, in seconds, the ledger deduplicates transfers that set
+    /// `created_at_time`. Retrying a transfer with the same `created_at_time` is only safe within
+    /// this window (minus the ledger's permitted clock drift).
+    pub async fn dedup_window_secs(&self) -> Result<u64, String> {
+        dedup_window_from_metadata(&self.metadata().await?)
+    }
+
+    /// Moves everything that `from` approved us (ICRC-2) to spend from `from` to `to`, and
+    /// returns the index of the block containing the transfer. Fails without calling the ledger's
+    /// ICRC-2 methods if the ledger doesn't announce ICRC-2.
+    // The ledger deducts the fee from the allowance on top of the amount, so the most we can
+    // move is the allowance minus the fee. Between reading the allowance and transferring, the
+    // owner can still lower it or spend their balance; the ledger then rejects the transfer with
+    // `InsufficientAllowance` or `InsufficientFunds`, and the sweep can simply be retried.
+    pub async fn sweep_approved(&self, from: Account, to: Account) -> Result<Nat, String> {
+        // The caller would never learn the outcome if an upgrade cut the sweep short.
+        let _in_flight = upgrade::begin();
+        if !supports(&self.supported_standards().await?, "ICRC-2") {
+            return Err(format!("The ledger {} doesn't support ICRC-2 approvals", self.canister_id));
+        }
+        let spender = Account {
+            owner: self.transport.canister_self(),
+            subaccount: None,
+        };
+        let allowance: Allowance = call_ledger(
+            &self.transport,
+            self.canister_id,
+            true,
+            "icrc2_allowance",
+            &AllowanceArgs {
+                account: from,
+                spender,
+            },
+        )
+        .await?;
+        let fee = self.fee().await?;
+        if allowance.allowance <= fee {
+            return Err(format!(
+                "Nothing to sweep: the allowance of {} doesn't exceed the fee of {}",
+                allowance.allowance, fee
+            ));
+        }
+        let args = TransferFromArgs {
+            spender_subaccount: None,
+            from,
+            to,
+            amount: nat_sub_checked(&allowance.allowance, &fee)?,
+            fee: Some(fee),
+            memo: None,
+            created_at_time: Some(self.transport.time()),
+        };
+        call_ledger::<_, _, Result<Nat, TransferFromError>>(
+            &self.transport,
+            self.canister_id,
+            true,
+            "icrc2_transfer_from",
+            &args,
+        )
+        .await?
+        .map_err(|e| format!("Ledger returned an error: {:?}", e))
+    }
+}
+
+impl<T: Transport> Ledger for Icrc1Ledger<T> {
+    type Account = Account;
+    type Amount = NumTokens;
+
+    async fn transfer(&self, to: Account, amount: NumTokens) -> Result<Nat, String> {
+        let mut attempts = 0;
+        let options = Icrc1TransferOptions::default();
+        self.transfer_with(to, amount, options, &retry::current(), &mut attempts)
+            .await
+            .map_err(String::from)
+    }
+
+    async fn balance(&self, of: Account) -> Result<NumTokens, String> {
+        call_ledger(&self.transport, self.canister_id, true, "icrc1_balance_of", &of).await
+    }
+
+    async fn fee(&self) -> Result<NumTokens, String> {
+        call_ledger(&self.transport, self.canister_id, true, "icrc1_fee", &()).await
+    }
+}
+
+impl<T: Transport> Icrc1Ledger<T> {
+    /// Returns the transfer fee, retrying with `policy` while asking fails in a way that may go
+    /// away.
+    // Like all our calls, the ICRC-1 examples issue their calls through a `Transport` rather than
+    // `Call` directly. On the IC, the transport issues the call as a `Call` built with the
+    // `untrusted_call` preset, since we can't vouch for arbitrary ledgers, and it reports failures
+    // the same way `CallError` does.
+    // But this way, the examples can also run against a simulated ledger (see the `simulate`
+    // feature).
+    pub async fn fee_with_retries(&self, policy: &RetryPolicy) -> Result<NumTokens, String> {
+        let transport = &self.transport;
+        let ledger = self.canister_id;
+        // Retrying forever would keep our canister from stopping while the ledger misbehaves, so
+        // the policy caps both the number of attempts and the time we spend on them.
+        let started_at = transport.time();
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            match transport
+                .call(OutboundCall::untrusted(
+                    ledger,
+                    "icrc1_fee",
+                    candid::encode_args(()).unwrap(),
+                ))
+                .await
+            {
+                // Candid decoding shouldn't fail with a correctly implemented ledger. However,
+                // since we are calling an arbitrary ledger, we don't know if it's correctly
+                // implemented. Return an error to the user.
+                Ok(reply) => {
+                    let (fee, encoding) = decode_fee(&reply)
+                        .map_err(|e| format!("Error obtaining the fee: {}", e))?;
+                    // Worth knowing about: the ledger's other replies may not be standard either.
+                    if encoding == FeeEncoding::Fallback {
+                        ic_cdk::println!(
+                            "Ledger {} returned a non-standard fee: {}",
+                            ledger,
+                            describe_reply(&reply)
+                        );
+                    }
+                    remember_icrc1_fee(ledger, &fee);
+                    return Ok(fee);
+                }
+                // The ledger couldn't even start processing our call. Retrying is pointless until
+                // someone tops it up.
+                Err(failure) if failure.is_callee_out_of_cycles() => {
+                    let top_up = Some(CALLEE_TOP_UP_CYCLES);
+                    return Err(on_callee_out_of_cycles(transport, ledger, top_up).await.into());
+                }
+                // The ledger doesn't exist; the user has to fix the principal.
+                Err(failure) if failure.invalid_target(ledger).is_some() => {
+                    return Err(AppError::InvalidTarget { target: ledger }.into())
+                }
+                // The ledger is stopped, most likely for an upgrade; see `is_callee_stopped`.
+                Err(failure) if failure.is_callee_stopped() => {
+                    if policy.backoff(transport, attempts, started_at).await.is_err() {
+                        return Err(AppError::CalleeStopped { target: ledger, attempts }.into());
+                    }
+                    continue;
+                }
+                // The system rejected our call
+                Err(CallFailure::Rejected { code, sync, message }) => {
+                    // Determine whether it makes sense to retry. Calls that fail with a
+                    // non-synchronous transient error are retryable, as far as the policy allows.
+                    // A synchronous reject means that our own canister couldn't send the call,
+                    // e.g., because its queues are full; retrying right away would only add to
+                    // the pressure, so we leave that to the user.
+                    if !sync && code == RejectCode::SysTransient {
+                        policy.backoff(transport, attempts, started_at).await?;
+                        continue;
+                    } else {
+                        // Other rejection types are not retryable. They could happen, for example,
+                        // if the target canister explicitly rejects the call (for example, because
+                        // it is stopped), if it gets deleted, or if a fatal system error occurs.
+                        return Err(format!(
+                            "Irrecoverable error: {}",
+                            describe_reject(code, &message)
+                        ));
+                    }
+                }
+                // Since getting the fee doesn't change the ledger state we can simply retry if the
+                // system returns a `SysUnknown` error with the ledger canister state being unknown.
+                Err(CallFailure::SysUnknown(_)) => {
+                    policy.backoff(transport, attempts, started_at).await?;
+                    continue;
+                }
+                // The ledger crashed while processing our request; report an error to the user.
+                Err(CallFailure::CanisterError(err)) => {
+                    return Err(format!("Ledger crashed: {}", err))
+                }
+            }
+        }
+    }
+
+    /// Transfers `amount` from our account (the default one, unless `options` name a subaccount)
+    /// to `to`, retrying with `policy`, and returns the index of the block containing the
+    /// transfer. Counts the calls to `icrc1_transfer` in `attempts`.
+    pub async fn transfer_with(
+        &self,
+        to: Account,
+        amount: NumTokens,
+        options: Icrc1TransferOptions,
+        policy: &RetryPolicy,
+        attempts: &mut u32,
+    ) -> Result<Nat, TransferFailure> {
+        let transport = &self.transport;
+        let ledger = self.canister_id;
+        // Transfers run one at a time. Two concurrent transfers of the same amount to the same
+        // account would otherwise pick the same `created_at_time`, and the ledger would reject the
+        // second one as a duplicate of the first.
+        let _guard = TRANSFER_LOCK.with(|lock| lock.clone()).lock().await;
+        let _in_flight = upgrade::begin();
+
+        // In the first step, obtain the fee, unless the caller chose one; `fee_with_retries`
+        // handles retries. We call it directly rather than through our own `icrc1_get_fee`
+        // endpoint: a self-call would cost an extra message, and it would let other messages run in
+        // between, which makes reasoning about our state harder.
+        let fee = match options.fee {
+            Some(fee) => {
+                check_fee_override(&fee, known_icrc1_fee(ledger).as_ref())?;
+                fee
+            }
+            None => self
+                .fee_with_retries(policy)
+                .await
+                // Since `fee_with_retries` already retries internally, just pass the error to the
+                // user if it fails.
+                .map_err(|e| format!("Error obtaining the fee from the ledger canister: {}", e))?,
+        };
+
+        // See `icrc1_transfer_arg` for an explanation of the individual fields.
+        let mut arg = TransferArg {
+            from_subaccount: options.from_subaccount,
+            ..icrc1_transfer_arg(to, amount, fee, options.memo, transport.time())
+        };
+        // Ledgers only accept a `created_at_time` that is at most their transaction window (24
+        // hours on the ICP and the standard ICRC-1 ledgers) in the past, and at most a permitted
+        // drift (a minute or two) in the future, both measured on the ledger's clock. Our clock and
+        // the ledger's are close, but they aren't the same, so we correct the timestamp once if the
+        // ledger rejects it.
+        let mut retimed = false;
+        // Whether an earlier attempt may have gone through without us learning about it.
+        let mut outcome_unknown = false;
+
+        // Each attempt counts, including the ones with a corrected timestamp. Those go out right
+        // away, though; only failed calls wait for the policy's backoff.
+        let started_at = transport.time();
+        loop {
+            if *attempts == policy.max_attempts {
+                return Err(AppError::RetriesExhausted { attempts: *attempts }.into());
+            }
+            *attempts += 1;
+            let reply = transport
+                .call(OutboundCall::untrusted(
+                    ledger,
+                    "icrc1_transfer",
+                    candid::encode_one(&arg).unwrap(),
+                ))
+                .await;
+            let result = match reply {
+                Ok(reply) => match candid::decode_one::<Result<Nat, Icrc1TransferError>>(&reply) {
+                    Ok(decoded) => Ok(decoded),
+                    // This should not happen if the ledger correctly implements the ICRC-1
+                    // standard. We could try to query the ledger to determine the state of the
+                    // transaction, but if the ledger is incorrect, it is unlikely to work anyway.
+                    // The ledger did reply, so the transfer may have happened.
+                    Err(e) => {
+                        let reason = format!(
+                            "Unable to decode the ledger response ({}): {}",
+                            e,
+                            describe_reply(&reply)
+                        );
+                        return Err(outcome_unknown(*attempts, reason).into());
+                    }
+                },
+                Err(failure) => Err(failure),
+            };
+            let block_index = match result {
+                // An earlier attempt, whose reply we didn't get, went through after all, and the
+                // ledger tells us where. We sent the same transfer every time, so it's ours.
+                Ok(Err(Icrc1TransferError::Duplicate { duplicate_of })) if outcome_unknown => {
+                    duplicate_of
+                }
+                // Our timestamp is ahead of the ledger's clock, so the ledger didn't execute this
+                // or any earlier attempt. Use the ledger's own time instead.
+                Ok(Err(Icrc1TransferError::CreatedInFuture { ledger_time })) if !retimed => {
+                    retimed = true;
+                    arg.created_at_time = Some(ledger_time);
+                    continue;
+                }
+                // Remember the right fee, so that we can catch a wrong fee override next time.
+                Ok(Err(Icrc1TransferError::BadFee { expected_fee })) => {
+                    remember_icrc1_fee(ledger, &expected_fee);
+                    return Err(TransferFailure::Ledger(Icrc1TransferError::BadFee {
+                        expected_fee,
+                    }));
+                }
+                // The timestamp is too old for the ledger to check for duplicates. A fresh one
+                // would make the ledger accept the transfer, but also forget about any earlier
+                // attempt, so we only do that if we know that no earlier attempt executed.
+                Ok(Err(Icrc1TransferError::TooOld)) if !retimed && !outcome_unknown => {
+                    retimed = true;
+                    arg.created_at_time = Some(transport.time());
+                    continue;
+                }
+                // The ledger canister returned an error. This could be because the transaction
+                // didn't happen, for example because our balance was too low, but it could also
+                // happen in the case where we were retrying for too long and the
+                // `created_at_time` was too old. In the later case, the transaction may or may
+                // not have happened. See the TransferError documentation to do more
+                // fine-grained  and sophisticated error handling here. For example, you can
+                // query the ledger to find out whether the transaction occurred.
+                Ok(Err(e)) => return Err(TransferFailure::Ledger(e)),
+                // Since the call is idempotent, we can safely retry if the system returns an error
+                // with the ledger canister state being unknown, as far as the policy allows.
+                // Without a limit, we could keep our canister from stopping by constantly retrying
+                // this call. Should we give up, the transfer may or may not have happened, so we
+                // tell the user that rather than just that we ran out of attempts.
+                Err(CallFailure::SysUnknown(reason)) => {
+                    outcome_unknown = true;
+                    if policy.backoff(transport, *attempts, started_at).await.is_err() {
+                        return Err(AppError::OutcomeUnknown {
+                            attempts: *attempts,
+                            reason,
+                        }
+                        .into());
+                    }
+                    continue;
+                }
+                // The ledger never executed the transfer, so no tokens moved and the user can retry
+                // once the ledger has cycles again.
+                Err(failure) if failure.is_callee_out_of_cycles() => {
+                    let top_up = Some(CALLEE_TOP_UP_CYCLES);
+                    return Err(on_callee_out_of_cycles(transport, ledger, top_up).await.into());
+                }
+                // The ledger doesn't exist, so no tokens moved; the user has to fix the principal.
+                Err(failure) if failure.invalid_target(ledger).is_some() => {
+                    return Err(AppError::InvalidTarget { target: ledger }.into())
+                }
+                // The ledger is stopped, most likely for an upgrade, and didn't execute this
+                // attempt. Retrying with the same arguments is safe, as for unknown outcomes.
+                Err(failure) if failure.is_callee_stopped() => {
+                    if policy.backoff(transport, *attempts, started_at).await.is_err() {
+                        return Err(AppError::CalleeStopped {
+                            target: ledger,
+                            attempts: *attempts,
+                        }
+                        .into());
+                    }
+                    continue;
+                }
+                // Non-synchronous transient errors can be sensibly retried. Synchronous ones mean
+                // that the call never left our canister, see `fee_with_retries`.
+                Err(CallFailure::Rejected {
+                    code: RejectCode::SysTransient,
+                    sync: false,
+                    ..
+                }) => {
+                    policy.backoff(transport, *attempts, started_at).await?;
+                    continue;
+                }
+                // This should not happen if the ledger is correct. Same as for Candid decoding, we
+                // could try to query the ledger, but if the ledger is incorrect, it is unlikely to
+                // work, so we just tell the user that the transfer may have happened.
+                Err(CallFailure::CanisterError(err)) => {
+                    let reason = format!("Ledger crashed: {}", err);
+                    return Err(outcome_unknown(*attempts, reason).into());
+                }
+                // What's left is the transfer's block, or a rejection that retrying won't fix, in
+                // which case the ledger didn't execute the transfer. Again, we could try to query
+                // the ledger, but it's unlikely that it would work.
+                result => flatten_ledger_result(result)?,
+            };
+            HISTORY.with(|h| {
+                h.borrow_mut().record(
+                    transport.time(),
+                    ledger,
+                    arg.to.to_string(),
+                    arg.amount.clone(),
+                    block_index.clone(),
+                )
+            });
+            return Ok(block_index);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::mock::MockTransport;
+    use futures::executor::block_on;
+    use std::cell::Cell;
+
+    fn ledger_id() -> Principal {
+        Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap()
+    }
+
+    #[test]
+    fn test_icp_transfer_reply_decodes() {
+        let reply = candid::encode_one(Ok::<BlockIndex, TransferError>(12)).unwrap();
+        assert_eq!(decode_icp_transfer_reply(&reply), Ok(Ok(12)));
+    }
+
+    #[test]
+    fn test_icp_transfer_reply_of_unexpected_type() {
+        // A canister that isn't the ICP ledger answered with some text instead.
+        let reply = candid::encode_one("not a ledger").unwrap();
+        let error = decode_icp_transfer_reply(&reply).unwrap_err();
+        assert!(error.contains("Unable to decode"), "{}", error);
+        assert!(error.contains("not a ledger"), "{}", error);
+    }
+
+    #[test]
+    fn test_duplicate_is_accepted() {
+        let reply = Err(TransferError::TxDuplicate { duplicate_of: 17 });
+        assert_eq!(accept_duplicate(reply), Ok(17));
+        assert_eq!(accept_duplicate(Ok(18)), Ok(18));
+        let reply = Err(TransferError::TxCreatedInFuture);
+        assert_eq!(accept_duplicate(reply), Err(TransferError::TxCreatedInFuture));
+    }
+
+    #[test]
+    fn test_bad_fee_hint() {
+        let hint = icp_transfer_error_hint(&TransferError::BadFee {
+            expected_fee: Tokens::from_e8s(10_000),
+        });
+        assert!(hint.contains("10000 e8s"), "{}", hint);
+    }
+
+    #[test]
+    fn test_insufficient_funds_hint() {
+        let hint = icp_transfer_error_hint(&TransferError::InsufficientFunds {
+            balance: Tokens::from_e8s(5),
+        });
+        assert!(hint.contains("5 e8s"), "{}", hint);
+        assert!(hint.contains("top up"), "{}", hint);
+    }
+
+    #[test]
+    fn test_too_old_hint() {
+        let hint = icp_transfer_error_hint(&TransferError::TxTooOld {
+            allowed_window_nanos: 86_400_000_000_000,
+        });
+        assert!(hint.contains("86400 seconds"), "{}", hint);
+    }
+
+    #[test]
+    fn test_created_in_future_hint() {
+        let hint = icp_transfer_error_hint(&TransferError::TxCreatedInFuture);
+        assert!(hint.contains("retry in a few seconds"), "{}", hint);
+    }
+
+    #[test]
+    fn test_duplicate_hint() {
+        let hint = icp_transfer_error_hint(&TransferError::TxDuplicate { duplicate_of: 17 });
+        assert!(hint.contains("block 17"), "{}", hint);
+    }
+
+    fn user() -> Principal {
+        Principal::from_slice(&[7; 29])
+    }
+
+    // Accepts any ledger, to check that both implementations satisfy the trait.
+    async fn transfer_via<L: Ledger>(ledger: &L, to: L::Account, amount: L::Amount) -> Result<Nat, String> {
+        ledger.transfer(to, amount).await
+    }
+
+    #[test]
+    fn test_icp_transfer() {
+        let ledger = IcpLedger {
+            canister_id: ledger_id(),
+            transport: MockTransport::new(),
+        };
+        ledger
+            .transport
+            .reply(&TransferFee {
+                transfer_fee: Tokens::from_e8s(10_000),
+            })
+            .reply(&Ok::<u64, ic_ledger_types::TransferError>(7));
+        let to = AccountIdentifier::new(&user(), &ic_ledger_types::DEFAULT_SUBACCOUNT);
+
+        let block = block_on(transfer_via(&ledger, to, Tokens::from_e8s(500))).unwrap();
+
+        assert_eq!(block, Nat::from(7_u64));
+        let calls = ledger.transport.calls();
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[0].method, "transfer_fee");
+        assert_eq!(calls[1].target, ledger_id());
+        assert_eq!(calls[1].method, "transfer");
+        assert!(!calls[1].bounded_wait);
+        let sent: TransferArgs = candid::decode_one(&calls[1].args).unwrap();
+        assert_eq!(
+            sent,
+            icp_transfer_args(to, Tokens::from_e8s(500), Tokens::from_e8s(10_000))
+        );
+        assert_eq!(ledger.transport.in_flight_during_calls()[1], 1);
+    }
+
+    #[test]
+    fn test_icp_fee_and_balance() {
+        let ledger = IcpLedger {
+            canister_id: ledger_id(),
+            transport: MockTransport::new(),
+        };
+        ledger
+            .transport
+            .reply(&TransferFee {
+                transfer_fee: Tokens::from_e8s(10_000),
+            })
+            .reply(&Tokens::from_e8s(123));
+        let account = AccountIdentifier::new(&user(), &ic_ledger_types::DEFAULT_SUBACCOUNT);
+
+        assert_eq!(block_on(ledger.fee()), Ok(Tokens::from_e8s(10_000)));
+        assert_eq!(block_on(ledger.balance(account)), Ok(Tokens::from_e8s(123)));
+        let methods: Vec<String> = ledger.transport.calls().into_iter().map(|c| c.method).collect();
+        assert_eq!(methods, vec!["transfer_fee", "account_balance"]);
+    }
+
+    #[test]
+    fn test_icp_fee_is_cached() {
+        let ledger = IcpLedger {
+            canister_id: ledger_id(),
+            transport: MockTransport::new(),
+        };
+        ledger
+            .transport
+            .reply(&TransferFee {
+                transfer_fee: Tokens::from_e8s(10_000),
+            })
+            .reply(&TransferFee {
+                transfer_fee: Tokens::from_e8s(20_000),
+            });
+
+        assert_eq!(block_on(ledger.cached_fee()), Ok(Tokens::from_e8s(10_000)));
+        // Within the TTL, the ledger isn't asked again.
+        assert_eq!(block_on(ledger.cached_fee()), Ok(Tokens::from_e8s(10_000)));
+        assert_eq!(ledger.transport.calls().len(), 1);
+
+        ledger.transport.now.set(ICP_FEE_TTL.as_nanos() as u64);
+        assert_eq!(block_on(ledger.cached_fee()), Ok(Tokens::from_e8s(20_000)));
+        assert_eq!(ledger.transport.calls().len(), 2);
+    }
+
+    #[test]
+    fn test_icrc1_transfer() {
+        let ledger = Icrc1Ledger {
+            canister_id: ledger_id(),
+            transport: MockTransport {
+                now: std::cell::Cell::new(1_000),
+                ..MockTransport::new()
+            },
+        };
+        ledger
+            .transport
+            .reply(&Nat::from(10_u64))
+            .reply(&Ok::<Nat, icrc_ledger_types::icrc1::transfer::TransferError>(Nat::from(3_u64)));
+        let to = Account {
+            owner: user(),
+            subaccount: None,
+        };
+
+        let block = block_on(transfer_via(&ledger, to, Nat::from(500_u64))).unwrap();
+
+        assert_eq!(block, Nat::from(3_u64));
+        let calls = ledger.transport.calls();
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[0].method, "icrc1_fee");
+        assert_eq!(calls[1].method, "icrc1_transfer");
+        assert!(calls.iter().all(|c| c.bounded_wait && c.untrusted));
+        let sent: TransferArg = candid::decode_one(&calls[1].args).unwrap();
+        assert_eq!(
+            sent,
+            icrc1_transfer_arg(to, Nat::from(500_u64), Nat::from(10_u64), None, 1_000)
+        );
+        assert_eq!(ledger.transport.in_flight_during_calls()[1], 1);
+    }
+
+    // Lets other futures run while a call is out, like awaiting a call on the IC does, and counts
+    // the transfers that are out at the same time.
+    struct SlowTransport {
+        mock: MockTransport,
+        transfers_out: Cell<usize>,
+        most_transfers_out: Cell<usize>,
+    }
+
+    impl Transport for SlowTransport {
+        async fn call(&self, call: OutboundCall) -> Result<Vec<u8>, CallFailure> {
+            let transfer = call.method == "icrc1_transfer";
+            if transfer {
+                self.transfers_out.set(self.transfers_out.get() + 1);
+                self.most_transfers_out
+                    .set(self.most_transfers_out.get().max(self.transfers_out.get()));
+            }
+            let mut yielded = false;
+            std::future::poll_fn(|cx| {
+                if yielded {
+                    std::task::Poll::Ready(())
+                } else {
+                    yielded = true;
+                    cx.waker().wake_by_ref();
+                    std::task::Poll::Pending
+                }
+            })
+            .await;
+            let reply = self.mock.call(call).await;
+            if transfer {
+                self.transfers_out.set(self.transfers_out.get() - 1);
+            }
+            reply
+        }
+
+        fn time(&self) -> u64 {
+            self.mock.time()
+        }
+
+        fn canister_self(&self) -> Principal {
+            self.mock.canister_self()
+        }
+
+        async fn sleep(&self, duration: Duration) {
+            self.mock.sleep(duration).await
+        }
+
+        fn cycles_refunded(&self) -> u128 {
+            self.mock.cycles_refunded()
+        }
+    }
+
+    #[test]
+    fn test_concurrent_icrc1_transfers_reach_the_ledger_one_at_a_time() {
+        let ledger = Icrc1Ledger {
+            canister_id: ledger_id(),
+            transport: SlowTransport {
+                mock: MockTransport::new(),
+                transfers_out: Cell::new(0),
+                most_transfers_out: Cell::new(0),
+            },
+        };
+        ledger
+            .transport
+            .mock
+            .reply(&Ok::<Nat, Icrc1TransferError>(Nat::from(1_u64)))
+            .reply(&Ok::<Nat, Icrc1TransferError>(Nat::from(2_u64)));
+        let to = Account {
+            owner: user(),
+            subaccount: None,
+        };
+        // With the fee given, each transfer is a single call.
+        let ledger = &ledger;
+        let transfer = |amount: u64| async move {
+            let options = Icrc1TransferOptions {
+                fee: Some(Nat::from(10_u64)),
+                ..Icrc1TransferOptions::default()
+            };
+            let mut attempts = 0;
+            let policy = RetryPolicy::default();
+            ledger
+                .transfer_with(to, Nat::from(amount), options, &policy, &mut attempts)
+                .await
+        };
+
+        let (first, second) = block_on(async { futures::join!(transfer(100), transfer(200)) });
+
+        assert_eq!((first.unwrap(), second.unwrap()), (Nat::from(1_u64), Nat::from(2_u64)));
+        // Without `TRANSFER_LOCK`, the second transfer would go out while the first one is still
+        // waiting for the ledger.
+        assert_eq!(ledger.transport.most_transfers_out.get(), 1);
+    }
+
+    fn standards(names: &[&str]) -> Vec<SupportedStandard> {
+        names
+            .iter()
+            .map(|name| SupportedStandard {
+                name: name.to_string(),
+                url: format!("https://github.com/dfinity/ICRC-1/standards/{}", name),
+            })
+            .collect()
+    }
+
+    fn approved(allowance: u64) -> Allowance {
+        Allowance {
+            allowance: Nat::from(allowance),
+            expires_at: None,
+        }
+    }
+
+    #[test]
+    fn test_sweep_transfers_allowance_minus_fee() {
+        let ledger = Icrc1Ledger {
+            canister_id: ledger_id(),
+            transport: MockTransport::new(),
+        };
+        ledger
+            .transport
+            .reply(&standards(&["ICRC-1", "ICRC-2"]))
+            .reply(&approved(1_000))
+            .reply(&Nat::from(10_u64))
+            .reply(&Ok::<Nat, TransferFromError>(Nat::from(4_u64)));
+        let from = Account {
+            owner: user(),
+            subaccount: None,
+        };
+        let to = Account {
+            owner: Principal::management_canister(),
+            subaccount: None,
+        };
+
+        assert_eq!(block_on(ledger.sweep_approved(from, to)), Ok(Nat::from(4_u64)));
+        let calls = ledger.transport.calls();
+        let args: AllowanceArgs = candid::decode_one(&calls[1].args).unwrap();
+        assert_eq!(args.spender.owner, ledger.transport.canister_self());
+        assert_eq!(calls[3].method, "icrc2_transfer_from");
+        let args: TransferFromArgs = candid::decode_one(&calls[3].args).unwrap();
+        assert_eq!((args.from, args.to), (from, to));
+        assert_eq!(args.amount, Nat::from(990_u64));
+        // The sweep blocks upgrades until it's done, see `upgrade::check_upgrade`.
+        assert_eq!(ledger.transport.in_flight_during_calls()[3], 1);
+        assert_eq!(upgrade::in_flight(), 0);
+    }
+
+    #[test]
+    fn test_sweep_of_allowance_that_barely_covers_fee_is_an_error() {
+        let ledger = Icrc1Ledger {
+            canister_id: ledger_id(),
+            transport: MockTransport::new(),
+        };
+        ledger
+            .transport
+            .reply(&standards(&["ICRC-1", "ICRC-2"]))
+            .reply(&approved(10))
+            .reply(&Nat::from(10_u64));
+        let from = Account {
+            owner: user(),
+            subaccount: None,
+        };
+
+        let error = block_on(ledger.sweep_approved(from, from)).unwrap_err();
+
+        assert!(error.contains("allowance of 10 doesn't exceed the fee of 10"), "{}", error);
+        // Nothing was transferred.
+        assert_eq!(ledger.transport.calls().len(), 3);
+    }
+
+    #[test]
+    fn test_sweep_on_ledger_without_icrc2_is_an_error() {
+        let ledger = Icrc1Ledger {
+            canister_id: ledger_id(),
+            transport: MockTransport::new(),
+        };
+        ledger.transport.reply(&standards(&["ICRC-1"]));
+        let from = Account {
+            owner: user(),
+            subaccount: None,
+        };
+
+        let error = block_on(ledger.sweep_approved(from, from)).unwrap_err();
+
+        assert!(error.contains("doesn't support ICRC-2"), "{}", error);
+        assert_eq!(ledger.transport.calls().len(), 1);
+    }
+
+    #[test]
+    fn test_valid_memo_is_passed_through() {
+        let memo = validate_memo(Some(b"invoice 42".to_vec())).unwrap();
+        let to = Account {
+            owner: user(),
+            subaccount: None,
+        };
+        let arg = icrc1_transfer_arg(to, Nat::from(1_u64), Nat::from(10_u64), memo, 0);
+        assert_eq!(arg.memo, Some(Icrc1Memo::from(b"invoice 42".to_vec())));
+        assert!(validate_memo(Some(vec![0; MAX_MEMO_BYTES])).is_ok());
+    }
+
+    #[test]
+    fn test_oversized_memo_is_rejected() {
+        let error = validate_memo(Some(vec![0; MAX_MEMO_BYTES + 1])).unwrap_err();
+        assert!(error.contains("33 bytes"), "{}", error);
+    }
+
+    #[test]
+    fn test_no_memo() {
+        assert_eq!(validate_memo(None), Ok(None));
+    }
+
+    #[test]
+    fn test_icrc1_balance() {
+        let ledger = Icrc1Ledger {
+            canister_id: ledger_id(),
+            transport: MockTransport::new(),
+        };
+        ledger.transport.reply(&Nat::from(99_u64));
+        let of = Account {
+            owner: user(),
+            subaccount: None,
+        };
+
+        assert_eq!(block_on(ledger.balance(of)), Ok(Nat::from(99_u64)));
+        let calls = ledger.transport.calls();
+        assert_eq!(calls[0].method, "icrc1_balance_of");
+        assert_eq!(candid::decode_one::<Account>(&calls[0].args).unwrap(), of);
+    }
+
+    #[test]
+    fn test_ledger_error_is_reported() {
+        let ledger = IcpLedger {
+            canister_id: ledger_id(),
+            transport: MockTransport::new(),
+        };
+        ledger
+            .transport
+            .reply(&TransferFee {
+                transfer_fee: Tokens::from_e8s(10_000),
+            })
+            .reply(&Err::<u64, _>(ic_ledger_types::TransferError::TxTooOld {
+                allowed_window_nanos: 1_000_000_000,
+            }));
+        let to = AccountIdentifier::new(&user(), &ic_ledger_types::DEFAULT_SUBACCOUNT);
+
+        let error = block_on(ledger.transfer(to, Tokens::from_e8s(1))).unwrap_err();
+
+        assert!(error.contains("created more than 1 seconds ago"), "{}", error);
+    }
+
+    #[test]
+    fn test_icrc1_decimals_from_metadata() {
+        let ledger = Icrc1Ledger {
+            canister_id: ledger_id(),
+            transport: MockTransport::new(),
+        };
+        ledger.transport.reply(&vec![
+            ("icrc1:symbol".to_string(), MetadataValue::Text("TKN".to_string())),
+            ("icrc1:decimals".to_string(), MetadataValue::Nat(Nat::from(6_u64))),
+        ]);
+
+        assert_eq!(block_on(ledger.decimals()), Ok(6));
+        assert_eq!(ledger.transport.calls()[0].method, "icrc1_metadata");
+    }
+
+    #[test]
+    fn test_supported_standards_are_decoded_and_cached() {
+        let ledger = Icrc1Ledger {
+            canister_id: Principal::from_slice(&[4; 10]),
+            transport: MockTransport::new(),
+        };
+        ledger.transport.reply(&standards(&["ICRC-1", "ICRC-2"]));
+
+        let standards = block_on(ledger.supported_standards()).unwrap();
+        assert!(supports(&standards, "ICRC-1"));
+        assert!(supports(&standards, "icrc-2"));
+        assert!(!supports(&standards, "ICRC-3"));
+
+        // The second time, the cached list is used.
+        assert_eq!(block_on(ledger.supported_standards()), Ok(standards));
+        assert_eq!(ledger.transport.calls().len(), 1);
+        assert_eq!(ledger.transport.calls()[0].method, "icrc1_supported_standards");
+    }
+
+    #[test]
+    fn test_tokens_to_nat() {
+        for e8s in [0, 1, 10_000, u64::MAX] {
+            assert_eq!(tokens_to_nat(Tokens::from_e8s(e8s)), Nat::from(e8s));
+        }
+    }
+
+    #[test]
+    fn test_fee_override_checks() {
+        let known = Nat::from(10_000_u64);
+        assert_eq!(check_fee_override(&Nat::from(10_000_u64), Some(&known)), Ok(()));
+        assert_eq!(check_fee_override(&Nat::from(20_000_u64), Some(&known)), Ok(()));
+        assert_eq!(check_fee_override(&Nat::from(1_u64), None), Ok(()));
+        assert!(check_fee_override(&Nat::from(9_999_u64), Some(&known))
+            .unwrap_err()
+            .contains("below"));
+    }
+
+    #[test]
+    fn test_dedup_window_from_metadata() {
+        let metadata = vec![
+            ("icrc1:decimals".to_string(), MetadataValue::Nat(Nat::from(8_u64))),
+            (
+                DEDUP_WINDOW_METADATA_KEY.to_string(),
+                MetadataValue::Nat(Nat::from(3_600_u64)),
+            ),
+        ];
+        assert_eq!(dedup_window_from_metadata(&metadata), Ok(3_600));
+    }
+
+    #[test]
+    fn test_dedup_window_falls_back_to_default() {
+        let ledger = Icrc1Ledger {
+            canister_id: ledger_id(),
+            transport: MockTransport::new(),
+        };
+        ledger.transport.reply(&vec![(
+            "icrc1:decimals".to_string(),
+            MetadataValue::Nat(Nat::from(8_u64)),
+        )]);
+
+        assert_eq!(block_on(ledger.dedup_window_secs()), Ok(24 * 60 * 60));
+    }
+
+    #[test]
+    fn test_dedup_window_of_wrong_type() {
+        let metadata = vec![(
+            DEDUP_WINDOW_METADATA_KEY.to_string(),
+            MetadataValue::Text("a day".to_string()),
+        )];
+        assert!(dedup_window_from_metadata(&metadata).is_err());
+    }
+
+    #[test]
+    fn test_parse_whole_amounts() {
+        assert_eq!(parse_human_amount("1", 8), Ok(Nat::from(100_000_000_u64)));
+        assert_eq!(parse_human_amount("42", 0), Ok(Nat::from(42_u64)));
+        assert_eq!(parse_human_amount("0", 8), Ok(Nat::from(0_u64)));
+    }
+
+    #[test]
+    fn test_parse_fractional_amounts() {
+        assert_eq!(parse_human_amount("1.5", 8), Ok(Nat::from(150_000_000_u64)));
+        // 1.1 isn't representable as a float; make sure nothing is lost.
+        assert_eq!(parse_human_amount("1.1", 8), Ok(Nat::from(110_000_000_u64)));
+        assert_eq!(parse_human_amount("0.00000001", 8), Ok(Nat::from(1_u64)));
+        assert_eq!(parse_human_amount("2.25", 2), Ok(Nat::from(225_u64)));
+        assert_eq!(
+            parse_human_amount("12345.000000000000000001", 18),
+            Ok("12345000000000000000001".parse::<Nat>().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_parse_rejects_too_many_decimals() {
+        assert!(parse_human_amount("0.000000001", 8).unwrap_err().contains("only 8 decimals"));
+        assert!(parse_human_amount("1.5", 0).is_err());
+    }
+
+    #[test]
+    fn test_parse_rejects_malformed_amounts() {
+        for amount in ["", ".5", "1.", "-1", "1.2.3", "1,5", " 1", "1e8"] {
+            assert!(parse_human_amount(amount, 8).is_err(), "{:?}", amount);
+        }
+    }
+}
@@ -1,202 +1,717 @@
-use candid::Principal;
-use ic_cdk::call::{CallError, RejectCode};
-use ic_cdk::{api::msg_caller, call::Call};
-use ic_cdk::api::canister_self;
-use ic_ledger_types::{AccountIdentifier, BlockIndex, Memo, Tokens, TransferArgs, TransferError};
-use ic_xrc_types::{Asset, GetExchangeRateRequest, GetExchangeRateResult};
+use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_cdk::api::msg_caller;
+use ic_cdk::api::canister_cycle_balance;
+use ic_ledger_types::{AccountIdentifier, BlockIndex, Tokens};
+use ic_xrc_types::{Asset, AssetClass, ExchangeRate, GetExchangeRateRequest, GetExchangeRateResult};
 use icrc_ledger_types::icrc1::account::Account;
-use icrc_ledger_types::icrc1::transfer::{NumTokens, TransferArg};
+use icrc_ledger_types::icrc1::transfer::{Memo as Icrc1Memo, NumTokens, TransferError as Icrc1TransferError};
+use ic_cdk::management_canister::{HttpRequestResult, TransformArgs};
+use std::cell::Cell;
+
+mod airdrop;
+mod budget;
+mod cache;
+mod cmc;
+mod cycles;
+mod decode;
+mod error;
+mod governance;
+mod health;
+mod history;
+mod http;
+mod icrc3;
+mod large_reply;
+mod ledger;
+mod lock;
+mod log;
+mod management;
+mod nat;
+mod omnibus;
+mod outbox;
+mod panics;
+mod pause;
+mod proposals;
+mod proxy;
+mod rate_limit;
+mod retry;
+#[cfg(feature = "simulate")]
+mod simulate;
+mod sns;
+mod timing;
+mod trace;
+mod transport;
+mod upgrade;
+mod watchdog;
+mod xrc;
+
+use airdrop::AIRDROPS;
+use budget::MethodSpend;
+use decode::decode_or_describe;
+use error::{ensure_cycles, AppError};
+use health::{health_status, mark_started, HealthStatus};
+use history::{History, TransferRecord, HISTORY};
+use icrc3::{BlockWithId, ExpectedTransfer};
+use ledger::{
+    parse_human_amount, tokens_to_nat, validate_memo, IcpLedger, Icrc1Ledger, Icrc1TransferOptions, Ledger,
+    TransferFailure, ICP_LEDGER_CANISTER_ID,
+};
+use log::{log_call, LogEntry, LOG};
+use pause::ensure_not_paused;
+use management::{CanisterStatusSummary, CreateOutcome};
+use nat::{nat_add, nat_sub_checked};
+use outbox::{JobStatus, OUTBOX};
+use proposals::{ProposalStatus, Proposals, PROPOSALS};
+use rate_limit::RATE_LIMITER;
+use retry::RetryPolicy;
+use trace::{Traced, TracedCall};
+use transport::{default_transport, OutboundCall, Transport};
+use xrc::{xrc_request, ExchangeRateSummary};
 
 // Hard-coded owner principal for illustration purposes
 const OWNER: &str = "gl542-2r2m3-znmmo-cjhz7-p332z-mbe6x-hmrnu-rv37c-mncas-i46u2-sqe";
 
+thread_local! {
+    // Whether anyone may call the read methods, see `InitArgs::public_reads`.
+    static PUBLIC_READS: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Guard that rejects calls from the anonymous principal (`2vxsx-fae`).
+// Ingress messages can be sent anonymously, without any signature. Calls from other canisters
+// always carry the calling canister's principal, so they are never anonymous. Guards run before
+// the method body, so a rejected call has no effect. Each canister in this repository is a
+// standalone example with its own copy of this guard; keep the copies identical.
+fn reject_anonymous() -> Result<(), String> {
+    check_not_anonymous(msg_caller())
+}
+
+fn check_not_anonymous(caller: Principal) -> Result<(), String> {
+    if caller == Principal::anonymous() {
+        Err("Anonymous callers are not allowed to call this method".to_string())
+    } else {
+        Ok(())
+    }
+}
+
+/// Guard for the read methods: unless the canister was installed with `public_reads`, only the
+/// owner may call them.
+// The reads don't change our state, but they aren't free: each one makes outbound calls that we
+// pay for, and `get_exchange_rate` attaches the XRC fee to every call. Opening them to everyone
+// means that anyone can spend our cycles; the rate limit doesn't help much against that, since
+// an attacker can send from as many principals as they like. Deployments that enable public
+// reads should monitor the cycle balance (see `health`) and top up accordingly.
+fn reads_allowed() -> Result<(), String> {
+    check_read_access(msg_caller(), PUBLIC_READS.with(|p| p.get()))
+}
+
+fn check_read_access(caller: Principal, public_reads: bool) -> Result<(), String> {
+    if public_reads || caller == Principal::from_text(OWNER).unwrap() {
+        Ok(())
+    } else {
+        Err("Only the owner can call this method".to_string())
+    }
+}
+
+/// Counts a call towards the caller's rate limit, failing if they exceeded it.
+// Transfers are the methods worth abusing: each one costs us cycles and ledger fees. Checking
+// before the first `await` means that a rejected call doesn't cost anything beyond this check.
+fn check_rate_limit() -> Result<(), String> {
+    RATE_LIMITER
+        .with(|r| r.borrow_mut().check(msg_caller(), ic_cdk::api::time()))
+        .map_err(String::from)
+}
 
 /// Transfers some ICP to the specified account.
 // Methods that call other canisters can use the async/await syntax to perform calls, and we thus
 // mark them as async.
-#[ic_cdk::update]
+#[ic_cdk::update(guard = "reject_anonymous")]
 pub async fn icp_transfer(to: AccountIdentifier, amount: Tokens) -> Result<(), String> {
+    ensure_owner()?;
+    check_rate_limit()?;
+    ensure_not_paused()?;
+    icp_ledger_client().transfer(to, amount).await.map(|_| ())
+}
+
+fn ensure_owner() -> Result<(), String> {
     // msg_caller() returns the identity of the user or canister who initiated the call.
     // Only allow the owner to transfer.
     if msg_caller() != Principal::from_text(OWNER).unwrap() {
         return Err("Only the owner can ask to transfer ICP".to_string());
     }
+    Ok(())
+}
 
-    // The ID of the ledger canister on the IC mainnet.
-    const ICP_LEDGER_CANISTER_ID: &str = "ryjl3-tyaaa-aaaaa-aaaba-cai";
-    let icp_ledger = Principal::from_text(ICP_LEDGER_CANISTER_ID).unwrap();
-    let args = TransferArgs {
-        // A "memo" is an arbitrary blob that has no meaning to the ledger, but can be used by
-        // the sender or receiver to attach additional information to the transaction. We
-        // just use the number 0 here as an example.
-        memo: Memo(0),
-        to,
-        amount,
-        // The ICP ledger canister charges a fee for transfers, which is deducted from the
-        // sender's account. The fee is fixed to 10_000 e8s (0.0001 ICP).
-        fee: Tokens::from_e8s(10_000),
-        // The ledger supports subaccounts, but we don't use them in this example.
-        from_subaccount: None,
-        // The created_at_time is used for deduplication, which we don't use in this example.
-        created_at_time: None,
+/// Transfers `usd` US dollars' worth of ICP to `to`, at the current ICP/USD rate of the XRC, and
+/// returns the index of the block containing the transfer.
+// The XRC charges its fee in cycles for every rate, so we reuse a rate for `xrc::RATE_TTL`,
+// which is well within the `xrc::MAX_RATE_AGE_SECS` that we accept.
+#[ic_cdk::update(guard = "reject_anonymous")]
+pub async fn transfer_usd_worth(to: AccountIdentifier, usd: f64) -> Result<BlockIndex, String> {
+    ensure_owner()?;
+    check_rate_limit()?;
+    ensure_not_paused()?;
+    let icp = Asset {
+        symbol: "ICP".to_string(),
+        class: AssetClass::Cryptocurrency,
+    };
+    let usd_asset = Asset {
+        symbol: "USD".to_string(),
+        class: AssetClass::FiatCurrency,
     };
+    // If the XRC can't provide a rate, e.g., because it can't reach enough exchanges, this
+    // reports its error, with a hint on what to do.
+    let spend = MethodSpend::start("transfer_usd_worth");
+    let rate = fetch_exchange_rate(icp, usd_asset, &spend).await?;
+    xrc::check_rate_age(rate.timestamp, ic_cdk::api::time(), xrc::MAX_RATE_AGE_SECS)?;
+    let e8s = xrc::usd_to_e8s(usd, rate.rate, rate.metadata.decimals)?;
+    icp_ledger_client().transfer_icp(to, Tokens::from_e8s(e8s)).await
+}
 
-    // Unbounded wait calls ensure that the system doesn't give up waiting on the response from the
-    // ledger, though the call might still fail.
-    // We will match on the result to show how to properly handle errors.
-    // Unbounded wait calls never return a `SysUnknown` error. The response might still be a different
-    // kind of error, either coming from the ledger or from the system (it's possible that the system
-    // fails the call before it reaches the ledger)
-    match Call::unbounded_wait(icp_ledger, "transfer")
-        // Sets the call argument, that the recipient will process.
-        .with_arg(&args)
-        // We are ready to execute the call. The type parameter specifies the expected return type
-        // of the call. In this case, we expect the ledger to return a `BlockIndex` if the transfer
-        // was successful, or a `TransferError` if it failed. The result of the entire await is a
-        // nested `Result`, which can contain an error if the call itself failed, or the value
-        // returned by the ledger (which is in itself a `Result`).
-        // Note that calls must be awaited to actually send them.
-        .call::<Result<BlockIndex, TransferError>>()
-        .await
-    {
-        // The transfer call succeeded
-        Ok(Ok(_i)) => Ok(()),
-        // The ledger canister returned an error, for example because our balance was too low.
-        // The transfer didn't happen, and we can report an error back to the user.
-        Ok(Err(e)) => Err(format!("Ledger returned an error: {:?}", e)),
-        // The Internet Computer rejected our call, for example because the system is overloaded.
-        // We know that the transfer didn't happen and return an error to the user.
-        Err(CallError::CallRejected(_)) => Err(format!("Error calling ledger canister: {:?}", e)),
-        // An error might happen because the response could not be decoded. We panic
-        // here because we assume that the ledger's response type is known and stable.
-        Err(CallError::StateUnknown(StateUnknown::CandidDecodeFailed(msg))) => panic!("Decoding failed: {}", msg),
-        // The ledger crashed while processing our request. We don't know if the transfer happened.
-        // Here, we assume that the ICP ledger is sufficiently well tested that it doesn't crash.
-        // For other canisters, more sophisticated error handling might be necessary (for example,
-        // they may fail because of a bug or running out of cycles to perform some operations).
-        Err(CallError::StateUnknown(StateUnknown::CanisterError(err))) => panic!("Ledger crashed: {:?}", err),
-        // This case is unreachable when using unbounded wait calls.
-        Err(CallError::StateUnknown(StateUnknown::SysUnknown(_))) => unreachable!("SysUnknown errors cannot happen when using"),
+/// The ICP ledger, reached through the default transport.
+fn icp_ledger_client() -> IcpLedger<impl Transport> {
+    IcpLedger {
+        canister_id: Principal::from_text(ICP_LEDGER_CANISTER_ID).unwrap(),
+        transport: default_transport(),
+    }
+}
+
+/// The ICRC-1 ledger `ledger`, reached through `transport`.
+fn icrc1_ledger<T: Transport>(transport: &T, ledger: Principal) -> Icrc1Ledger<&T> {
+    Icrc1Ledger {
+        canister_id: ledger,
+        transport,
     }
 }
 
+/// Converts `amount` of our ICP into cycles for `target`, via the cycles minting canister, and
+/// returns the number of cycles that `target` received.
+#[ic_cdk::update(guard = "controllers_only")]
+pub async fn top_up_with_icp(target: Principal, amount: Tokens) -> Result<Nat, String> {
+    ensure_not_paused()?;
+    let ledger = icp_ledger_client();
+    let fee = ledger.cached_fee().await?;
+    cmc::top_up_with_icp_via(&ledger.transport, ledger.canister_id, target, amount, fee).await
+}
+
+/// Returns the fee that the ICP ledger charges for a transfer. The fee is cached for
+/// `ICP_FEE_TTL`.
+#[ic_cdk::update(guard = "reads_allowed")]
+pub async fn icp_fee() -> Result<Tokens, String> {
+    icp_ledger_client().cached_fee().await
+}
+
 /// Obtain the fee that the ledger canister charges for a transfer.
-#[ic_cdk::update]
+#[ic_cdk::update(guard = "reads_allowed")]
 pub async fn icrc1_get_fee(ledger: Principal) -> Result<NumTokens, String> {
-    loop {
-        match Call::bounded_wait(ledger, "icrc1_fee")
-            .call()
-            .await
-        {
-            Ok(fee) => return Ok(fee),
-            // The system rejected our call
-            Err(CallError::CallRejected(rejection)) => {
-                // Determine whether it makes sense to retry. Calls that fail with a non-synchronous
-                // transient error are retryable. For a production system, one might want to limit the
-                // number of retries to avoid spinning in a retry loop forever in some way.
-                // We could use a fixed number of attempts, a timeout, or just check that the caller
-                // isn't stopping.
-                if rejection.is_sync() && rejection.reject_code() == RejectCode::SysTransient
-                {
-                    continue;
-                } else {
-                    // Other rejection types are not retryable. They could happen, for example, if
-                    // the target canister explicitly rejects the call (for example, because it is
-                    // stopped), if it gets deleted, or if a fatal system error occurs.
-                    return Err(format!(
-                        "Irrecoverable error: {:?}",
-                        rejection
-                    ));
-                }
-            }
-            // Since getting the fee doesn't change the ledger state we can simply retry if the
-            // system returns a `SysUnknown` error with the ledger canister state being unknown.
-            // Again, we omit limiting the number of retries for simplicity.
-            Err(CallError::StateUnknown(StateUnknown::SysUnknown(_))) => continue,
-            // Candid decoding shouldn't fail with a correctly implemented ledger. However, since
-            // we are calling an arbitrary ledger, we don't know if it's correctly implemented.
-            // Return an error to the user.
-            Err(CallError::StateUknown(StateUnknown::CandidDecodeFailed(msg))) =>
-                return Err(format!("Unable to decode the fee: {}", msg)),
-            // The ledger crashed while processing our request; report an error to the user.
-            Err(CallError::StateUnknown(StateUnknown::CanisterError(err))) =>
-                return Err(format!("Ledger crashed: {:?}", err))
+    let transport = default_transport();
+    log_call(
+        &transport,
+        "icrc1_get_fee",
+        icrc1_ledger(&transport, ledger).fee_with_retries(&retry::current()),
+    )
+    .await
+}
+
+/// Transfer the tokens on the specified ledger. Callers that know the ledger's fee can pass it as
+/// `fee`, which saves asking the ledger for it first. With `precheck`, the transfer is only
+/// attempted if `precheck_transfer` passes. The `memo`, of at most `ledger::MAX_MEMO_BYTES` bytes, is
+/// recorded with the transfer, e.g., as a payment reference.
+// The tokens come out of our own account, so only controllers may transfer them.
+#[ic_cdk::update(guard = "controllers_only")]
+pub async fn icrc1_transfer(
+    ledger: Principal,
+    to: Account,
+    amount: NumTokens,
+    fee: Option<NumTokens>,
+    precheck: Option<bool>,
+    memo: Option<Vec<u8>>,
+) -> Result<(), String> {
+    check_rate_limit()?;
+    let memo = validate_memo(memo)?;
+    ensure_not_paused()?;
+    // Record the calls of this transfer, see `call_graph`.
+    trace::reset();
+    let transport = Traced(default_transport());
+    let fee = match (precheck, fee) {
+        // The precheck asks the ledger for the fee, so the transfer doesn't have to.
+        (Some(true), None) => Some(precheck_transfer_via(&transport, ledger, to, &amount).await?),
+        (Some(true), Some(fee)) => {
+            precheck_transfer_via(&transport, ledger, to, &amount).await?;
+            Some(fee)
         }
+        (_, fee) => fee,
+    };
+    log_call(
+        &transport,
+        "icrc1_transfer",
+        icrc1_transfer_via(&transport, ledger, to, amount, fee, memo, &retry::current()),
+    )
+    .await
+    .map(|_| ())
+}
+
+/// Checks that a transfer of `amount` to `to` on `ledger` can succeed: that our balance covers
+/// the amount plus the fee, and that `to` isn't our own account.
+// A transfer that the ledger is bound to reject with `InsufficientFunds` still costs a call, and
+// the ledger's error doesn't say how much is missing. The balance can still change before the
+// transfer executes, so the check is advisory; the ledger has the final say.
+#[ic_cdk::update(guard = "reject_anonymous")]
+pub async fn precheck_transfer(ledger: Principal, to: Account, amount: NumTokens) -> Result<(), String> {
+    precheck_transfer_via(&default_transport(), ledger, to, &amount)
+        .await
+        .map(|_| ())
+}
+
+/// Does the work of `precheck_transfer`, and returns the ledger's fee.
+async fn precheck_transfer_via<T: Transport>(
+    transport: &T,
+    ledger: Principal,
+    to: Account,
+    amount: &NumTokens,
+) -> Result<NumTokens, String> {
+    let from = Account {
+        owner: transport.canister_self(),
+        subaccount: None,
+    };
+    if to == from {
+        return Err("The recipient is our own account; the transfer would only burn the fee".to_string());
     }
+    let fee = icrc1_ledger(transport, ledger).fee_with_retries(&retry::current()).await?;
+    let balance = own_balance_via(transport, ledger).await?;
+    let required = nat_add(amount, &fee);
+    if balance < required {
+        let shortfall = nat_sub_checked(&required, &balance)?;
+        return Err(format!(
+            "Insufficient funds: the transfer requires {} (the amount plus a fee of {}), but our \
+             balance is {}, {} short",
+            required, fee, balance, shortfall
+        ));
+    }
+    Ok(fee)
 }
 
-/// Transfer the tokens on the specified ledger
-#[ic_cdk::update]
-pub async fn icrc1_transfer(ledger: Principal, to: Account, amount: NumTokens) -> Result<(), String> {
-    // In the first step, obtain the fee. Use the method above to handle retries.
-    let fee: NumTokens = Call::bounded_wait(canister_self(), "icrc1_get_fee")
-        .call()
+/// Reads the balance of our default account on `ledger`.
+async fn own_balance_via<T: Transport>(transport: &T, ledger: Principal) -> Result<NumTokens, String> {
+    let account = Account {
+        owner: transport.canister_self(),
+        subaccount: None,
+    };
+    let reply = transport
+        .call(OutboundCall::untrusted(
+            ledger,
+            "icrc1_balance_of",
+            candid::encode_one(account).unwrap(),
+        ))
         .await
-        // Since `icrc1_get_fee` already retries internally, just pass the error to the user
-        // if it fails.
-        .map_err(|e| format!("Error obtaining the fee from the ledger canister: {:?}", e))?;
+        .map_err(|e| format!("Error obtaining our balance: {:?}", e))?;
+    decode_or_describe(&reply).map_err(|e| format!("Error obtaining our balance: {}", e))
+}
 
-    let arg = TransferArg {
-        from_subaccount: None,
-        to,
-        fee: Some(fee),
-        // Setting the created time ensures that the ledger performs deduplication of transactions,
-        // such that they can be safely retried. This is very useful for bounded wait calls.
-        created_at_time: Some(ic_cdk::api::time()),
-        memo: None,
-        amount,
+/// Transfers our whole balance on `ledger` to `to`, minus the fee, and returns the index of the
+/// block containing the transfer.
+// Only controllers may empty the wallet. The balance can change between reading it and
+// transferring: if it dropped, the ledger rejects the transfer with `InsufficientFunds` and the
+// sweep can be retried; if it grew, the difference stays behind.
+#[ic_cdk::update(guard = "controllers_only")]
+pub async fn icrc1_sweep(ledger: Principal, to: Account) -> Result<Nat, String> {
+    ensure_not_paused()?;
+    let transport = default_transport();
+    log_call(&transport, "icrc1_sweep", icrc1_sweep_via(&transport, ledger, to)).await
+}
+
+async fn icrc1_sweep_via<T: Transport>(transport: &T, ledger: Principal, to: Account) -> Result<Nat, String> {
+    let policy = retry::current();
+    let fee = icrc1_ledger(transport, ledger).fee_with_retries(&policy).await?;
+    let balance = own_balance_via(transport, ledger).await?;
+    // A balance of exactly the fee would transfer nothing, and the ledger would still charge
+    // the fee; most ledgers reject such transfers anyway.
+    if balance <= fee {
+        return Err(format!(
+            "Nothing to sweep: our balance of {} doesn't exceed the fee of {}",
+            balance, fee
+        ));
+    }
+    let amount = nat_sub_checked(&balance, &fee)?;
+    icrc1_transfer_via(transport, ledger, to, amount, Some(fee), None, &policy).await
+}
+
+/// Queues a transfer of `amount` to `to` on `ledger`, and returns the ID of the job, which
+/// `job_status` reports on. The transfer is retried in the background until it succeeds or
+/// fails for good, even across upgrades.
+// `icrc1_transfer` gives up after a few attempts and leaves the rest to the caller, who may not
+// be around to retry. The outbox takes over that responsibility: the job is recorded before any
+// call is made, and every attempt sends the same transfer, so the ledger's deduplication makes
+// retrying safe, for as long as the ledger's deduplication window (24 hours on most ledgers).
+// The transfers come out of our own account, so only controllers may queue them.
+#[ic_cdk::update(guard = "controllers_only")]
+pub fn enqueue_transfer(ledger: Principal, to: Account, amount: NumTokens) -> Result<u64, String> {
+    check_rate_limit()?;
+    ensure_not_paused()?;
+    let id = OUTBOX.with(|o| o.borrow_mut().enqueue(ledger, to, amount, ic_cdk::api::time()));
+    // No need to wait for the next interval.
+    outbox::run();
+    Ok(id)
+}
+
+/// Reports on the transfer queued by `enqueue_transfer` as job `id`.
+#[ic_cdk::query]
+pub fn job_status(id: u64) -> JobStatus {
+    OUTBOX.with(|o| o.borrow().status(id))
+}
+
+/// Queues transfers of the given amounts to the given recipients on `ledger`, and returns the ID
+/// of the airdrop, which `airdrop_progress` reports on. The transfers go out in batches in the
+/// background, and survive upgrades.
+// The fee is fetched once here, rather than before every transfer, which would double the calls.
+// The transfers are deduplicated like the outbox's, so an unknown outcome is simply retried.
+#[ic_cdk::update(guard = "controllers_only")]
+pub async fn airdrop(ledger: Principal, recipients: Vec<(Account, NumTokens)>) -> Result<u64, String> {
+    ensure_not_paused()?;
+    if recipients.is_empty() {
+        return Err("An airdrop needs at least one recipient".to_string());
+    }
+    let fee = Icrc1Ledger {
+        canister_id: ledger,
+        transport: default_transport(),
+    }
+    .fee()
+    .await?;
+    let id = AIRDROPS.with(|a| a.borrow_mut().enqueue(ledger, fee, recipients));
+    airdrop::run();
+    Ok(id)
+}
+
+/// Returns how many transfers of airdrop `id` are done, how many it has in total, and how many
+/// failed. Transfers that are neither done nor failed are still pending.
+#[ic_cdk::query]
+pub fn airdrop_progress(id: u64) -> Option<(u32, u32, u32)> {
+    AIRDROPS.with(|a| a.borrow().progress(id))
+}
+
+/// Like `icrc1_transfer`, but takes the amount in whole tokens, as a decimal string such as
+/// `"1.5"`, and returns the index of the block containing the transfer.
+// Frontends otherwise have to know each token's decimals and scale amounts themselves, which is
+// an easy place to be off by a factor of ten. Like `icrc1_transfer`, only controllers may call it.
+#[ic_cdk::update(guard = "controllers_only")]
+pub async fn icrc1_transfer_human(ledger: Principal, to: Account, amount: String) -> Result<Nat, String> {
+    check_rate_limit()?;
+    ensure_not_paused()?;
+    transfer_human(ledger, to, amount).await
+}
+
+/// Like `icrc1_transfer`, but returns the index of the block containing the transfer, or the
+/// ledger's error as is, so that frontends can recover from errors themselves (e.g., look for
+/// the transfer on the ledger after a `TooOld`).
+// Failures that didn't come from the ledger, such as a rejected call, are reported as a
+// `GenericError` with code `NOT_FROM_LEDGER`. Like `icrc1_transfer`, only controllers may call it.
+#[ic_cdk::update(guard = "controllers_only")]
+pub async fn icrc1_transfer_detailed(
+    ledger: Principal,
+    to: Account,
+    amount: NumTokens,
+) -> Result<Nat, Icrc1TransferError> {
+    check_rate_limit().map_err(|e| TransferFailure::Other(e).into_transfer_error())?;
+    ensure_not_paused().map_err(|e| TransferFailure::Other(e.into()).into_transfer_error())?;
+    let transport = default_transport();
+    log_call(
+        &transport,
+        "icrc1_transfer_detailed",
+        icrc1_transfer_typed_via(&transport, ledger, to, amount, None, None, &retry::current()),
+    )
+    .await
+    .map_err(TransferFailure::into_transfer_error)
+}
+
+async fn transfer_human(ledger: Principal, to: Account, amount: String) -> Result<Nat, String> {
+    let transport = default_transport();
+    let decimals = Icrc1Ledger {
+        canister_id: ledger,
+        transport: default_transport(),
+    }
+    .decimals()
+    .await?;
+    let amount = parse_human_amount(&amount, decimals)?;
+    icrc1_transfer_via(&transport, ledger, to, amount, None, None, &retry::current()).await
+}
+
+async fn icrc1_transfer_via<T: Transport>(
+    transport: &T,
+    ledger: Principal,
+    to: Account,
+    amount: NumTokens,
+    fee_override: Option<NumTokens>,
+    memo: Option<Icrc1Memo>,
+    policy: &RetryPolicy,
+) -> Result<Nat, String> {
+    icrc1_transfer_typed_via(transport, ledger, to, amount, fee_override, memo, policy)
+        .await
+        .map_err(String::from)
+}
+
+/// A transfer's result, together with what it took to get there.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct CallOutcome {
+    /// How many times we sent the transfer to the ledger.
+    pub attempts: u32,
+    /// How long the transfer took, in nanoseconds, including obtaining the fee.
+    pub elapsed_ns: u64,
+    /// The cycles that the calls got back. Ledger transfers don't attach any, so a non-zero
+    /// value would point at a bug in our accounting.
+    pub refunded_cycles: u128,
+    /// The index of the block containing the transfer.
+    pub result: Result<Nat, AppError>,
+}
+
+/// Like `icrc1_transfer`, but returns a `CallOutcome` describing how the transfer went, even if
+/// it failed. Errors are only returned for calls that we rejected before doing anything.
+// Meant for diagnosing flaky transfers: an error alone doesn't tell whether the ledger was slow,
+// or whether we retried and the ledger failed every time. Like `icrc1_transfer`, only controllers
+// may call it.
+#[ic_cdk::update(guard = "controllers_only")]
+pub async fn icrc1_transfer_verbose(
+    ledger: Principal,
+    to: Account,
+    amount: NumTokens,
+    fee: Option<NumTokens>,
+) -> Result<CallOutcome, String> {
+    check_rate_limit()?;
+    ensure_not_paused()?;
+    let policy = retry::current();
+    Ok(icrc1_transfer_outcome_via(&default_transport(), ledger, to, amount, fee, None, &policy).await)
+}
+
+async fn icrc1_transfer_outcome_via<T: Transport>(
+    transport: &T,
+    ledger: Principal,
+    to: Account,
+    amount: NumTokens,
+    fee_override: Option<NumTokens>,
+    memo: Option<Icrc1Memo>,
+    policy: &RetryPolicy,
+) -> CallOutcome {
+    let started_at = transport.time();
+    let refunded_before = transport.cycles_refunded();
+    let mut attempts = 0;
+    let options = Icrc1TransferOptions {
+        fee: fee_override,
+        memo,
+        ..Icrc1TransferOptions::default()
     };
+    let result = log_call(
+        transport,
+        "icrc1_transfer_verbose",
+        icrc1_ledger(transport, ledger).transfer_with(to, amount, options, policy, &mut attempts),
+    )
+    .await;
+    CallOutcome {
+        attempts,
+        elapsed_ns: transport.time() - started_at,
+        refunded_cycles: transport.cycles_refunded() - refunded_before,
+        result: result.map_err(AppError::from),
+    }
+}
 
-    loop {
-        match Call::bounded_wait(ledger, "icrc1_transfer")
-            .with_arg(&arg)
-            .call::<Result<BlockIndex, TransferError>>()
-            .await {
-            Ok(Ok(_)) => Ok(()),
-            // The ledger canister returned an error. This could be because the transaction didn't
-            // happen, for example because our balance was too low, but it could also happen in the
-            // case where we were retrying for too long and the `created_at_time` was too old.
-            // In the later case, the transaction may or may not have happened. See the TransferError
-            // documentation to do more fine-grained  and sophisticated error handling here. For
-            // example, you can query the ledger to find out whether the transaction occurred.
-            Ok(Err(e)) => Err(format!("Ledger returned an error: {:?}", e)),
-            // Since the call is idempotent, we can safely retry if the system returns an error with
-            // the ledger canister state being unknown. For production, you likely need to limit the
-            // number of retries in some way, at the very least to make sure that you don't prevent
-            // your canister from stopping because it's constantly retrying this call.
-            Err(CallError::StateUnknown(StateUnknown::SysUnknown(_))) => continue,
-            Err(CallError::CallRejected(rejection)) => {
-                // Non-synchronous transient errors can be sensibly retried
-                if rejection.is_sync() && rejection.reject_code() == RejectCode::SysTransient {
-                    continue
-                } else {
-                    // Again, we could try to query the ledger, but it's unlikely that it would
-                    // work.
-                    return Err(format!("Irrecoverable error: {:?}", rejection));
-                }
-            }
-            // This should not happen if the ledger correctly implements the ICRC-1 standard.
-            // We could try to query the ledger to determine the state of the transaction, but
-            // if the ledger is incorrect, it is unlikely to work anyway
-            Err(CallError::StateUnknown(StateUnknown::CandidDecodeFailed(msg))) => {
-                return Err(format!("Unable to decode the ledger response: {}", msg))
-            }
-            // This should not happen if the ledger is correct. Same as for Candid decoding, we could
-            // try to query the ledger, but if the ledger is incorrect, it is unlikely to work, so
-            // we just report an error to the user
-            Err(CallError::StateUnknown(StateUnknown::CanisterError(err))) => {
-                return Err(format!("Ledger crashed: {:?}", err))
-            }
-        }
+async fn icrc1_transfer_typed_via<T: Transport>(
+    transport: &T,
+    ledger: Principal,
+    to: Account,
+    amount: NumTokens,
+    fee_override: Option<NumTokens>,
+    memo: Option<Icrc1Memo>,
+    policy: &RetryPolicy,
+) -> Result<Nat, TransferFailure> {
+    let mut attempts = 0;
+    let options = Icrc1TransferOptions {
+        fee: fee_override,
+        memo,
+        ..Icrc1TransferOptions::default()
+    };
+    icrc1_ledger(transport, ledger)
+        .transfer_with(to, amount, options, policy, &mut attempts)
+        .await
+}
+
+/// Returns the balance of `account` on `ledger`.
+#[ic_cdk::update(guard = "reads_allowed")]
+pub async fn icrc1_balance_of(ledger: Principal, account: Account) -> Result<NumTokens, String> {
+    Icrc1Ledger {
+        canister_id: ledger,
+        transport: default_transport(),
+    }
+    .balance(account)
+    .await
+}
+
+/// Transfers all the tokens that `from` approved us to spend on `ledger` (ICRC-2) to `to`, minus
+/// the fee, and returns the index of the block containing the transfer.
+// Only controllers may sweep: whoever calls this decides where the approved funds go.
+#[ic_cdk::update(guard = "controllers_only")]
+pub async fn sweep_approved(ledger: Principal, from: Account, to: Account) -> Result<Nat, String> {
+    ensure_not_paused()?;
+    Icrc1Ledger {
+        canister_id: ledger,
+        transport: default_transport(),
     }
+    .sweep_approved(from, to)
+    .await
+}
+
+/// Returns the (name, URL) pairs of the standards that `ledger` supports, so that callers can
+/// check for, e.g., ICRC-2 before relying on approvals.
+#[ic_cdk::update(guard = "reads_allowed")]
+pub async fn icrc1_supported_standards(ledger: Principal) -> Result<Vec<(String, String)>, String> {
+    let standards = Icrc1Ledger {
+        canister_id: ledger,
+        transport: default_transport(),
+    }
+    .supported_standards()
+    .await?;
+    Ok(standards.into_iter().map(|s| (s.name, s.url)).collect())
+}
+
+/// Returns how long, in seconds, `ledger` protects transfers against duplicates via their
+/// `created_at_time`. Retrying a transfer with the same arguments is safe for that long.
+#[ic_cdk::update(guard = "reads_allowed")]
+pub async fn dedup_window_secs(ledger: Principal) -> Result<u64, String> {
+    Icrc1Ledger {
+        canister_id: ledger,
+        transport: default_transport(),
+    }
+    .dedup_window_secs()
+    .await
+}
+
+/// Returns the `total` blocks starting at index `start` of an ICRC-3 ledger, in order, including
+/// the ones the ledger has moved to its archives. Returns at most `MAX_BLOCKS_PER_CALL` (1,000)
+/// blocks; ask again from the index after the last one for more.
+#[ic_cdk::update(guard = "reads_allowed")]
+pub async fn icrc3_get_all_blocks(ledger: Principal, start: u64, total: u64) -> Result<Vec<BlockWithId>, String> {
+    icrc3::get_all_blocks(&default_transport(), ledger, start, total).await
+}
+
+/// Transfers `amount` of `user`'s funds, which we hold in a subaccount derived from `user`, to
+/// `to` on `ledger`, and returns the index of the block containing the transfer. Only `user` and
+/// the controllers may do so. Users deposit to the account that `user_deposit_account` returns.
+#[ic_cdk::update(guard = "reject_anonymous")]
+pub async fn icrc1_transfer_for_user(
+    ledger: Principal,
+    user: Principal,
+    to: Account,
+    amount: NumTokens,
+) -> Result<Nat, String> {
+    let caller = msg_caller();
+    omnibus::check_acting_for(caller, user, ic_cdk::api::is_controller(&caller))?;
+    check_rate_limit()?;
+    ensure_not_paused()?;
+    let ledger = Icrc1Ledger {
+        canister_id: ledger,
+        transport: default_transport(),
+    };
+    omnibus::transfer_for_user(&ledger, user, to, amount).await
+}
+
+/// Returns the account of ours that holds `user`'s funds, on any ledger.
+#[ic_cdk::query]
+pub fn user_deposit_account(user: Principal) -> Account {
+    omnibus::user_account(ic_cdk::api::canister_self(), user)
+}
+
+/// Returns whether block `block_index` of `ledger` holds a transfer of `amount` from our default
+/// account to `to`, with `memo`. After a transfer whose outcome we didn't learn, this tells for
+/// sure whether the block that the ledger points to, e.g., in a `Duplicate` error, is ours.
+#[ic_cdk::update(guard = "reject_anonymous")]
+pub async fn confirm_transfer(
+    ledger: Principal,
+    block_index: u64,
+    to: Account,
+    amount: NumTokens,
+    memo: Option<Vec<u8>>,
+) -> Result<bool, String> {
+    check_rate_limit()?;
+    let transport = default_transport();
+    let expected = ExpectedTransfer {
+        from: Account {
+            owner: transport.canister_self(),
+            subaccount: None,
+        },
+        to,
+        amount,
+        memo,
+    };
+    icrc3::confirm_transfer(&transport, ledger, block_index, &expected).await
 }
 
 /// Return the exchange rate between the base and quote assets, where the result consists of the
 /// exchange rate as an integer, and the number of decimals in the exchange rate.
-#[ic_cdk::update]
+#[ic_cdk::update(guard = "reads_allowed")]
 pub async fn get_exchange_rate(base: Asset, quote: Asset) -> Result<(u64, u32), String> {
-    const XRC_CANISTER_ID: &str = "uf6dk-hyaaa-aaaaq-qaaaq-cai";
-    let xrc = Principal::from_text(XRC_CANISTER_ID).unwrap();
+    let spend = MethodSpend::start("get_exchange_rate");
+    let rate = fetch_exchange_rate(base, quote, &spend).await?;
+    Ok((rate.rate, rate.metadata.decimals))
+}
+
+/// Like `get_exchange_rate`, but also returns the XRC's metadata about how the rate was
+/// computed, such as the number of sources and their standard deviation. Callers can use it to
+/// decide whether they trust the rate enough.
+#[ic_cdk::update(guard = "reads_allowed")]
+pub async fn get_exchange_rate_full(base: Asset, quote: Asset) -> Result<ExchangeRateSummary, String> {
+    let spend = MethodSpend::start("get_exchange_rate_full");
+    let rate = fetch_exchange_rate(base, quote, &spend).await?;
+    Ok(ExchangeRateSummary::from(&rate))
+}
+
+/// Sets the most cycles that one execution of `method` may attach to its calls in total. The
+/// methods with a limit are listed in `budget::DEFAULT_METHOD_LIMITS`.
+#[ic_cdk::update(guard = "controllers_only")]
+pub fn set_method_cycles_limit(method: String, limit: u128) {
+    budget::set_method_limit(&method, limit);
+}
+
+/// Lets us attach cycles to `callee` again, after it was blocked for keeping more cycles than its
+/// service costs.
+#[ic_cdk::update(guard = "controllers_only")]
+pub fn unblock_callee(callee: Principal) {
+    cycles::unblock(callee);
+}
+
+/// Returns the retry policy that the ICRC-1 endpoints currently use.
+#[ic_cdk::query]
+pub fn retry_config() -> RetryPolicy {
+    retry::current()
+}
+
+/// Makes the ICRC-1 endpoints retry with `policy` from now on. Executions that already started
+/// keep the policy they started with.
+#[ic_cdk::update(guard = "controllers_only")]
+pub fn set_retry_config(policy: RetryPolicy) -> Result<(), String> {
+    retry::set_current(policy)
+}
+
+/// Returns the rate from the cache, or fetches it from the XRC, charging the fee of every attempt
+/// to `spend`.
+async fn fetch_exchange_rate(
+    base: Asset,
+    quote: Asset,
+    spend: &MethodSpend,
+) -> Result<ExchangeRate, String> {
+    ensure_not_paused()?;
+    let key = xrc::rate_key(&base, &quote);
+    xrc::RATE_CACHE
+        .with(|c| c.clone())
+        .get_or_insert_with_async(
+            key,
+            xrc::RATE_TTL,
+            ic_cdk::api::time(),
+            request_exchange_rate(base, quote, spend),
+        )
+        .await
+}
+
+async fn request_exchange_rate(
+    base: Asset,
+    quote: Asset,
+    spend: &MethodSpend,
+) -> Result<ExchangeRate, String> {
+    let xrc = Principal::from_text(xrc::XRC_CANISTER_ID).unwrap();
 
     let args = GetExchangeRateRequest {
         base_asset: base,
@@ -204,25 +719,1327 @@ pub async fn get_exchange_rate(base: Asset, quote: Asset) -> Result<(u64, u32),
         timestamp: None,
     };
 
-    // The XRC charges a fee (in cycles) for its services. The fee is currently 1 billion cycles.
-    const XRC_FEES: u128 = 1_000_000_000;
+    // The XRC charges a fee (in cycles) for its services, which we attach to the request. The
+    // fee is currently 1 billion cycles; see `InitArgs` to configure it.
+    let (args, xrc_fee) = xrc_request(&args);
+
+    // The XRC may ask us to come back later (see `retry_while_pending`). Each attempt costs
+    // the full fee, so we only retry a few times.
+    const MAX_XRC_ATTEMPTS: u32 = 4;
+
+    let transport = &default_transport();
+    xrc::retry_while_pending(transport, MAX_XRC_ATTEMPTS, || {
+        let args = args.clone();
+        async move {
+            // Bail out with a clear error if we can't pay the fee, instead of failing the call.
+            ensure_cycles(canister_cycle_balance(), xrc_fee)?;
+            // With the retries, a single execution could otherwise attach the fee many times over.
+            spend.charge(xrc_fee)?;
+
+            // We will use a bounded wait call here, since the attached amount of cycles isn't very
+            // large. For larger cycle transfers, an unbounded wait call is safer.
+            // We attach the fee; it is deducted from the caller's cycles balance, and whatever the
+            // XRC doesn't charge is refunded. The wrapper records the refund, see `cycles_report`.
+            // For simplicity, we will bail out on any call errors. In a real system, we might
+            // want to retry, as we did when obtaining transfer fees.
+            // The XRC keeps at most its fee; should it keep more, we log it and, if configured,
+            // stop calling it.
+            cycles::call_with_cycles_accounted::<_, GetExchangeRateResult>(
+                transport,
+                xrc,
+                "get_exchange_rate",
+                args,
+                xrc_fee,
+                xrc_fee,
+            )
+            .await
+            .map(|(result, _refunded)| result)
+        }
+    })
+    .await
+}
+
+/// Fetches `url` and returns the body of the response, after applying the transform registered
+/// under `transform` (`http::DEFAULT_TRANSFORM` if none is given).
+#[ic_cdk::update(guard = "reads_allowed")]
+pub async fn get_price_via_http(url: String, transform: Option<String>) -> Result<String, String> {
+    ensure_not_paused()?;
+    let transform = transform.unwrap_or_else(|| http::DEFAULT_TRANSFORM.to_string());
+    http::get_price_via_http(&default_transport(), url, &transform).await
+}
+
+/// Called by the system to transform the responses of our HTTP outcalls; the context holds the
+/// name of the transform to apply.
+#[ic_cdk::query(hidden = true)]
+fn transform(args: TransformArgs) -> HttpRequestResult {
+    let name = String::from_utf8(args.context).unwrap_or_default();
+    // Trapping makes the outcall fail, which is the best we can do with an unknown transform.
+    http::apply_transform(&name, args.response).unwrap_or_else(|e| ic_cdk::trap(&e))
+}
+
+/// Guard that only lets the canister's controllers through.
+fn controllers_only() -> Result<(), String> {
+    if ic_cdk::api::is_controller(&msg_caller()) {
+        Ok(())
+    } else {
+        Err("Only controllers can call this method".to_string())
+    }
+}
+
+/// Proposes an ICP transfer, which is executed once enough distinct controllers approved it, see
+/// `InitArgs::approval_threshold`. The proposer's approval is counted automatically. Returns the proposal ID.
+// This turns the single `OWNER` of `icp_transfer` into an M-of-N scheme, where the N are the
+// canister's controllers.
+#[ic_cdk::update(guard = "controllers_only")]
+pub async fn propose_transfer(to: AccountIdentifier, amount: Tokens) -> Result<u64, String> {
+    // Check before recording the proposal, as with a threshold of 1 it would execute right away.
+    ensure_not_paused()?;
+    let id = PROPOSALS.with(|p| p.borrow_mut().propose(msg_caller(), to, amount));
+    execute_if_approved(id).await;
+    Ok(id)
+}
+
+/// Approves a transfer proposal, executing it if this was the last approval needed.
+#[ic_cdk::update(guard = "controllers_only")]
+pub async fn approve(proposal_id: u64) -> Result<ProposalStatus, String> {
+    // Check before recording the approval, so that the approver can simply retry later.
+    ensure_not_paused()?;
+    PROPOSALS.with(|p| p.borrow_mut().approve(proposal_id, msg_caller()))?;
+    execute_if_approved(proposal_id).await;
+    Ok(PROPOSALS
+        .with(|p| p.borrow().status(proposal_id))
+        .expect("The proposal was approved, so it must exist"))
+}
+
+async fn execute_if_approved(id: u64) {
+    let Some((to, amount)) =
+        PROPOSALS.with(|p| {
+            p.borrow_mut().claim_for_execution(
+                id,
+                proposals::approval_threshold(),
+                ic_cdk::api::is_controller,
+            )
+        })
+    else {
+        return;
+    };
+    let _in_flight = proposals::transfer_started(id);
+    // The transfer records itself in the history.
+    let result = icp_ledger_client().transfer(to, amount).await;
+    PROPOSALS.with(|p| p.borrow_mut().finish(id, result));
+}
+
+/// Waits until the canister `id` reports that it's running, or until the IC time passes
+/// `deadline` (in nanoseconds since the epoch). We must be a controller of `id`.
+// Use this after `start_canister`, before routing traffic to the started canister.
+#[ic_cdk::update(guard = "controllers_only")]
+pub async fn wait_until_running(id: Principal, deadline: u64) -> Result<(), String> {
+    let transport = default_transport();
+    log_call(
+        &transport,
+        "wait_until_running",
+        management::wait_until_running_via(&transport, id, deadline),
+    )
+    .await
+}
+
+/// Installs a wasm module that exceeds the message size limit on the empty canister `target`,
+/// from `chunks` that concatenate to the module with SHA-256 hash `wasm_hash`.
+// To keep the example short, all chunks arrive in this one message, so they are still subject
+// to its size limit. A real deployment would collect the chunks over several calls first.
+#[ic_cdk::update(guard = "controllers_only")]
+pub async fn install_large(target: Principal, chunks: Vec<Vec<u8>>, wasm_hash: Vec<u8>) -> Result<(), String> {
+    management::install_large_via(&default_transport(), target, chunks, wasm_hash).await
+}
+
+/// The cycles a new canister gets: enough for the creation fee, with the rest as its balance.
+const NEW_CANISTER_CYCLES: u128 = 1_000_000_000_000;
+
+/// Creates a canister controlled by us and installs `wasm` on it. If the installation fails, the
+/// outcome still carries the ID of the new canister, so that `retry_install` can try again.
+#[ic_cdk::update(guard = "controllers_only")]
+pub async fn create_and_install(wasm: Vec<u8>, arg: Vec<u8>) -> Result<CreateOutcome, String> {
+    ensure_not_paused()?;
+    ensure_cycles(canister_cycle_balance(), NEW_CANISTER_CYCLES)?;
+    management::create_and_install_via(&default_transport(), NEW_CANISTER_CYCLES, wasm, arg).await
+}
+
+/// Installs `wasm` on the canister `id` created by an earlier `create_and_install` whose
+/// installation failed.
+#[ic_cdk::update(guard = "controllers_only")]
+pub async fn retry_install(id: Principal, wasm: Vec<u8>, arg: Vec<u8>) -> Result<(), String> {
+    management::retry_install_via(&default_transport(), id, wasm, arg).await
+}
 
-    // We will use a bounded wait call here, since the attached amount of cycles isn't very large.
-    // For larger cycle transfers, an unbounded wait call is safer.
-    match Call::bounded_wait(xrc, "get_exchange_rate")
-        .with_arg(&args)
-        // We attach the fee here; it is deducted from the caller's cycles balance.
-        .with_cycles(XRC_FEES)
-        .call::<GetExchangeRateResult>()
+/// Calls `method` on `target`, which must take no arguments and return a blob, and returns the
+/// blob. Replies over `transport::MAX_UNTRUSTED_REPLY_BYTES` are refused with a dedicated error.
+#[ic_cdk::update(guard = "reject_anonymous")]
+pub async fn fetch_blob(target: Principal, method: String) -> Result<Vec<u8>, String> {
+    large_reply::fetch_blob_via(&default_transport(), target, &method).await
+}
+
+/// Calls `method` on `target` with the Candid-encoded `arg_bytes`, expecting a reply of type
+/// `variant { Ok : T; Err : E }`. Returns the `Ok` value Candid-encoded, or the `Err` value as
+/// text, without us knowing `T` or `E`; see `decode::decode_variant_result`.
+#[ic_cdk::update(guard = "reject_anonymous")]
+pub async fn call_expecting_variant_result(
+    target: Principal,
+    method: String,
+    arg_bytes: Vec<u8>,
+) -> Result<Vec<u8>, String> {
+    let reply = default_transport()
+        .call(OutboundCall::untrusted(target, &method, arg_bytes))
         .await
-    {
-        Ok(Ok(rate)) => Ok((rate.rate, rate.metadata.decimals)),
-        // The XRC canister returned an error. This could be because the assets are unknown,
-        // because the XRC canister cannot make outgoing calls, and other reasons. We don't do
-        // any sophisticated error handling here.
-        Ok(Err(e)) => Err(format!("XRC returned an error: {:?}", e)),
-        // For simplicity, we will bail out on any errors. In a real system, we might want to
-        // retry, as we did when obtaining transfer fees.
-        Err(e) => Err(format!("Error calling XRC: {:?}", e)),
-    }
-}
\ No newline at end of file
+        .map_err(|e| format!("Error calling {}: {:?}", method, e))?;
+    decode::decode_variant_result(&reply)
+}
+
+/// Returns the controllers of this canister, so that users can check who can change its code.
+/// This only works if the canister is one of its own controllers.
+#[ic_cdk::update(guard = "reject_anonymous")]
+pub async fn my_controllers() -> Result<Vec<Principal>, String> {
+    management::my_controllers_via(&default_transport()).await
+}
+
+/// Returns the status of each of the given canisters, in order. We must be a controller of a
+/// canister to see its status; for the others, the corresponding entry is an error.
+#[ic_cdk::update(guard = "controllers_only")]
+pub async fn statuses(ids: Vec<Principal>) -> Vec<Result<CanisterStatusSummary, String>> {
+    management::canister_statuses_via(&default_transport(), ids).await
+}
+
+/// Times a `get` call on `counter` with a bounded and with an unbounded wait, and returns both
+/// durations in nanoseconds.
+#[ic_cdk::update(guard = "reject_anonymous")]
+pub async fn compare_wait_modes(counter: Principal) -> Result<(u64, u64), String> {
+    timing::compare_wait_modes_via(&default_transport(), counter).await
+}
+
+/// Returns a one-line summary of NNS proposal `id`: its title, status, and voting deadline.
+#[ic_cdk::update(guard = "reject_anonymous")]
+pub async fn get_proposal(id: u64) -> Result<String, String> {
+    check_rate_limit()?;
+    governance::get_proposal_via(&default_transport(), id).await
+}
+
+/// Returns the IDs of the neurons of the SNS whose governance canister is `governance` on which
+/// `of` has any permission.
+#[ic_cdk::update(guard = "reject_anonymous")]
+pub async fn sns_list_neurons(governance: Principal, of: Principal) -> Result<Vec<sns::NeuronId>, String> {
+    check_rate_limit()?;
+    sns::list_neurons_via(&default_transport(), &retry::current(), governance, of).await
+}
+
+/// Asks the relay canister `via` to call `get` on `counter` for us, and returns the counter's
+/// value. `via` must have a `relay` method like the one in `tests/mocks/mock_relay`.
+#[ic_cdk::update(guard = "reject_anonymous")]
+pub async fn proxy_get(via: Principal, counter: Principal) -> Result<Nat, String> {
+    check_rate_limit()?;
+    proxy::proxy_get_via(&default_transport(), via, counter).await
+}
+
+/// Calls `method` on `target` with `args`, a Candid-encoded argument list such as the output of
+/// `didc encode`, and returns its reply, which must be a `nat`. Controllers only: the call goes
+/// out under our principal.
+#[ic_cdk::update(guard = "controllers_only")]
+pub async fn call_nat_method(target: Principal, method: String, args: Vec<u8>) -> Result<Nat, String> {
+    Ok(transport::call_precoded(&default_transport(), target, &method, args).await?)
+}
+
+/// Returns the calls made by the endpoints that have been outstanding for longer than
+/// `watchdog::STUCK_THRESHOLD`, with how long they have been outstanding, in nanoseconds.
+#[ic_cdk::query]
+pub fn stuck_calls() -> Vec<(String, u64)> {
+    watchdog::stuck(ic_cdk::api::time(), watchdog::STUCK_THRESHOLD)
+}
+
+/// Returns the total cycles attached to outbound calls so far, and how many of them were refunded.
+#[ic_cdk::query]
+pub fn cycles_report() -> (u128, u128) {
+    let report = cycles::report();
+    (report.attached, report.refunded)
+}
+
+/// Returns the name of the reject code numbered `raw`, as our error messages give it, e.g.,
+/// `"SysTransient"` for 2.
+#[ic_cdk::query]
+pub fn reject_code_name(raw: i32) -> Result<String, String> {
+    transport::parse_reject_code(raw).map(|code| format!("{:?}", code))
+}
+
+/// Returns the most recent calls made by the endpoints, oldest first.
+#[ic_cdk::query]
+pub fn call_log() -> Vec<LogEntry> {
+    LOG.with(|log| log.borrow().entries())
+}
+
+/// Returns the calls, in order, that the last `icrc1_transfer` made to get the transfer through,
+/// such as asking the ledger for its fee before retrying the transfer.
+#[ic_cdk::query]
+pub fn call_graph() -> Vec<TracedCall> {
+    trace::calls()
+}
+
+/// Returns a page of the transfers executed by this canister, oldest first. Pass the returned
+/// cursor as `after` to get the next page; no cursor means there are no more transfers.
+#[ic_cdk::query]
+pub fn transfer_history(after: Option<u64>, limit: u16) -> (Vec<TransferRecord>, Option<u64>) {
+    HISTORY.with(|h| h.borrow().page(after, limit))
+}
+
+/// Reports the canister's health. It makes no outbound calls, so monitoring can scrape it
+/// frequently and cheaply.
+#[ic_cdk::query]
+pub fn health() -> HealthStatus {
+    PROPOSALS.with(|p| {
+        OUTBOX.with(|o| {
+            AIRDROPS.with(|a| {
+                health_status(
+                    ic_cdk::api::time(),
+                    canister_cycle_balance(),
+                    ic_cdk::stable::stable_size(),
+                    &p.borrow(),
+                    &o.borrow(),
+                    &a.borrow(),
+                    OWNER,
+                )
+            })
+        })
+    })
+}
+
+/// Settings passed when installing or upgrading the canister. Missing settings take their
+/// defaults.
+// Heap state doesn't survive upgrades, so the settings must be passed again on upgrade.
+#[derive(CandidType, Deserialize, Default)]
+pub struct InitArgs {
+    /// The cycles to attach to XRC requests, `xrc::DEFAULT_XRC_FEE` by default.
+    pub xrc_fee: Option<u128>,
+    /// Whether anyone, rather than just the owner, may call the read methods such as
+    /// `get_exchange_rate` and `icrc1_balance_of`. Off by default; see `reads_allowed` before
+    /// turning it on.
+    pub public_reads: Option<bool>,
+    /// The cycle balance below which the methods that spend cycles are paused,
+    /// `pause::DEFAULT_CYCLES_WATERMARK` by default.
+    pub cycles_watermark: Option<u128>,
+    /// Whether to print panics to the canister log before trapping, see `panics::install`. Off
+    /// by default.
+    pub log_panics: Option<bool>,
+    /// Whether to stop attaching cycles to callees that keep more than their service costs,
+    /// see `cycles::refund_anomaly`. Off by default, in which case we only log them.
+    pub block_overcharging_callees: Option<bool>,
+    /// How many distinct controllers must approve a transfer proposal,
+    /// `proposals::DEFAULT_APPROVAL_THRESHOLD` by default. Keep it at most the number of
+    /// controllers, or no proposal executes.
+    pub approval_threshold: Option<u32>,
+}
+
+fn apply_init_args(args: Option<InitArgs>) {
+    let args = args.unwrap_or_default();
+    // Installed first, so that it covers the rest of the initialization. The hook doesn't survive
+    // upgrades either, so `post_upgrade` installs it again.
+    if args.log_panics.unwrap_or(false) {
+        panics::install();
+    }
+    xrc::set_xrc_fee(args.xrc_fee.unwrap_or(xrc::DEFAULT_XRC_FEE));
+    PUBLIC_READS.with(|p| p.set(args.public_reads.unwrap_or(false)));
+    pause::set_watermark(args.cycles_watermark.unwrap_or(pause::DEFAULT_CYCLES_WATERMARK));
+    cycles::set_block_overcharging(args.block_overcharging_callees.unwrap_or(false));
+    proposals::set_approval_threshold(
+        args.approval_threshold
+            .map_or(proposals::DEFAULT_APPROVAL_THRESHOLD, |t| t as usize),
+    );
+}
+
+#[ic_cdk::init]
+fn init(args: Option<InitArgs>) {
+    apply_init_args(args);
+    http::register_default_transforms();
+    mark_started(ic_cdk::api::time());
+    watchdog::start();
+    pause::start();
+    outbox::start();
+    airdrop::start();
+}
+
+/// Lets the next upgrade go through even if transfers are in flight. They may or may not
+/// happen, and proposals among them stay marked as executing until `resolve_stuck_proposal`.
+#[ic_cdk::update(guard = "controllers_only")]
+pub fn force_upgrade() {
+    upgrade::set_force_upgrade(true);
+}
+
+/// Settles proposal `id`, which is stuck executing because `force_upgrade` cut its transfer
+/// short. Look for the transfer on the ledger first, and pass the index of its block if it's
+/// there; without one, the proposal is marked failed. Stuck proposals count as in flight, so
+/// they block upgrades until they're settled.
+#[ic_cdk::update(guard = "controllers_only")]
+pub fn resolve_stuck_proposal(id: u64, block_index: Option<Nat>) -> Result<ProposalStatus, String> {
+    let status = PROPOSALS.with(|p| {
+        p.borrow_mut()
+            .resolve_interrupted(id, proposals::transfer_in_flight(id), block_index)
+    })?;
+    if let ProposalStatus::Executed(block_index) = &status {
+        let (to, amount) = PROPOSALS
+            .with(|p| p.borrow().transfer(id))
+            .expect("The proposal was resolved, so it must exist");
+        HISTORY.with(|h| {
+            h.borrow_mut().record(
+                ic_cdk::api::time(),
+                icp_ledger_client().canister_id,
+                to.to_string(),
+                tokens_to_nat(amount),
+                block_index.clone(),
+            )
+        });
+    }
+    Ok(status)
+}
+
+// Proposals, the transfer history, the outbox, the airdrops, and the retry policy must survive upgrades, so we save them to
+// stable memory before the upgrade and restore them afterwards.
+#[ic_cdk::pre_upgrade]
+fn pre_upgrade() {
+    // Trapping in `pre_upgrade` aborts the upgrade, and the old code keeps running.
+    // Besides proposals, that's ICP transfers, which aren't deduplicated, and ICRC-1 transfers.
+    // Those are, but the caller waiting for one would never learn its outcome. A running outbox
+    // or airdrop batch would lose the outcome of its current transfer, which it then retries.
+    let in_flight = PROPOSALS.with(|p| p.borrow().executing())
+        + upgrade::in_flight()
+        + usize::from(outbox::is_processing())
+        + usize::from(airdrop::is_processing());
+    if let Err(e) = upgrade::check_upgrade(in_flight, upgrade::force_upgrade_requested()) {
+        ic_cdk::trap(&e);
+    }
+    let proposals = PROPOSALS.with(|p| p.borrow().clone());
+    let history = HISTORY.with(|h| h.borrow().clone());
+    let outbox = OUTBOX.with(|o| o.borrow().clone());
+    let airdrops = AIRDROPS.with(|a| a.borrow().clone());
+    let retry_policy = retry::current();
+    ic_cdk::storage::stable_save((proposals, history, Some(outbox), Some(airdrops), Some(retry_policy)))
+        .expect("Failed to save the state");
+}
+
+#[ic_cdk::post_upgrade]
+fn post_upgrade(args: Option<InitArgs>) {
+    apply_init_args(args);
+    // Versions before the approval queue saved nothing, so we start from the default state. Those
+    // before the outbox, the airdrops, and the retry settings didn't save them; Candid decodes the
+    // missing values as `None`.
+    let mut header = [0; 4];
+    let stable_size = ic_cdk::stable::stable_size();
+    if stable_size > 0 {
+        ic_cdk::stable::stable_read(0, &mut header);
+    }
+    let (proposals, history, outbox, airdrops, retry_policy): (
+        Proposals,
+        History,
+        Option<outbox::Outbox>,
+        Option<airdrop::Airdrops>,
+        Option<RetryPolicy>,
+    ) = if upgrade::has_saved_state(stable_size, &header) {
+        ic_cdk::storage::stable_restore().expect("Failed to restore the state")
+    } else {
+        Default::default()
+    };
+    PROPOSALS.with(|p| *p.borrow_mut() = proposals);
+    HISTORY.with(|h| *h.borrow_mut() = history);
+    OUTBOX.with(|o| *o.borrow_mut() = outbox.unwrap_or_default());
+    AIRDROPS.with(|a| *a.borrow_mut() = airdrops.unwrap_or_default());
+    if let Some(policy) = retry_policy {
+        // It was valid when it was set.
+        retry::set_current(policy).expect("Failed to restore the retry policy");
+    }
+    http::register_default_transforms();
+    mark_started(ic_cdk::api::time());
+    // Timers don't survive upgrades either.
+    watchdog::start();
+    pause::start();
+    outbox::start();
+    airdrop::start();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use ic_cdk::call::RejectCode;
+    use icrc_ledger_types::icrc1::transfer::TransferArg;
+    use ledger::{remember_icrc1_fee, NOT_FROM_LEDGER};
+    use transport::mock::{sys_unknown, MockTransport};
+    use transport::CallFailure;
+
+    #[test]
+    fn test_anonymous_caller_is_rejected() {
+        // `icrc1_transfer` and the other mutating methods are guarded by `reject_anonymous`, so
+        // an anonymous ingress message is turned away before any outbound call is made.
+        assert!(check_not_anonymous(Principal::anonymous()).is_err());
+    }
+
+    #[test]
+    fn test_authenticated_caller_is_accepted() {
+        let owner = Principal::from_text(OWNER).unwrap();
+        assert_eq!(check_not_anonymous(owner), Ok(()));
+        assert_eq!(check_not_anonymous(Principal::management_canister()), Ok(()));
+    }
+
+    #[test]
+    fn test_non_owner_read_is_rejected_by_default() {
+        let other = Principal::management_canister();
+        assert!(check_read_access(other, false).is_err());
+        assert!(check_read_access(Principal::anonymous(), false).is_err());
+        let owner = Principal::from_text(OWNER).unwrap();
+        assert_eq!(check_read_access(owner, false), Ok(()));
+    }
+
+    #[test]
+    fn test_public_reads_allow_anyone() {
+        assert_eq!(check_read_access(Principal::management_canister(), true), Ok(()));
+        assert_eq!(check_read_access(Principal::anonymous(), true), Ok(()));
+    }
+
+    fn transfer_attempts(transport: &MockTransport) -> Vec<TransferArg> {
+        transport
+            .calls()
+            .iter()
+            .filter(|c| c.method == "icrc1_transfer")
+            .map(|c| candid::decode_one(&c.args).unwrap())
+            .collect()
+    }
+
+    fn fee_transport(now: u64) -> MockTransport {
+        let transport = MockTransport {
+            now: Cell::new(now),
+            ..MockTransport::new()
+        };
+        transport.reply(&Nat::from(10_u64));
+        transport
+    }
+
+    fn transfer_one_token(transport: &MockTransport) -> Result<Nat, String> {
+        let to = Account {
+            owner: Principal::from_slice(&[2; 29]),
+            subaccount: None,
+        };
+        block_on(icrc1_transfer_via(
+            transport,
+            Principal::anonymous(),
+            to,
+            Nat::from(1_u64),
+            None,
+            None,
+            &RetryPolicy::default(),
+        ))
+    }
+
+    #[test]
+    fn test_transfer_trace_records_the_calls_in_order() {
+        let transport = fee_transport(2_000);
+        transport
+            .fail(sys_unknown())
+            .reply(&Ok::<Nat, Icrc1TransferError>(Nat::from(5_u64)));
+        let ledger = Principal::from_slice(&[1; 10]);
+        let to = Account {
+            owner: Principal::from_slice(&[2; 29]),
+            subaccount: None,
+        };
+        trace::reset();
+
+        let result = block_on(icrc1_transfer_via(
+            &Traced(transport),
+            ledger,
+            to,
+            Nat::from(1_u64),
+            None,
+            None,
+            &RetryPolicy::default(),
+        ));
+
+        assert_eq!(result, Ok(Nat::from(5_u64)));
+        let call = |method: &str| TracedCall {
+            target: ledger,
+            method: method.to_string(),
+        };
+        assert_eq!(
+            trace::calls(),
+            vec![call("icrc1_fee"), call("icrc1_transfer"), call("icrc1_transfer")]
+        );
+    }
+
+    #[test]
+    fn test_transfer_makes_no_self_call() {
+        let transport = fee_transport(2_000);
+        transport.reply(&Ok::<Nat, Icrc1TransferError>(Nat::from(5_u64)));
+
+        assert_eq!(transfer_one_token(&transport), Ok(Nat::from(5_u64)));
+
+        let calls = transport.calls();
+        assert!(calls.iter().all(|c| c.target != transport.canister_self()));
+        let methods: Vec<_> = calls.iter().map(|c| c.method.as_str()).collect();
+        assert_eq!(methods, vec!["icrc1_fee", "icrc1_transfer"]);
+    }
+
+    fn transfer_with_fee(transport: &MockTransport, fee: u64) -> Result<Nat, String> {
+        let to = Account {
+            owner: Principal::from_slice(&[2; 29]),
+            subaccount: None,
+        };
+        block_on(icrc1_transfer_via(
+            transport,
+            Principal::anonymous(),
+            to,
+            Nat::from(1_u64),
+            Some(Nat::from(fee)),
+            None,
+            &RetryPolicy::default(),
+        ))
+    }
+
+    #[test]
+    fn test_fee_override_is_used_without_asking_the_ledger() {
+        let transport = MockTransport::new();
+        transport.reply(&Ok::<Nat, Icrc1TransferError>(Nat::from(5_u64)));
+
+        assert_eq!(transfer_with_fee(&transport, 10), Ok(Nat::from(5_u64)));
+
+        let attempts = transfer_attempts(&transport);
+        assert_eq!(transport.calls().len(), 1);
+        assert_eq!(attempts[0].fee, Some(Nat::from(10_u64)));
+    }
+
+    #[test]
+    fn test_too_low_fee_override_is_rejected() {
+        remember_icrc1_fee(Principal::anonymous(), &Nat::from(10_u64));
+        let transport = MockTransport::new();
+
+        let result = transfer_with_fee(&transport, 5);
+
+        assert!(result.unwrap_err().contains("below the ledger's fee of 10"));
+        assert!(transport.calls().is_empty());
+    }
+
+    #[test]
+    fn test_transfer_created_in_future_is_retimed_once() {
+        let transport = fee_transport(2_000);
+        transport
+            .reply(&Err::<Nat, _>(Icrc1TransferError::CreatedInFuture { ledger_time: 1_000 }))
+            .reply(&Ok::<Nat, Icrc1TransferError>(Nat::from(5_u64)));
+
+        let result = transfer_one_token(&transport);
+
+        assert_eq!(result, Ok(Nat::from(5_u64)));
+        let attempts = transfer_attempts(&transport);
+        assert_eq!(attempts.len(), 2);
+        assert_eq!(attempts[0].created_at_time, Some(2_000));
+        assert_eq!(attempts[1].created_at_time, Some(1_000));
+    }
+
+    #[test]
+    fn test_transfer_too_old_is_retimed_once() {
+        let transport = fee_transport(2_000);
+        transport
+            .reply(&Err::<Nat, _>(Icrc1TransferError::TooOld))
+            .reply(&Err::<Nat, _>(Icrc1TransferError::TooOld));
+
+        let result = transfer_one_token(&transport);
+
+        // The corrected attempt failed as well, and we didn't try a third time.
+        assert!(result.unwrap_err().contains("TooOld"));
+        assert_eq!(transfer_attempts(&transport).len(), 2);
+    }
+
+    #[test]
+    fn test_transfer_too_old_after_unknown_outcome_is_not_retimed() {
+        let transport = fee_transport(2_000);
+        transport
+            .fail(sys_unknown())
+            .reply(&Err::<Nat, _>(Icrc1TransferError::TooOld));
+
+        let result = transfer_one_token(&transport);
+
+        // The first attempt may have executed, so a fresh timestamp could transfer twice.
+        assert!(result.unwrap_err().contains("TooOld"));
+        assert_eq!(transfer_attempts(&transport).len(), 2);
+    }
+
+    #[test]
+    fn test_duplicate_after_unknown_outcome_is_the_transfer() {
+        let transport = fee_transport(2_000);
+        transport
+            .fail(sys_unknown())
+            .reply(&Err::<Nat, _>(Icrc1TransferError::Duplicate {
+                duplicate_of: Nat::from(7_u64),
+            }));
+
+        let result = transfer_one_token(&transport);
+
+        // The first attempt went through, in block 7.
+        assert_eq!(result, Ok(Nat::from(7_u64)));
+        assert_eq!(transfer_attempts(&transport).len(), 2);
+        let (records, _) = transfer_history(None, 100);
+        assert_eq!(records.last().unwrap().block_index, Nat::from(7_u64));
+    }
+
+    // Runs the transfer the way `icrc1_transfer_detailed` does, and returns its result as a
+    // frontend would decode it.
+    fn transfer_detailed(transport: &MockTransport) -> Result<Nat, Icrc1TransferError> {
+        let to = Account {
+            owner: Principal::from_slice(&[2; 29]),
+            subaccount: None,
+        };
+        let result = block_on(icrc1_transfer_typed_via(
+            transport,
+            Principal::anonymous(),
+            to,
+            Nat::from(1_u64),
+            None,
+            None,
+            &RetryPolicy::default(),
+        ))
+        .map_err(TransferFailure::into_transfer_error);
+        let bytes = candid::encode_one(result).unwrap();
+        candid::decode_one(&bytes).unwrap()
+    }
+
+    #[test]
+    fn test_undecodable_transfer_reply_is_outcome_unknown() {
+        let transport = fee_transport(2_000);
+        transport.reply(&"not a ledger");
+
+        let error = transfer_one_token(&transport).unwrap_err();
+
+        assert!(error.contains("check before retrying"), "{}", error);
+        assert!(error.contains("not a ledger"), "{}", error);
+    }
+
+    #[test]
+    fn test_detailed_transfer_success() {
+        let transport = fee_transport(2_000);
+        transport.reply(&Ok::<Nat, Icrc1TransferError>(Nat::from(5_u64)));
+
+        assert_eq!(transfer_detailed(&transport), Ok(Nat::from(5_u64)));
+    }
+
+    #[test]
+    fn test_detailed_transfer_keeps_ledger_error() {
+        let transport = fee_transport(2_000);
+        transport
+            .reply(&Err::<Nat, _>(Icrc1TransferError::TooOld))
+            .reply(&Err::<Nat, _>(Icrc1TransferError::TooOld));
+
+        assert_eq!(transfer_detailed(&transport), Err(Icrc1TransferError::TooOld));
+    }
+
+    #[test]
+    fn test_detailed_transfer_reports_call_failure_as_generic_error() {
+        let transport = fee_transport(2_000);
+        transport.fail(CallFailure::CanisterError("trapped".to_string()));
+
+        match transfer_detailed(&transport) {
+            Err(Icrc1TransferError::GenericError { error_code, message }) => {
+                assert_eq!(error_code, Nat::from(NOT_FROM_LEDGER));
+                assert!(message.contains("trapped"), "{}", message);
+            }
+            result => panic!("Unexpected result: {:?}", result),
+        }
+    }
+
+    fn precheck(transport: &MockTransport, amount: u64) -> Result<NumTokens, String> {
+        let to = Account {
+            owner: Principal::from_slice(&[2; 29]),
+            subaccount: None,
+        };
+        block_on(precheck_transfer_via(transport, Principal::anonymous(), to, &Nat::from(amount)))
+    }
+
+    #[test]
+    fn test_precheck_one_short_is_an_error() {
+        let transport = fee_transport(2_000);
+        // The fee is 10.
+        transport.reply(&Nat::from(109_u64));
+
+        let error = precheck(&transport, 100).unwrap_err();
+
+        assert!(error.contains("requires 110"), "{}", error);
+        assert!(error.contains("1 short"), "{}", error);
+        let calls = transport.calls();
+        assert_eq!(calls[1].method, "icrc1_balance_of");
+        let account: Account = candid::decode_one(&calls[1].args).unwrap();
+        assert_eq!(account.owner, transport.canister_self());
+    }
+
+    #[test]
+    fn test_precheck_exact_balance_passes() {
+        let transport = fee_transport(2_000);
+        transport.reply(&Nat::from(110_u64));
+
+        assert_eq!(precheck(&transport, 100), Ok(Nat::from(10_u64)));
+    }
+
+    #[test]
+    fn test_precheck_rejects_transfer_to_self() {
+        let transport = fee_transport(2_000);
+        let to = Account {
+            owner: transport.canister_self(),
+            subaccount: None,
+        };
+
+        let result = block_on(precheck_transfer_via(&transport, Principal::anonymous(), to, &Nat::from(1_u64)));
+
+        assert!(result.unwrap_err().contains("our own account"));
+        assert!(transport.calls().is_empty());
+    }
+
+    fn sweep(transport: &MockTransport) -> Result<Nat, String> {
+        let to = Account {
+            owner: Principal::from_slice(&[2; 29]),
+            subaccount: None,
+        };
+        block_on(icrc1_sweep_via(transport, Principal::anonymous(), to))
+    }
+
+    #[test]
+    fn test_sweep_transfers_balance_minus_fee() {
+        let transport = fee_transport(2_000);
+        transport
+            .reply(&Nat::from(110_u64))
+            .reply(&Ok::<Nat, Icrc1TransferError>(Nat::from(5_u64)));
+
+        assert_eq!(sweep(&transport), Ok(Nat::from(5_u64)));
+
+        let transfers = transfer_attempts(&transport);
+        assert_eq!(transfers.len(), 1);
+        assert_eq!(transfers[0].amount, Nat::from(100_u64));
+        assert_eq!(transfers[0].fee, Some(Nat::from(10_u64)));
+    }
+
+    #[test]
+    fn test_sweep_of_balance_equal_to_fee_is_an_error() {
+        let transport = fee_transport(2_000);
+        // The fee is 10.
+        transport.reply(&Nat::from(10_u64));
+
+        let error = sweep(&transport).unwrap_err();
+
+        assert!(error.contains("Nothing to sweep"), "{}", error);
+        assert!(transfer_attempts(&transport).is_empty());
+    }
+
+    #[test]
+    fn test_verbose_transfer_counts_attempts() {
+        let transport = fee_transport(2_000);
+        transport.call_latency.set(100);
+        transport
+            .fail(sys_unknown())
+            .fail(sys_unknown())
+            .reply(&Ok::<Nat, Icrc1TransferError>(Nat::from(5_u64)));
+        let to = Account {
+            owner: Principal::from_slice(&[2; 29]),
+            subaccount: None,
+        };
+
+        let outcome = block_on(icrc1_transfer_outcome_via(
+            &transport,
+            Principal::anonymous(),
+            to,
+            Nat::from(1_u64),
+            None,
+            None,
+            &RetryPolicy::default(),
+        ));
+
+        assert_eq!(
+            outcome,
+            CallOutcome {
+                attempts: 3,
+                // One call for the fee, three for the transfer, and the default backoff of 100
+                // and then 200 milliseconds between them.
+                elapsed_ns: 400 + 300_000_000,
+                refunded_cycles: 0,
+                result: Ok(Nat::from(5_u64)),
+            }
+        );
+    }
+
+    #[test]
+    fn test_verbose_transfer_reports_exhausted_attempts() {
+        let transport = fee_transport(2_000);
+        transport
+            .fail(sys_unknown())
+            .fail(sys_unknown());
+        let to = Account {
+            owner: Principal::from_slice(&[2; 29]),
+            subaccount: None,
+        };
+
+        let outcome = block_on(icrc1_transfer_outcome_via(
+            &transport,
+            Principal::anonymous(),
+            to,
+            Nat::from(1_u64),
+            None,
+            None,
+            &attempts(2),
+        ));
+
+        assert_eq!(outcome.attempts, 2);
+        // The error isn't flattened into a string on the way.
+        assert_eq!(
+            outcome.result,
+            Err(AppError::OutcomeUnknown {
+                attempts: 2,
+                reason: "Timeout expired".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_verbose_transfer_reports_refunds() {
+        let transport = fee_transport(2_000);
+        transport.refund_per_call.set(7);
+        transport.reply(&Ok::<Nat, Icrc1TransferError>(Nat::from(5_u64)));
+        let to = Account {
+            owner: Principal::from_slice(&[2; 29]),
+            subaccount: None,
+        };
+
+        let outcome = block_on(icrc1_transfer_outcome_via(
+            &transport,
+            Principal::anonymous(),
+            to,
+            Nat::from(1_u64),
+            None,
+            None,
+            &RetryPolicy::default(),
+        ));
+
+        // The fee call and the transfer.
+        assert_eq!(outcome.refunded_cycles, 14);
+    }
+
+    #[test]
+    fn test_missing_ledger_is_an_invalid_target() {
+        let ledger = Principal::from_slice(&[9; 10]);
+        let destination_invalid = || CallFailure::Rejected {
+            code: RejectCode::DestinationInvalid,
+            message: "Canister not found".to_string(),
+            sync: true,
+        };
+        let expected: String = AppError::InvalidTarget { target: ledger }.into();
+
+        let transport = MockTransport::new();
+        transport.fail(destination_invalid());
+        let result = block_on(icrc1_ledger(&transport, ledger).fee_with_retries(&attempts(3)));
+        assert_eq!(result, Err(expected.clone()));
+        // Not retried.
+        assert_eq!(transport.calls().len(), 1);
+
+        let transport = MockTransport::new();
+        transport.fail(destination_invalid());
+        let to = Account {
+            owner: Principal::anonymous(),
+            subaccount: None,
+        };
+        let result = block_on(icrc1_transfer_via(
+            &transport,
+            ledger,
+            to,
+            NumTokens::from(1_u64),
+            Some(NumTokens::from(10_u64)),
+            None,
+            &attempts(3),
+        ));
+        assert_eq!(result, Err(expected));
+        assert_eq!(transport.calls().len(), 1);
+    }
+
+    /// The default policy, but with at most `max_attempts` attempts.
+    fn attempts(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            ..RetryPolicy::default()
+        }
+    }
+
+    #[test]
+    fn test_get_fee_gives_up_after_max_attempts() {
+        let transport = MockTransport::new();
+        for _ in 0..3 {
+            transport.fail(sys_unknown());
+        }
+
+        let ledger = icrc1_ledger(&transport, Principal::anonymous());
+        let result = block_on(ledger.fee_with_retries(&attempts(3)));
+
+        assert_eq!(result, Err(AppError::RetriesExhausted { attempts: 3 }.into()));
+        assert_eq!(transport.calls().len(), 3);
+    }
+
+    #[test]
+    fn test_transfer_gives_up_after_max_attempts() {
+        let transport = fee_transport(2_000);
+        transport
+            .fail(sys_unknown())
+            .fail(sys_unknown());
+        let to = Account {
+            owner: Principal::from_slice(&[2; 29]),
+            subaccount: None,
+        };
+
+        let result = block_on(icrc1_transfer_via(
+            &transport,
+            Principal::anonymous(),
+            to,
+            Nat::from(1_u64),
+            None,
+            None,
+            &attempts(2),
+        ));
+
+        let expected = AppError::OutcomeUnknown {
+            attempts: 2,
+            reason: "Timeout expired".to_string(),
+        };
+        assert_eq!(result, Err(expected.into()));
+        assert_eq!(transfer_attempts(&transport).len(), 2);
+    }
+
+    #[test]
+    fn test_transfer_reports_what_the_system_knows_about_an_unknown_outcome() {
+        let transport = fee_transport(2_000);
+        let reason = "SysUnknown { reject_message: \"Request timed out\" }";
+        transport.fail(CallFailure::SysUnknown(reason.to_string()));
+        let to = Account {
+            owner: Principal::from_slice(&[2; 29]),
+            subaccount: None,
+        };
+
+        let result = block_on(icrc1_transfer_via(
+            &transport,
+            Principal::anonymous(),
+            to,
+            Nat::from(1_u64),
+            None,
+            None,
+            &attempts(1),
+        ));
+
+        let expected = AppError::OutcomeUnknown {
+            attempts: 1,
+            reason: reason.to_string(),
+        };
+        assert_eq!(result, Err(expected.into()));
+    }
+
+    fn transient(sync: bool) -> CallFailure {
+        CallFailure::Rejected {
+            code: RejectCode::SysTransient,
+            message: "Queue full".to_string(),
+            sync,
+        }
+    }
+
+    #[test]
+    fn test_get_fee_retries_asynchronous_transient_rejects() {
+        let transport = MockTransport::new();
+        transport.fail(transient(false)).reply(&Nat::from(10_u64));
+
+        let ledger = icrc1_ledger(&transport, Principal::anonymous());
+        let result = block_on(ledger.fee_with_retries(&attempts(3)));
+
+        assert_eq!(result, Ok(Nat::from(10_u64)));
+        assert_eq!(transport.calls().len(), 2);
+    }
+
+    #[test]
+    fn test_get_fee_doesnt_retry_synchronous_transient_rejects() {
+        let transport = MockTransport::new();
+        transport.fail(transient(true)).reply(&Nat::from(10_u64));
+
+        let ledger = icrc1_ledger(&transport, Principal::anonymous());
+        let error = block_on(ledger.fee_with_retries(&attempts(3))).unwrap_err();
+
+        assert!(error.contains("Irrecoverable error"), "{}", error);
+        assert_eq!(transport.calls().len(), 1);
+    }
+
+    #[test]
+    fn test_transfer_retries_asynchronous_transient_rejects() {
+        let transport = fee_transport(2_000);
+        transport
+            .fail(transient(false))
+            .reply(&Ok::<Nat, Icrc1TransferError>(Nat::from(5_u64)));
+
+        let result = transfer_one_token(&transport);
+
+        assert_eq!(result, Ok(Nat::from(5_u64)));
+        assert_eq!(transfer_attempts(&transport).len(), 2);
+    }
+
+    #[test]
+    fn test_transfer_doesnt_retry_synchronous_transient_rejects() {
+        let transport = fee_transport(2_000);
+        transport
+            .fail(transient(true))
+            .reply(&Ok::<Nat, Icrc1TransferError>(Nat::from(5_u64)));
+
+        let error = transfer_one_token(&transport).unwrap_err();
+
+        assert!(error.contains("Error calling canister"), "{}", error);
+        assert!(error.contains("Queue full"), "{}", error);
+        assert_eq!(transfer_attempts(&transport).len(), 1);
+    }
+
+    fn ledger_stopped() -> CallFailure {
+        CallFailure::Rejected {
+            code: RejectCode::CanisterError,
+            message: "IC0508: Canister ryjl3-tyaaa-aaaaa-aaaba-cai is stopped and therefore does \
+                      not have a CallContextManager"
+                .to_string(),
+            sync: false,
+        }
+    }
+
+    #[test]
+    fn test_transfer_waits_out_a_ledger_upgrade() {
+        let transport = fee_transport(2_000);
+        transport
+            .fail(ledger_stopped())
+            .reply(&Ok::<Nat, Icrc1TransferError>(Nat::from(5_u64)));
+
+        let result = transfer_one_token(&transport);
+
+        assert_eq!(result, Ok(Nat::from(5_u64)));
+        let attempts = transfer_attempts(&transport);
+        assert_eq!(attempts.len(), 2);
+        // The retry waited for the backoff, and sent the same transfer.
+        assert!(transport.now.get() >= 2_000 + 100_000_000);
+        assert_eq!(attempts[0], attempts[1]);
+    }
+
+    #[test]
+    fn test_transfer_reports_a_ledger_that_stays_stopped() {
+        let transport = fee_transport(2_000);
+        transport.fail(ledger_stopped()).fail(ledger_stopped());
+        let to = Account {
+            owner: Principal::from_slice(&[2; 29]),
+            subaccount: None,
+        };
+
+        let result = block_on(icrc1_transfer_via(
+            &transport,
+            Principal::anonymous(),
+            to,
+            Nat::from(1_u64),
+            None,
+            None,
+            &attempts(2),
+        ));
+
+        let expected = AppError::CalleeStopped {
+            target: Principal::anonymous(),
+            attempts: 2,
+        };
+        assert_eq!(result, Err(expected.into()));
+    }
+
+    #[test]
+    fn test_get_fee_gives_up_at_the_deadline() {
+        let transport = MockTransport::new();
+        for _ in 0..10 {
+            transport.fail(sys_unknown());
+        }
+        // Waits of 1, 2, and 4 seconds fit into the 10 seconds, the next one of 8 doesn't.
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            deadline_secs: 10,
+            base_delay_ms: 1_000,
+            max_delay_ms: 60_000,
+        };
+
+        let ledger = icrc1_ledger(&transport, Principal::anonymous());
+        let result = block_on(ledger.fee_with_retries(&policy));
+
+        assert_eq!(result, Err(AppError::RetriesExhausted { attempts: 4 }.into()));
+        assert_eq!(transport.calls().len(), 4);
+        assert_eq!(transport.time(), 7_000_000_000);
+    }
+
+    #[test]
+    fn test_transfer_gives_up_at_the_deadline() {
+        let transport = fee_transport(2_000);
+        for _ in 0..10 {
+            transport.fail(sys_unknown());
+        }
+        let to = Account {
+            owner: Principal::from_slice(&[2; 29]),
+            subaccount: None,
+        };
+        // The first retry would already start after the deadline.
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            deadline_secs: 1,
+            base_delay_ms: 2_000,
+            max_delay_ms: 2_000,
+        };
+
+        let result = block_on(icrc1_transfer_via(
+            &transport,
+            Principal::anonymous(),
+            to,
+            Nat::from(1_u64),
+            None,
+            None,
+            &policy,
+        ));
+
+        let expected = AppError::OutcomeUnknown {
+            attempts: 1,
+            reason: "Timeout expired".to_string(),
+        };
+        assert_eq!(result, Err(expected.into()));
+        assert_eq!(transfer_attempts(&transport).len(), 1);
+    }
+}
+
+#[cfg(all(test, feature = "simulate"))]
+mod simulate_tests {
+    use super::*;
+    use futures::executor::block_on;
+    use ic_cdk::management_canister::CanisterStatusType;
+    use ic_ledger_types::DEFAULT_SUBACCOUNT;
+    use simulate::SimulatedTransport;
+    use transport::mock::sys_unknown;
+
+    fn ledger() -> Principal {
+        Principal::from_slice(&[1; 10])
+    }
+
+    fn alice() -> Account {
+        Account {
+            owner: Principal::from_slice(&[2; 29]),
+            subaccount: None,
+        }
+    }
+
+    #[test]
+    fn test_icrc1_transfer_end_to_end() {
+        SimulatedTransport::set_balance(SimulatedTransport::own_account(), Nat::from(100_000_u64));
+
+        block_on(icrc1_transfer_via(
+            &SimulatedTransport,
+            ledger(),
+            alice(),
+            Nat::from(50_000_u64),
+            None,
+            None,
+            &RetryPolicy::default(),
+        ))
+        .unwrap();
+
+        assert_eq!(SimulatedTransport::balance(&alice()), Nat::from(50_000_u64));
+        // The amount plus the 10_000 fee were deducted.
+        assert_eq!(
+            SimulatedTransport::balance(&SimulatedTransport::own_account()),
+            Nat::from(40_000_u64)
+        );
+    }
+
+    #[test]
+    fn test_icrc1_transfer_insufficient_funds() {
+        SimulatedTransport::set_balance(SimulatedTransport::own_account(), Nat::from(5_000_u64));
+
+        let result = block_on(icrc1_transfer_via(
+            &SimulatedTransport,
+            ledger(),
+            alice(),
+            Nat::from(1_u64),
+            None,
+            None,
+            &RetryPolicy::default(),
+        ));
+
+        assert!(result.unwrap_err().contains("InsufficientFunds"));
+        assert_eq!(SimulatedTransport::balance(&alice()), Nat::from(0_u64));
+    }
+
+    #[test]
+    fn test_icrc1_transfer_retries_after_sys_unknown() {
+        SimulatedTransport::set_balance(SimulatedTransport::own_account(), Nat::from(100_000_u64));
+        // Lose the response to the first transfer attempt.
+        SimulatedTransport::fail_next("icrc1_transfer", sys_unknown());
+
+        let result = block_on(icrc1_transfer_via(
+            &SimulatedTransport,
+            ledger(),
+            alice(),
+            Nat::from(1_000_u64),
+            None,
+            None,
+            &RetryPolicy::default(),
+        ));
+
+        // The first attempt never reached the ledger, and the retry went through exactly once.
+        assert!(result.is_ok(), "{:?}", result);
+        assert_eq!(SimulatedTransport::balance(&alice()), Nat::from(1_000_u64));
+    }
+
+    #[test]
+    fn test_icrc1_transfer_human() {
+        SimulatedTransport::set_balance(SimulatedTransport::own_account(), Nat::from(300_000_000_u64));
+
+        let block = block_on(transfer_human(ledger(), alice(), "1.5".to_string())).unwrap();
+
+        // The simulated token has 8 decimals.
+        assert_eq!(SimulatedTransport::balance(&alice()), Nat::from(150_000_000_u64));
+        let (records, _) = transfer_history(None, 100);
+        assert_eq!(records.last().unwrap().block_index, block);
+    }
+
+    #[test]
+    fn test_icp_transfer_end_to_end() {
+        let own = SimulatedTransport::own_icp_account();
+        SimulatedTransport::set_icp_balance(own, Tokens::from_e8s(100_000));
+        let to = AccountIdentifier::new(&alice().owner, &DEFAULT_SUBACCOUNT);
+
+        let block = block_on(icp_ledger_client().transfer_icp(to, Tokens::from_e8s(50_000)));
+
+        assert_eq!(block, Ok(0));
+        assert_eq!(SimulatedTransport::icp_balance(&to), Tokens::from_e8s(50_000));
+        // The amount plus the 10_000 fee were deducted.
+        assert_eq!(SimulatedTransport::icp_balance(&own), Tokens::from_e8s(40_000));
+    }
+
+    #[test]
+    fn test_exchange_rate_from_the_simulated_xrc() {
+        let (args, fee) = xrc_request(&GetExchangeRateRequest {
+            base_asset: Asset {
+                symbol: "ICP".to_string(),
+                class: AssetClass::Cryptocurrency,
+            },
+            quote_asset: Asset {
+                symbol: "USD".to_string(),
+                class: AssetClass::FiatCurrency,
+            },
+            timestamp: None,
+        });
+
+        let (result, _) = block_on(cycles::call_with_cycles_accounted::<_, GetExchangeRateResult>(
+            &SimulatedTransport,
+            Principal::from_text(xrc::XRC_CANISTER_ID).unwrap(),
+            "get_exchange_rate",
+            args,
+            fee,
+            fee,
+        ))
+        .unwrap();
+
+        assert_eq!(result.unwrap().rate, simulate::SIMULATED_RATE);
+    }
+
+    #[test]
+    fn test_canister_status_from_the_simulated_management_canister() {
+        let status = block_on(management::canister_status_via(&SimulatedTransport, ledger()));
+        assert_eq!(status, Ok(CanisterStatusType::Running));
+    }
+}
@@ -0,0 +1,125 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll, Waker};
+
+#[derive(Default)]
+struct LockState {
+    locked: bool,
+    waiters: VecDeque<Waker>,
+}
+
+/// A lock that async code can hold across `await`s, so that its holders run one after another.
+// Messages interleave at every `await`: while one call to a method waits for a response, other
+// calls to the same (or another) method may start running. Code that must not interleave with
+// itself, such as two transfers picking timestamps or nonces, takes the lock first. Cloning gives
+// another handle to the same lock.
+#[derive(Clone, Default)]
+pub struct AsyncLock(Rc<RefCell<LockState>>);
+
+/// Releases the lock when dropped.
+// On the IC, the futures of a message that traps are dropped during the cleanup, so a trap while
+// holding the lock releases it too.
+pub struct LockGuard(Rc<RefCell<LockState>>);
+
+/// The future returned by `AsyncLock::lock`.
+pub struct Lock(Rc<RefCell<LockState>>);
+
+impl AsyncLock {
+    /// Waits until the lock is free, and takes it.
+    pub fn lock(&self) -> Lock {
+        Lock(self.0.clone())
+    }
+}
+
+impl Future for Lock {
+    type Output = LockGuard;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<LockGuard> {
+        let mut state = self.0.borrow_mut();
+        if state.locked {
+            state.waiters.push_back(cx.waker().clone());
+            Poll::Pending
+        } else {
+            state.locked = true;
+            Poll::Ready(LockGuard(self.0.clone()))
+        }
+    }
+}
+
+impl Drop for LockGuard {
+    // We wake all waiters rather than just the first: a waiter whose future was dropped in the
+    // meantime would never take the lock, and the others would wait forever. The ones that don't
+    // get the lock go back to the queue.
+    fn drop(&mut self) {
+        let waiters: Vec<Waker> = {
+            let mut state = self.0.borrow_mut();
+            state.locked = false;
+            state.waiters.drain(..).collect()
+        };
+        for waiter in waiters {
+            waiter.wake();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+
+    // Stands in for awaiting a call: lets the other futures run once.
+    struct YieldNow(bool);
+
+    impl Future for YieldNow {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            if self.0 {
+                Poll::Ready(())
+            } else {
+                self.0 = true;
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+    }
+
+    async fn hold(lock: &AsyncLock, name: &'static str, events: &RefCell<Vec<String>>) {
+        let _guard = lock.lock().await;
+        events.borrow_mut().push(format!("{} start", name));
+        YieldNow(false).await;
+        YieldNow(false).await;
+        events.borrow_mut().push(format!("{} end", name));
+    }
+
+    #[test]
+    fn test_holders_run_sequentially() {
+        let lock = AsyncLock::default();
+        let events = RefCell::new(Vec::new());
+
+        block_on(async {
+            futures::join!(hold(&lock, "a", &events), hold(&lock, "b", &events));
+        });
+
+        assert_eq!(events.into_inner(), vec!["a start", "a end", "b start", "b end"]);
+    }
+
+    #[test]
+    fn test_dropped_waiter_does_not_block_others() {
+        let lock = AsyncLock::default();
+        let guard = block_on(lock.lock());
+        // A waiter that gives up before getting the lock.
+        let waker = futures::task::noop_waker();
+        let mut abandoned = Box::pin(lock.lock());
+        assert!(abandoned.as_mut().poll(&mut Context::from_waker(&waker)).is_pending());
+        drop(abandoned);
+        drop(guard);
+
+        let events = RefCell::new(Vec::new());
+        block_on(hold(&lock, "c", &events));
+        assert_eq!(events.into_inner(), vec!["c start", "c end"]);
+    }
+}
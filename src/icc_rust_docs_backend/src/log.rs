@@ -0,0 +1,148 @@
+use candid::{CandidType, Deserialize};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fmt::Debug;
+use std::future::Future;
+
+use crate::transport::Transport;
+use crate::watchdog;
+
+/// The number of entries the log keeps; older entries are dropped.
+pub const LOG_CAPACITY: usize = 100;
+
+/// A call that we awaited, with its timing and outcome.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct LogEntry {
+    pub label: String,
+    /// When we issued the call and when its result came back, in nanoseconds since the epoch.
+    pub started_at: u64,
+    pub finished_at: u64,
+    /// The error, if the call failed.
+    pub outcome: Result<(), String>,
+}
+
+/// A ring buffer of the most recent log entries.
+#[derive(Default)]
+pub struct Log {
+    entries: VecDeque<LogEntry>,
+}
+
+thread_local! {
+    pub static LOG: RefCell<Log> = RefCell::new(Log::default());
+}
+
+impl Log {
+    pub fn push(&mut self, entry: LogEntry) {
+        if self.entries.len() == LOG_CAPACITY {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+
+    /// The entries, oldest first.
+    pub fn entries(&self) -> Vec<LogEntry> {
+        self.entries.iter().cloned().collect()
+    }
+}
+
+/// Awaits `fut` and logs its outcome under `label`, taking the times from `transport`.
+// Wrapping the whole future, rather than logging inside each example, gives every call the same
+// entry format, and the examples stay focused on the call itself.
+pub async fn log_call<T: Transport, R, E: Debug>(
+    transport: &T,
+    label: &str,
+    fut: impl Future<Output = Result<R, E>>,
+) -> Result<R, E> {
+    let started_at = transport.time();
+    // Let the watchdog know about the call while it's outstanding.
+    let in_flight = watchdog::register(label, started_at);
+    let result = fut.await;
+    drop(in_flight);
+    let entry = LogEntry {
+        label: label.to_string(),
+        started_at,
+        finished_at: transport.time(),
+        outcome: result.as_ref().map(|_| ()).map_err(|e| format!("{:?}", e)),
+    };
+    LOG.with(|log| log.borrow_mut().push(entry));
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::mock::MockTransport;
+    use futures::executor::block_on;
+    use std::time::Duration;
+
+    fn last_entry() -> LogEntry {
+        LOG.with(|log| log.borrow().entries().pop().unwrap())
+    }
+
+    #[test]
+    fn test_success_is_logged() {
+        let transport = MockTransport::new();
+        let result = block_on(log_call(&transport, "get_fee", async {
+            transport.sleep(Duration::from_nanos(30)).await;
+            Ok::<u64, String>(10)
+        }));
+
+        assert_eq!(result, Ok(10));
+        assert_eq!(
+            last_entry(),
+            LogEntry {
+                label: "get_fee".to_string(),
+                started_at: 0,
+                finished_at: 30,
+                outcome: Ok(()),
+            }
+        );
+    }
+
+    #[test]
+    fn test_error_is_logged() {
+        let transport = MockTransport::new();
+        let result = block_on(log_call(&transport, "transfer", async {
+            Err::<(), String>("insufficient funds".to_string())
+        }));
+
+        assert!(result.is_err());
+        let entry = last_entry();
+        assert_eq!(entry.label, "transfer");
+        assert!(entry.outcome.unwrap_err().contains("insufficient funds"));
+    }
+
+    #[test]
+    fn test_long_running_call_is_reported_as_stuck() {
+        let transport = MockTransport::new();
+        let threshold = watchdog::STUCK_THRESHOLD;
+        let stuck_while_running = block_on(log_call(&transport, "wait_until_running", async {
+            transport.sleep(threshold * 2).await;
+            Ok::<_, String>(watchdog::stuck(transport.time(), threshold))
+        }))
+        .unwrap();
+
+        assert_eq!(
+            stuck_while_running,
+            vec![("wait_until_running".to_string(), (threshold * 2).as_nanos() as u64)]
+        );
+        // Once the call completed, it's no longer stuck.
+        assert!(watchdog::stuck(transport.time(), threshold).is_empty());
+    }
+
+    #[test]
+    fn test_oldest_entries_are_dropped() {
+        let mut log = Log::default();
+        for i in 0..LOG_CAPACITY + 1 {
+            log.push(LogEntry {
+                label: i.to_string(),
+                started_at: 0,
+                finished_at: 0,
+                outcome: Ok(()),
+            });
+        }
+        let entries = log.entries();
+        assert_eq!(entries.len(), LOG_CAPACITY);
+        assert_eq!(entries[0].label, "1");
+    }
+}
@@ -0,0 +1,666 @@
+use candid::{CandidType, Deserialize, Nat, Principal};
+use futures::future::join_all;
+use ic_cdk::call::RejectCode;
+use ic_cdk::management_canister::{
+    CanisterInstallMode, CanisterStatusArgs, CanisterStatusType, ChunkHash, ClearChunkStoreArgs,
+    CreateCanisterArgs, CreateCanisterResult, DepositCyclesArgs, InstallChunkedCodeArgs,
+    InstallCodeArgs, UploadChunkArgs,
+};
+use sha2::{Digest, Sha256};
+use std::time::Duration;
+
+use crate::budget::check_cycles;
+use crate::decode::decode_or_describe;
+use crate::error::AppError;
+use crate::transport::{CallFailure, OutboundCall, Transport};
+
+/// The first delay between two status polls; it doubles after every poll, up to the maximum.
+const INITIAL_POLL_DELAY: Duration = Duration::from_millis(500);
+const MAX_POLL_DELAY: Duration = Duration::from_secs(8);
+
+// The full `canister_status` reply has many fields. Candid lets us decode just the ones we need
+// and skips the rest.
+#[derive(CandidType, Deserialize)]
+struct StatusOnly {
+    status: CanisterStatusType,
+}
+
+#[derive(CandidType, Deserialize)]
+struct SettingsOnly {
+    settings: ControllersOnly,
+}
+
+#[derive(CandidType, Deserialize)]
+struct ControllersOnly {
+    controllers: Vec<Principal>,
+}
+
+/// The parts of a `canister_status` reply that a dashboard shows.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct CanisterStatusSummary {
+    pub status: CanisterStatusType,
+    pub module_hash: Option<Vec<u8>>,
+    pub memory_size: Nat,
+    pub cycles: Nat,
+    pub idle_cycles_burned_per_day: Nat,
+}
+
+/// Asks the management canister for the status (running, stopping or stopped) of `id`. We
+/// must be one of its controllers.
+pub async fn canister_status_via<T: Transport>(
+    transport: &T,
+    id: Principal,
+) -> Result<CanisterStatusType, String> {
+    let reply = transport
+        .call(OutboundCall {
+            target: Principal::management_canister(),
+            method: "canister_status".to_string(),
+            args: candid::encode_one(CanisterStatusArgs { canister_id: id }).unwrap(),
+            cycles: 0,
+            bounded_wait: true,
+            untrusted: false,
+        })
+        .await
+        .map_err(|e| format!("Error getting the status of {}: {:?}", id, e))?;
+    decode_or_describe::<StatusOnly>(&reply)
+        .map(|s| s.status)
+        .map_err(|e| format!("Error getting the status of {}: {}", id, e))
+}
+
+/// Returns the controllers of our own canister.
+// Only controllers may call `canister_status`, so this only works if the canister controls
+// itself. A canister that doesn't can still learn its controllers through `canister_info`, but
+// that reports the history of changes rather than the current settings.
+pub async fn my_controllers_via<T: Transport>(transport: &T) -> Result<Vec<Principal>, String> {
+    let id = transport.canister_self();
+    let reply = transport
+        .call(OutboundCall {
+            target: Principal::management_canister(),
+            method: "canister_status".to_string(),
+            args: candid::encode_one(CanisterStatusArgs { canister_id: id }).unwrap(),
+            cycles: 0,
+            bounded_wait: true,
+            untrusted: false,
+        })
+        .await
+        .map_err(|e| match e {
+            CallFailure::Rejected {
+                code: RejectCode::CanisterError,
+                message,
+                ..
+            } => format!(
+                "Canister {} can't read its own controllers, as it isn't one of them; add it as a \
+                 controller first ({})",
+                id, message
+            ),
+            e => format!("Error getting the controllers: {:?}", e),
+        })?;
+    decode_or_describe::<SettingsOnly>(&reply)
+        .map(|s| s.settings.controllers)
+        .map_err(|e| format!("Error getting the controllers: {}", e))
+}
+
+/// Gets the status of each of `ids`, in the same order. A failure for one canister, e.g.,
+/// because we aren't one of its controllers, doesn't affect the others.
+// The calls are issued concurrently: all of them are sent before any reply is awaited, so the
+// total time is that of the slowest call rather than the sum of all.
+pub async fn canister_statuses_via<T: Transport>(
+    transport: &T,
+    ids: Vec<Principal>,
+) -> Vec<Result<CanisterStatusSummary, String>> {
+    join_all(ids.into_iter().map(|id| async move {
+        let reply = transport
+            .call(OutboundCall {
+                target: Principal::management_canister(),
+                method: "canister_status".to_string(),
+                args: candid::encode_one(CanisterStatusArgs { canister_id: id }).unwrap(),
+                cycles: 0,
+                bounded_wait: true,
+                untrusted: false,
+            })
+            .await
+            .map_err(|e| format!("Error getting the status of {}: {:?}", id, e))?;
+        decode_or_describe(&reply).map_err(|e| format!("Error getting the status of {}: {}", id, e))
+    }))
+    .await
+}
+
+/// Polls the status of `id` until it's running, backing off between polls, or until the IC
+/// time passes `deadline` (in nanoseconds since the epoch).
+// A canister is only ready for traffic once `start_canister` completed *and* it reports
+// `Running`; in between, it may still be `Stopping` from an earlier `stop_canister`.
+pub async fn wait_until_running_via<T: Transport>(
+    transport: &T,
+    id: Principal,
+    deadline: u64,
+) -> Result<(), String> {
+    let mut delay = INITIAL_POLL_DELAY;
+    loop {
+        let status = canister_status_via(transport, id).await?;
+        if status == CanisterStatusType::Running {
+            return Ok(());
+        }
+        if transport.time() + delay.as_nanos() as u64 > deadline {
+            return Err(format!(
+                "Canister {} is still {:?} and the deadline has passed",
+                id, status
+            ));
+        }
+        transport.sleep(delay).await;
+        delay = (delay * 2).min(MAX_POLL_DELAY);
+    }
+}
+
+/// Deposits `cycles` of our own cycles into `id`. Anyone can top up any canister.
+pub async fn top_up_canister_via<T: Transport>(
+    transport: &T,
+    id: Principal,
+    cycles: u128,
+) -> Result<(), String> {
+    check_cycles(Principal::management_canister(), cycles)?;
+    transport
+        .call(OutboundCall {
+            target: Principal::management_canister(),
+            method: "deposit_cycles".to_string(),
+            args: candid::encode_one(DepositCyclesArgs { canister_id: id }).unwrap(),
+            cycles,
+            // The cycles are only deposited once the call executes, so we want to learn whether
+            // it did.
+            bounded_wait: false,
+            untrusted: false,
+        })
+        .await
+        .map(|_| ())
+        .map_err(|e| format!("Error topping up {}: {:?}", id, e))
+}
+
+/// Builds the error for a call that failed because `callee` is out of cycles. If `top_up` is
+/// set and we control the callee, it first deposits that many cycles into it.
+// We only top up canisters that we control: it's our job to keep those running, while paying
+// for someone else's canister just because it failed our call would be an easy way to get
+// drained. Being allowed to read the status is how we find out that we control the callee.
+pub async fn on_callee_out_of_cycles<T: Transport>(
+    transport: &T,
+    callee: Principal,
+    top_up: Option<u128>,
+) -> AppError {
+    let topped_up = match top_up {
+        Some(cycles) if canister_status_via(transport, callee).await.is_ok() => {
+            top_up_canister_via(transport, callee, cycles).await.ok().map(|_| cycles)
+        }
+        _ => None,
+    };
+    AppError::CalleeOutOfCycles { callee, topped_up }
+}
+
+/// What `create_and_install_via` achieved. If the canister was created but the installation
+/// failed, the canister still exists, with the cycles we gave it, and `retry_install_via` can
+/// install it again.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct CreateOutcome {
+    pub canister_id: Principal,
+    pub installed: bool,
+    pub error: Option<String>,
+}
+
+/// Creates a canister with `cycles` of our cycles, of which the creation fee is deducted, and
+/// installs `wasm` on it, passing it `arg`. We become the canister's controller.
+// Creating and installing are two separate calls, and the second one can fail after the first
+// succeeded. Reporting that as a plain error would lose the ID of the new canister, and with it
+// the cycles we put in. So only a failed creation is an error; a failed installation is part of
+// the outcome.
+pub async fn create_and_install_via<T: Transport>(
+    transport: &T,
+    cycles: u128,
+    wasm: Vec<u8>,
+    arg: Vec<u8>,
+) -> Result<CreateOutcome, String> {
+    check_cycles(Principal::management_canister(), cycles)?;
+    let reply = transport
+        .call(OutboundCall {
+            target: Principal::management_canister(),
+            method: "create_canister".to_string(),
+            args: candid::encode_one(CreateCanisterArgs { settings: None }).unwrap(),
+            cycles,
+            // With a bounded wait, we might not learn the ID of a canister that was created.
+            bounded_wait: false,
+            untrusted: false,
+        })
+        .await
+        .map_err(|e| format!("Error creating the canister: {:?}", e))?;
+    let canister_id = decode_or_describe::<CreateCanisterResult>(&reply)
+        .map_err(|e| format!("Error creating the canister: {}", e))?
+        .canister_id;
+    let error = retry_install_via(transport, canister_id, wasm, arg).await.err();
+    Ok(CreateOutcome {
+        canister_id,
+        installed: error.is_none(),
+        error,
+    })
+}
+
+/// Installs `wasm` on the empty canister `id`, passing it `arg`. We must be a controller of `id`.
+pub async fn retry_install_via<T: Transport>(
+    transport: &T,
+    id: Principal,
+    wasm: Vec<u8>,
+    arg: Vec<u8>,
+) -> Result<(), String> {
+    call_management(
+        transport,
+        "install_code",
+        &InstallCodeArgs {
+            mode: CanisterInstallMode::Install,
+            canister_id: id,
+            wasm_module: wasm,
+            arg,
+        },
+    )
+    .await
+    .map(|_| ())
+}
+
+/// Installs a wasm module that is too large for a single message on `target`, which must be
+/// empty. The module is given as `chunks` to be concatenated, and `wasm_hash` is the SHA-256
+/// hash of the whole module. We must be a controller of `target`.
+// The chunks are stored in `target`'s own chunk store, from which `install_chunked_code`
+// assembles the module. We check the hash before uploading anything, so that a wrong module
+// doesn't cost us the upload, and we clear the store afterwards whatever happened, since
+// stored chunks count towards the canister's memory.
+pub async fn install_large_via<T: Transport>(
+    transport: &T,
+    target: Principal,
+    chunks: Vec<Vec<u8>>,
+    wasm_hash: Vec<u8>,
+) -> Result<(), String> {
+    let mut hasher = Sha256::new();
+    for chunk in &chunks {
+        hasher.update(chunk);
+    }
+    let actual_hash = hasher.finalize().to_vec();
+    if actual_hash != wasm_hash {
+        return Err(format!(
+            "The chunks hash to {}, not to the expected {}",
+            hex::encode(actual_hash),
+            hex::encode(wasm_hash)
+        ));
+    }
+
+    let result = upload_and_install(transport, target, chunks, wasm_hash).await;
+    let cleared = call_management(
+        transport,
+        "clear_chunk_store",
+        &ClearChunkStoreArgs { canister_id: target },
+    )
+    .await;
+    // Report the installation error first, as it's the one the user cares about.
+    result?;
+    cleared.map(|_| ())
+}
+
+async fn upload_and_install<T: Transport>(
+    transport: &T,
+    target: Principal,
+    chunks: Vec<Vec<u8>>,
+    wasm_hash: Vec<u8>,
+) -> Result<(), String> {
+    let mut chunk_hashes_list = Vec::with_capacity(chunks.len());
+    for chunk in chunks {
+        let reply = call_management(
+            transport,
+            "upload_chunk",
+            &UploadChunkArgs {
+                canister_id: target,
+                chunk,
+            },
+        )
+        .await?;
+        let hash: ChunkHash = decode_or_describe(&reply)
+            .map_err(|e| format!("Error uploading a chunk: {}", e))?;
+        chunk_hashes_list.push(hash);
+    }
+    call_management(
+        transport,
+        "install_chunked_code",
+        &InstallChunkedCodeArgs {
+            mode: CanisterInstallMode::Install,
+            target_canister: target,
+            store_canister: None,
+            chunk_hashes_list,
+            wasm_module_hash: wasm_hash,
+            arg: candid::encode_args(()).unwrap(),
+        },
+    )
+    .await
+    .map(|_| ())
+}
+
+// Calls on the management canister that change state, so we wait for the outcome.
+async fn call_management<T: Transport, A: CandidType>(
+    transport: &T,
+    method: &str,
+    arg: &A,
+) -> Result<Vec<u8>, String> {
+    transport
+        .call(OutboundCall {
+            target: Principal::management_canister(),
+            method: method.to_string(),
+            args: candid::encode_one(arg).unwrap(),
+            cycles: 0,
+            bounded_wait: false,
+            untrusted: false,
+        })
+        .await
+        .map_err(|e| format!("Error calling {}: {:?}", method, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::mock::MockTransport;
+    use futures::executor::block_on;
+
+    fn target() -> Principal {
+        Principal::from_slice(&[3; 10])
+    }
+
+    fn status(status: CanisterStatusType) -> StatusOnly {
+        StatusOnly { status }
+    }
+
+    #[test]
+    fn test_waits_until_running() {
+        let transport = MockTransport::new();
+        transport
+            .reply(&status(CanisterStatusType::Stopping))
+            .reply(&status(CanisterStatusType::Stopping))
+            .reply(&status(CanisterStatusType::Running));
+
+        let deadline = Duration::from_secs(60).as_nanos() as u64;
+        assert_eq!(block_on(wait_until_running_via(&transport, target(), deadline)), Ok(()));
+
+        let calls = transport.calls();
+        assert_eq!(calls.len(), 3);
+        assert!(calls.iter().all(|c| c.method == "canister_status"));
+        // We backed off for 500ms, then for 1s.
+        assert_eq!(transport.time(), Duration::from_millis(1_500).as_nanos() as u64);
+    }
+
+    #[test]
+    fn test_gives_up_at_deadline() {
+        let transport = MockTransport::new();
+        for _ in 0..3 {
+            transport.reply(&status(CanisterStatusType::Stopped));
+        }
+
+        let deadline = Duration::from_secs(2).as_nanos() as u64;
+        let result = block_on(wait_until_running_via(&transport, target(), deadline));
+
+        assert!(result.unwrap_err().contains("deadline"));
+        assert!(transport.time() <= deadline);
+    }
+
+    #[test]
+    fn test_tops_up_controlled_callee() {
+        let transport = MockTransport::new();
+        transport
+            .reply(&status(CanisterStatusType::Running))
+            .reply(&());
+
+        let error = block_on(on_callee_out_of_cycles(&transport, target(), Some(1_000)));
+
+        assert_eq!(
+            error,
+            AppError::CalleeOutOfCycles {
+                callee: target(),
+                topped_up: Some(1_000)
+            }
+        );
+        let calls = transport.calls();
+        assert_eq!(calls[1].method, "deposit_cycles");
+        assert_eq!(calls[1].cycles, 1_000);
+    }
+
+    #[test]
+    fn test_does_not_top_up_uncontrolled_callee() {
+        let transport = MockTransport::new();
+        transport.fail(CallFailure::Rejected {
+            code: RejectCode::CanisterError,
+            message: "Only controllers of the canister can call canister_status".to_string(),
+            sync: false,
+        });
+
+        let error = block_on(on_callee_out_of_cycles(&transport, target(), Some(1_000)));
+
+        assert_eq!(
+            error,
+            AppError::CalleeOutOfCycles {
+                callee: target(),
+                topped_up: None
+            }
+        );
+        assert_eq!(transport.calls().len(), 1);
+    }
+
+    #[test]
+    fn test_no_top_up_without_budget() {
+        let transport = MockTransport::new();
+        let error = block_on(on_callee_out_of_cycles(&transport, target(), None));
+        assert_eq!(
+            error,
+            AppError::CalleeOutOfCycles {
+                callee: target(),
+                topped_up: None
+            }
+        );
+        assert!(transport.calls().is_empty());
+    }
+
+    #[test]
+    fn test_my_controllers() {
+        #[derive(CandidType)]
+        struct Settings {
+            controllers: Vec<Principal>,
+            compute_allocation: Nat,
+        }
+        #[derive(CandidType)]
+        struct Status {
+            status: CanisterStatusType,
+            settings: Settings,
+        }
+        let transport = MockTransport::new();
+        let controllers = vec![transport.canister_self(), target()];
+        transport.reply(&Status {
+            status: CanisterStatusType::Running,
+            settings: Settings {
+                controllers: controllers.clone(),
+                compute_allocation: Nat::from(0_u64),
+            },
+        });
+
+        assert_eq!(block_on(my_controllers_via(&transport)), Ok(controllers));
+        let args: CanisterStatusArgs = candid::decode_one(&transport.calls()[0].args).unwrap();
+        assert_eq!(args.canister_id, transport.canister_self());
+    }
+
+    #[test]
+    fn test_my_controllers_when_not_a_controller() {
+        let transport = MockTransport::new();
+        transport.fail(CallFailure::Rejected {
+            code: RejectCode::CanisterError,
+            message: "Only controllers of the canister can call canister_status".to_string(),
+            sync: false,
+        });
+
+        let error = block_on(my_controllers_via(&transport)).unwrap_err();
+
+        assert!(error.contains("isn't one of them"), "{}", error);
+    }
+
+    #[test]
+    fn test_statuses_isolate_failures() {
+        let transport = MockTransport::new();
+        let running = CanisterStatusSummary {
+            status: CanisterStatusType::Running,
+            module_hash: Some(vec![0xab; 32]),
+            memory_size: Nat::from(1_000_u64),
+            cycles: Nat::from(5_000_000_000_000_u64),
+            idle_cycles_burned_per_day: Nat::from(100_u64),
+        };
+        transport
+            .fail(CallFailure::Rejected {
+                code: RejectCode::CanisterError,
+                message: "Only controllers of the canister can call canister_status".to_string(),
+                sync: false,
+            })
+            .reply(&running);
+        let other = Principal::from_slice(&[4; 10]);
+
+        let results = block_on(canister_statuses_via(&transport, vec![target(), other]));
+
+        assert_eq!(results.len(), 2);
+        assert!(results[0].as_ref().unwrap_err().contains("Only controllers"));
+        assert_eq!(results[1], Ok(running));
+        let targets: Vec<Principal> = transport
+            .calls()
+            .iter()
+            .map(|c| candid::decode_one::<CanisterStatusArgs>(&c.args).unwrap().canister_id)
+            .collect();
+        assert_eq!(targets, vec![target(), other]);
+    }
+
+    fn wasm_chunks() -> (Vec<Vec<u8>>, Vec<u8>) {
+        let chunks = vec![b"\0asm".to_vec(), vec![1, 0, 0, 0]];
+        let hash = Sha256::digest(chunks.concat()).to_vec();
+        (chunks, hash)
+    }
+
+    fn chunk_hash(chunk: &[u8]) -> ChunkHash {
+        ChunkHash {
+            hash: Sha256::digest(chunk).to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_install_large() {
+        let (chunks, hash) = wasm_chunks();
+        let transport = MockTransport::new();
+        transport
+            .reply(&chunk_hash(&chunks[0]))
+            .reply(&chunk_hash(&chunks[1]))
+            .reply(&())
+            .reply(&());
+
+        let result = block_on(install_large_via(&transport, target(), chunks.clone(), hash.clone()));
+
+        assert_eq!(result, Ok(()));
+        let calls = transport.calls();
+        let methods: Vec<&str> = calls.iter().map(|c| c.method.as_str()).collect();
+        assert_eq!(
+            methods,
+            vec!["upload_chunk", "upload_chunk", "install_chunked_code", "clear_chunk_store"]
+        );
+        let install: InstallChunkedCodeArgs = candid::decode_one(&calls[2].args).unwrap();
+        assert_eq!(install.wasm_module_hash, hash);
+        assert_eq!(install.chunk_hashes_list, vec![chunk_hash(&chunks[0]), chunk_hash(&chunks[1])]);
+    }
+
+    #[test]
+    fn test_install_large_rejects_wrong_hash() {
+        let (chunks, _) = wasm_chunks();
+        let transport = MockTransport::new();
+
+        let result = block_on(install_large_via(&transport, target(), chunks, vec![0; 32]));
+
+        assert!(result.unwrap_err().contains(&hex::encode([0; 32])));
+        // Nothing was uploaded, let alone installed.
+        assert!(transport.calls().is_empty());
+    }
+
+    #[test]
+    fn test_install_large_clears_store_after_failure() {
+        let (chunks, hash) = wasm_chunks();
+        let transport = MockTransport::new();
+        transport
+            .reply(&chunk_hash(&chunks[0]))
+            .reply(&chunk_hash(&chunks[1]))
+            .fail(CallFailure::Rejected {
+                code: RejectCode::CanisterError,
+                message: "Wasm module hash mismatch".to_string(),
+                sync: false,
+            })
+            .reply(&());
+
+        let result = block_on(install_large_via(&transport, target(), chunks, hash));
+
+        assert!(result.unwrap_err().contains("hash mismatch"));
+        assert_eq!(transport.calls().last().unwrap().method, "clear_chunk_store");
+    }
+
+    #[test]
+    fn test_failed_install_returns_created_canister() {
+        let transport = MockTransport::new();
+        transport
+            .reply(&CreateCanisterResult {
+                canister_id: target(),
+            })
+            .fail(CallFailure::Rejected {
+                code: RejectCode::CanisterError,
+                message: "Wasm module has an invalid format".to_string(),
+                sync: false,
+            });
+
+        let outcome =
+            block_on(create_and_install_via(&transport, 1_000_000_000_000, vec![0; 8], vec![]))
+                .unwrap();
+
+        assert_eq!(outcome.canister_id, target());
+        assert!(!outcome.installed);
+        assert!(outcome.error.unwrap().contains("invalid format"));
+        assert_eq!(transport.calls()[0].cycles, 1_000_000_000_000);
+    }
+
+    #[test]
+    fn test_creation_over_cycles_cap_is_not_attempted() {
+        let transport = MockTransport::new();
+
+        let result = block_on(create_and_install_via(
+            &transport,
+            crate::budget::MANAGEMENT_CYCLES_CAP + 1,
+            vec![0; 8],
+            vec![],
+        ));
+
+        assert!(result.unwrap_err().contains("Refusing to attach"));
+        assert!(transport.calls().is_empty());
+    }
+
+    #[test]
+    fn test_retry_install_after_failure() {
+        let transport = MockTransport::new();
+        transport.reply(&());
+
+        let result = block_on(retry_install_via(&transport, target(), vec![0; 8], vec![]));
+
+        assert_eq!(result, Ok(()));
+        let call = &transport.calls()[0];
+        assert_eq!(call.method, "install_code");
+        let args: InstallCodeArgs = candid::decode_one(&call.args).unwrap();
+        assert_eq!(args.canister_id, target());
+    }
+
+    #[test]
+    fn test_failed_creation_is_an_error() {
+        let transport = MockTransport::new();
+        transport.fail(CallFailure::Rejected {
+            code: RejectCode::CanisterReject,
+            message: "Insufficient cycles".to_string(),
+            sync: false,
+        });
+
+        let result = block_on(create_and_install_via(&transport, 1, vec![0; 8], vec![]));
+
+        assert!(result.unwrap_err().contains("creating the canister"));
+        assert_eq!(transport.calls().len(), 1);
+    }
+}
@@ -0,0 +1,52 @@
+use candid::Nat;
+
+// `Nat` wraps an arbitrary-precision `BigUint`, so additions and multiplications can't overflow.
+// Subtraction is the catch: `Nat`'s `-` panics if the result would be negative, which traps the
+// whole message. These helpers take references, which also saves the `.clone()`s that the
+// operators need, and make the one fallible operation return an error instead.
+
+pub fn nat_add(a: &Nat, b: &Nat) -> Nat {
+    Nat(&a.0 + &b.0)
+}
+
+/// Returns `a - b`, or an error if `b` is larger than `a`.
+pub fn nat_sub_checked(a: &Nat, b: &Nat) -> Result<Nat, String> {
+    if b > a {
+        Err(format!("Can't subtract {} from {}: the result would be negative", b, a))
+    } else {
+        Ok(Nat(&a.0 - &b.0))
+    }
+}
+
+pub fn nat_mul(a: &Nat, b: &Nat) -> Nat {
+    Nat(&a.0 * &b.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add_and_mul_beyond_u128() {
+        let max = Nat::from(u128::MAX);
+
+        assert_eq!(
+            nat_add(&max, &Nat::from(1_u32)).to_string().replace('_', ""),
+            "340282366920938463463374607431768211456"
+        );
+        let squared = nat_mul(&max, &max);
+        assert_eq!(nat_sub_checked(&squared, &max), Ok(nat_mul(&max, &Nat::from(u128::MAX - 1))));
+    }
+
+    #[test]
+    fn test_sub_checked() {
+        assert_eq!(nat_sub_checked(&Nat::from(10_u32), &Nat::from(4_u32)), Ok(Nat::from(6_u32)));
+        assert_eq!(nat_sub_checked(&Nat::from(10_u32), &Nat::from(10_u32)), Ok(Nat::from(0_u32)));
+    }
+
+    #[test]
+    fn test_sub_checked_rejects_underflow() {
+        let error = nat_sub_checked(&Nat::from(4_u32), &Nat::from(10_u32)).unwrap_err();
+        assert!(error.contains("negative"), "{}", error);
+    }
+}
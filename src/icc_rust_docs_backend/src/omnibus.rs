@@ -0,0 +1,145 @@
+use candid::{Nat, Principal};
+use icrc_ledger_types::icrc1::account::{Account, Subaccount};
+use icrc_ledger_types::icrc1::transfer::NumTokens;
+
+use crate::cmc::canister_subaccount;
+use crate::ledger::{Icrc1Ledger, Icrc1TransferOptions};
+use crate::retry;
+use crate::transport::Transport;
+
+// In the omnibus pattern, we hold the funds of all our users on the ledger, each user's in a
+// subaccount of ours derived from their principal. The ledger only knows about our accounts; which
+// user owns what is up to us, and only we can move the funds. Users deposit by transferring to
+// `user_account`, and we transfer out of it on their behalf.
+
+/// The subaccount of ours that holds `user`'s funds.
+// We use the encoding that the CMC uses for canisters: the length of the principal followed by
+// its bytes. Distinct principals get distinct subaccounts, and anyone can compute the subaccount
+// of a principal.
+pub fn user_subaccount(user: Principal) -> Subaccount {
+    canister_subaccount(user)
+}
+
+/// The account that holds `user`'s funds, to which they deposit.
+pub fn user_account(canister: Principal, user: Principal) -> Account {
+    Account {
+        owner: canister,
+        subaccount: Some(user_subaccount(user)),
+    }
+}
+
+/// Checks that `caller` may move `user`'s funds: only `user` themselves, and controllers, may.
+pub fn check_acting_for(caller: Principal, user: Principal, caller_is_controller: bool) -> Result<(), String> {
+    if caller == user || caller_is_controller {
+        Ok(())
+    } else {
+        Err(format!("Only {} and the controllers can move the funds of {}", user, user))
+    }
+}
+
+/// Transfers `amount` from `user`'s subaccount to `to`, and returns the index of the block
+/// containing the transfer.
+// The transfer is retried, serialized and recorded like any other of ours, see
+// `Icrc1Ledger::transfer_with`.
+pub async fn transfer_for_user<T: Transport>(
+    ledger: &Icrc1Ledger<T>,
+    user: Principal,
+    to: Account,
+    amount: NumTokens,
+) -> Result<Nat, String> {
+    let options = Icrc1TransferOptions {
+        from_subaccount: Some(user_subaccount(user)),
+        ..Icrc1TransferOptions::default()
+    };
+    let mut attempts = 0;
+    ledger
+        .transfer_with(to, amount, options, &retry::current(), &mut attempts)
+        .await
+        .map_err(String::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::mock::{sys_unknown, MockTransport};
+    use futures::executor::block_on;
+    use icrc_ledger_types::icrc1::transfer::{TransferArg, TransferError};
+
+    fn user(n: u8) -> Principal {
+        Principal::from_slice(&[n; 29])
+    }
+
+    #[test]
+    fn test_transfer_moves_funds_from_the_users_subaccount() {
+        let ledger = Icrc1Ledger {
+            canister_id: Principal::from_slice(&[1; 10]),
+            transport: MockTransport::new(),
+        };
+        ledger
+            .transport
+            .reply(&Nat::from(10_u64))
+            .reply(&Ok::<Nat, TransferError>(Nat::from(3_u64)));
+        let to = Account {
+            owner: user(9),
+            subaccount: None,
+        };
+
+        let result = block_on(transfer_for_user(&ledger, user(2), to, Nat::from(500_u64)));
+
+        assert_eq!(result, Ok(Nat::from(3_u64)));
+        let calls = ledger.transport.calls();
+        assert_eq!(calls[1].method, "icrc1_transfer");
+        let arg: TransferArg = candid::decode_one(&calls[1].args).unwrap();
+        assert_eq!(arg.from_subaccount, Some(user_subaccount(user(2))));
+        assert_eq!(arg.to, to);
+        assert_eq!(arg.amount, Nat::from(500_u64));
+        // The transfer blocks upgrades while it waits for the ledger.
+        assert_eq!(ledger.transport.in_flight_during_calls()[1], 1);
+    }
+
+    #[test]
+    fn test_transfer_for_user_is_retried_after_an_unknown_outcome() {
+        let ledger = Icrc1Ledger {
+            canister_id: Principal::from_slice(&[1; 10]),
+            transport: MockTransport::new(),
+        };
+        ledger
+            .transport
+            .reply(&Nat::from(10_u64))
+            .fail(sys_unknown())
+            .reply(&Ok::<Nat, TransferError>(Nat::from(3_u64)));
+        let to = Account {
+            owner: user(9),
+            subaccount: None,
+        };
+
+        let result = block_on(transfer_for_user(&ledger, user(2), to, Nat::from(500_u64)));
+
+        assert_eq!(result, Ok(Nat::from(3_u64)));
+        // The retry sent the same transfer, so the ledger would have deduplicated it.
+        let calls = ledger.transport.calls();
+        let first: TransferArg = candid::decode_one(&calls[1].args).unwrap();
+        let second: TransferArg = candid::decode_one(&calls[2].args).unwrap();
+        assert_eq!(first, second);
+        assert_eq!(first.from_subaccount, Some(user_subaccount(user(2))));
+    }
+
+    #[test]
+    fn test_users_get_distinct_subaccounts() {
+        assert_ne!(user_subaccount(user(2)), user_subaccount(user(3)));
+        // A shorter principal whose bytes are a prefix of a longer one's.
+        assert_ne!(
+            user_subaccount(Principal::from_slice(&[2; 10])),
+            user_subaccount(user(2))
+        );
+        let canister = Principal::from_slice(&[1; 10]);
+        assert_eq!(user_account(canister, user(2)).subaccount, Some(user_subaccount(user(2))));
+    }
+
+    #[test]
+    fn test_only_the_user_and_controllers_act_for_the_user() {
+        assert_eq!(check_acting_for(user(2), user(2), false), Ok(()));
+        assert_eq!(check_acting_for(user(3), user(2), true), Ok(()));
+        assert!(check_acting_for(user(3), user(2), false).is_err());
+    }
+}
@@ -0,0 +1,376 @@
+use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_cdk::call::RejectCode;
+use icrc_ledger_types::icrc1::account::Account;
+use icrc_ledger_types::icrc1::transfer::{Memo, NumTokens, TransferArg, TransferError};
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use crate::decode::describe_reply;
+use crate::transport::{describe_reject, CallFailure, OutboundCall, Transport};
+
+/// How often the processor goes through the pending jobs.
+pub const PROCESS_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How many times we try a job before marking it failed.
+pub const MAX_JOB_ATTEMPTS: u32 = 20;
+
+/// Where a queued transfer stands.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum JobStatus {
+    /// Not done yet; the processor will try again.
+    Pending { attempts: u32 },
+    /// The transfer is in the block with the given index.
+    Done { block_index: Nat },
+    /// We gave up on the transfer.
+    Failed { error: String },
+    /// There's no job with this ID.
+    Unknown,
+}
+
+/// A transfer of `amount` from our default account on `ledger` to `to`.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Job {
+    pub ledger: Principal,
+    pub to: Account,
+    pub amount: NumTokens,
+    /// Fixed when the job is queued, so that every attempt sends the same transfer.
+    pub created_at_time: u64,
+    pub status: JobStatus,
+}
+
+impl Job {
+    // The ledger only deduplicates identical transfers, so each attempt must send exactly the same
+    // arguments. The job's ID as the memo keeps two jobs with the same recipient and amount,
+    // queued in the same nanosecond, from being taken for duplicates of each other. We leave the
+    // fee to the ledger, which also keeps the arguments stable if the fee changes.
+    fn transfer_arg(&self, id: u64) -> TransferArg {
+        TransferArg {
+            from_subaccount: None,
+            to: self.to,
+            fee: None,
+            created_at_time: Some(self.created_at_time),
+            memo: Some(Memo::from(id)),
+            amount: self.amount.clone(),
+        }
+    }
+}
+
+/// The transfers that we promised to make, and what became of them.
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct Outbox {
+    next_id: u64,
+    jobs: BTreeMap<u64, Job>,
+}
+
+impl Outbox {
+    /// Queues a transfer, and returns the ID of its job.
+    pub fn enqueue(&mut self, ledger: Principal, to: Account, amount: NumTokens, now: u64) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.jobs.insert(
+            id,
+            Job {
+                ledger,
+                to,
+                amount,
+                created_at_time: now,
+                status: JobStatus::Pending { attempts: 0 },
+            },
+        );
+        id
+    }
+
+    pub fn status(&self, id: u64) -> JobStatus {
+        self.jobs
+            .get(&id)
+            .map_or(JobStatus::Unknown, |job| job.status.clone())
+    }
+
+    /// The number of jobs that aren't done or failed yet.
+    pub fn pending_jobs(&self) -> usize {
+        self.jobs
+            .values()
+            .filter(|job| matches!(job.status, JobStatus::Pending { .. }))
+            .count()
+    }
+
+    fn pending(&self) -> Vec<(u64, Job)> {
+        self.jobs
+            .iter()
+            .filter(|(_, job)| matches!(job.status, JobStatus::Pending { .. }))
+            .map(|(id, job)| (*id, job.clone()))
+            .collect()
+    }
+
+    fn record(&mut self, id: u64, outcome: Attempt) {
+        let Some(job) = self.jobs.get_mut(&id) else {
+            return;
+        };
+        let attempts = match job.status {
+            JobStatus::Pending { attempts } => attempts + 1,
+            _ => return,
+        };
+        job.status = match outcome {
+            Attempt::Done(block_index) => JobStatus::Done { block_index },
+            Attempt::Failed(error) => JobStatus::Failed { error },
+            Attempt::Retry(error) if attempts >= MAX_JOB_ATTEMPTS => JobStatus::Failed {
+                error: format!("Giving up after {} attempts: {}", attempts, error),
+            },
+            Attempt::Retry(_) => JobStatus::Pending { attempts },
+        };
+    }
+}
+
+thread_local! {
+    pub static OUTBOX: RefCell<Outbox> = RefCell::new(Outbox::default());
+    // Whether the processor is going through the jobs, so that timers don't start a second run.
+    static PROCESSING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Marks the processor as running for as long as it's alive.
+// On the IC, the futures of a message that traps are dropped during the cleanup, so a trap in the
+// middle of a run doesn't leave the processor marked as running, and later timers still run it.
+struct Processing(());
+
+impl Drop for Processing {
+    fn drop(&mut self) {
+        PROCESSING.with(|p| p.set(false));
+    }
+}
+
+/// Whether the processor is going through the jobs.
+pub fn is_processing() -> bool {
+    PROCESSING.with(|p| p.get())
+}
+
+/// Marks the processor as running, unless it already is.
+fn begin_processing() -> Option<Processing> {
+    if PROCESSING.with(|p| p.replace(true)) {
+        None
+    } else {
+        Some(Processing(()))
+    }
+}
+
+/// What a single attempt at a transfer came to.
+pub enum Attempt {
+    Done(Nat),
+    Failed(String),
+    /// Worth trying again later; the string says why this attempt failed.
+    Retry(String),
+}
+
+async fn attempt<T: Transport>(transport: &T, id: u64, job: &Job) -> Attempt {
+    send_transfer(transport, job.ledger, &job.transfer_arg(id)).await
+}
+
+/// Sends `arg` to `ledger`, and sorts the result into done, failed, or worth retrying. Retrying
+/// is only safe if `arg` sets `created_at_time`, so that the ledger deduplicates the attempts.
+pub async fn send_transfer<T: Transport>(transport: &T, ledger: Principal, arg: &TransferArg) -> Attempt {
+    let result = transport
+        .call(OutboundCall::untrusted(
+            ledger,
+            "icrc1_transfer",
+            candid::encode_one(arg).unwrap(),
+        ))
+        .await;
+    match result {
+        Ok(reply) => match candid::decode_one::<Result<Nat, TransferError>>(&reply) {
+            Ok(Ok(block_index)) => Attempt::Done(block_index),
+            // An earlier attempt went through, but we never learned about it.
+            Ok(Err(TransferError::Duplicate { duplicate_of })) => Attempt::Done(duplicate_of),
+            Ok(Err(e @ TransferError::TemporarilyUnavailable))
+            | Ok(Err(e @ TransferError::CreatedInFuture { .. })) => {
+                Attempt::Retry(format!("Ledger returned an error: {:?}", e))
+            }
+            // Past the ledger's deduplication window, a retry could transfer a second time, so we
+            // stop. An earlier attempt may still have gone through.
+            Ok(Err(TransferError::TooOld)) => Attempt::Failed(
+                "The transfer is too old for the ledger to deduplicate; check the ledger for \
+                 whether it executed"
+                    .to_string(),
+            ),
+            Ok(Err(e)) => Attempt::Failed(format!("Ledger returned an error: {:?}", e)),
+            Err(e) => Attempt::Failed(format!(
+                "Unable to decode the ledger response ({}): {}",
+                e,
+                describe_reply(&reply)
+            )),
+        },
+        // The transfer may or may not have executed; retrying is safe thanks to deduplication.
+        Err(CallFailure::SysUnknown(reason)) => {
+            Attempt::Retry(format!("The outcome of the call is unknown: {}", reason))
+        }
+        Err(CallFailure::Rejected { code, message, .. })
+            if matches!(code, RejectCode::SysTransient | RejectCode::SysUnknown) =>
+        {
+            Attempt::Retry(describe_reject(code, &message))
+        }
+        Err(failure) if failure.is_callee_out_of_cycles() => {
+            Attempt::Retry(format!("Ledger {} is out of cycles", ledger))
+        }
+        // Most likely the ledger is being upgraded. If it stays stopped, the job runs out of
+        // attempts like any other.
+        Err(failure) if failure.is_callee_stopped() => Attempt::Retry(format!("Ledger {} is stopped", ledger)),
+        Err(CallFailure::Rejected { code, message, .. }) => Attempt::Failed(describe_reject(code, &message)),
+        Err(CallFailure::CanisterError(e)) => Attempt::Failed(format!("Ledger crashed: {}", e)),
+    }
+}
+
+/// Tries each pending job once, and records the outcomes.
+// Each job's outcome is recorded as soon as it's known, so an upgrade or a trap in a later job
+// doesn't lose it. The jobs run one after the other: the outbox trades speed for a bounded number
+// of open calls.
+pub async fn process<T: Transport>(transport: &T) {
+    let Some(_processing) = begin_processing() else {
+        return;
+    };
+    let pending = OUTBOX.with(|o| o.borrow().pending());
+    for (id, job) in pending {
+        let outcome = attempt(transport, id, &job).await;
+        OUTBOX.with(|o| o.borrow_mut().record(id, outcome));
+    }
+}
+
+/// Goes through the pending jobs every `PROCESS_INTERVAL`.
+pub fn start() {
+    ic_cdk_timers::set_timer_interval(PROCESS_INTERVAL, run);
+}
+
+/// Goes through the pending jobs right away, rather than at the next interval.
+pub fn run() {
+    ic_cdk::futures::spawn(async { process(&crate::transport::default_transport()).await });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::mock::{sys_unknown, MockTransport};
+    use futures::executor::block_on;
+
+    fn to() -> Account {
+        Account {
+            owner: Principal::from_slice(&[2; 29]),
+            subaccount: None,
+        }
+    }
+
+    fn enqueue(amount: u64) -> u64 {
+        OUTBOX.with(|o| {
+            o.borrow_mut()
+                .enqueue(Principal::anonymous(), to(), Nat::from(amount), 1_000)
+        })
+    }
+
+    fn status(id: u64) -> JobStatus {
+        OUTBOX.with(|o| o.borrow().status(id))
+    }
+
+    #[test]
+    fn test_enqueue_process_done() {
+        let transport = MockTransport::new();
+        transport.reply(&Ok::<Nat, TransferError>(Nat::from(7_u64)));
+        let id = enqueue(100);
+        assert_eq!(status(id), JobStatus::Pending { attempts: 0 });
+
+        block_on(process(&transport));
+
+        assert_eq!(
+            status(id),
+            JobStatus::Done {
+                block_index: Nat::from(7_u64)
+            }
+        );
+        let arg: TransferArg = candid::decode_one(&transport.calls()[0].args).unwrap();
+        assert_eq!(arg.created_at_time, Some(1_000));
+        assert_eq!(arg.memo, Some(Memo::from(id)));
+        // Done jobs aren't tried again.
+        block_on(process(&transport));
+        assert_eq!(transport.calls().len(), 1);
+    }
+
+    #[test]
+    fn test_permanent_failure_is_marked_failed() {
+        let transport = MockTransport::new();
+        transport.reply(&Err::<Nat, TransferError>(TransferError::InsufficientFunds {
+            balance: Nat::from(5_u64),
+        }));
+        let id = enqueue(100);
+
+        block_on(process(&transport));
+
+        match status(id) {
+            JobStatus::Failed { error } => assert!(error.contains("InsufficientFunds"), "{}", error),
+            status => panic!("Expected a failed job, got {:?}", status),
+        }
+    }
+
+    #[test]
+    fn test_unknown_outcome_is_retried_with_the_same_transfer() {
+        let transport = MockTransport::new();
+        transport
+            .fail(sys_unknown())
+            .reply(&Err::<Nat, TransferError>(TransferError::Duplicate {
+                duplicate_of: Nat::from(3_u64),
+            }));
+        let id = enqueue(100);
+
+        block_on(process(&transport));
+        assert_eq!(status(id), JobStatus::Pending { attempts: 1 });
+        block_on(process(&transport));
+
+        // The first attempt went through after all; the ledger says so.
+        assert_eq!(
+            status(id),
+            JobStatus::Done {
+                block_index: Nat::from(3_u64)
+            }
+        );
+        let calls = transport.calls();
+        assert_eq!(calls[0].args, calls[1].args);
+    }
+
+    #[test]
+    fn test_gives_up_after_max_attempts() {
+        let transport = MockTransport::new();
+        for _ in 0..MAX_JOB_ATTEMPTS {
+            transport.fail(sys_unknown());
+        }
+        let id = enqueue(100);
+
+        for _ in 0..MAX_JOB_ATTEMPTS {
+            block_on(process(&transport));
+        }
+
+        assert!(matches!(status(id), JobStatus::Failed { .. }));
+    }
+
+    #[test]
+    fn test_same_transfer_twice_gets_different_memos() {
+        let first = enqueue(100);
+        let second = enqueue(100);
+        OUTBOX.with(|o| {
+            let o = o.borrow();
+            let args: Vec<_> = o.pending().iter().map(|(id, job)| job.transfer_arg(*id)).collect();
+            assert_eq!(args.len(), 2);
+            assert_ne!(args[0], args[1]);
+        });
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn test_dropped_run_lets_the_next_one_start() {
+        // What's left of a run that trapped after an `await`.
+        let run = begin_processing();
+        assert!(run.is_some());
+        assert!(begin_processing().is_none());
+        drop(run);
+        assert!(begin_processing().is_some());
+    }
+
+    #[test]
+    fn test_unknown_job() {
+        assert_eq!(status(42), JobStatus::Unknown);
+    }
+}
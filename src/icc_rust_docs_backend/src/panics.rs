@@ -0,0 +1,64 @@
+use std::cell::Cell;
+
+thread_local! {
+    static HOOK_INSTALLED: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Makes every panic print its message and location to the canister log before the canister
+/// traps. Controllers read the log with `dfx canister logs`.
+// A trap rolls back all the state changes of the message: heap, stable memory, our own call log
+// (`log::LOG`), everything except the canister log that the system keeps. So writing the panic
+// to our own ring buffer would be undone right away, and printing is the only record that stays.
+// The hook that `ic_cdk` installs is what turns the panic into a trap with the panic's message;
+// ours runs first and then hands over to it.
+pub fn install() {
+    install_with(|message| ic_cdk::api::debug_print(message));
+}
+
+/// Like `install`, but passes the messages to `print`.
+pub fn install_with(print: fn(&str)) {
+    if HOOK_INSTALLED.with(|i| i.replace(true)) {
+        return;
+    }
+    let previous = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        print(&format!("[PANIC] {}", info));
+        previous(info);
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    fn installed() -> bool {
+        HOOK_INSTALLED.with(|i| i.get())
+    }
+
+    thread_local! {
+        static PRINTED: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+    }
+
+    fn record(message: &str) {
+        PRINTED.with(|p| p.borrow_mut().push(message.to_string()));
+    }
+
+    #[test]
+    fn test_hook_prints_the_panic() {
+        assert!(!installed());
+        install_with(record);
+        assert!(installed());
+
+        let result = std::panic::catch_unwind(|| panic!("Ledger crashed"));
+
+        // The hook is global; put the default one back for the other tests.
+        let _ = std::panic::take_hook();
+        assert!(result.is_err());
+        let printed = PRINTED.with(|p| p.borrow().clone());
+        assert_eq!(printed.len(), 1);
+        assert!(printed[0].starts_with("[PANIC] "), "{}", printed[0]);
+        assert!(printed[0].contains("Ledger crashed"), "{}", printed[0]);
+        assert!(printed[0].contains("panics.rs"), "{}", printed[0]);
+    }
+}
@@ -0,0 +1,85 @@
+use std::cell::Cell;
+use std::time::Duration;
+
+use crate::error::AppError;
+
+/// The cycle balance below which we pause, unless configured otherwise at init.
+pub const DEFAULT_CYCLES_WATERMARK: u128 = 500_000_000_000;
+
+/// How often we compare the cycle balance against the watermark.
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+thread_local! {
+    static WATERMARK: Cell<u128> = const { Cell::new(DEFAULT_CYCLES_WATERMARK) };
+    static PAUSED: Cell<bool> = const { Cell::new(false) };
+}
+
+pub fn set_watermark(watermark: u128) {
+    WATERMARK.with(|w| w.set(watermark));
+}
+
+/// Pauses the methods that spend cycles if `balance` is below the watermark, and resumes them
+/// otherwise. Returns whether they are now paused.
+pub fn update(balance: u128) -> bool {
+    let paused = balance < WATERMARK.with(|w| w.get());
+    PAUSED.with(|p| p.set(paused));
+    paused
+}
+
+pub fn is_paused() -> bool {
+    PAUSED.with(|p| p.get())
+}
+
+/// Fails with `AppError::Paused` while the methods that spend cycles are paused.
+// Once the balance drops below the freezing threshold, the system stops executing our messages,
+// including the responses to calls we already made. A transfer could then be left half done,
+// with the lock held and the history not updated. Refusing new work early leaves the remaining
+// cycles to the calls already in flight.
+pub fn ensure_not_paused() -> Result<(), AppError> {
+    if is_paused() {
+        Err(AppError::Paused)
+    } else {
+        Ok(())
+    }
+}
+
+/// Checks the cycle balance now and then every `CHECK_INTERVAL`, pausing or resuming accordingly.
+// Topping up the canister doesn't notify it, so resuming has to wait for the next check.
+pub fn start() {
+    let check = || {
+        let balance = ic_cdk::api::canister_cycle_balance();
+        let was_paused = is_paused();
+        match (was_paused, update(balance)) {
+            (false, true) => ic_cdk::println!("Pausing: the cycle balance is down to {}", balance),
+            (true, false) => ic_cdk::println!("Resuming: the cycle balance is back at {}", balance),
+            _ => {}
+        }
+    };
+    check();
+    ic_cdk_timers::set_timer_interval(CHECK_INTERVAL, check);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_methods_are_rejected_while_paused() {
+        set_watermark(1_000);
+        assert!(update(999));
+        assert_eq!(ensure_not_paused(), Err(AppError::Paused));
+    }
+
+    #[test]
+    fn test_methods_resume_after_balance_recovers() {
+        set_watermark(1_000);
+        update(999);
+        assert!(!update(1_000));
+        assert_eq!(ensure_not_paused(), Ok(()));
+    }
+
+    #[test]
+    fn test_not_paused_by_default() {
+        assert_eq!(ensure_not_paused(), Ok(()));
+    }
+}
@@ -0,0 +1,299 @@
+use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_ledger_types::{AccountIdentifier, Tokens};
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// The number of distinct controllers that must approve a transfer before it's executed, unless
+/// the init args say otherwise.
+pub const DEFAULT_APPROVAL_THRESHOLD: usize = 2;
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum ProposalStatus {
+    /// Waiting for more approvals.
+    Open,
+    /// Enough approvals were collected, and the transfer is in flight.
+    Executing,
+    Executed(Nat),
+    Failed(String),
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Proposal {
+    pub to: AccountIdentifier,
+    pub amount: Tokens,
+    pub approvals: BTreeSet<Principal>,
+    pub status: ProposalStatus,
+}
+
+/// All transfer proposals, open and closed.
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct Proposals {
+    next_id: u64,
+    proposals: BTreeMap<u64, Proposal>,
+}
+
+thread_local! {
+    pub static PROPOSALS: RefCell<Proposals> = RefCell::new(Proposals::default());
+    static APPROVAL_THRESHOLD: Cell<usize> = const { Cell::new(DEFAULT_APPROVAL_THRESHOLD) };
+    // The proposals whose transfer this code is awaiting. Heap memory doesn't survive upgrades,
+    // so a proposal that's executing but not in here had its transfer cut short by one.
+    static TRANSFERS_IN_FLIGHT: RefCell<BTreeSet<u64>> = RefCell::new(BTreeSet::new());
+}
+
+/// Marks the transfer of a proposal as in flight for as long as it's alive.
+pub struct TransferInFlight(u64);
+
+impl Drop for TransferInFlight {
+    fn drop(&mut self) {
+        TRANSFERS_IN_FLIGHT.with(|t| t.borrow_mut().remove(&self.0));
+    }
+}
+
+/// Registers the transfer of proposal `id` as in flight.
+pub fn transfer_started(id: u64) -> TransferInFlight {
+    TRANSFERS_IN_FLIGHT.with(|t| t.borrow_mut().insert(id));
+    TransferInFlight(id)
+}
+
+pub fn transfer_in_flight(id: u64) -> bool {
+    TRANSFERS_IN_FLIGHT.with(|t| t.borrow().contains(&id))
+}
+
+/// Sets the number of approvals a transfer needs. A threshold of 0 is taken as 1, as the proposer
+/// always approves.
+// The canister can't read its controllers without a call to the management canister, so it can't
+// check the threshold against their number. A threshold above it means that no transfer executes;
+// the controllers then have to upgrade with a lower one.
+pub fn set_approval_threshold(threshold: usize) {
+    APPROVAL_THRESHOLD.with(|t| t.set(threshold.max(1)));
+}
+
+pub fn approval_threshold() -> usize {
+    APPROVAL_THRESHOLD.with(|t| t.get())
+}
+
+impl Proposals {
+    /// Stores a new proposal, counting the proposer as its first approval, and returns its ID.
+    pub fn propose(&mut self, proposer: Principal, to: AccountIdentifier, amount: Tokens) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.proposals.insert(
+            id,
+            Proposal {
+                to,
+                amount,
+                approvals: BTreeSet::from([proposer]),
+                status: ProposalStatus::Open,
+            },
+        );
+        id
+    }
+
+    /// Records an approval. Approving twice has no effect.
+    pub fn approve(&mut self, id: u64, approver: Principal) -> Result<(), String> {
+        let proposal = self
+            .proposals
+            .get_mut(&id)
+            .ok_or_else(|| format!("No proposal with ID {}", id))?;
+        if proposal.status != ProposalStatus::Open {
+            return Err(format!("Proposal {} is no longer open", id));
+        }
+        proposal.approvals.insert(approver);
+        Ok(())
+    }
+
+    /// If the proposal has enough approvals and hasn't been executed yet, marks it as executing
+    /// and returns the transfer to perform. Only approvals for which `is_controller` holds count.
+    // Marking the proposal *before* the transfer is awaited is what guarantees that it executes
+    // at most once: any approval that arrives while the transfer is in flight sees that the
+    // proposal is no longer open. Approvers are checked again here, rather than only when they
+    // approve, because a controller may have been removed since.
+    pub fn claim_for_execution(
+        &mut self,
+        id: u64,
+        threshold: usize,
+        is_controller: impl Fn(&Principal) -> bool,
+    ) -> Option<(AccountIdentifier, Tokens)> {
+        let proposal = self.proposals.get_mut(&id)?;
+        let approvals = proposal.approvals.iter().filter(|a| is_controller(a)).count();
+        if proposal.status == ProposalStatus::Open && approvals >= threshold {
+            proposal.status = ProposalStatus::Executing;
+            Some((proposal.to, proposal.amount))
+        } else {
+            None
+        }
+    }
+
+    /// Records the outcome of an executed transfer.
+    pub fn finish(&mut self, id: u64, result: Result<Nat, String>) {
+        if let Some(proposal) = self.proposals.get_mut(&id) {
+            proposal.status = match result {
+                Ok(block) => ProposalStatus::Executed(block),
+                Err(e) => ProposalStatus::Failed(e),
+            };
+        }
+    }
+
+    /// Settles a proposal whose transfer was cut short by an upgrade, so that it's no longer
+    /// executing. `block_index` is the block holding the transfer, if the ledger has one.
+    // We can't tell whether such a transfer happened: the ledger's reply went to code that no
+    // longer exists. The ICP ledger doesn't deduplicate these transfers, so we can't find out by
+    // sending them again either; someone has to look at the ledger.
+    pub fn resolve_interrupted(
+        &mut self,
+        id: u64,
+        in_flight: bool,
+        block_index: Option<Nat>,
+    ) -> Result<ProposalStatus, String> {
+        let proposal = self
+            .proposals
+            .get_mut(&id)
+            .ok_or_else(|| format!("No proposal with ID {}", id))?;
+        if proposal.status != ProposalStatus::Executing || in_flight {
+            return Err(format!("Proposal {} isn't stuck executing", id));
+        }
+        proposal.status = match block_index {
+            Some(block) => ProposalStatus::Executed(block),
+            None => ProposalStatus::Failed(
+                "The transfer was interrupted by an upgrade, and isn't on the ledger".to_string(),
+            ),
+        };
+        Ok(proposal.status.clone())
+    }
+
+    /// The transfer that proposal `id` asks for.
+    pub fn transfer(&self, id: u64) -> Option<(AccountIdentifier, Tokens)> {
+        self.proposals.get(&id).map(|p| (p.to, p.amount))
+    }
+
+    pub fn status(&self, id: u64) -> Option<ProposalStatus> {
+        self.proposals.get(&id).map(|p| p.status.clone())
+    }
+
+    /// The number of proposals whose transfer is in flight.
+    pub fn executing(&self) -> usize {
+        self.proposals
+            .values()
+            .filter(|p| p.status == ProposalStatus::Executing)
+            .count()
+    }
+
+    /// The number of proposals that are open or executing.
+    pub fn pending(&self) -> usize {
+        self.proposals
+            .values()
+            .filter(|p| matches!(p.status, ProposalStatus::Open | ProposalStatus::Executing))
+            .count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn controller(n: u8) -> Principal {
+        Principal::from_slice(&[n; 29])
+    }
+
+    fn propose(proposals: &mut Proposals) -> u64 {
+        proposals.propose(
+            controller(1),
+            AccountIdentifier::new(&controller(9), &ic_ledger_types::DEFAULT_SUBACCOUNT),
+            Tokens::from_e8s(1_000),
+        )
+    }
+
+    #[test]
+    fn test_threshold_enforced() {
+        let mut proposals = Proposals::default();
+        let id = propose(&mut proposals);
+        // Only the proposer approved so far.
+        assert_eq!(proposals.claim_for_execution(id, 2, |_| true), None);
+        // The proposer approving again doesn't count.
+        proposals.approve(id, controller(1)).unwrap();
+        assert_eq!(proposals.claim_for_execution(id, 2, |_| true), None);
+        // A second controller tips it over.
+        proposals.approve(id, controller(2)).unwrap();
+        assert!(proposals.claim_for_execution(id, 2, |_| true).is_some());
+        assert_eq!(proposals.status(id), Some(ProposalStatus::Executing));
+    }
+
+    #[test]
+    fn test_executes_exactly_once() {
+        let mut proposals = Proposals::default();
+        let id = propose(&mut proposals);
+        proposals.approve(id, controller(2)).unwrap();
+        assert!(proposals.claim_for_execution(id, 2, |_| true).is_some());
+        // A late approval while the transfer is in flight neither counts nor re-triggers it.
+        assert!(proposals.approve(id, controller(3)).is_err());
+        assert_eq!(proposals.claim_for_execution(id, 2, |_| true), None);
+        assert_eq!(proposals.pending(), 1);
+        assert_eq!(proposals.executing(), 1);
+        proposals.finish(id, Ok(Nat::from(17_u64)));
+        assert_eq!(proposals.status(id), Some(ProposalStatus::Executed(Nat::from(17_u64))));
+        assert_eq!(proposals.pending(), 0);
+        assert_eq!(proposals.executing(), 0);
+        assert_eq!(proposals.claim_for_execution(id, 2, |_| true), None);
+    }
+
+    #[test]
+    fn test_approvals_of_removed_controllers_dont_count() {
+        let mut proposals = Proposals::default();
+        let id = propose(&mut proposals);
+        proposals.approve(id, controller(2)).unwrap();
+        // Controller 2 was removed after approving.
+        let is_controller = |p: &Principal| *p != controller(2);
+        assert_eq!(proposals.claim_for_execution(id, 2, is_controller), None);
+        assert_eq!(proposals.status(id), Some(ProposalStatus::Open));
+        proposals.approve(id, controller(3)).unwrap();
+        assert!(proposals.claim_for_execution(id, 2, is_controller).is_some());
+    }
+
+    #[test]
+    fn test_interrupted_transfer_is_resolved() {
+        let mut proposals = Proposals::default();
+        let executed = propose(&mut proposals);
+        let failed = propose(&mut proposals);
+        for id in [executed, failed] {
+            proposals.approve(id, controller(2)).unwrap();
+            proposals.claim_for_execution(id, 2, |_| true).unwrap();
+        }
+
+        // A transfer that's still awaited can't be resolved.
+        assert!(proposals.resolve_interrupted(executed, true, None).is_err());
+        assert_eq!(
+            proposals.resolve_interrupted(executed, false, Some(Nat::from(9_u64))),
+            Ok(ProposalStatus::Executed(Nat::from(9_u64)))
+        );
+        assert!(matches!(
+            proposals.resolve_interrupted(failed, false, None),
+            Ok(ProposalStatus::Failed(_))
+        ));
+        assert_eq!(proposals.executing(), 0);
+        // Resolved proposals stay resolved.
+        assert!(proposals.resolve_interrupted(executed, false, None).is_err());
+    }
+
+    #[test]
+    fn test_transfers_in_flight_are_tracked() {
+        let transfer = transfer_started(3);
+        assert!(transfer_in_flight(3));
+        assert!(!transfer_in_flight(4));
+        drop(transfer);
+        assert!(!transfer_in_flight(3));
+    }
+
+    #[test]
+    fn test_unknown_proposal() {
+        let mut proposals = Proposals::default();
+        assert!(proposals.approve(5, controller(1)).is_err());
+        assert_eq!(proposals.status(5), None);
+    }
+
+    #[test]
+    fn test_ids_are_sequential() {
+        let mut proposals = Proposals::default();
+        assert_eq!(propose(&mut proposals), 0);
+        assert_eq!(propose(&mut proposals), 1);
+    }
+}
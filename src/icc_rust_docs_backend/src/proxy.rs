@@ -0,0 +1,95 @@
+use candid::{CandidType, Deserialize, Nat, Principal};
+
+use crate::decode::decode_or_describe;
+use crate::transport::{OutboundCall, Transport};
+
+/// The argument of a relay's `relay` method: the call that the relay should make for us, with
+/// Candid-encoded `args`. The relay replies with the raw reply of that call, or with an error
+/// describing why the call failed.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct RelayRequest {
+    pub target: Principal,
+    pub method: String,
+    pub args: Vec<u8>,
+}
+
+/// Asks the relay canister `via` to call `get` on `counter`, and returns the counter's value.
+// With a relay in the middle, a call can fail on either hop, and the two failures mean different
+// things. If the call to the relay fails, we don't know whether the relay made its own call.
+// If the relay's call to the counter fails, the relay still replies to us, with the error in an
+// `Err`: from the system's point of view, our call succeeded. So the relay has to pass its
+// failures on as values, and we report which hop they happened on. The counter only ever sees
+// the relay as its caller; it can't tell that the call was made on our behalf, let alone on
+// behalf of our caller, which is why delegation doesn't work for methods that check the caller.
+pub async fn proxy_get_via<T: Transport>(transport: &T, via: Principal, counter: Principal) -> Result<Nat, String> {
+    let request = RelayRequest {
+        target: counter,
+        method: "get".to_string(),
+        args: candid::encode_args(()).unwrap(),
+    };
+    let reply = transport
+        .call(OutboundCall::untrusted(via, "relay", candid::encode_one(&request).unwrap()))
+        .await
+        .map_err(|e| format!("Error calling relay {}: {:?}", via, e))?;
+    let relayed = decode_or_describe::<Result<Vec<u8>, String>>(&reply)
+        .map_err(|e| format!("Unexpected reply from relay {}: {}", via, e))?
+        .map_err(|e| format!("Relay {} failed to call get on {}: {}", via, counter, e))?;
+    decode_or_describe::<Nat>(&relayed).map_err(|e| format!("Unexpected reply from counter {}: {}", counter, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::mock::MockTransport;
+    use crate::transport::CallFailure;
+    use futures::executor::block_on;
+    use ic_cdk::call::RejectCode;
+
+    fn relay() -> Principal {
+        Principal::from_slice(&[3; 10])
+    }
+
+    fn counter() -> Principal {
+        Principal::from_slice(&[4; 10])
+    }
+
+    #[test]
+    fn test_relay_passes_the_counters_reply_on() {
+        let transport = MockTransport::new();
+        let counter_reply = candid::encode_one(Nat::from(42_u64)).unwrap();
+        transport.reply(&Ok::<Vec<u8>, String>(counter_reply));
+
+        let result = block_on(proxy_get_via(&transport, relay(), counter()));
+
+        assert_eq!(result, Ok(Nat::from(42_u64)));
+        let calls = transport.calls();
+        assert_eq!((calls[0].target, calls[0].method.as_str()), (relay(), "relay"));
+        let request: RelayRequest = candid::decode_one(&calls[0].args).unwrap();
+        assert_eq!((request.target, request.method.as_str()), (counter(), "get"));
+    }
+
+    #[test]
+    fn test_failure_on_the_second_hop_names_the_counter() {
+        let transport = MockTransport::new();
+        transport.reply(&Err::<Vec<u8>, String>("Canister not found".to_string()));
+
+        let error = block_on(proxy_get_via(&transport, relay(), counter())).unwrap_err();
+
+        assert!(error.contains("failed to call get on"), "{}", error);
+        assert!(error.contains("Canister not found"), "{}", error);
+    }
+
+    #[test]
+    fn test_failure_on_the_first_hop_names_the_relay() {
+        let transport = MockTransport::new();
+        transport.fail(CallFailure::Rejected {
+            code: RejectCode::DestinationInvalid,
+            message: "Canister not found".to_string(),
+            sync: true,
+        });
+
+        let error = block_on(proxy_get_via(&transport, relay(), counter())).unwrap_err();
+
+        assert!(error.starts_with(&format!("Error calling relay {}", relay())), "{}", error);
+    }
+}
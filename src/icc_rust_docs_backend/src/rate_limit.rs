@@ -0,0 +1,107 @@
+use candid::Principal;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use crate::error::AppError;
+
+/// The number of calls a caller can make in a burst.
+pub const BUCKET_CAPACITY: u32 = 5;
+
+/// How often a caller earns back one call.
+pub const REFILL_INTERVAL: Duration = Duration::from_secs(10);
+
+// One token bucket per caller. A caller that hasn't called in a while has a full bucket, and each
+// call takes out one token.
+struct Bucket {
+    tokens: u32,
+    // The time at which the next token is counted from, in nanoseconds since the epoch.
+    last_refill: u64,
+}
+
+/// Rate limits callers with a token bucket each, refilled based on the IC time.
+#[derive(Default)]
+pub struct RateLimiter {
+    buckets: BTreeMap<Principal, Bucket>,
+}
+
+thread_local! {
+    pub static RATE_LIMITER: RefCell<RateLimiter> = RefCell::new(RateLimiter::default());
+}
+
+impl RateLimiter {
+    /// Takes a token from `caller`'s bucket, or tells it how long to wait if the bucket is empty.
+    pub fn check(&mut self, caller: Principal, now: u64) -> Result<(), AppError> {
+        let interval = REFILL_INTERVAL.as_nanos() as u64;
+        let bucket = self.buckets.entry(caller).or_insert(Bucket {
+            tokens: BUCKET_CAPACITY,
+            last_refill: now,
+        });
+        let refills = now.saturating_sub(bucket.last_refill) / interval;
+        if refills > 0 {
+            bucket.tokens = (bucket.tokens as u64 + refills).min(BUCKET_CAPACITY as u64) as u32;
+            // Keep the time already spent towards the next token, unless the bucket is full.
+            bucket.last_refill = if bucket.tokens == BUCKET_CAPACITY {
+                now
+            } else {
+                bucket.last_refill + refills * interval
+            };
+        }
+        if bucket.tokens == 0 {
+            let retry_after = bucket.last_refill + interval - now;
+            return Err(AppError::RateLimited {
+                retry_after_secs: Duration::from_nanos(retry_after).as_secs_f64().ceil() as u64,
+            });
+        }
+        if bucket.tokens == BUCKET_CAPACITY {
+            // The next token only counts from the moment one is missing.
+            bucket.last_refill = now;
+        }
+        bucket.tokens -= 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECOND: u64 = 1_000_000_000;
+
+    fn caller(n: u8) -> Principal {
+        Principal::from_slice(&[n; 29])
+    }
+
+    #[test]
+    fn test_burst_beyond_capacity_is_rejected() {
+        let mut limiter = RateLimiter::default();
+        for _ in 0..BUCKET_CAPACITY {
+            assert_eq!(limiter.check(caller(1), 0), Ok(()));
+        }
+        assert_eq!(
+            limiter.check(caller(1), SECOND),
+            Err(AppError::RateLimited {
+                retry_after_secs: 9
+            })
+        );
+        // Other callers have their own bucket.
+        assert_eq!(limiter.check(caller(2), SECOND), Ok(()));
+    }
+
+    #[test]
+    fn test_bucket_refills_over_time() {
+        let mut limiter = RateLimiter::default();
+        for _ in 0..BUCKET_CAPACITY {
+            limiter.check(caller(1), 0).unwrap();
+        }
+        assert!(limiter.check(caller(1), 9 * SECOND).is_err());
+        // One token is back after one interval, but only one.
+        assert_eq!(limiter.check(caller(1), 10 * SECOND), Ok(()));
+        assert!(limiter.check(caller(1), 11 * SECOND).is_err());
+        // After a long pause, the bucket is full again, but doesn't overflow.
+        for _ in 0..BUCKET_CAPACITY {
+            assert_eq!(limiter.check(caller(1), 1_000 * SECOND), Ok(()));
+        }
+        assert!(limiter.check(caller(1), 1_000 * SECOND).is_err());
+    }
+}
@@ -0,0 +1,275 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk::call::RejectCode;
+use std::cell::Cell;
+use std::time::Duration;
+
+use crate::error::AppError;
+use crate::transport::{CallFailure, OutboundCall, Transport};
+
+/// How often, and for how long, the ICRC-1 examples retry a call that failed in a retryable way.
+// Attempts and time limit different things: the attempts bound the cycles we spend on a ledger
+// that keeps failing, and the deadline bounds how long a user waits for an answer (and how long
+// our canister can't stop because of an open call context). We give up on whichever comes first.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// How many times to try a call, including the first attempt.
+    pub max_attempts: u32,
+    /// How long after the first attempt to stop retrying, in seconds.
+    pub deadline_secs: u64,
+    /// How long to wait before the first retry, in milliseconds. Every later retry waits twice as
+    /// long as the one before.
+    pub base_delay_ms: u64,
+    /// The longest we ever wait between two attempts, in milliseconds.
+    pub max_delay_ms: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 10,
+            deadline_secs: 60,
+            base_delay_ms: 100,
+            max_delay_ms: 5_000,
+        }
+    }
+}
+
+thread_local! {
+    // The policy that the endpoints retry with; controllers change it with `set_retry_config`.
+    static CURRENT: Cell<RetryPolicy> = Cell::new(RetryPolicy::default());
+}
+
+/// The policy that the endpoints currently retry with.
+pub fn current() -> RetryPolicy {
+    CURRENT.with(|c| c.get())
+}
+
+/// Makes the endpoints retry with `policy` from now on, unless it's nonsensical.
+pub fn set_current(policy: RetryPolicy) -> Result<(), String> {
+    policy.validate()?;
+    CURRENT.with(|c| c.set(policy));
+    Ok(())
+}
+
+impl RetryPolicy {
+    // A policy without attempts would fail every call without making it, and one whose first
+    // delay exceeds the cap is most likely a typo.
+    fn validate(&self) -> Result<(), String> {
+        if self.max_attempts == 0 {
+            return Err("The retry policy must allow at least one attempt".to_string());
+        }
+        if self.base_delay_ms > self.max_delay_ms {
+            return Err(format!(
+                "The base delay of {} ms exceeds the maximum delay of {} ms",
+                self.base_delay_ms, self.max_delay_ms
+            ));
+        }
+        Ok(())
+    }
+
+    /// The wait before the next attempt, after `attempts` attempts that started at `started_at`,
+    /// or `None` if the policy allows no further attempt. Times are in nanoseconds, like
+    /// `ic_cdk::api::time()`.
+    pub fn next_delay(&self, attempts: u32, started_at: u64, now: u64) -> Option<Duration> {
+        if attempts >= self.max_attempts {
+            return None;
+        }
+        let exponent = attempts.saturating_sub(1).min(63);
+        let delay_ms = self
+            .base_delay_ms
+            .saturating_mul(1_u64 << exponent)
+            .min(self.max_delay_ms);
+        // No point in waiting if the deadline passes before we can try again.
+        let deadline = started_at.saturating_add(self.deadline_secs.saturating_mul(1_000_000_000));
+        if now.saturating_add(delay_ms.saturating_mul(1_000_000)) > deadline {
+            return None;
+        }
+        Some(Duration::from_millis(delay_ms))
+    }
+
+    /// Waits before the next attempt, or fails with `RetriesExhausted` if there shouldn't be one.
+    pub async fn backoff<T: Transport>(&self, transport: &T, attempts: u32, started_at: u64) -> Result<(), AppError> {
+        match self.next_delay(attempts, started_at, transport.time()) {
+            Some(delay) => {
+                transport.sleep(delay).await;
+                Ok(())
+            }
+            None => Err(AppError::RetriesExhausted { attempts }),
+        }
+    }
+}
+
+/// Returns true if a call that doesn't change any state should be retried after `failure`.
+// For reads, unlike for transfers, we don't care whether an attempt executed, so we can retry
+// every failure that may be temporary: transient rejects, unknown outcomes, and a callee that is
+// stopped, e.g., for an upgrade. Errors that retrying won't fix are returned right away.
+pub fn is_retryable_read_failure(failure: &CallFailure) -> bool {
+    matches!(
+        failure,
+        CallFailure::SysUnknown(_)
+            | CallFailure::Rejected {
+                code: RejectCode::SysTransient,
+                ..
+            }
+    ) || failure.is_callee_stopped()
+}
+
+/// Makes `call`, which must not change any state, such as a call to a query method, and retries
+/// it with `policy` while it fails in a way that may go away (see `is_retryable_read_failure`).
+/// Returns the raw reply.
+pub async fn call_read_only<T: Transport>(
+    transport: &T,
+    policy: &RetryPolicy,
+    call: OutboundCall,
+) -> Result<Vec<u8>, String> {
+    let started_at = transport.time();
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        match transport.call(call.clone()).await {
+            Ok(reply) => return Ok(reply),
+            Err(failure) if failure.invalid_target(call.target).is_some() => {
+                return Err(AppError::InvalidTarget { target: call.target }.into())
+            }
+            Err(failure) if is_retryable_read_failure(&failure) => {
+                policy.backoff(transport, attempts, started_at).await?;
+            }
+            Err(failure) => return Err(format!("Error calling {}: {:?}", call.method, failure)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::mock::{sys_unknown, MockTransport};
+    use candid::Principal;
+    use futures::executor::block_on;
+
+    const SECOND: u64 = 1_000_000_000;
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 5,
+            deadline_secs: 10,
+            base_delay_ms: 1_000,
+            max_delay_ms: 3_000,
+        }
+    }
+
+    #[test]
+    fn test_delay_doubles_up_to_the_cap() {
+        let delays: Vec<_> = (1..5).map(|a| policy().next_delay(a, 0, 0).unwrap()).collect();
+        let expected: Vec<_> = [1, 2, 3, 3].into_iter().map(Duration::from_secs).collect();
+        assert_eq!(delays, expected);
+    }
+
+    #[test]
+    fn test_max_attempts_ends_retries_before_the_deadline() {
+        assert!(policy().next_delay(4, 0, 0).is_some());
+        assert_eq!(policy().next_delay(5, 0, 0), None);
+    }
+
+    #[test]
+    fn test_deadline_ends_retries_before_max_attempts() {
+        // After the first attempt, the retry would start at 9 + 1 seconds, right at the deadline.
+        assert!(policy().next_delay(1, 0, 9 * SECOND).is_some());
+        assert_eq!(policy().next_delay(1, 0, 9 * SECOND + 1), None);
+        // The same holds for a later start.
+        assert_eq!(policy().next_delay(2, 100 * SECOND, 109 * SECOND), None);
+    }
+
+    #[test]
+    fn test_current_reflects_a_new_policy() {
+        assert_eq!(current(), RetryPolicy::default());
+
+        assert_eq!(set_current(policy()), Ok(()));
+
+        assert_eq!(current(), policy());
+    }
+
+    #[test]
+    fn test_nonsensical_policies_are_rejected() {
+        let no_attempts = RetryPolicy {
+            max_attempts: 0,
+            ..policy()
+        };
+        assert!(set_current(no_attempts).is_err());
+        let inverted = RetryPolicy {
+            base_delay_ms: 5_000,
+            ..policy()
+        };
+        assert!(set_current(inverted).unwrap_err().contains("exceeds"));
+        assert_eq!(current(), RetryPolicy::default());
+    }
+
+    #[test]
+    fn test_zero_base_delay_retries_immediately() {
+        let policy = RetryPolicy {
+            base_delay_ms: 0,
+            ..policy()
+        };
+        assert_eq!(policy.next_delay(3, 0, 0), Some(Duration::ZERO));
+    }
+
+    fn rejected(code: RejectCode, sync: bool, message: &str) -> CallFailure {
+        CallFailure::Rejected {
+            code,
+            message: message.to_string(),
+            sync,
+        }
+    }
+
+    #[test]
+    fn test_retryable_read_failures() {
+        let stopped = "Canister aaaaa-aa is stopped";
+        let cases = [
+            (rejected(RejectCode::SysFatal, true, "fatal"), false),
+            (rejected(RejectCode::SysFatal, false, "fatal"), false),
+            (rejected(RejectCode::SysTransient, true, "busy"), true),
+            (rejected(RejectCode::SysTransient, false, "busy"), true),
+            (rejected(RejectCode::DestinationInvalid, true, "no such canister"), false),
+            (rejected(RejectCode::DestinationInvalid, false, "no such canister"), false),
+            (rejected(RejectCode::CanisterReject, false, "not allowed"), false),
+            (rejected(RejectCode::CanisterReject, false, stopped), true),
+            (rejected(RejectCode::CanisterError, false, "trapped"), false),
+            (rejected(RejectCode::CanisterError, false, stopped), true),
+            (rejected(RejectCode::SysUnknown, false, "deadline expired"), false),
+            (CallFailure::CanisterError("Canister trapped".to_string()), false),
+            (CallFailure::CanisterError(stopped.to_string()), true),
+            (CallFailure::SysUnknown("deadline expired".to_string()), true),
+        ];
+        for (failure, expected) in cases {
+            assert_eq!(is_retryable_read_failure(&failure), expected, "{:?}", failure);
+        }
+    }
+
+    fn read(transport: &MockTransport) -> Result<Vec<u8>, String> {
+        let call = OutboundCall::untrusted(Principal::anonymous(), "list_neurons", vec![]);
+        block_on(call_read_only(transport, &policy(), call))
+    }
+
+    #[test]
+    fn test_read_only_call_is_retried_until_it_succeeds() {
+        let transport = MockTransport::new();
+        transport.fail(sys_unknown()).reply(&42_u64);
+
+        let reply = read(&transport).unwrap();
+
+        assert_eq!(candid::decode_one::<u64>(&reply).unwrap(), 42);
+        assert_eq!(transport.calls().len(), 2);
+        // The retry waited for the first delay.
+        assert_eq!(transport.now.get(), SECOND);
+    }
+
+    #[test]
+    fn test_read_only_call_isnt_retried_after_a_crash() {
+        let transport = MockTransport::new();
+        transport.fail(CallFailure::CanisterError("Canister trapped".to_string()));
+
+        let error = read(&transport).unwrap_err();
+
+        assert!(error.contains("Canister trapped"), "{}", error);
+        assert_eq!(transport.calls().len(), 1);
+    }
+}
@@ -0,0 +1,307 @@
+//! An in-memory stand-in for the canisters that the examples talk to, enabled by the `simulate`
+//! feature. With it, the transport-based endpoints run without a replica, e.g., in `cargo test`.
+//! Production builds don't include any of this.
+use candid::{CandidType, Nat, Principal};
+use ic_cdk::call::RejectCode;
+use ic_cdk::management_canister::CanisterStatusType;
+use ic_ledger_types::{
+    AccountBalanceArgs, AccountIdentifier, Tokens, TransferArgs, TransferError as IcpTransferError, TransferFee,
+    DEFAULT_SUBACCOUNT,
+};
+use ic_xrc_types::{ExchangeRate, ExchangeRateMetadata, GetExchangeRateRequest, GetExchangeRateResult};
+use icrc_ledger_types::icrc::generic_metadata_value::MetadataValue;
+use icrc_ledger_types::icrc1::account::Account;
+use icrc_ledger_types::icrc1::transfer::{TransferArg, TransferError};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, VecDeque};
+use std::time::Duration;
+
+use crate::nat::{nat_add, nat_sub_checked};
+use crate::transport::{CallFailure, OutboundCall, Transport};
+
+/// The canister ID that the simulation uses for ourselves.
+pub const SIMULATED_SELF: Principal = Principal::from_slice(&[0xca, 0xfe]);
+
+/// The number of decimals of the simulated token.
+pub const SIMULATED_DECIMALS: u8 = 8;
+
+/// The fee of the simulated ICP ledger.
+pub const SIMULATED_ICP_FEE: Tokens = Tokens::from_e8s(10_000);
+
+/// The rate that the simulated XRC reports for every pair of assets, with 9 decimals.
+pub const SIMULATED_RATE: u64 = 10_000_000_000;
+
+/// The state of the simulated world: a single ICRC-1 ledger, a single ICP ledger, the XRC, the
+/// management canister and a single counter. Calls are dispatched on the method name, whatever
+/// the target.
+struct World {
+    now: u64,
+    fee: Nat,
+    balances: BTreeMap<Account, Nat>,
+    // The transfers executed so far, in block order, to detect duplicates.
+    transfers: Vec<(Account, TransferArg)>,
+    icp_balances: BTreeMap<AccountIdentifier, Tokens>,
+    // The number of ICP transfers executed so far.
+    icp_blocks: u64,
+    counter: Nat,
+    // Failures to inject instead of executing the next calls to a method, by method name.
+    failures: BTreeMap<String, VecDeque<CallFailure>>,
+}
+
+thread_local! {
+    static WORLD: RefCell<World> = RefCell::new(World {
+        now: 1_700_000_000_000_000_000,
+        fee: Nat::from(10_000_u64),
+        balances: BTreeMap::new(),
+        transfers: Vec::new(),
+        icp_balances: BTreeMap::new(),
+        icp_blocks: 0,
+        counter: Nat::from(0_u64),
+        failures: BTreeMap::new(),
+    });
+}
+
+/// A transport whose calls are served by the simulated world.
+pub struct SimulatedTransport;
+
+// Only the tests set up and inspect the world; the endpoints see it through the transport.
+#[cfg(test)]
+impl SimulatedTransport {
+    /// Our own default account on the simulated ledger.
+    pub fn own_account() -> Account {
+        Account {
+            owner: SIMULATED_SELF,
+            subaccount: None,
+        }
+    }
+
+    pub fn set_balance(account: Account, amount: Nat) {
+        WORLD.with(|w| w.borrow_mut().balances.insert(account, amount));
+    }
+
+    pub fn balance(account: &Account) -> Nat {
+        WORLD.with(|w| w.borrow().balances.get(account).cloned().unwrap_or_default())
+    }
+
+    /// Our own default account on the simulated ICP ledger.
+    pub fn own_icp_account() -> AccountIdentifier {
+        AccountIdentifier::new(&SIMULATED_SELF, &DEFAULT_SUBACCOUNT)
+    }
+
+    pub fn set_icp_balance(account: AccountIdentifier, amount: Tokens) {
+        WORLD.with(|w| w.borrow_mut().icp_balances.insert(account, amount));
+    }
+
+    pub fn icp_balance(account: &AccountIdentifier) -> Tokens {
+        WORLD.with(|w| {
+            w.borrow()
+                .icp_balances
+                .get(account)
+                .copied()
+                .unwrap_or(Tokens::from_e8s(0))
+        })
+    }
+
+    /// Makes the next call to `method` fail with `failure` instead of executing.
+    pub fn fail_next(method: &str, failure: CallFailure) {
+        WORLD.with(|w| {
+            w.borrow_mut()
+                .failures
+                .entry(method.to_string())
+                .or_default()
+                .push_back(failure)
+        });
+    }
+}
+
+fn encode<R: CandidType>(reply: &R) -> Result<Vec<u8>, CallFailure> {
+    Ok(candid::encode_one(reply).expect("Failed to encode the simulated reply"))
+}
+
+// The parts of a `canister_status` reply that we read, see `management.rs`.
+#[derive(CandidType)]
+struct SimulatedStatus {
+    status: CanisterStatusType,
+    module_hash: Option<Vec<u8>>,
+    memory_size: Nat,
+    cycles: Nat,
+    idle_cycles_burned_per_day: Nat,
+    settings: SimulatedSettings,
+}
+
+#[derive(CandidType)]
+struct SimulatedSettings {
+    controllers: Vec<Principal>,
+}
+
+fn decode<A: CandidType + for<'de> candid::Deserialize<'de>>(args: &[u8]) -> Result<A, CallFailure> {
+    // A real canister would reject arguments it can't decode, and so do we.
+    candid::decode_one(args).map_err(|e| CallFailure::Rejected {
+        code: RejectCode::CanisterReject,
+        message: format!("Failed to decode the arguments: {}", e),
+        sync: false,
+    })
+}
+
+impl World {
+    fn handle(&mut self, call: &OutboundCall) -> Result<Vec<u8>, CallFailure> {
+        match call.method.as_str() {
+            "icrc1_fee" => encode(&self.fee),
+            "icrc1_metadata" => encode(&vec![
+                ("icrc1:decimals".to_string(), MetadataValue::Nat(SIMULATED_DECIMALS.into())),
+                ("icrc1:fee".to_string(), MetadataValue::Nat(self.fee.clone())),
+            ]),
+            "icrc1_balance_of" => {
+                let account: Account = decode(&call.args)?;
+                encode(&self.balances.get(&account).cloned().unwrap_or_default())
+            }
+            "icrc1_transfer" => {
+                let arg: TransferArg = decode(&call.args)?;
+                encode(&self.transfer(arg))
+            }
+            "transfer_fee" => encode(&TransferFee {
+                transfer_fee: SIMULATED_ICP_FEE,
+            }),
+            "account_balance" => {
+                let args: AccountBalanceArgs = decode(&call.args)?;
+                encode(&self.icp_balances.get(&args.account).copied().unwrap_or(Tokens::from_e8s(0)))
+            }
+            "transfer" => {
+                let args: TransferArgs = decode(&call.args)?;
+                encode(&self.icp_transfer(args))
+            }
+            "get_exchange_rate" => {
+                let request: GetExchangeRateRequest = decode(&call.args)?;
+                encode(&self.exchange_rate(request))
+            }
+            // Every canister we ask about is running, and controlled by us.
+            "canister_status" => encode(&SimulatedStatus {
+                status: CanisterStatusType::Running,
+                module_hash: None,
+                memory_size: Nat::from(0_u64),
+                cycles: Nat::from(0_u64),
+                idle_cycles_burned_per_day: Nat::from(0_u64),
+                settings: SimulatedSettings {
+                    controllers: vec![SIMULATED_SELF],
+                },
+            }),
+            "deposit_cycles" => encode(&()),
+            "get" => encode(&self.counter),
+            "set" => {
+                self.counter = decode(&call.args)?;
+                encode(&())
+            }
+            "increment" => {
+                self.counter += 1_u32;
+                encode(&())
+            }
+            method => Err(CallFailure::Rejected {
+                code: RejectCode::DestinationInvalid,
+                message: format!("The simulation has no method {}", method),
+                sync: false,
+            }),
+        }
+    }
+
+    fn transfer(&mut self, arg: TransferArg) -> Result<Nat, TransferError> {
+        let from = Account {
+            owner: SIMULATED_SELF,
+            subaccount: arg.from_subaccount,
+        };
+        if let Some(i) = self
+            .transfers
+            .iter()
+            .position(|(f, a)| *f == from && *a == arg && a.created_at_time.is_some())
+        {
+            return Err(TransferError::Duplicate {
+                duplicate_of: Nat::from(i),
+            });
+        }
+        if arg.fee.as_ref().is_some_and(|fee| *fee != self.fee) {
+            return Err(TransferError::BadFee {
+                expected_fee: self.fee.clone(),
+            });
+        }
+        let balance = self.balances.get(&from).cloned().unwrap_or_default();
+        let debit = nat_add(&arg.amount, &self.fee);
+        let Ok(remaining) = nat_sub_checked(&balance, &debit) else {
+            return Err(TransferError::InsufficientFunds { balance });
+        };
+        self.balances.insert(from, remaining);
+        *self.balances.entry(arg.to).or_default() += arg.amount.clone();
+        self.transfers.push((from, arg));
+        Ok(Nat::from(self.transfers.len() - 1))
+    }
+
+    // Like the real ICP ledger without `created_at_time`, this doesn't deduplicate transfers.
+    fn icp_transfer(&mut self, args: TransferArgs) -> Result<u64, IcpTransferError> {
+        let from = AccountIdentifier::new(&SIMULATED_SELF, &args.from_subaccount.unwrap_or(DEFAULT_SUBACCOUNT));
+        if args.fee != SIMULATED_ICP_FEE {
+            return Err(IcpTransferError::BadFee {
+                expected_fee: SIMULATED_ICP_FEE,
+            });
+        }
+        let balance = self.icp_balances.get(&from).copied().unwrap_or(Tokens::from_e8s(0));
+        let debit = args.amount.e8s().saturating_add(args.fee.e8s());
+        let Some(remaining) = balance.e8s().checked_sub(debit) else {
+            return Err(IcpTransferError::InsufficientFunds { balance });
+        };
+        self.icp_balances.insert(from, Tokens::from_e8s(remaining));
+        let to = self.icp_balances.entry(args.to).or_insert(Tokens::from_e8s(0));
+        *to = Tokens::from_e8s(to.e8s() + args.amount.e8s());
+        self.icp_blocks += 1;
+        Ok(self.icp_blocks - 1)
+    }
+
+    fn exchange_rate(&self, request: GetExchangeRateRequest) -> GetExchangeRateResult {
+        Ok(ExchangeRate {
+            base_asset: request.base_asset,
+            quote_asset: request.quote_asset,
+            timestamp: request.timestamp.unwrap_or(self.now / 1_000_000_000),
+            rate: SIMULATED_RATE,
+            metadata: ExchangeRateMetadata {
+                decimals: 9,
+                base_asset_num_queried_sources: 1,
+                base_asset_num_received_rates: 1,
+                quote_asset_num_queried_sources: 1,
+                quote_asset_num_received_rates: 1,
+                standard_deviation: 0,
+                forex_timestamp: None,
+            },
+        })
+    }
+}
+
+impl Transport for SimulatedTransport {
+    async fn call(&self, call: OutboundCall) -> Result<Vec<u8>, CallFailure> {
+        WORLD.with(|w| {
+            let mut world = w.borrow_mut();
+            let failure = world
+                .failures
+                .get_mut(&call.method)
+                .and_then(|failures| failures.pop_front());
+            match failure {
+                Some(failure) => Err(failure),
+                None => world.handle(&call),
+            }
+        })
+    }
+
+    fn time(&self) -> u64 {
+        WORLD.with(|w| w.borrow().now)
+    }
+
+    fn canister_self(&self) -> Principal {
+        SIMULATED_SELF
+    }
+
+    // Simulated time passes only when someone sleeps.
+    async fn sleep(&self, duration: Duration) {
+        WORLD.with(|w| w.borrow_mut().now += duration.as_nanos() as u64);
+    }
+
+    // None of the simulated canisters charge cycles.
+    fn cycles_refunded(&self) -> u128 {
+        0
+    }
+}
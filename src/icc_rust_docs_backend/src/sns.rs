@@ -0,0 +1,135 @@
+use candid::{CandidType, Deserialize, Principal};
+
+use crate::decode::decode_or_describe;
+use crate::retry::{call_read_only, RetryPolicy};
+use crate::transport::{OutboundCall, Transport};
+
+/// How many neurons we ask an SNS governance canister for per call. The canister caps the page
+/// size itself (at 100 by default), so asking for more wouldn't save calls.
+pub const NEURONS_PER_PAGE: u32 = 100;
+
+/// The ID of an SNS neuron, which, unlike an NNS neuron ID, is a blob.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct NeuronId {
+    pub id: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct ListNeurons {
+    of_principal: Option<Principal>,
+    limit: u32,
+    start_page_at: Option<NeuronId>,
+}
+
+// Like for NNS proposals, we only declare the fields we read; the decoder skips the others.
+#[derive(CandidType, Deserialize)]
+struct Neuron {
+    id: Option<NeuronId>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct ListNeuronsResponse {
+    neurons: Vec<Neuron>,
+}
+
+/// Returns the IDs of the neurons on which `of` has any permission, according to the SNS
+/// governance canister `governance`. Every SNS has its own governance canister; its ID is listed
+/// by the SNS root canister, and by the NNS's SNS-W canister.
+// The governance canister returns the neurons in pages, ordered by ID, so we keep asking for the
+// page that starts after the last neuron we got, until we get a short page. Listing neurons
+// doesn't change anything, so each page is retried like any read-only call.
+pub async fn list_neurons_via<T: Transport>(
+    transport: &T,
+    policy: &RetryPolicy,
+    governance: Principal,
+    of: Principal,
+) -> Result<Vec<NeuronId>, String> {
+    let mut ids = Vec::new();
+    let mut start_page_at = None;
+    loop {
+        let arg = ListNeurons {
+            of_principal: Some(of),
+            limit: NEURONS_PER_PAGE,
+            start_page_at,
+        };
+        let call = OutboundCall::untrusted(governance, "list_neurons", candid::encode_one(&arg).unwrap());
+        let reply = call_read_only(transport, policy, call).await?;
+        let page = decode_or_describe::<ListNeuronsResponse>(&reply)
+            .map_err(|e| format!("Error calling list_neurons: {}", e))?;
+        let full_page = page.neurons.len() >= NEURONS_PER_PAGE as usize;
+        ids.extend(page.neurons.into_iter().filter_map(|neuron| neuron.id));
+        start_page_at = ids.last().cloned();
+        if !full_page || start_page_at.is_none() {
+            return Ok(ids);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::mock::MockTransport;
+    use futures::executor::block_on;
+
+    // A neuron with a few more of the fields that the real one has.
+    #[derive(CandidType)]
+    struct FullNeuron {
+        id: Option<NeuronId>,
+        cached_neuron_stake_e8s: u64,
+        created_timestamp_seconds: u64,
+    }
+
+    fn neuron_id(n: u8) -> NeuronId {
+        NeuronId { id: vec![n; 32] }
+    }
+
+    #[derive(CandidType)]
+    struct FullNeuronsResponse {
+        neurons: Vec<FullNeuron>,
+    }
+
+    fn page(ids: impl Iterator<Item = u8>) -> FullNeuronsResponse {
+        FullNeuronsResponse {
+            neurons: ids
+                .map(|n| FullNeuron {
+                    id: Some(neuron_id(n)),
+                    cached_neuron_stake_e8s: 100_000_000,
+                    created_timestamp_seconds: 1_700_000_000,
+                })
+                .collect(),
+        }
+    }
+
+    fn list(transport: &MockTransport) -> Result<Vec<NeuronId>, String> {
+        let governance = Principal::from_slice(&[5; 10]);
+        let of = Principal::from_slice(&[6; 29]);
+        block_on(list_neurons_via(transport, &RetryPolicy::default(), governance, of))
+    }
+
+    #[test]
+    fn test_neuron_list_decodes_into_ids() {
+        let transport = MockTransport::new();
+        transport.reply(&page(1..=2));
+
+        let ids = list(&transport);
+
+        assert_eq!(ids, Ok(vec![neuron_id(1), neuron_id(2)]));
+        let arg: ListNeurons = candid::decode_one(&transport.calls()[0].args).unwrap();
+        assert_eq!(arg.of_principal, Some(Principal::from_slice(&[6; 29])));
+        assert_eq!(arg.start_page_at, None);
+    }
+
+    #[test]
+    fn test_full_pages_are_followed_by_the_next_one() {
+        let transport = MockTransport::new();
+        transport.reply(&page(0..100)).reply(&page(100..=100));
+
+        let ids = list(&transport).unwrap();
+
+        assert_eq!(ids.len(), 101);
+        let calls = transport.calls();
+        assert_eq!(calls.len(), 2);
+        let arg: ListNeurons = candid::decode_one(&calls[1].args).unwrap();
+        assert_eq!(arg.start_page_at, Some(neuron_id(99)));
+    }
+}
@@ -0,0 +1,76 @@
+use candid::{Nat, Principal};
+
+use crate::decode::decode_or_describe;
+use crate::transport::{OutboundCall, Transport};
+
+// Calls `get` on `counter` and returns how long it took until the response came back, in
+// nanoseconds. The IC time only changes between messages, so this is the time from the message
+// that issued the call to the one that handles the response.
+async fn time_get<T: Transport>(
+    transport: &T,
+    counter: Principal,
+    bounded_wait: bool,
+) -> Result<u64, String> {
+    let started_at = transport.time();
+    let reply = transport
+        .call(OutboundCall {
+            target: counter,
+            method: "get".to_string(),
+            args: candid::encode_args(()).unwrap(),
+            cycles: 0,
+            bounded_wait,
+            untrusted: false,
+        })
+        .await
+        .map_err(|e| format!("Error calling get: {:?}", e))?;
+    decode_or_describe::<Nat>(&reply).map_err(|e| format!("Error calling get: {}", e))?;
+    Ok(transport.time() - started_at)
+}
+
+/// Times a `get` call on `counter` with a bounded wait, and then one with an unbounded wait.
+/// Returns both durations in nanoseconds, in that order.
+// The calls run one after the other, so that neither slows down the other. Without failures,
+// both kinds of calls take about the same time: the wait mode only makes a difference once
+// something goes wrong, when a bounded wait call may give up early with `SysUnknown`, while an
+// unbounded wait call waits for the outcome, however long it takes.
+pub async fn compare_wait_modes_via<T: Transport>(
+    transport: &T,
+    counter: Principal,
+) -> Result<(u64, u64), String> {
+    let bounded = time_get(transport, counter, true).await?;
+    let unbounded = time_get(transport, counter, false).await?;
+    Ok((bounded, unbounded))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::mock::MockTransport;
+    use futures::executor::block_on;
+
+    #[test]
+    fn test_both_timings_are_recorded() {
+        let transport = MockTransport::new();
+        transport.call_latency.set(2_000_000);
+        transport
+            .reply(&Nat::from(7_u64))
+            .reply(&Nat::from(7_u64));
+
+        let result = block_on(compare_wait_modes_via(&transport, Principal::anonymous()));
+
+        assert_eq!(result, Ok((2_000_000, 2_000_000)));
+        let modes: Vec<bool> = transport.calls().iter().map(|c| c.bounded_wait).collect();
+        assert_eq!(modes, vec![true, false]);
+    }
+
+    #[test]
+    fn test_failed_call_is_an_error() {
+        let transport = MockTransport::new();
+        transport.reply(&"not a number");
+
+        let result = block_on(compare_wait_modes_via(&transport, Principal::anonymous()));
+
+        assert!(result.unwrap_err().contains("Error calling get"));
+        assert_eq!(transport.calls().len(), 1);
+    }
+}
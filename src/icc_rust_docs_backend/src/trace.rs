@@ -0,0 +1,61 @@
+use candid::{CandidType, Deserialize, Principal};
+use std::cell::RefCell;
+use std::time::Duration;
+
+use crate::transport::{CallFailure, OutboundCall, Transport};
+
+/// An outbound call, as recorded by `Traced`.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct TracedCall {
+    pub target: Principal,
+    pub method: String,
+}
+
+thread_local! {
+    // The calls made since the last `reset`, in the order they were issued.
+    static TRACE: RefCell<Vec<TracedCall>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Clears the trace; endpoints that trace their calls do this on entry.
+pub fn reset() {
+    TRACE.with(|t| t.borrow_mut().clear());
+}
+
+/// The calls recorded since the last `reset`, oldest first.
+pub fn calls() -> Vec<TracedCall> {
+    TRACE.with(|t| t.borrow().clone())
+}
+
+/// A transport that records every call in the trace before passing it on to the wrapped one.
+// This is a debugging aid, not an audit log: executions of traced endpoints interleave at every
+// `await`, so if two of them overlap, the trace holds the calls of both, and the second one's
+// `reset` drops those the first one made so far.
+pub struct Traced<T>(pub T);
+
+impl<T: Transport> Transport for Traced<T> {
+    async fn call(&self, call: OutboundCall) -> Result<Vec<u8>, CallFailure> {
+        TRACE.with(|t| {
+            t.borrow_mut().push(TracedCall {
+                target: call.target,
+                method: call.method.clone(),
+            })
+        });
+        self.0.call(call).await
+    }
+
+    fn time(&self) -> u64 {
+        self.0.time()
+    }
+
+    fn canister_self(&self) -> Principal {
+        self.0.canister_self()
+    }
+
+    async fn sleep(&self, duration: Duration) {
+        self.0.sleep(duration).await
+    }
+
+    fn cycles_refunded(&self) -> u128 {
+        self.0.cycles_refunded()
+    }
+}
@@ -0,0 +1,711 @@
+use candid::{CandidType, Deserialize, IDLArgs, Principal};
+use ic_cdk::call::{Call, CallError, RejectCode, StateUnknown};
+use std::cell::{Cell, RefCell};
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+use crate::decode::decode_or_describe;
+use crate::error::AppError;
+
+/// An outbound call, with the arguments already Candid-encoded.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OutboundCall {
+    pub target: Principal,
+    pub method: String,
+    pub args: Vec<u8>,
+    pub cycles: u128,
+    pub bounded_wait: bool,
+    /// Whether the callee is a canister we don't control, see `untrusted_call`.
+    pub untrusted: bool,
+}
+
+impl OutboundCall {
+    /// A call without cycles to a canister we don't control, such as an arbitrary ICRC-1
+    /// ledger. On the IC, it's issued with the `untrusted_call` preset.
+    pub fn untrusted(target: Principal, method: &str, args: Vec<u8>) -> Self {
+        Self {
+            target,
+            method: method.to_string(),
+            args,
+            cycles: 0,
+            bounded_wait: true,
+            untrusted: true,
+        }
+    }
+
+    /// How the call waits for its response. Calls to untrusted callees use `UNTRUSTED_WAIT`.
+    pub fn wait_mode(&self) -> WaitMode {
+        if self.untrusted {
+            UNTRUSTED_WAIT
+        } else if self.bounded_wait {
+            WaitMode::Bounded { timeout_secs: None }
+        } else {
+            WaitMode::Unbounded
+        }
+    }
+}
+
+/// How long a call waits for its response.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WaitMode {
+    /// Until the callee responds, however long that takes.
+    Unbounded,
+    /// Until the callee responds or the timeout expires, whichever comes first. Without
+    /// `timeout_secs`, the system's default timeout applies.
+    Bounded { timeout_secs: Option<u32> },
+}
+
+impl WaitMode {
+    /// Starts building a call to `method` on `target` that waits this way.
+    pub fn call<'m, 'a>(self, target: Principal, method: &'m str) -> Call<'m, 'a> {
+        match self {
+            WaitMode::Unbounded => Call::unbounded_wait(target, method),
+            WaitMode::Bounded { timeout_secs: None } => Call::bounded_wait(target, method),
+            WaitMode::Bounded {
+                timeout_secs: Some(secs),
+            } => Call::bounded_wait(target, method).change_timeout(secs),
+        }
+    }
+}
+
+/// Why a call didn't produce a reply. This mirrors `CallError`, minus the decoding step, which
+/// is left to the caller.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CallFailure {
+    Rejected {
+        code: RejectCode,
+        message: String,
+        sync: bool,
+    },
+    CanisterError(String),
+    /// The system gave up waiting for the reply of a bounded wait call. `ic_cdk` reports this as
+    /// `StateUnknown::SysUnknown`, whose payload describes what the system knows, such as the
+    /// reject message it produced; we keep its description.
+    SysUnknown(String),
+}
+
+impl From<CallError> for CallFailure {
+    fn from(e: CallError) -> Self {
+        match e {
+            CallError::CallRejected(rejection) => CallFailure::Rejected {
+                code: rejection.reject_code(),
+                message: rejection.reject_message().to_string(),
+                sync: rejection.is_sync(),
+            },
+            CallError::StateUnknown(StateUnknown::CanisterError(err)) => {
+                CallFailure::CanisterError(format!("{:?}", err))
+            }
+            CallError::StateUnknown(StateUnknown::SysUnknown(e)) => {
+                CallFailure::SysUnknown(format!("{:?}", e))
+            }
+            // Only calls that decode the reply for us fail this way; our transports ask for the
+            // raw reply. The callee did reply, so like a trap, the call may have taken effect.
+            CallError::StateUnknown(StateUnknown::CandidDecodeFailed(msg)) => {
+                CallFailure::CanisterError(format!("Unable to decode the reply: {}", msg))
+            }
+        }
+    }
+}
+
+impl CallFailure {
+    /// Returns true if the call failed because the callee doesn't have enough cycles to process
+    /// it. The call was never executed in that case.
+    // The system doesn't have a dedicated reject code for this, so we recognize it by the
+    // message. Depending on where the check fails, the reject is reported as a canister error or
+    // as a canister reject.
+    pub fn is_callee_out_of_cycles(&self) -> bool {
+        let message = match self {
+            CallFailure::Rejected {
+                code: RejectCode::CanisterError | RejectCode::CanisterReject,
+                message,
+                ..
+            } => message,
+            CallFailure::CanisterError(message) => message,
+            _ => return false,
+        };
+        message.to_lowercase().contains("out of cycles")
+    }
+
+    /// Returns true if the call was rejected because the callee is stopped or stopping, and so
+    /// never executed it.
+    // Controllers stop a canister before upgrading it, so that no calls are in flight during the
+    // upgrade, and start it again right after; in between, the system rejects every call with
+    // "Canister ... is stopped" or "... is stopping". Nothing in the reject tells an upgrade
+    // window from a canister that was stopped for good, so we go by time: the callers retry with
+    // backoff, and only if the canister stays stopped beyond their retry policy do they treat it
+    // as stopped for good. Like for running out of cycles, there's no dedicated reject code, so
+    // we recognize the reject by its message.
+    pub fn is_callee_stopped(&self) -> bool {
+        let message = match self {
+            CallFailure::Rejected {
+                code: RejectCode::CanisterError | RejectCode::CanisterReject,
+                message,
+                ..
+            } => message,
+            CallFailure::CanisterError(message) => message,
+            _ => return false,
+        };
+        let message = message.to_lowercase();
+        message.contains("is stopped") || message.contains("is stopping")
+    }
+
+    /// Returns the error to report if the call was rejected because its target doesn't exist
+    /// (e.g., a mistyped principal) or has no code installed.
+    // The system rejects such calls with `DestinationInvalid` without executing anything. Unlike
+    // transient rejects, this won't go away by retrying, but unlike most other errors, the user
+    // can fix it, so it gets its own error.
+    pub fn invalid_target(&self, target: Principal) -> Option<AppError> {
+        match self {
+            CallFailure::Rejected {
+                code: RejectCode::DestinationInvalid,
+                ..
+            } => Some(AppError::InvalidTarget { target }),
+            _ => None,
+        }
+    }
+
+    /// Returns the error to report if the call failed because the reply of `method` was too
+    /// large: either larger than we accept from an untrusted callee (see `check_reply_size`),
+    /// or larger than the system allows any reply to be, which makes the callee trap.
+    pub fn reply_too_large(&self, target: Principal, method: &str) -> Option<AppError> {
+        let message = match self {
+            CallFailure::CanisterError(message) => message,
+            CallFailure::Rejected {
+                code: RejectCode::CanisterError,
+                message,
+                ..
+            } => message,
+            _ => return None,
+        };
+        (message.starts_with(REPLY_TOO_LARGE) || message.contains("payload size"))
+            .then(|| AppError::ReplyTooLarge {
+                target,
+                method: method.to_string(),
+            })
+    }
+}
+
+/// Turns the number of a reject code, as the interface specification assigns them, back into a
+/// `RejectCode`; see `reject_code_to_raw`.
+// Our error messages give reject codes as numbers, which frontends can store and compare without
+// depending on how the Rust CDK spells the variants.
+pub fn parse_reject_code(raw: i32) -> Result<RejectCode, String> {
+    match raw {
+        1 => Ok(RejectCode::SysFatal),
+        2 => Ok(RejectCode::SysTransient),
+        3 => Ok(RejectCode::DestinationInvalid),
+        4 => Ok(RejectCode::CanisterReject),
+        5 => Ok(RejectCode::CanisterError),
+        6 => Ok(RejectCode::SysUnknown),
+        _ => Err(format!("Unknown reject code {}", raw)),
+    }
+}
+
+/// The number of `code` in the interface specification.
+pub fn reject_code_to_raw(code: RejectCode) -> i32 {
+    match code {
+        RejectCode::SysFatal => 1,
+        RejectCode::SysTransient => 2,
+        RejectCode::DestinationInvalid => 3,
+        RejectCode::CanisterReject => 4,
+        RejectCode::CanisterError => 5,
+        RejectCode::SysUnknown => 6,
+    }
+}
+
+/// Describes a reject for users, giving the code both as a number and by name.
+pub fn describe_reject(code: RejectCode, message: &str) -> String {
+    format!("reject code {} ({:?}): {}", reject_code_to_raw(code), code, message)
+}
+
+/// The way our helpers talk to the outside world.
+// All outbound calls go through this trait, so that tests can replace the IC with a scripted fake
+// and check which calls were made, and so that the `simulate` feature can replace it with an
+// in-memory one. `IcTransport` shows how each call is issued with `Call`.
+pub trait Transport {
+    /// Issues the call and returns the raw Candid-encoded reply.
+    async fn call(&self, call: OutboundCall) -> Result<Vec<u8>, CallFailure>;
+
+    /// The current IC time, in nanoseconds since the epoch. It lives here so that tests control
+    /// time together with the replies.
+    fn time(&self) -> u64;
+
+    /// Our own canister ID, for self-calls.
+    fn canister_self(&self) -> Principal;
+
+    /// Waits for `duration` before resuming, e.g., to back off between retries.
+    async fn sleep(&self, duration: Duration);
+
+    /// The cycles refunded by the callees of the calls made through this transport so far.
+    fn cycles_refunded(&self) -> u128;
+}
+
+// Lets helpers that hold a transport, such as `Icrc1Ledger`, borrow one that someone else owns.
+impl<T: Transport> Transport for &T {
+    async fn call(&self, call: OutboundCall) -> Result<Vec<u8>, CallFailure> {
+        (**self).call(call).await
+    }
+
+    fn time(&self) -> u64 {
+        (**self).time()
+    }
+
+    fn canister_self(&self) -> Principal {
+        (**self).canister_self()
+    }
+
+    async fn sleep(&self, duration: Duration) {
+        (**self).sleep(duration).await
+    }
+
+    fn cycles_refunded(&self) -> u128 {
+        (**self).cycles_refunded()
+    }
+}
+
+/// Calls `method` on `target` with `precoded_args`, which someone else Candid-encoded, and
+/// decodes the reply into `R`. The arguments must be a valid Candid message, e.g., the output of
+/// `candid::encode_args`; we check that before sending them.
+// `Call::with_raw_args` takes any bytes, and a callee that gets garbage rejects the call with a
+// decoding error that names neither the argument nor the problem. Parsing the message here
+// catches that without a call, and with a precise error. It doesn't check that the arguments fit
+// the method's signature, which only the callee knows. The callee is treated as untrusted.
+pub async fn call_precoded<T: Transport, R: CandidType + for<'de> Deserialize<'de>>(
+    transport: &T,
+    target: Principal,
+    method: &str,
+    precoded_args: Vec<u8>,
+) -> Result<R, AppError> {
+    IDLArgs::from_bytes(&precoded_args).map_err(|e| AppError::InvalidArgs {
+        message: e.to_string(),
+    })?;
+    let reply = transport
+        .call(OutboundCall::untrusted(target, method, precoded_args))
+        .await
+        .map_err(|failure| {
+            failure
+                .invalid_target(target)
+                .or_else(|| failure.reply_too_large(target, method))
+                .unwrap_or_else(|| AppError::CallFailed {
+                    message: format!("{:?}", failure),
+                })
+        })?;
+    decode_or_describe(&reply).map_err(|message| AppError::CallFailed { message })
+}
+
+/// How long we wait for an untrusted callee to respond before giving up.
+pub const UNTRUSTED_CALL_TIMEOUT_SECS: u32 = 60;
+
+/// How calls to untrusted callees wait, see `untrusted_call`.
+pub const UNTRUSTED_WAIT: WaitMode = WaitMode::Bounded {
+    timeout_secs: Some(UNTRUSTED_CALL_TIMEOUT_SECS),
+};
+
+/// The largest reply we accept from an untrusted callee. ICRC-1 replies are a few dozen bytes.
+pub const MAX_UNTRUSTED_REPLY_BYTES: usize = 16 * 1024;
+
+// How the error for an oversized reply starts, so that `reply_too_large` can recognize it.
+const REPLY_TOO_LARGE: &str = "The reply is too large";
+
+/// Fails if `reply` is larger than we accept from the callee: `MAX_UNTRUSTED_REPLY_BYTES` for
+/// an `untrusted` callee, and no limit beyond the system's for the others.
+pub fn check_reply_size(untrusted: bool, reply: Vec<u8>) -> Result<Vec<u8>, CallFailure> {
+    if untrusted && reply.len() > MAX_UNTRUSTED_REPLY_BYTES {
+        Err(CallFailure::CanisterError(format!(
+            "{}: {} bytes exceed the limit of {} bytes for untrusted callees",
+            REPLY_TOO_LARGE,
+            reply.len(),
+            MAX_UNTRUSTED_REPLY_BYTES
+        )))
+    } else {
+        Ok(reply)
+    }
+}
+
+/// A call builder preset for canisters that we don't control.
+// A malicious or broken callee could otherwise stall us: with an unbounded wait, it can hold on
+// to our call for as long as it wants, and we can't even stop our canister in the meantime. A
+// bounded wait with a moderate timeout caps that, at the price of possibly not learning the
+// outcome. The IC has no way to cap the reply size up front, so `IcTransport` checks it once the
+// reply is in, before any decoding. Calls to system canisters, like the ICP ledger or the
+// management canister, are trusted and keep using unbounded waits.
+pub fn untrusted_call<'m, 'a>(target: Principal, method: &'m str) -> Call<'m, 'a> {
+    UNTRUSTED_WAIT.call(target, method)
+}
+
+/// The transport used by the endpoints: the IC itself, or an in-memory simulation of the
+/// canisters we talk to if the `simulate` feature is enabled.
+#[cfg(not(feature = "simulate"))]
+pub fn default_transport() -> IcTransport {
+    IcTransport::default()
+}
+
+#[cfg(feature = "simulate")]
+pub fn default_transport() -> crate::simulate::SimulatedTransport {
+    crate::simulate::SimulatedTransport
+}
+
+/// The real thing: issues calls on the Internet Computer.
+#[derive(Default)]
+pub struct IcTransport {
+    refunded: Cell<u128>,
+}
+
+impl Transport for IcTransport {
+    async fn call(&self, call: OutboundCall) -> Result<Vec<u8>, CallFailure> {
+        let builder = if call.untrusted {
+            untrusted_call(call.target, &call.method)
+        } else {
+            call.wait_mode().call(call.target, &call.method)
+        };
+        let result = builder
+            .with_raw_args(&call.args)
+            .with_cycles(call.cycles)
+            .call_raw()
+            .await;
+        // There's only a refund once a response came back. A call that the system rejected
+        // synchronously never left, its cycles are simply still ours, and reading the refund
+        // outside of a response would trap.
+        if !matches!(&result, Err(CallError::CallRejected(rejection)) if rejection.is_sync()) {
+            self.refunded
+                .set(self.refunded.get() + ic_cdk::api::msg_cycles_refunded());
+        }
+        let reply = result.map_err(CallFailure::from)?;
+        check_reply_size(call.untrusted, reply)
+    }
+
+    fn time(&self) -> u64 {
+        ic_cdk::api::time()
+    }
+
+    fn canister_self(&self) -> Principal {
+        ic_cdk::api::canister_self()
+    }
+
+    async fn sleep(&self, duration: Duration) {
+        TimerSleep::new(duration).await
+    }
+
+    fn cycles_refunded(&self) -> u128 {
+        self.refunded.get()
+    }
+}
+
+/// A future that completes once a canister timer fires.
+// Canisters can't block, but they can schedule a timer and await it. Other messages are
+// processed in the meantime, as with any other `await`.
+struct TimerSleep {
+    // Whether the timer fired, and the waker of the task awaiting it.
+    state: Rc<RefCell<(bool, Option<Waker>)>>,
+}
+
+impl TimerSleep {
+    fn new(duration: Duration) -> Self {
+        let state = Rc::new(RefCell::new((false, None::<Waker>)));
+        let timer_state = state.clone();
+        ic_cdk_timers::set_timer(duration, move || {
+            let mut state = timer_state.borrow_mut();
+            state.0 = true;
+            if let Some(waker) = state.1.take() {
+                waker.wake();
+            }
+        });
+        Self { state }
+    }
+}
+
+impl Future for TimerSleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut state = self.state.borrow_mut();
+        if state.0 {
+            Poll::Ready(())
+        } else {
+            state.1 = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::mock::{sys_unknown, MockTransport};
+    use super::*;
+    use candid::Nat;
+    use futures::executor::block_on;
+
+    #[test]
+    fn test_untrusted_preset() {
+        let target = Principal::anonymous();
+        let call = OutboundCall::untrusted(target, "icrc1_fee", vec![1, 2]);
+        assert_eq!(call.target, target);
+        assert_eq!(call.method, "icrc1_fee");
+        assert_eq!(call.args, vec![1, 2]);
+        // A bounded wait of a minute, without cycles.
+        assert!(call.bounded_wait);
+        assert_eq!(
+            call.wait_mode(),
+            WaitMode::Bounded {
+                timeout_secs: Some(60)
+            }
+        );
+        assert_eq!(call.cycles, 0);
+        // Replies up to the limit are passed on...
+        assert!(call.untrusted);
+        let at_limit = vec![0; MAX_UNTRUSTED_REPLY_BYTES];
+        assert_eq!(check_reply_size(call.untrusted, at_limit.clone()), Ok(at_limit));
+        // ... and larger ones are refused before decoding, unlike from a trusted callee.
+        let too_large = vec![0; MAX_UNTRUSTED_REPLY_BYTES + 1];
+        let failure = check_reply_size(call.untrusted, too_large.clone()).unwrap_err();
+        assert_eq!(
+            failure.reply_too_large(target, &call.method),
+            Some(AppError::ReplyTooLarge {
+                target,
+                method: "icrc1_fee".to_string(),
+            })
+        );
+        assert!(check_reply_size(false, too_large).is_ok());
+    }
+
+    #[test]
+    fn test_trusted_wait_modes() {
+        let call = OutboundCall {
+            bounded_wait: false,
+            untrusted: false,
+            ..OutboundCall::untrusted(Principal::anonymous(), "transfer", vec![])
+        };
+        assert_eq!(call.wait_mode(), WaitMode::Unbounded);
+        let call = OutboundCall {
+            bounded_wait: true,
+            ..call
+        };
+        assert_eq!(call.wait_mode(), WaitMode::Bounded { timeout_secs: None });
+    }
+
+    #[test]
+    fn test_destination_invalid_is_an_invalid_target() {
+        let target = Principal::from_slice(&[9; 10]);
+        let failure = CallFailure::Rejected {
+            code: RejectCode::DestinationInvalid,
+            message: "Canister not found".to_string(),
+            sync: true,
+        };
+        assert_eq!(failure.invalid_target(target), Some(AppError::InvalidTarget { target }));
+        let transient = CallFailure::Rejected {
+            code: RejectCode::SysTransient,
+            message: "Canister not found".to_string(),
+            sync: true,
+        };
+        assert_eq!(transient.invalid_target(target), None);
+        assert_eq!(sys_unknown().invalid_target(target), None);
+    }
+
+    #[test]
+    fn test_reject_codes_round_trip() {
+        let codes = [
+            RejectCode::SysFatal,
+            RejectCode::SysTransient,
+            RejectCode::DestinationInvalid,
+            RejectCode::CanisterReject,
+            RejectCode::CanisterError,
+            RejectCode::SysUnknown,
+        ];
+        for code in codes {
+            assert_eq!(parse_reject_code(reject_code_to_raw(code)), Ok(code));
+        }
+        for raw in 1..=6 {
+            assert_eq!(reject_code_to_raw(parse_reject_code(raw).unwrap()), raw);
+        }
+    }
+
+    #[test]
+    fn test_unknown_reject_codes_are_errors() {
+        for raw in [-1, 0, 7, 42] {
+            assert!(parse_reject_code(raw).is_err());
+        }
+    }
+
+    #[test]
+    fn test_out_of_cycles_reject_is_recognized() {
+        let message = "Canister rrkah-fqaaa-aaaaa-aaaaq-cai is out of cycles".to_string();
+        assert!(CallFailure::Rejected {
+            code: RejectCode::CanisterReject,
+            message: message.clone(),
+            sync: false,
+        }
+        .is_callee_out_of_cycles());
+        assert!(CallFailure::CanisterError(message).is_callee_out_of_cycles());
+    }
+
+    #[test]
+    fn test_precoded_args_round_trip() {
+        let transport = MockTransport::new();
+        transport.reply(&Nat::from(5_u64));
+        let args = candid::encode_args((Nat::from(2_u64), Nat::from(3_u64))).unwrap();
+
+        let result: Result<Nat, _> = block_on(call_precoded(&transport, Principal::anonymous(), "add", args.clone()));
+
+        assert_eq!(result, Ok(Nat::from(5_u64)));
+        // The arguments went out exactly as they were encoded.
+        assert_eq!(transport.calls()[0].args, args);
+    }
+
+    #[test]
+    fn test_invalid_precoded_args_are_not_sent() {
+        let transport = MockTransport::new();
+
+        let result: Result<Nat, _> = block_on(call_precoded(
+            &transport,
+            Principal::anonymous(),
+            "add",
+            b"not candid".to_vec(),
+        ));
+
+        assert!(matches!(result, Err(AppError::InvalidArgs { .. })), "{:?}", result);
+        assert!(transport.calls().is_empty());
+    }
+
+    #[test]
+    fn test_stopped_callee_is_recognized() {
+        for message in [
+            "IC0508: Canister rrkah-fqaaa-aaaaa-aaaaq-cai is stopped and therefore does not have a \
+             CallContextManager",
+            "IC0509: Canister rrkah-fqaaa-aaaaa-aaaaq-cai is stopping",
+        ] {
+            let failure = CallFailure::Rejected {
+                code: RejectCode::CanisterError,
+                message: message.to_string(),
+                sync: false,
+            };
+            assert!(failure.is_callee_stopped(), "{}", message);
+        }
+        assert!(!CallFailure::CanisterError("Canister trapped".to_string()).is_callee_stopped());
+        assert!(!sys_unknown().is_callee_stopped());
+    }
+
+    #[test]
+    fn test_other_failures_are_not_out_of_cycles() {
+        assert!(!sys_unknown().is_callee_out_of_cycles());
+        assert!(!CallFailure::CanisterError("Canister trapped".to_string()).is_callee_out_of_cycles());
+        // The message only counts if the callee produced the reject.
+        assert!(!CallFailure::Rejected {
+            code: RejectCode::SysTransient,
+            message: "Canister is out of cycles".to_string(),
+            sync: true,
+        }
+        .is_callee_out_of_cycles());
+    }
+}
+
+#[cfg(test)]
+pub mod mock {
+    use super::*;
+    use candid::CandidType;
+    use std::cell::Cell;
+    use std::collections::VecDeque;
+
+    /// A transport that replays scripted replies in order and remembers the calls it saw.
+    /// Sleeping doesn't wait, but advances `now`, and so does every call, by `call_latency`.
+    /// Every call that isn't rejected synchronously gets `refund_per_call` cycles back.
+    pub struct MockTransport {
+        pub now: Cell<u64>,
+        pub call_latency: Cell<u64>,
+        pub refund_per_call: Cell<u128>,
+        refunded: Cell<u128>,
+        pub self_id: Principal,
+        replies: RefCell<VecDeque<Result<Vec<u8>, CallFailure>>>,
+        calls: RefCell<Vec<OutboundCall>>,
+        in_flight: RefCell<Vec<usize>>,
+    }
+
+    /// A `SysUnknown` failure like the one the system reports when a bounded wait call times out.
+    pub fn sys_unknown() -> CallFailure {
+        CallFailure::SysUnknown("Timeout expired".to_string())
+    }
+
+    impl MockTransport {
+        pub fn new() -> Self {
+            Self {
+                now: Cell::new(0),
+                call_latency: Cell::new(0),
+                refund_per_call: Cell::new(0),
+                refunded: Cell::new(0),
+                self_id: Principal::from_slice(&[0xca; 10]),
+                replies: RefCell::new(VecDeque::new()),
+                calls: RefCell::new(Vec::new()),
+                in_flight: RefCell::new(Vec::new()),
+            }
+        }
+
+        /// Queues a successful reply.
+        pub fn reply<R: CandidType>(&self, reply: &R) -> &Self {
+            let bytes = candid::encode_one(reply).expect("Failed to encode the mock reply");
+            self.replies.borrow_mut().push_back(Ok(bytes));
+            self
+        }
+
+        /// Queues a failed call.
+        pub fn fail(&self, failure: CallFailure) -> &Self {
+            self.replies.borrow_mut().push_back(Err(failure));
+            self
+        }
+
+        /// The calls made so far, in order.
+        pub fn calls(&self) -> Vec<OutboundCall> {
+            self.calls.borrow().clone()
+        }
+
+        /// For each call made so far, how many operations were registered with `upgrade::begin`
+        /// while it was made.
+        pub fn in_flight_during_calls(&self) -> Vec<usize> {
+            self.in_flight.borrow().clone()
+        }
+    }
+
+    impl Default for MockTransport {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl Transport for MockTransport {
+        async fn call(&self, call: OutboundCall) -> Result<Vec<u8>, CallFailure> {
+            let method = call.method.clone();
+            let untrusted = call.untrusted;
+            self.calls.borrow_mut().push(call);
+            self.in_flight.borrow_mut().push(crate::upgrade::in_flight());
+            self.now.set(self.now.get() + self.call_latency.get());
+            let result = self
+                .replies
+                .borrow_mut()
+                .pop_front()
+                .unwrap_or_else(|| panic!("Unexpected call to {}", method));
+            if !matches!(result, Err(CallFailure::Rejected { sync: true, .. })) {
+                self.refunded
+                    .set(self.refunded.get() + self.refund_per_call.get());
+            }
+            // Like `IcTransport`, we don't pass on oversized replies from untrusted callees.
+            result.and_then(|reply| check_reply_size(untrusted, reply))
+        }
+
+        fn time(&self) -> u64 {
+            self.now.get()
+        }
+
+        fn canister_self(&self) -> Principal {
+            self.self_id
+        }
+
+        async fn sleep(&self, duration: Duration) {
+            self.now.set(self.now.get() + duration.as_nanos() as u64);
+        }
+
+        fn cycles_refunded(&self) -> u128 {
+            self.refunded.get()
+        }
+    }
+}
@@ -0,0 +1,106 @@
+use std::cell::Cell;
+
+thread_local! {
+    // Set by a controller to upgrade even though operations are in flight. Heap memory doesn't
+    // survive the upgrade, so the flag only lets a single upgrade through.
+    static FORCE_UPGRADE: Cell<bool> = const { Cell::new(false) };
+    static IN_FLIGHT: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Marks a non-idempotent operation as in flight for as long as it's alive, see `check_upgrade`.
+// Dropping the guard also covers the early returns and the `?`s of the operation. If the
+// operation traps after an `await`, its future is dropped during cleanup as well.
+pub struct InFlight(());
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        IN_FLIGHT.with(|n| n.set(n.get() - 1));
+    }
+}
+
+/// Registers an operation that must not be cut short by an upgrade.
+pub fn begin() -> InFlight {
+    IN_FLIGHT.with(|n| n.set(n.get() + 1));
+    InFlight(())
+}
+
+/// The number of operations registered with `begin` that are still in flight.
+pub fn in_flight() -> usize {
+    IN_FLIGHT.with(|n| n.get())
+}
+
+pub fn set_force_upgrade(force: bool) {
+    FORCE_UPGRADE.with(|f| f.set(force));
+}
+
+pub fn force_upgrade_requested() -> bool {
+    FORCE_UPGRADE.with(|f| f.get())
+}
+
+/// Whether stable memory holds the state saved by `pre_upgrade`, judging by its size in pages and
+/// its first bytes. Versions before the approval queue saved nothing, so upgrading from them
+/// finds stable memory empty.
+// `stable_save` writes a Candid message, and those start with the magic bytes `DIDL`.
+pub fn has_saved_state(stable_size: u64, header: &[u8]) -> bool {
+    stable_size > 0 && header.starts_with(b"DIDL")
+}
+
+/// Decides whether an upgrade may proceed while `in_flight` non-idempotent operations are
+/// awaiting their calls.
+// The responses to calls made by the old code are delivered to the new code, which doesn't know
+// the futures that were waiting for them. The operations then never record their outcome, and
+// since they aren't idempotent, we can't simply run them again. Stopping the canister before
+// upgrading waits for all outstanding calls, but nothing forces the controllers to do so.
+pub fn check_upgrade(in_flight: usize, force: bool) -> Result<(), String> {
+    if in_flight == 0 || force {
+        Ok(())
+    } else {
+        Err(format!(
+            "Refusing to upgrade with {} operations in flight; stop the canister first, or call \
+             force_upgrade to upgrade anyway",
+            in_flight
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_upgrade_without_pending_jobs() {
+        assert_eq!(check_upgrade(0, false), Ok(()));
+    }
+
+    #[test]
+    fn test_guards_count_operations_in_flight() {
+        let first = begin();
+        let second = begin();
+        assert_eq!(in_flight(), 2);
+        drop(first);
+        assert_eq!(in_flight(), 1);
+        drop(second);
+        assert_eq!(in_flight(), 0);
+    }
+
+    #[test]
+    fn test_upgrade_blocked_with_pending_jobs() {
+        let error = check_upgrade(2, false).unwrap_err();
+        assert!(error.contains("2 operations in flight"), "{}", error);
+    }
+
+    #[test]
+    fn test_empty_stable_memory_has_no_saved_state() {
+        assert!(!has_saved_state(0, &[]));
+        // Memory that was grown but never written is all zeros.
+        assert!(!has_saved_state(1, &[0; 4]));
+        let saved = candid::encode_args((Some(1_u64),)).unwrap();
+        assert!(has_saved_state(1, &saved[..4]));
+    }
+
+    #[test]
+    fn test_upgrade_allowed_after_force() {
+        set_force_upgrade(true);
+        assert_eq!(check_upgrade(2, force_upgrade_requested()), Ok(()));
+    }
+}
@@ -0,0 +1,100 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// How long a call may be outstanding before we consider it stuck.
+pub const STUCK_THRESHOLD: Duration = Duration::from_secs(5 * 60);
+
+/// How often the watchdog looks for stuck calls.
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The calls that we issued and whose results haven't come back yet.
+#[derive(Default)]
+pub struct InFlight {
+    next_id: u64,
+    // The label of each call and when it started, in nanoseconds since the epoch.
+    calls: BTreeMap<u64, (String, u64)>,
+}
+
+thread_local! {
+    pub static IN_FLIGHT: RefCell<InFlight> = RefCell::new(InFlight::default());
+}
+
+/// Deregisters its call when dropped.
+// Dropping covers every way a call can end: its result comes back, or the message awaiting it
+// traps, in which case the CDK drops the future during the cleanup.
+pub struct InFlightCall(u64);
+
+impl Drop for InFlightCall {
+    fn drop(&mut self) {
+        IN_FLIGHT.with(|f| f.borrow_mut().calls.remove(&self.0));
+    }
+}
+
+/// Registers a call labeled `label` that starts at `now`. Keep the returned value alive until
+/// the call completes.
+pub fn register(label: &str, now: u64) -> InFlightCall {
+    IN_FLIGHT.with(|f| {
+        let mut in_flight = f.borrow_mut();
+        let id = in_flight.next_id;
+        in_flight.next_id += 1;
+        in_flight.calls.insert(id, (label.to_string(), now));
+        InFlightCall(id)
+    })
+}
+
+/// Returns the label of each call that has been outstanding for longer than `threshold` at
+/// `now`, with how long it has been outstanding, in nanoseconds.
+// Unbounded wait calls to an unresponsive callee never time out, and a canister with such a call
+// can't be stopped, so they are worth knowing about. Bounded wait calls end up here too if their
+// timeout exceeds the threshold.
+pub fn stuck(now: u64, threshold: Duration) -> Vec<(String, u64)> {
+    let threshold = threshold.as_nanos() as u64;
+    IN_FLIGHT.with(|f| {
+        f.borrow()
+            .calls
+            .values()
+            .map(|(label, started_at)| (label.clone(), now.saturating_sub(*started_at)))
+            .filter(|(_, outstanding)| *outstanding > threshold)
+            .collect()
+    })
+}
+
+/// Checks for stuck calls every `CHECK_INTERVAL`, and writes them to the canister log.
+pub fn start() {
+    ic_cdk_timers::set_timer_interval(CHECK_INTERVAL, || {
+        for (label, outstanding) in stuck(ic_cdk::api::time(), STUCK_THRESHOLD) {
+            ic_cdk::println!(
+                "Call {} has been outstanding for {} seconds",
+                label,
+                Duration::from_nanos(outstanding).as_secs()
+            );
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINUTE: u64 = 60_000_000_000;
+
+    #[test]
+    fn test_long_running_call_is_stuck() {
+        let _slow = register("wait_until_running", 0);
+        let _fast = register("icrc1_get_fee", 9 * MINUTE);
+
+        assert_eq!(
+            stuck(10 * MINUTE, STUCK_THRESHOLD),
+            vec![("wait_until_running".to_string(), 10 * MINUTE)]
+        );
+    }
+
+    #[test]
+    fn test_completed_call_is_not_stuck() {
+        let call = register("icrc1_transfer", 0);
+        drop(call);
+
+        assert!(stuck(10 * MINUTE, STUCK_THRESHOLD).is_empty());
+    }
+}
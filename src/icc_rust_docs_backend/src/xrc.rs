@@ -0,0 +1,499 @@
+use candid::{CandidType, Deserialize};
+use ic_xrc_types::{Asset, ExchangeRate, ExchangeRateError, GetExchangeRateRequest, GetExchangeRateResult};
+use std::cell::Cell;
+use std::future::Future;
+use std::time::Duration;
+
+use crate::cache::TtlCache;
+use crate::transport::Transport;
+
+/// The ID of the exchange rate canister (XRC) on the IC mainnet.
+pub const XRC_CANISTER_ID: &str = "uf6dk-hyaaa-aaaaq-qaaaq-cai";
+
+/// The fee, in cycles, that the XRC charges per request, unless configured otherwise at init.
+pub const DEFAULT_XRC_FEE: u128 = 1_000_000_000;
+
+/// How long we wait before asking the XRC again after a retryable error; the delay doubles after
+/// every attempt.
+pub const INITIAL_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// How long after the first attempt we stop retrying.
+pub const RETRY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long we reuse a rate before asking the XRC again. Every request costs the XRC's fee, so
+/// callers asking for the same pair in quick succession share one.
+pub const RATE_TTL: Duration = Duration::from_secs(60);
+
+/// How many asset pairs we keep the rate of.
+pub const RATE_CACHE_ENTRIES: usize = 32;
+
+thread_local! {
+    static XRC_FEE: Cell<u128> = const { Cell::new(DEFAULT_XRC_FEE) };
+    pub static RATE_CACHE: TtlCache<String, ExchangeRate> = TtlCache::new(RATE_CACHE_ENTRIES);
+}
+
+/// The key of the rate of `base` in `quote` in `RATE_CACHE`.
+// The symbol alone isn't enough, as a cryptocurrency and a fiat currency may share it.
+pub fn rate_key(base: &Asset, quote: &Asset) -> String {
+    format!("{}:{:?}/{}:{:?}", base.symbol, base.class, quote.symbol, quote.class)
+}
+
+// The XRC doesn't advertise its fee, so we can't look it up. Should the fee change, the
+// controllers pass the new one when upgrading, without changing the code.
+pub fn set_xrc_fee(fee: u128) {
+    XRC_FEE.with(|f| f.set(fee));
+}
+
+pub fn xrc_fee() -> u128 {
+    XRC_FEE.with(|f| f.get())
+}
+
+/// Returns the encoded arguments of a `get_exchange_rate` call, and the cycles to attach to it.
+pub fn xrc_request(request: &GetExchangeRateRequest) -> (Vec<u8>, u128) {
+    (candid::encode_one(request).unwrap(), xrc_fee())
+}
+
+/// An exchange rate together with the XRC's metadata on how it was obtained.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ExchangeRateSummary {
+    /// The rate, scaled by `10^decimals`.
+    pub rate: u64,
+    pub decimals: u32,
+    /// The time (in seconds since the epoch) the rate refers to.
+    pub timestamp: u64,
+    pub base_asset_num_queried_sources: u64,
+    pub base_asset_num_received_rates: u64,
+    pub quote_asset_num_queried_sources: u64,
+    pub quote_asset_num_received_rates: u64,
+    /// The standard deviation of the received rates, scaled like `rate`. A large value means
+    /// that the sources disagree.
+    pub standard_deviation: u64,
+    /// The timestamp of the forex rates used, if any of the assets is a fiat currency.
+    pub forex_timestamp: Option<u64>,
+}
+
+impl From<&ExchangeRate> for ExchangeRateSummary {
+    fn from(rate: &ExchangeRate) -> Self {
+        let metadata = &rate.metadata;
+        Self {
+            rate: rate.rate,
+            decimals: metadata.decimals,
+            timestamp: rate.timestamp,
+            base_asset_num_queried_sources: metadata.base_asset_num_queried_sources as u64,
+            base_asset_num_received_rates: metadata.base_asset_num_received_rates as u64,
+            quote_asset_num_queried_sources: metadata.quote_asset_num_queried_sources as u64,
+            quote_asset_num_received_rates: metadata.quote_asset_num_received_rates as u64,
+            standard_deviation: metadata.standard_deviation,
+            forex_timestamp: metadata.forex_timestamp,
+        }
+    }
+}
+
+/// Returns true if the XRC might answer the same request successfully when asked again.
+// `Pending` means that the XRC is still fetching rates for the requested assets, and
+// `RateLimited` means that it's currently serving too many requests. Both are expected to go
+// away on their own. All other errors stem from the request itself (e.g., an unknown asset) or
+// from our setup (e.g., too few cycles attached), so retrying them just burns cycles.
+pub fn is_retryable_xrc_error(error: &ExchangeRateError) -> bool {
+    matches!(
+        error,
+        ExchangeRateError::Pending | ExchangeRateError::RateLimited
+    )
+}
+
+/// Requests a rate with `attempt`, retrying with a backoff while the XRC answers with a retryable
+/// error, up to `max_attempts` times and until `RETRY_TIMEOUT` has passed. Other errors are
+/// returned right away.
+// The XRC doesn't fetch rates ahead of time. The first request for a pair of assets at a given
+// minute makes it query the exchanges through HTTPS outcalls, which takes a few seconds; requests
+// that arrive in the meantime get `Pending` rather than waiting. Asking again right away would
+// most likely get `Pending` again, so we give the XRC a moment between attempts.
+pub async fn retry_while_pending<T, F, Fut>(
+    transport: &T,
+    max_attempts: u32,
+    mut attempt: F,
+) -> Result<ExchangeRate, String>
+where
+    T: Transport,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<GetExchangeRateResult, String>>,
+{
+    let deadline = transport.time() + RETRY_TIMEOUT.as_nanos() as u64;
+    let mut delay = INITIAL_RETRY_DELAY;
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        match attempt().await? {
+            Ok(rate) => return Ok(rate),
+            Err(e)
+                if is_retryable_xrc_error(&e)
+                    && attempts < max_attempts
+                    && transport.time() + delay.as_nanos() as u64 <= deadline =>
+            {
+                transport.sleep(delay).await;
+                delay *= 2;
+            }
+            // The XRC canister returned an error. This could be because the assets are unknown,
+            // because the XRC canister cannot make outgoing calls, and other reasons. We translate
+            // the error into a hint that tells the user what to do about it.
+            Err(e) => return Err(format!("XRC returned an error: {}", xrc_error_hint(&e))),
+        }
+    }
+}
+
+/// Describes an XRC error in a way that tells the user what to do about it.
+pub fn xrc_error_hint(error: &ExchangeRateError) -> String {
+    match error {
+        ExchangeRateError::AnonymousPrincipalNotAllowed => {
+            "The XRC doesn't serve anonymous callers; call it from a canister".to_string()
+        }
+        ExchangeRateError::Pending => {
+            "The XRC is still fetching the rate; retry shortly".to_string()
+        }
+        ExchangeRateError::CryptoBaseAssetNotFound => {
+            "The base asset isn't a cryptocurrency known to the XRC; check its symbol".to_string()
+        }
+        ExchangeRateError::CryptoQuoteAssetNotFound => {
+            "The quote asset isn't a cryptocurrency known to the XRC; check its symbol".to_string()
+        }
+        ExchangeRateError::StablecoinRateNotFound => {
+            "The XRC couldn't find stablecoin rates needed to convert to USD".to_string()
+        }
+        ExchangeRateError::StablecoinRateTooFewRates => {
+            "The XRC received too few stablecoin rates to compute a reliable rate".to_string()
+        }
+        ExchangeRateError::StablecoinRateZeroRate => {
+            "The XRC received a zero stablecoin rate and refused to divide by it".to_string()
+        }
+        ExchangeRateError::ForexInvalidTimestamp => {
+            "No forex rates are available for the requested timestamp; try an older one"
+                .to_string()
+        }
+        ExchangeRateError::ForexBaseAssetNotFound => {
+            "The base asset isn't a fiat currency known to the XRC; check its symbol".to_string()
+        }
+        ExchangeRateError::ForexQuoteAssetNotFound => {
+            "The quote asset isn't a fiat currency known to the XRC; check its symbol".to_string()
+        }
+        ExchangeRateError::ForexAssetsNotFound => {
+            "Neither asset is a fiat currency known to the XRC".to_string()
+        }
+        ExchangeRateError::RateLimited => {
+            "The XRC is rate limiting requests; retry shortly".to_string()
+        }
+        ExchangeRateError::NotEnoughCycles => {
+            "Not enough cycles were attached to pay the XRC fee".to_string()
+        }
+        ExchangeRateError::FailedToAcceptCycles => {
+            "The XRC failed to accept the attached cycles".to_string()
+        }
+        ExchangeRateError::InconsistentRatesReceived => {
+            "The XRC received rates that diverge too much to be trusted".to_string()
+        }
+        ExchangeRateError::Other(other) => {
+            format!("The XRC returned error {}: {}", other.code, other.description)
+        }
+    }
+}
+
+/// How old a rate may be, in seconds, before we refuse to pay based on it.
+pub const MAX_RATE_AGE_SECS: u64 = 5 * 60;
+
+/// Checks that a rate with the XRC's `timestamp` (in seconds) is at most `max_age_secs` old at
+/// `now` (in nanoseconds).
+// Without a timestamp in the request, the XRC returns the rate for the start of the current
+// minute, so a fresh rate is at most a minute or two old. An older one means the XRC is serving
+// from its cache while the exchanges are unreachable, and prices may have moved since.
+pub fn check_rate_age(timestamp: u64, now: u64, max_age_secs: u64) -> Result<(), String> {
+    let age_secs = (now / 1_000_000_000).saturating_sub(timestamp);
+    if age_secs > max_age_secs {
+        return Err(format!(
+            "The XRC's rate is {} seconds old, more than the {} seconds we accept; retry later",
+            age_secs, max_age_secs
+        ));
+    }
+    Ok(())
+}
+
+/// Converts `usd` US dollars into e8s of ICP, at a rate of `rate` US dollars per ICP with
+/// `decimals` decimals, rounding down.
+// Most decimal amounts have no exact float representation: 0.29 is stored as 0.28999999999999998,
+// so scaling it to e8s and truncating would lose an e8. We only use the float to get a whole
+// number of micro-dollars, rounding to the nearest, and compute the rest with integers.
+pub fn usd_to_e8s(usd: f64, rate: u64, decimals: u32) -> Result<u64, String> {
+    if !usd.is_finite() || usd <= 0.0 {
+        return Err(format!("The amount must be a positive number of US dollars, got {}", usd));
+    }
+    if rate == 0 {
+        return Err("The XRC returned a rate of 0, which we can't convert at".to_string());
+    }
+    let too_large = || format!("The amount of {} US dollars is too large", usd);
+    let micro_usd = (usd * 1_000_000.0).round();
+    if micro_usd >= u64::MAX as f64 {
+        return Err(too_large());
+    }
+    // e8s = usd * 10^8 / (rate / 10^decimals) = micro_usd * 10^(8 + decimals) / (rate * 10^6)
+    let numerator = 10_u128
+        .checked_pow(8 + decimals)
+        .and_then(|scale| (micro_usd as u128).checked_mul(scale))
+        .ok_or_else(too_large)?;
+    let e8s = u64::try_from(numerator / (u128::from(rate) * 1_000_000)).map_err(|_| too_large())?;
+    if e8s == 0 {
+        return Err(format!("{} US dollars is less than one e8 of ICP", usd));
+    }
+    Ok(e8s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::mock::MockTransport;
+    use futures::executor::block_on;
+    use ic_xrc_types::{Asset, AssetClass, ExchangeRateMetadata, OtherError};
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+
+    fn all_errors() -> Vec<ExchangeRateError> {
+        vec![
+            ExchangeRateError::AnonymousPrincipalNotAllowed,
+            ExchangeRateError::Pending,
+            ExchangeRateError::CryptoBaseAssetNotFound,
+            ExchangeRateError::CryptoQuoteAssetNotFound,
+            ExchangeRateError::StablecoinRateNotFound,
+            ExchangeRateError::StablecoinRateTooFewRates,
+            ExchangeRateError::StablecoinRateZeroRate,
+            ExchangeRateError::ForexInvalidTimestamp,
+            ExchangeRateError::ForexBaseAssetNotFound,
+            ExchangeRateError::ForexQuoteAssetNotFound,
+            ExchangeRateError::ForexAssetsNotFound,
+            ExchangeRateError::RateLimited,
+            ExchangeRateError::NotEnoughCycles,
+            ExchangeRateError::FailedToAcceptCycles,
+            ExchangeRateError::InconsistentRatesReceived,
+            ExchangeRateError::Other(OtherError {
+                code: 42,
+                description: "something odd".to_string(),
+            }),
+        ]
+    }
+
+    fn request() -> GetExchangeRateRequest {
+        GetExchangeRateRequest {
+            base_asset: Asset {
+                symbol: "ICP".to_string(),
+                class: AssetClass::Cryptocurrency,
+            },
+            quote_asset: Asset {
+                symbol: "USD".to_string(),
+                class: AssetClass::FiatCurrency,
+            },
+            timestamp: None,
+        }
+    }
+
+    #[test]
+    fn test_default_fee_is_attached() {
+        assert_eq!(xrc_request(&request()).1, DEFAULT_XRC_FEE);
+    }
+
+    #[test]
+    fn test_configured_fee_is_attached() {
+        set_xrc_fee(2_500_000_000);
+        let (args, cycles) = xrc_request(&request());
+        assert_eq!(cycles, 2_500_000_000);
+        let decoded: GetExchangeRateRequest = candid::decode_one(&args).unwrap();
+        assert_eq!(decoded.base_asset.symbol, "ICP");
+    }
+
+    #[test]
+    fn test_only_pending_and_rate_limited_are_retryable() {
+        let cases = [
+            (ExchangeRateError::AnonymousPrincipalNotAllowed, false),
+            (ExchangeRateError::Pending, true),
+            (ExchangeRateError::CryptoBaseAssetNotFound, false),
+            (ExchangeRateError::CryptoQuoteAssetNotFound, false),
+            (ExchangeRateError::StablecoinRateNotFound, false),
+            (ExchangeRateError::StablecoinRateTooFewRates, false),
+            (ExchangeRateError::StablecoinRateZeroRate, false),
+            (ExchangeRateError::ForexInvalidTimestamp, false),
+            (ExchangeRateError::ForexBaseAssetNotFound, false),
+            (ExchangeRateError::ForexQuoteAssetNotFound, false),
+            (ExchangeRateError::ForexAssetsNotFound, false),
+            (ExchangeRateError::RateLimited, true),
+            (ExchangeRateError::NotEnoughCycles, false),
+            (ExchangeRateError::FailedToAcceptCycles, false),
+            (ExchangeRateError::InconsistentRatesReceived, false),
+            (
+                ExchangeRateError::Other(OtherError {
+                    code: 42,
+                    description: "something odd".to_string(),
+                }),
+                false,
+            ),
+        ];
+        // Every variant has a case.
+        assert_eq!(cases.len(), all_errors().len());
+        for (error, expected) in cases {
+            assert_eq!(is_retryable_xrc_error(&error), expected, "{:?}", error);
+        }
+    }
+
+    #[test]
+    fn test_hints_are_distinct() {
+        let hints: Vec<String> = all_errors().iter().map(xrc_error_hint).collect();
+        for (i, hint) in hints.iter().enumerate() {
+            assert!(!hint.is_empty());
+            assert!(!hints[i + 1..].contains(hint), "duplicate hint: {}", hint);
+        }
+    }
+
+    #[test]
+    fn test_other_error_hint_includes_details() {
+        let hint = xrc_error_hint(&ExchangeRateError::Other(OtherError {
+            code: 42,
+            description: "something odd".to_string(),
+        }));
+        assert!(hint.contains("42"));
+        assert!(hint.contains("something odd"));
+    }
+
+    fn icp_usd_rate() -> ExchangeRate {
+        ExchangeRate {
+            base_asset: Asset {
+                symbol: "ICP".to_string(),
+                class: AssetClass::Cryptocurrency,
+            },
+            quote_asset: Asset {
+                symbol: "USD".to_string(),
+                class: AssetClass::FiatCurrency,
+            },
+            timestamp: 1_700_000_000,
+            rate: 12_345_000_000,
+            metadata: ExchangeRateMetadata {
+                decimals: 9,
+                base_asset_num_queried_sources: 7,
+                base_asset_num_received_rates: 6,
+                quote_asset_num_queried_sources: 10,
+                quote_asset_num_received_rates: 9,
+                standard_deviation: 40_000_000,
+                forex_timestamp: Some(1_699_920_000),
+            },
+        }
+    }
+
+    // Makes the attempts of `retry_while_pending` answer with `results` in turn, and returns the
+    // outcome together with the times at which the attempts were made.
+    fn retry_with(
+        max_attempts: u32,
+        results: Vec<GetExchangeRateResult>,
+    ) -> (Result<ExchangeRate, String>, Vec<u64>) {
+        let transport = MockTransport::new();
+        let results = RefCell::new(VecDeque::from(results));
+        let attempted_at = RefCell::new(vec![]);
+        let outcome = block_on(retry_while_pending(&transport, max_attempts, || {
+            attempted_at.borrow_mut().push(transport.time());
+            let result = results.borrow_mut().pop_front().unwrap();
+            async move { Ok(result) }
+        }));
+        (outcome, attempted_at.into_inner())
+    }
+
+    #[test]
+    fn test_pending_is_retried_after_a_delay() {
+        let (outcome, attempted_at) =
+            retry_with(3, vec![Err(ExchangeRateError::Pending), Ok(icp_usd_rate())]);
+        assert_eq!(outcome, Ok(icp_usd_rate()));
+        assert_eq!(attempted_at, vec![0, INITIAL_RETRY_DELAY.as_nanos() as u64]);
+    }
+
+    #[test]
+    fn test_pending_gives_up_after_max_attempts() {
+        let (outcome, attempted_at) = retry_with(2, vec![Err(ExchangeRateError::Pending); 2]);
+        assert!(outcome.unwrap_err().contains("still fetching"));
+        assert_eq!(attempted_at.len(), 2);
+    }
+
+    #[test]
+    fn test_other_errors_are_not_retried() {
+        let (outcome, attempted_at) =
+            retry_with(3, vec![Err(ExchangeRateError::CryptoBaseAssetNotFound)]);
+        assert!(outcome.is_err());
+        assert_eq!(attempted_at.len(), 1);
+    }
+
+    #[test]
+    fn test_summary_of_full_response() {
+        let response: GetExchangeRateResult = Ok(icp_usd_rate());
+        // Go through Candid, as the XRC's reply would.
+        let bytes = candid::encode_one(&response).unwrap();
+        let decoded: GetExchangeRateResult = candid::decode_one(&bytes).unwrap();
+
+        let summary = ExchangeRateSummary::from(&decoded.unwrap());
+
+        assert_eq!(
+            summary,
+            ExchangeRateSummary {
+                rate: 12_345_000_000,
+                decimals: 9,
+                timestamp: 1_700_000_000,
+                base_asset_num_queried_sources: 7,
+                base_asset_num_received_rates: 6,
+                quote_asset_num_queried_sources: 10,
+                quote_asset_num_received_rates: 9,
+                standard_deviation: 40_000_000,
+                forex_timestamp: Some(1_699_920_000),
+            }
+        );
+    }
+
+    #[test]
+    fn test_usd_to_e8s_at_fixed_rate() {
+        // 12.345 USD per ICP, as the mock XRC in the integration tests reports it.
+        assert_eq!(usd_to_e8s(10.0, 12_345_000_000, 9), Ok(81_004_455));
+        assert_eq!(usd_to_e8s(12.345, 12_345_000_000, 9), Ok(100_000_000));
+        // The same rate with fewer decimals gives the same amount.
+        assert_eq!(usd_to_e8s(10.0, 12_345, 3), Ok(81_004_455));
+    }
+
+    #[test]
+    fn test_usd_to_e8s_avoids_float_error() {
+        // `0.29 * 1e8` is 28999999.999999996, which truncates to one e8 short.
+        assert_eq!(usd_to_e8s(0.29, 1_000_000_000, 9), Ok(29_000_000));
+    }
+
+    #[test]
+    fn test_usd_to_e8s_rejects_bad_amounts() {
+        for usd in [0.0, -1.0, f64::NAN, f64::INFINITY, 1e30] {
+            assert!(usd_to_e8s(usd, 12_345_000_000, 9).is_err(), "{}", usd);
+        }
+        // Less than an e8 at 1000 USD per ICP.
+        assert!(usd_to_e8s(0.000001, 1_000_000_000_000, 9).is_err());
+        assert!(usd_to_e8s(10.0, 0, 9).is_err());
+    }
+
+    #[test]
+    fn test_stale_rate_is_rejected() {
+        let now = 1_700_000_600 * 1_000_000_000;
+        assert_eq!(check_rate_age(1_700_000_400, now, 300), Ok(()));
+        assert_eq!(check_rate_age(1_700_000_300, now, 300), Ok(()));
+        assert!(check_rate_age(1_700_000_299, now, 300).is_err());
+    }
+
+    #[test]
+    fn test_rate_key_tells_asset_classes_apart() {
+        let asset = |symbol: &str, class| Asset {
+            symbol: symbol.to_string(),
+            class,
+        };
+        let usd = asset("USD", AssetClass::FiatCurrency);
+
+        assert_ne!(
+            rate_key(&asset("XDR", AssetClass::Cryptocurrency), &usd),
+            rate_key(&asset("XDR", AssetClass::FiatCurrency), &usd)
+        );
+        assert_ne!(
+            rate_key(&asset("ICP", AssetClass::Cryptocurrency), &usd),
+            rate_key(&usd, &asset("ICP", AssetClass::Cryptocurrency))
+        );
+    }
+}
@@ -0,0 +1,331 @@
+//! End-to-end tests that install the backend in PocketIC, next to the mock ledger, XRC and relay
+//! from `tests/mocks/`, and call its endpoints like a user would.
+//!
+//! The tests need the PocketIC server and the wasm modules of the canisters, so they are
+//! ignored by default. `tests/run_integration.sh` builds the modules and runs the tests; it
+//! expects the server binary in `POCKET_IC_BIN`.
+
+use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_xrc_types::{Asset, AssetClass};
+use icrc_ledger_types::icrc1::account::Account;
+use icrc_ledger_types::icrc1::transfer::TransferError;
+use pocket_ic::{query_candid, update_candid_as, PocketIc, PocketIcBuilder};
+
+/// The ID of the XRC on the IC mainnet, which the backend calls; see `xrc::XRC_CANISTER_ID`.
+const XRC_CANISTER_ID: &str = "uf6dk-hyaaa-aaaaq-qaaaq-cai";
+
+/// What the backend holds on the mock ledger at the start of each test.
+const INITIAL_BALANCE: u64 = 1_000_000;
+
+/// The fee of the mock ledger.
+const FEE: u64 = 10_000;
+
+/// The backend's init arguments; the settings we leave out take their defaults.
+#[derive(CandidType, Deserialize)]
+struct InitArgs {
+    public_reads: Option<bool>,
+}
+
+struct Env {
+    pic: PocketIc,
+    backend: Principal,
+    ledger: Principal,
+}
+
+fn wasm(var: &str) -> Vec<u8> {
+    let path = std::env::var(var).unwrap_or_else(|_| panic!("{} must point to a wasm module", var));
+    std::fs::read(&path).unwrap_or_else(|e| panic!("Can't read {}: {}", path, e))
+}
+
+fn user() -> Principal {
+    Principal::from_slice(&[1; 29])
+}
+
+/// The controller of the backend, who may move its funds.
+fn controller() -> Principal {
+    Principal::from_slice(&[2; 29])
+}
+
+fn setup() -> Env {
+    // The XRC lives on a system subnet, so that's where PocketIC can create a canister with its ID.
+    let pic = PocketIcBuilder::new()
+        .with_sns_subnet()
+        .with_application_subnet()
+        .build();
+
+    let backend = pic.create_canister_with_settings(Some(controller()), None);
+    pic.add_cycles(backend, 10_000_000_000_000);
+
+    let ledger = pic.create_canister();
+    pic.add_cycles(ledger, 1_000_000_000_000);
+    let initial_balances = vec![(
+        Account {
+            owner: backend,
+            subaccount: None,
+        },
+        Nat::from(INITIAL_BALANCE),
+    )];
+    pic.install_canister(
+        ledger,
+        wasm("MOCK_LEDGER_WASM"),
+        candid::encode_one(initial_balances).unwrap(),
+        None,
+    );
+
+    let xrc = pic
+        .create_canister_with_id(None, None, Principal::from_text(XRC_CANISTER_ID).unwrap())
+        .expect("Failed to create the XRC");
+    pic.add_cycles(xrc, 1_000_000_000_000);
+    pic.install_canister(xrc, wasm("MOCK_XRC_WASM"), candid::encode_args(()).unwrap(), None);
+
+    let args = Some(InitArgs {
+        public_reads: Some(true),
+    });
+    pic.install_canister(
+        backend,
+        wasm("BACKEND_WASM"),
+        candid::encode_one(args).unwrap(),
+        Some(controller()),
+    );
+
+    Env {
+        pic,
+        backend,
+        ledger,
+    }
+}
+
+/// Installs the counter canister from `src/counter` next to the backend.
+fn install_counter(env: &Env) -> Principal {
+    let counter = env.pic.create_canister();
+    env.pic.add_cycles(counter, 1_000_000_000_000);
+    env.pic
+        .install_canister(counter, wasm("COUNTER_WASM"), candid::encode_args(()).unwrap(), None);
+    counter
+}
+
+fn recipient() -> Account {
+    Account {
+        owner: user(),
+        subaccount: None,
+    }
+}
+
+impl Env {
+    fn icrc1_transfer(&self, amount: u64) -> Result<(), String> {
+        let (result,): (Result<(), String>,) = update_candid_as(
+            &self.pic,
+            self.backend,
+            controller(),
+            "icrc1_transfer",
+            (self.ledger, recipient(), Nat::from(amount), None::<Nat>),
+        )
+        .expect("The call to the backend failed");
+        result
+    }
+
+    fn icrc1_transfer_detailed(&self, amount: u64) -> Result<Nat, TransferError> {
+        let (result,): (Result<Nat, TransferError>,) = update_candid_as(
+            &self.pic,
+            self.backend,
+            controller(),
+            "icrc1_transfer_detailed",
+            (self.ledger, recipient(), Nat::from(amount)),
+        )
+        .expect("The call to the backend failed");
+        result
+    }
+
+    fn balance_of(&self, account: Account) -> Nat {
+        let (balance,): (Nat,) = query_candid(&self.pic, self.ledger, "icrc1_balance_of", (account,))
+            .expect("The call to the ledger failed");
+        balance
+    }
+
+    fn my_controllers(&self) -> Result<Vec<Principal>, String> {
+        let (result,): (Result<Vec<Principal>, String>,) =
+            update_candid_as(&self.pic, self.backend, user(), "my_controllers", ())
+                .expect("The call to the backend failed");
+        result
+    }
+}
+
+#[test]
+#[ignore = "needs PocketIC and the wasm modules, see the module docs"]
+fn test_icrc1_transfer_returns_block_index() {
+    let env = setup();
+
+    assert_eq!(env.icrc1_transfer(100_000), Ok(()));
+    // The second transfer lands in the second block.
+    assert_eq!(env.icrc1_transfer_detailed(100_000), Ok(Nat::from(1_u64)));
+}
+
+#[test]
+#[ignore = "needs PocketIC and the wasm modules, see the module docs"]
+fn test_icrc1_transfer_reports_ledger_error() {
+    let env = setup();
+
+    let error = env.icrc1_transfer(INITIAL_BALANCE).unwrap_err();
+    assert!(error.contains("InsufficientFunds"), "{}", error);
+    assert_eq!(
+        env.icrc1_transfer_detailed(INITIAL_BALANCE),
+        Err(TransferError::InsufficientFunds {
+            balance: Nat::from(INITIAL_BALANCE)
+        })
+    );
+    // Just enough to cover the fee as well.
+    assert_eq!(env.icrc1_transfer_detailed(INITIAL_BALANCE - FEE), Ok(Nat::from(0_u64)));
+}
+
+#[test]
+#[ignore = "needs PocketIC and the wasm modules, see the module docs"]
+fn test_concurrent_transfers_dont_spend_the_balance_twice() {
+    let env = setup();
+    // The balance covers one transfer and its fee, but not two.
+    let amount = INITIAL_BALANCE / 2 + FEE;
+    let args = candid::encode_args((env.ledger, recipient(), Nat::from(amount), None::<Nat>)).unwrap();
+
+    // Submitting both calls before awaiting either puts them in the same round, so the second
+    // one starts while the first is waiting for the ledger's response. The ledger executes
+    // transfers one at a time anyway, so this only checks the outcome: exactly one transfer goes
+    // through. That the backend's transfer lock holds the second transfer back until the first one
+    // is done is checked by `test_concurrent_icrc1_transfers_reach_the_ledger_one_at_a_time`.
+    let first = env
+        .pic
+        .submit_call(env.backend, controller(), "icrc1_transfer", args.clone())
+        .expect("Failed to submit the first transfer");
+    let second = env
+        .pic
+        .submit_call(env.backend, controller(), "icrc1_transfer", args)
+        .expect("Failed to submit the second transfer");
+    let results: Vec<Result<(), String>> = [first, second]
+        .into_iter()
+        .map(|id| {
+            let reply = env.pic.await_call(id).expect("The call to the backend failed");
+            candid::decode_one(&reply).unwrap()
+        })
+        .collect();
+
+    assert_eq!(results.iter().filter(|r| r.is_ok()).count(), 1, "{:?}", results);
+    let error = results.iter().find_map(|r| r.clone().err()).unwrap();
+    assert!(error.contains("InsufficientFunds"), "{}", error);
+    assert_eq!(env.balance_of(recipient()), Nat::from(amount));
+    assert_eq!(
+        env.balance_of(Account {
+            owner: env.backend,
+            subaccount: None,
+        }),
+        Nat::from(INITIAL_BALANCE - amount - FEE)
+    );
+}
+
+#[test]
+#[ignore = "needs PocketIC and the wasm modules, see the module docs"]
+fn test_my_controllers() {
+    let env = setup();
+    // Only `controller()` controls the backend, which isn't allowed to read its own status.
+    let error = env.my_controllers().unwrap_err();
+    assert!(error.contains("isn't one of them"), "{}", error);
+
+    let controllers = vec![controller(), env.backend];
+    env.pic
+        .set_controllers(env.backend, Some(controller()), controllers.clone())
+        .expect("Failed to set the controllers");
+
+    let mut returned = env.my_controllers().unwrap();
+    returned.sort();
+    let mut expected = controllers;
+    expected.sort();
+    assert_eq!(returned, expected);
+}
+
+#[test]
+#[ignore = "needs PocketIC and the wasm modules, see the module docs"]
+fn test_get_exchange_rate() {
+    let env = setup();
+    let icp = Asset {
+        symbol: "ICP".to_string(),
+        class: AssetClass::Cryptocurrency,
+    };
+    let usd = Asset {
+        symbol: "USD".to_string(),
+        class: AssetClass::FiatCurrency,
+    };
+
+    let (result,): (Result<(u64, u32), String>,) =
+        update_candid_as(&env.pic, env.backend, user(), "get_exchange_rate", (icp, usd))
+            .expect("The call to the backend failed");
+
+    assert_eq!(result, Ok((12_345_000_000, 9)));
+}
+
+#[test]
+#[ignore = "needs PocketIC and the wasm modules, see the module docs"]
+fn test_proxy_get_through_a_relay() {
+    let env = setup();
+    let counter = install_counter(&env);
+    let relay = env.pic.create_canister();
+    env.pic.add_cycles(relay, 1_000_000_000_000);
+    env.pic
+        .install_canister(relay, wasm("MOCK_RELAY_WASM"), candid::encode_args(()).unwrap(), None);
+    let () = update_candid_as(&env.pic, counter, user(), "set", (Nat::from(42_u64),))
+        .expect("Failed to set the counter");
+    let proxy_get = |via: Principal, counter: Principal| {
+        let (result,): (Result<Nat, String>,) =
+            update_candid_as(&env.pic, env.backend, user(), "proxy_get", (via, counter))
+                .expect("The call to the backend failed");
+        result
+    };
+
+    assert_eq!(proxy_get(relay, counter), Ok(Nat::from(42_u64)));
+
+    // The relay's call fails, but the relay still replies to us.
+    let missing = Principal::from_slice(&[0xff; 10]);
+    let error = proxy_get(relay, missing).unwrap_err();
+    assert!(error.starts_with(&format!("Relay {} failed to call get on {}", relay, missing)), "{}", error);
+    // Our own call fails: the counter has no `relay` method.
+    let error = proxy_get(counter, counter).unwrap_err();
+    assert!(error.starts_with(&format!("Error calling relay {}", counter)), "{}", error);
+}
+
+#[test]
+#[ignore = "needs PocketIC and the wasm modules, see the module docs"]
+fn test_anonymous_mutations_are_rejected() {
+    let env = setup();
+    let counter = install_counter(&env);
+    let anonymous = Principal::anonymous();
+    let get = || {
+        let (value,): (Nat,) = query_candid(&env.pic, counter, "get", ()).expect("Failed to get the counter");
+        value
+    };
+
+    let one = Nat::from(1_u64);
+    let rejected = [
+        env.pic.update_call(counter, anonymous, "set", candid::encode_args((&one,)).unwrap()),
+        env.pic.update_call(counter, anonymous, "set_checked", candid::encode_args((&one,)).unwrap()),
+        env.pic.update_call(counter, anonymous, "increment", candid::encode_args(()).unwrap()),
+        env.pic.update_call(counter, anonymous, "increment_by", candid::encode_args((&one,)).unwrap()),
+        env.pic.update_call(
+            counter,
+            anonymous,
+            "compare_and_swap",
+            candid::encode_args((Nat::from(0_u64), &one)).unwrap(),
+        ),
+    ];
+    for result in rejected {
+        let reject = result.expect_err("An anonymous call went through");
+        assert!(reject.reject_message.contains("Anonymous callers"), "{:?}", reject);
+    }
+    assert_eq!(get(), Nat::from(0_u64));
+    // The same call from an authenticated user goes through.
+    let () = update_candid_as(&env.pic, counter, user(), "increment", ()).expect("Failed to increment");
+    assert_eq!(get(), one);
+
+    // The backend turns a transfer away before calling the ledger unless a controller asks for
+    // it, whether the caller is anonymous or a signed-in user.
+    let args = candid::encode_args((env.ledger, recipient(), Nat::from(100_000_u64), None::<Nat>)).unwrap();
+    for caller in [anonymous, user()] {
+        assert!(env.pic.update_call(env.backend, caller, "icrc1_transfer", args.clone()).is_err());
+    }
+    assert_eq!(env.balance_of(recipient()), Nat::from(0_u64));
+}
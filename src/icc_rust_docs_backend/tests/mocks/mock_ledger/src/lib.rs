@@ -0,0 +1,66 @@
+//! A minimal ICRC-1 ledger for the integration tests: it keeps balances and hands out block
+//! indices, but has no archive, no deduplication, and no minting account.
+//!
+//! Its replies depend only on the calls it gets and their order: it reads neither the time nor
+//! any randomness, so a test that knows the order of its calls can predict every balance and block
+//! index.
+
+use candid::Nat;
+use ic_cdk::api::msg_caller;
+use icrc_ledger_types::icrc1::account::Account;
+use icrc_ledger_types::icrc1::transfer::{TransferArg, TransferError};
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+
+/// The fee the ledger charges for every transfer.
+const FEE: u64 = 10_000;
+
+thread_local! {
+    static BALANCES: RefCell<BTreeMap<Account, Nat>> = RefCell::new(BTreeMap::new());
+    static NEXT_BLOCK: Cell<u64> = const { Cell::new(0) };
+}
+
+/// Starts the ledger with the given balances.
+#[ic_cdk::init]
+fn init(initial_balances: Vec<(Account, Nat)>) {
+    BALANCES.with(|b| b.borrow_mut().extend(initial_balances));
+}
+
+#[ic_cdk::query]
+fn icrc1_fee() -> Nat {
+    Nat::from(FEE)
+}
+
+#[ic_cdk::query]
+fn icrc1_balance_of(account: Account) -> Nat {
+    balance(&account)
+}
+
+#[ic_cdk::update]
+fn icrc1_transfer(arg: TransferArg) -> Result<Nat, TransferError> {
+    let fee = Nat::from(FEE);
+    if arg.fee.as_ref().is_some_and(|f| *f != fee) {
+        return Err(TransferError::BadFee { expected_fee: fee });
+    }
+    let from = Account {
+        owner: msg_caller(),
+        subaccount: arg.from_subaccount,
+    };
+    let from_balance = balance(&from);
+    if from_balance < arg.amount.clone() + fee.clone() {
+        return Err(TransferError::InsufficientFunds {
+            balance: from_balance,
+        });
+    }
+    BALANCES.with(|b| {
+        let mut balances = b.borrow_mut();
+        balances.insert(from, from_balance - arg.amount.clone() - fee);
+        *balances.entry(arg.to).or_insert_with(|| Nat::from(0_u64)) += arg.amount;
+    });
+    let block_index = NEXT_BLOCK.with(|n| n.replace(n.get() + 1));
+    Ok(Nat::from(block_index))
+}
+
+fn balance(account: &Account) -> Nat {
+    BALANCES.with(|b| b.borrow().get(account).cloned().unwrap_or_else(|| Nat::from(0_u64)))
+}
@@ -0,0 +1,24 @@
+//! A relay for the integration tests: it makes any call it's asked to, under its own principal,
+//! and passes the raw reply back, or the failure as an error.
+
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::call::Call;
+
+/// The same as the backend's `proxy::RelayRequest`.
+#[derive(CandidType, Deserialize)]
+struct RelayRequest {
+    target: Principal,
+    method: String,
+    args: Vec<u8>,
+}
+
+// Replying with the failure, rather than trapping or rejecting, lets the caller tell a failure
+// of our call apart from a failure of its call to us.
+#[ic_cdk::update]
+async fn relay(request: RelayRequest) -> Result<Vec<u8>, String> {
+    Call::bounded_wait(request.target, &request.method)
+        .with_raw_args(&request.args)
+        .call_raw()
+        .await
+        .map_err(|e| format!("{:?}", e))
+}
@@ -0,0 +1,38 @@
+//! A minimal exchange rate canister for the integration tests: it charges the XRC's fee and
+//! answers every request with the same rate.
+
+use ic_cdk::api::{msg_cycles_accept, msg_cycles_available};
+use ic_xrc_types::{
+    ExchangeRate, ExchangeRateError, ExchangeRateMetadata, GetExchangeRateRequest,
+    GetExchangeRateResult,
+};
+
+/// The fee the real XRC charges per request.
+const FEE: u128 = 1_000_000_000;
+
+/// The rate we report, with `DECIMALS` decimals: 12.345.
+const RATE: u64 = 12_345_000_000;
+const DECIMALS: u32 = 9;
+
+#[ic_cdk::update]
+fn get_exchange_rate(request: GetExchangeRateRequest) -> GetExchangeRateResult {
+    if msg_cycles_available() < FEE {
+        return Err(ExchangeRateError::NotEnoughCycles);
+    }
+    msg_cycles_accept(FEE);
+    Ok(ExchangeRate {
+        base_asset: request.base_asset,
+        quote_asset: request.quote_asset,
+        timestamp: request.timestamp.unwrap_or(1_700_000_000),
+        rate: RATE,
+        metadata: ExchangeRateMetadata {
+            decimals: DECIMALS,
+            base_asset_num_queried_sources: 1,
+            base_asset_num_received_rates: 1,
+            quote_asset_num_queried_sources: 1,
+            quote_asset_num_received_rates: 1,
+            standard_deviation: 0,
+            forex_timestamp: None,
+        },
+    })
+}
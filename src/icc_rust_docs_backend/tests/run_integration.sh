@@ -0,0 +1,26 @@
+#!/bin/sh
+# Builds the backend and the mock canisters, and runs the PocketIC integration tests against them.
+# Expects the PocketIC server binary in POCKET_IC_BIN.
+set -e
+cd "$(dirname "$0")/.."
+
+if [ -z "$POCKET_IC_BIN" ]; then
+    echo "Set POCKET_IC_BIN to the PocketIC server binary."
+    exit 1
+fi
+
+build() {
+    cargo build --target wasm32-unknown-unknown --release --manifest-path "$1/Cargo.toml"
+}
+build .
+build tests/mocks/mock_ledger
+build tests/mocks/mock_xrc
+build tests/mocks/mock_relay
+build ../counter
+
+export BACKEND_WASM="$(cargo metadata --format-version 1 --no-deps | jq -r .target_directory)/wasm32-unknown-unknown/release/icc_rust_docs_backend.wasm"
+export MOCK_LEDGER_WASM="$PWD/tests/mocks/mock_ledger/target/wasm32-unknown-unknown/release/mock_ledger.wasm"
+export MOCK_XRC_WASM="$PWD/tests/mocks/mock_xrc/target/wasm32-unknown-unknown/release/mock_xrc.wasm"
+export MOCK_RELAY_WASM="$PWD/tests/mocks/mock_relay/target/wasm32-unknown-unknown/release/mock_relay.wasm"
+export COUNTER_WASM="$(cargo metadata --format-version 1 --no-deps --manifest-path ../counter/Cargo.toml | jq -r .target_directory)/wasm32-unknown-unknown/release/counter.wasm"
+cargo test --test integration -- --ignored
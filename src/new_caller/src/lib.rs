@@ -1,63 +1,94 @@
 use candid::{Nat, Principal};
 use ic_cdk::api::management_canister::ecdsa::SignWithEcdsaResponse;
 use ic_cdk::call::{Call, CallError};
-use ic_cdk::api::time;
+use ic_cdk::api::{msg_caller, time};
 use ic_cdk::management_canister::{SignWithEcdsaArgs, EcdsaKeyId, EcdsaCurve};
 use ic_cdk_macros::update;
 use sha2::{Sha256, Digest};
 
+/// Guard that rejects calls from the anonymous principal (`2vxsx-fae`).
+// Ingress messages can be sent anonymously, without any signature. Calls from other canisters
+// always carry the calling canister's principal, so they are never anonymous. Guards run before
+// the method body, so a rejected call has no effect. Each canister in this repository is a
+// standalone example with its own copy of this guard; keep the copies identical.
+fn reject_anonymous() -> Result<(), String> {
+    check_not_anonymous(msg_caller())
+}
+
+fn check_not_anonymous(caller: Principal) -> Result<(), String> {
+    if caller == Principal::anonymous() {
+        Err("Anonymous callers are not allowed to call this method".to_string())
+    } else {
+        Ok(())
+    }
+}
+
+/// The calls that `increment_twice` makes to the counter canister.
+// Going through a trait lets the tests stand in for the counter canister, and control when each
+// call executes relative to the others.
+trait Counter {
+    async fn get(&self) -> Nat;
+    async fn inc(&self);
+}
+
+// To make a call, you must provide the principal (i.e., ID) of the canister you're calling, and
+// the method name that you're calling.
+impl Counter for Principal {
+    async fn get(&self) -> Nat {
+        // We must choose between bounded and unbounded wait calls. Unbounded wait calls have a
+        // simple failure semantics, so we start with them.
+        Call::new(*self, "get")
+            // `Call` follows the builder pattern; we can customize call options before we
+            // finalize the call by issuing the `call()` method. We don't need to set any options
+            // for `get` so we just issue `call()`.
+            // Call automatically deserializes a Candid-encoded response into its type argument.
+            // Here, we use the turbofish syntax to specify that we expect a Candid Nat (i.e., a
+            // non-negative integer) as the response.
+            .call::<Nat>()
+            // The `call` method only creates a Future, but it doesn't actually run it. To issue
+            // the call, you must `await` it.
+            .await
+            // Calls can *always* fail. Robust applications must handle failures properly, but for
+            // this first example we just panic if an error happens.
+            .expect("Failed to get the value. Bail out")
+    }
+
+    async fn inc(&self) {
+        // Following the exact same pattern, we can increment the counter. An alternative pattern
+        // to turbofish is to specify the type of the variable being assigned.
+        let _: () = Call::new(*self, "inc")
+            .call()
+            .await
+            .expect("Failed to increment the value. Bail out");
+    }
+}
+
 // When calling other canisters:
 // 1. The simplest is to mark your function as `update`. Then you can always call any public
 //    endpoint on any other canister.
 // 2. Mark the function as `async`. Then you can use the `Call` API to call other canisters.
 // We expect the caller to provide the principal (i.e., ID) of the counter canister.
-#[update]
+#[update(guard = "reject_anonymous")]
 pub async fn increment_twice(counter: Principal) -> (Nat, Nat) {
-    // Let's check the initial value of the counter
-    // To make a call, you must provide the principal (i.e., ID) of the canister you're
-    // calling, and the method name that you're calling. We'll ask our caller to provide the
-    // principal of the counter canister.
-    // We must choose between bounded and unbounded wait calls. Unbounded wait calls have a simple
-    // failure semantics, so we start with them.
-    let initial = Call::new(counter, "get")
-        // `Call` follows the builder pattern; we can customize call options before we finalize
-        // the call by issuing the `call()` method. We don't need to set any options for `get` so we
-        // just issue `call()`.
-        // Call automatically deserializes a Candid-encoded response into its type argument. Here,
-        // we use the turbofish syntax to specify that we expect a Candid Nat (i.e., a non-negative
-        // integer) as the response.
-        .call::<Nat>()
-        // The `call` method only creates a Future, but it doesn't actually run it. To issue the
-        // call, you must `await` it.
-        .await
-        // Calls can *always* fail. Robust applications must handle failures properly, but for this
-        // first example we just panic if an error happens.
-        .expect("An error happened during the call. Bail out in this simple example");
-
-    // Following the exact same pattern, we can increment the counter.
-    let _ = Call::new(counter, "inc")
-        .call::<()>()
-        .await
-        .expect("Error in the first increment. Bail out");
-
-    // An alternative pattern to turbofish is to specify the type of the variable being assigned.
-    let _: () = Call::new(counter, "inc")
-        .call()
-        .await
-        .expect("Failed in the second increment. Bail out");
-
-    let end: Nat = Call::new(counter, "get")
-        .call()
-        .await
-        .expect("Failed to get the final value. Bail out");
+    increment_twice_on(&counter).await
+}
+
+async fn increment_twice_on<C: Counter>(counter: &C) -> (Nat, Nat) {
+    // Let's check the initial value of the counter, increment it twice, and check it again.
+    let initial = counter.get().await;
+    counter.inc().await;
+    counter.inc().await;
+    let end = counter.get().await;
 
     // It looks like we should be able to assert:
     // assert!(final == initial + 2);
-    // But this is *NOT* guaranteed to hold!
+    // But this is *NOT* guaranteed to hold! Every `await` lets other messages run, both on our
+    // canister and on the counter, including other calls to `increment_twice`. Their increments
+    // can land between our `get`s. The tests below show this happening.
     (initial, end)
 }
 
-#[update]
+#[update(guard = "reject_anonymous")]
 pub async fn sign_message(message: String) -> Result<String, String> {
     let message_hash = Sha256::digest(&message).to_vec();
 
@@ -77,4 +108,67 @@ pub async fn sign_message(message: String) -> Result<String, String> {
         Ok(signature ) => Ok(hex::encode(signature.signature)),
         Err(err) => Err(format!("Error signing message: {:?}", err)),
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use std::cell::RefCell;
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    // Returns `Pending` once before completing, like a call waiting for its reply.
+    struct YieldNow(bool);
+
+    impl Future for YieldNow {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            if self.0 {
+                Poll::Ready(())
+            } else {
+                self.0 = true;
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+    }
+
+    // A counter canister in memory. Each call is executed when its "reply" arrives, after the
+    // caller yielded once, so that concurrent callers take turns deterministically.
+    struct MockCounter(RefCell<Nat>);
+
+    impl Counter for MockCounter {
+        async fn get(&self) -> Nat {
+            YieldNow(false).await;
+            self.0.borrow().clone()
+        }
+
+        async fn inc(&self) {
+            YieldNow(false).await;
+            *self.0.borrow_mut() += 1_u32;
+        }
+    }
+
+    #[test]
+    fn test_increment_twice_alone() {
+        let counter = MockCounter(RefCell::new(Nat::from(5_u32)));
+        let (initial, end) = block_on(increment_twice_on(&counter));
+        assert_eq!(end - initial, Nat::from(2_u32));
+    }
+
+    #[test]
+    fn test_concurrent_increment_twice_interleave() {
+        let counter = MockCounter(RefCell::new(Nat::from(0_u32)));
+        // `join!` polls the two calls in turn, so each of their calls to the counter executes
+        // between two calls of the other.
+        let ((initial_a, end_a), (initial_b, end_b)) = block_on(async {
+            futures::join!(increment_twice_on(&counter), increment_twice_on(&counter))
+        });
+
+        let deltas = [end_a - initial_a, end_b - initial_b];
+        assert!(deltas.iter().any(|d| *d > Nat::from(2_u32)), "{:?}", deltas);
+        assert_eq!(*counter.0.borrow(), Nat::from(4_u32));
+    }
+}
@@ -0,0 +1,134 @@
+//! Reading paginated IC APIs, shared by the canisters in this repository.
+
+use std::future::Future;
+
+/// Reads all pages of a paginated API and returns their items in order. `fetch_page` is given
+/// the continuation of the previous page (`None` for the first page); `extract_continuation`
+/// returns the continuation for the next page, or `None` after the last page. Fails if the source
+/// has more than `max_pages` pages.
+// Paginated IC APIs (ICRC-3 archives, `bitcoin_get_utxos`, ICRC-103 allowances, ...) differ in
+// how they mark the next page, but are all read the same way. The cap keeps a source that never
+// stops returning continuations from keeping us busy forever.
+pub async fn read_paginated<C, P, T, F, Fut>(
+    fetch_page: F,
+    mut extract_items: impl FnMut(P) -> Result<Vec<T>, String>,
+    extract_continuation: impl FnMut(&P) -> Result<Option<C>, String>,
+    max_pages: u32,
+) -> Result<Vec<T>, String>
+where
+    F: FnMut(Option<C>) -> Fut,
+    Fut: Future<Output = Result<P, String>>,
+{
+    let mut items = vec![];
+    visit_pages(
+        fetch_page,
+        |page| {
+            items.extend(extract_items(page)?);
+            Ok(())
+        },
+        extract_continuation,
+        max_pages,
+    )
+    .await?;
+    Ok(items)
+}
+
+/// Like `read_paginated`, but hands each page to `visit` as soon as it arrives instead of
+/// collecting the items, so that only one page is kept in the heap at a time.
+pub async fn visit_pages<C, P, F, Fut>(
+    mut fetch_page: F,
+    mut visit: impl FnMut(P) -> Result<(), String>,
+    mut extract_continuation: impl FnMut(&P) -> Result<Option<C>, String>,
+    max_pages: u32,
+) -> Result<(), String>
+where
+    F: FnMut(Option<C>) -> Fut,
+    Fut: Future<Output = Result<P, String>>,
+{
+    let mut continuation = None;
+    for _ in 0..max_pages {
+        let page = fetch_page(continuation).await?;
+        continuation = extract_continuation(&page)?;
+        visit(page)?;
+        if continuation.is_none() {
+            return Ok(());
+        }
+    }
+    Err(format!("Gave up after reading {} pages", max_pages))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use std::cell::RefCell;
+
+    // A source of the numbers 0 to 3, served two per page, where the continuation is the offset
+    // of the next page.
+    struct Page {
+        items: Vec<u32>,
+        next: Option<usize>,
+    }
+
+    fn fetch(offset: Option<usize>) -> Page {
+        let offset = offset.unwrap_or(0);
+        let end = (offset + 2).min(4);
+        Page {
+            items: (offset as u32..end as u32).collect(),
+            next: (end < 4).then_some(end),
+        }
+    }
+
+    fn read(max_pages: u32, requested: &RefCell<Vec<Option<usize>>>) -> Result<Vec<u32>, String> {
+        block_on(read_paginated(
+            |offset| {
+                requested.borrow_mut().push(offset);
+                async move { Ok(fetch(offset)) }
+            },
+            |page: Page| Ok(page.items),
+            |page: &Page| Ok(page.next),
+            max_pages,
+        ))
+    }
+
+    #[test]
+    fn test_all_pages_are_read() {
+        let requested = RefCell::new(vec![]);
+        assert_eq!(read(10, &requested), Ok(vec![0, 1, 2, 3]));
+        assert_eq!(requested.into_inner(), vec![None, Some(2)]);
+    }
+
+    #[test]
+    fn test_page_cap() {
+        let requested = RefCell::new(vec![]);
+        assert!(read(1, &requested).unwrap_err().contains("1 pages"));
+        assert_eq!(requested.into_inner(), vec![None]);
+    }
+
+    #[test]
+    fn test_errors_are_passed_on() {
+        let result = block_on(read_paginated(
+            |_: Option<()>| async { Err::<Page, _>("rejected".to_string()) },
+            |page: Page| Ok(page.items),
+            |page: &Page| Ok(page.next.map(|_| ())),
+            10,
+        ));
+        assert_eq!(result, Err("rejected".to_string()));
+    }
+
+    #[test]
+    fn test_pages_are_visited_one_by_one() {
+        let mut visited = vec![];
+        let result = block_on(visit_pages(
+            |offset| async move { Ok(fetch(offset)) },
+            |page: Page| {
+                visited.push(page.items);
+                Ok(())
+            },
+            |page: &Page| Ok(page.next),
+            10,
+        ));
+        assert_eq!(result, Ok(()));
+        assert_eq!(visited, vec![vec![0, 1], vec![2, 3]]);
+    }
+}